@@ -0,0 +1,44 @@
+# Bash completion for chsqlar. Source this file, or copy it into
+# /usr/share/bash-completion/completions/chsqlar.
+#
+# Stored file names are completed by asking the archive itself through the hidden
+# `__complete` subcommand.
+
+_chsqlar() {
+    local cur=${COMP_WORDS[COMP_CWORD]}
+    local subcommands="Add List Extract Cat PutBlob GetBlob Stats Info Migrate Orphans Doctor Du
+        Compact Pull Split Merge Verify Cmp Manifest Checksum SetTag GetTags TrainDict Daemon Watch
+        ChunkFile Bench help"
+    local database="" subcommand="" i word
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word=${COMP_WORDS[i]}
+        case $word in
+            --name-encoding|--threads|--memory-limit|--chunk-cache-mb|--store-dir| \
+            --remote|--on-success|--on-failure|--color|--key-file| \
+            --chunk-bits|--chunk-size|--chunker|--max-chunk-bytes|--hash|--hash-bytes| \
+            --page-size|--cache-size|--sync|--auto-vacuum|--journal|--temp-dir)
+                ((i++)) ;;
+            -*) ;;
+            *)
+                if [[ -z $database ]]; then
+                    database=$word
+                elif [[ -z $subcommand ]]; then
+                    subcommand=$word
+                fi ;;
+        esac
+    done
+
+    if [[ -z $database ]]; then
+        COMPREPLY=($(compgen -f -- "$cur"))
+    elif [[ -z $subcommand ]]; then
+        COMPREPLY=($(compgen -W "$subcommands" -- "$cur"))
+    elif [[ $cur != -* ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($(chsqlar "$database" __complete "$subcommand" "$cur" 2>/dev/null))
+        compopt -o nospace 2>/dev/null
+        [[ ${#COMPREPLY[@]} -eq 1 && ${COMPREPLY[0]} != */ ]] && compopt +o nospace 2>/dev/null
+    fi
+}
+
+complete -F _chsqlar chsqlar
@@ -0,0 +1,43 @@
+# Fish completion for chsqlar. Copy this file into ~/.config/fish/completions/.
+#
+# Stored file names are completed by asking the archive itself through the hidden
+# `__complete` subcommand.
+
+function __chsqlar_positionals
+    set -l words (commandline -opc)
+    set -l positionals
+    set -l skip 0
+    for word in $words[2..-1]
+        if test $skip -eq 1
+            set skip 0
+            continue
+        end
+        switch $word
+            case --name-encoding --threads --memory-limit --chunk-cache-mb --store-dir \
+                --remote --on-success --on-failure --color --key-file \
+                --chunk-bits --chunk-size --chunker --max-chunk-bytes --hash --hash-bytes \
+                --page-size --cache-size --sync --auto-vacuum --journal --temp-dir
+                set skip 1
+            case '-*'
+            case '*'
+                set -a positionals $word
+        end
+    end
+    printf '%s\n' $positionals
+end
+
+function __chsqlar_needs_subcommand
+    test (count (__chsqlar_positionals)) -eq 1
+end
+
+function __chsqlar_stored_names
+    set -l positionals (__chsqlar_positionals)
+    test (count $positionals) -ge 2; or return
+    chsqlar $positionals[1] __complete $positionals[2] (commandline -ct) 2>/dev/null
+end
+
+complete -c chsqlar -f
+complete -c chsqlar -n 'test (count (__chsqlar_positionals)) -eq 0' -F
+complete -c chsqlar -n __chsqlar_needs_subcommand \
+    -a 'Add List Extract Cat PutBlob GetBlob Stats Info Migrate Orphans Doctor Du Compact Pull Split Merge Verify Cmp Manifest Checksum SetTag GetTags TrainDict Daemon Watch ChunkFile Bench'
+complete -c chsqlar -n 'test (count (__chsqlar_positionals)) -ge 2' -a '(__chsqlar_stored_names)'
@@ -0,0 +1,509 @@
+//! The interactive browser behind the `tui` feature. Reads go through the same functions as
+//! the CLI; only extracting and removing write anything.
+
+use crate::{
+    create_file_safe, decode_name, get_file, human_size, refuse_filtered, remove_files,
+    remove_loose_chunks, restore_mtime, split_chunk_list, stays_inside, write_file_to, write_zeros,
+    ChunkCache, NameEncoding, SqliteDatabase, StoredReader,
+};
+use failure::{format_err, Error};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use rusqlite::types::Value;
+use rusqlite::NO_PARAMS;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How much of a file Enter shows.
+const PREVIEW_BYTES: u64 = 64 * 1024;
+
+struct Entry {
+    name: PathBuf,
+    size: i64,
+    chunks: Vec<String>,
+    inline: i64,
+}
+
+enum Screen {
+    Tree,
+    Preview {
+        title: String,
+        lines: Vec<String>,
+        scroll: u16,
+    },
+    Extract {
+        input: String,
+    },
+    ConfirmRemove,
+}
+
+pub(crate) struct Browser<'a> {
+    db: &'a mut SqliteDatabase,
+    encoding: NameEncoding,
+    cache: &'a ChunkCache<'a>,
+    files: Vec<Entry>,
+    chunk_sizes: HashMap<String, u64>,
+    /// The directory being shown, empty for the root.
+    dir: PathBuf,
+    /// The children of `dir`, each with whether it is a directory.
+    rows: Vec<(PathBuf, bool)>,
+    list: ListState,
+    marked: BTreeSet<PathBuf>,
+    screen: Screen,
+    message: String,
+}
+
+pub(crate) fn browse_cmd(
+    db: &mut SqliteDatabase,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    let mut browser = Browser::new(db, encoding, cache)?;
+
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal);
+    ratatui::restore();
+
+    result
+}
+
+impl<'a> Browser<'a> {
+    /// A browser showing the root of the archive on `db`.
+    pub(crate) fn new(
+        db: &'a mut SqliteDatabase,
+        encoding: NameEncoding,
+        cache: &'a ChunkCache<'a>,
+    ) -> Result<Self, Error> {
+        let mut browser = Browser {
+            db,
+            encoding,
+            cache,
+            files: Vec::new(),
+            chunk_sizes: HashMap::new(),
+            dir: PathBuf::new(),
+            rows: Vec::new(),
+            list: ListState::default(),
+            marked: BTreeSet::new(),
+            screen: Screen::Tree,
+            message: String::new(),
+        };
+        browser.load()?;
+
+        Ok(browser)
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        let trans = self.db.connection.transaction()?;
+
+        self.chunk_sizes.clear();
+        {
+            let mut stmt =
+                trans.prepare("SELECT hash, COALESCE(size, length(data)) FROM chunks")?;
+            for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+                let (hash, size): (String, Option<i64>) = row?;
+                self.chunk_sizes.insert(hash, size.unwrap_or(0) as u64);
+            }
+        }
+
+        self.files.clear();
+        {
+            let mut stmt =
+                trans.prepare("SELECT name, size, chunks, length(inline_data) FROM files")?;
+            for row in stmt.query_map(NO_PARAMS, |row| {
+                let name: Value = row.get(0);
+                let size: i64 = row.get(1);
+                let chunks: String = row.get(2);
+                let inline: Option<i64> = row.get(3);
+                (name, size, chunks, inline)
+            })? {
+                let (name, size, chunks, inline) = row?;
+                self.files.push(Entry {
+                    name: decode_name(name, self.encoding)?,
+                    size,
+                    chunks: split_chunk_list(&chunks),
+                    inline: inline.unwrap_or(0),
+                });
+            }
+        }
+        self.files.sort_by(|a, b| a.name.cmp(&b.name));
+        drop(trans);
+
+        // Climb back out of directories that no longer have anything in them.
+        while !self.dir.as_os_str().is_empty() && self.under(&self.dir).next().is_none() {
+            self.dir.pop();
+        }
+        self.show_dir(None);
+
+        Ok(())
+    }
+
+    fn run(&mut self, terminal: &mut ratatui::DefaultTerminal) -> Result<(), Error> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key.code,
+                _ => continue,
+            };
+
+            let screen = std::mem::replace(&mut self.screen, Screen::Tree);
+            self.screen = match screen {
+                Screen::Tree => match key {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Up | KeyCode::Char('k') => {
+                        self.list.select_previous();
+                        Screen::Tree
+                    }
+                    KeyCode::Down | KeyCode::Char('j') => {
+                        self.list.select_next();
+                        Screen::Tree
+                    }
+                    KeyCode::Enter | KeyCode::Right => self.open(),
+                    KeyCode::Left | KeyCode::Backspace => {
+                        if !self.dir.as_os_str().is_empty() {
+                            let from = self.dir.clone();
+                            self.dir.pop();
+                            self.show_dir(Some(&from));
+                        }
+                        Screen::Tree
+                    }
+                    KeyCode::Char('e') if self.selected().is_some() => Screen::Extract {
+                        input: ".".to_owned(),
+                    },
+                    KeyCode::Char('d') => {
+                        if let Some((path, _)) = self.selected() {
+                            let path = path.clone();
+                            if !self.marked.remove(&path) {
+                                self.marked.insert(path);
+                            }
+                            self.list.select_next();
+                        }
+                        Screen::Tree
+                    }
+                    KeyCode::Char('x') if !self.marked.is_empty() => Screen::ConfirmRemove,
+                    _ => Screen::Tree,
+                },
+                Screen::Preview {
+                    title,
+                    lines,
+                    scroll,
+                } => {
+                    let scroll = match key {
+                        KeyCode::Up | KeyCode::Char('k') => scroll.saturating_sub(1),
+                        KeyCode::Down | KeyCode::Char('j') => scroll.saturating_add(1),
+                        KeyCode::PageUp => scroll.saturating_sub(20),
+                        KeyCode::PageDown => scroll.saturating_add(20),
+                        KeyCode::Char('q') | KeyCode::Esc | KeyCode::Left => continue,
+                        _ => scroll,
+                    };
+                    let scroll = scroll.min(lines.len().saturating_sub(1) as u16);
+                    Screen::Preview {
+                        title,
+                        lines,
+                        scroll,
+                    }
+                }
+                Screen::Extract { mut input } => match key {
+                    KeyCode::Esc => Screen::Tree,
+                    KeyCode::Enter => {
+                        self.message = match self.extract(Path::new(&input)) {
+                            Ok(n) => format!("extracted {} files to {}", n, input),
+                            Err(e) => format!("extract failed: {}", e),
+                        };
+                        Screen::Tree
+                    }
+                    KeyCode::Backspace => {
+                        input.pop();
+                        Screen::Extract { input }
+                    }
+                    KeyCode::Char(c) => {
+                        input.push(c);
+                        Screen::Extract { input }
+                    }
+                    _ => Screen::Extract { input },
+                },
+                Screen::ConfirmRemove => match key {
+                    KeyCode::Char('y') => {
+                        self.message = match self.remove() {
+                            Ok((files, chunks)) => {
+                                format!("removed {} files and {} chunks", files, chunks)
+                            }
+                            Err(e) => format!("remove failed: {}", e),
+                        };
+                        Screen::Tree
+                    }
+                    KeyCode::Char('n') | KeyCode::Esc => Screen::Tree,
+                    _ => Screen::ConfirmRemove,
+                },
+            };
+        }
+    }
+
+    /// The stored files at or below `path`.
+    fn under<'b>(&'b self, path: &'b Path) -> impl Iterator<Item = &'b Entry> + 'b {
+        self.files.iter().filter(move |f| f.name.starts_with(path))
+    }
+
+    fn selected(&self) -> Option<&(PathBuf, bool)> {
+        self.list.selected().and_then(|i| self.rows.get(i))
+    }
+
+    /// Lists the children of `self.dir`, directories first, selecting `select` if given.
+    fn show_dir(&mut self, select: Option<&Path>) {
+        let mut children = BTreeSet::new();
+        for f in self.under(&self.dir) {
+            let rest = f.name.strip_prefix(&self.dir).unwrap_or(&f.name);
+            let mut components = rest.components();
+            if let Some(first) = components.next() {
+                let is_dir = components.next().is_some();
+                children.insert((!is_dir, self.dir.join(first.as_os_str())));
+            }
+        }
+
+        self.rows = children
+            .into_iter()
+            .map(|(is_file, path)| (path, !is_file))
+            .collect();
+        let index = select.and_then(|p| self.rows.iter().position(|(row, _)| row == p));
+        self.list.select(match self.rows.is_empty() {
+            true => None,
+            false => Some(index.unwrap_or(0)),
+        });
+    }
+
+    fn open(&mut self) -> Screen {
+        let (path, is_dir) = match self.selected() {
+            Some(row) => row.clone(),
+            None => return Screen::Tree,
+        };
+
+        if is_dir {
+            self.dir = path;
+            self.show_dir(None);
+            return Screen::Tree;
+        }
+
+        match self.preview(&path) {
+            Ok(lines) => Screen::Preview {
+                title: path.display().to_string(),
+                lines,
+                scroll: 0,
+            },
+            Err(e) => {
+                self.message = format!("preview failed: {}", e);
+                Screen::Tree
+            }
+        }
+    }
+
+    /// Reads the start of `name`, as text if it has no NUL bytes and as a hex dump if it
+    /// does.
+    fn preview(&mut self, name: &Path) -> Result<Vec<String>, Error> {
+        let mut trans = self.db.connection.transaction()?;
+        let f = get_file(&mut trans, name.to_path_buf(), self.encoding)?;
+        let mut data = Vec::new();
+        StoredReader::new(&trans, &f, self.cache)
+            .take(PREVIEW_BYTES)
+            .read_to_end(&mut data)?;
+
+        let mut lines: Vec<String> = if data.contains(&0) {
+            data.chunks(16)
+                .enumerate()
+                .map(|(i, row)| {
+                    let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                    let text: String = row
+                        .iter()
+                        .map(|&b| match b {
+                            0x20..=0x7e => b as char,
+                            _ => '.',
+                        })
+                        .collect();
+                    format!("{:08x}  {:<47}  {}", i * 16, hex.join(" "), text)
+                })
+                .collect()
+        } else {
+            String::from_utf8_lossy(&data)
+                .lines()
+                .map(|line| {
+                    line.replace('\t', "    ")
+                        .chars()
+                        .map(|c| if c.is_control() { '.' } else { c })
+                        .collect()
+                })
+                .collect()
+        };
+
+        if f.size as u64 > PREVIEW_BYTES {
+            lines.push(format!(
+                "(showing the first {} of {})",
+                human_size(PREVIEW_BYTES as f64),
+                human_size(f.size as f64)
+            ));
+        }
+
+        Ok(lines)
+    }
+
+    /// Writes the selection into `dest`, keeping its name and everything below it.
+    fn extract(&mut self, dest: &Path) -> Result<usize, Error> {
+        let (path, _) = self
+            .selected()
+            .cloned()
+            .ok_or_else(|| format_err!("nothing selected"))?;
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let names: Vec<PathBuf> = self.under(&path).map(|f| f.name.clone()).collect();
+
+        let mut trans = self.db.connection.transaction()?;
+        for name in &names {
+            let relative = name.strip_prefix(parent).unwrap_or(name);
+            if !stays_inside(relative) {
+                return Err(format_err!(
+                    "{} would land outside {}",
+                    name.display(),
+                    dest.display()
+                ));
+            }
+
+            let stored = get_file(&mut trans, name.clone(), self.encoding)?;
+            refuse_filtered(&stored, "Browse")?;
+            let mut out = create_file_safe(&dest.join(relative))?;
+            write_file_to(&mut trans, &stored, self.cache, &mut out, write_zeros)?;
+            restore_mtime(&out, &stored)?;
+        }
+
+        Ok(names.len())
+    }
+
+    /// Deletes the marked files and the chunks only they used.
+    fn remove(&mut self) -> Result<(usize, usize), Error> {
+        let names: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|f| self.marked.iter().any(|m| f.name.starts_with(m)))
+            .map(|f| f.name.clone())
+            .collect();
+
+        let mut trans = self.db.connection.transaction()?;
+        let freed = remove_files(&mut trans, &names, self.encoding)?;
+        trans.commit()?;
+        remove_loose_chunks(&self.db.connection, &freed)?;
+
+        self.marked.clear();
+        self.load()?;
+
+        Ok((names.len(), freed.len()))
+    }
+
+    /// Logical and stored bytes of everything at or below `path`, counting each chunk once.
+    pub(crate) fn sizes(&self, path: &Path) -> (usize, u64, u64) {
+        let mut seen = HashSet::new();
+        let (mut count, mut logical, mut stored) = (0, 0, 0);
+        for f in self.under(path) {
+            count += 1;
+            logical += f.size as u64;
+            stored += f.inline as u64;
+            for hash in &f.chunks {
+                if seen.insert(hash) {
+                    stored += self.chunk_sizes.get(hash).cloned().unwrap_or(0);
+                }
+            }
+        }
+
+        (count, logical, stored)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        match &self.screen {
+            Screen::Preview {
+                title,
+                lines,
+                scroll,
+            } => {
+                let text: Vec<Line> = lines.iter().map(|l| Line::raw(l.as_str())).collect();
+                let block = Block::default().borders(Borders::ALL).title(title.as_str());
+                frame.render_widget(Paragraph::new(text).block(block).scroll((*scroll, 0)), main);
+                frame.render_widget(Line::raw("up/down scroll  q back"), status);
+                return;
+            }
+            Screen::ConfirmRemove => {
+                let mut text = vec![
+                    Line::raw("Remove these from the archive? (y/n)"),
+                    Line::raw(""),
+                ];
+                for path in &self.marked {
+                    let (count, logical, _) = self.sizes(path);
+                    text.push(Line::raw(format!(
+                        "  {}  ({} files, {})",
+                        path.display(),
+                        count,
+                        human_size(logical as f64)
+                    )));
+                }
+                let block = Block::default().borders(Borders::ALL).title("remove");
+                frame.render_widget(Paragraph::new(text).block(block), main);
+                frame.render_widget(Line::raw("y remove  n cancel"), status);
+                return;
+            }
+            Screen::Tree | Screen::Extract { .. } => {}
+        }
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|(path, is_dir)| {
+                let name = path.file_name().unwrap_or_else(|| path.as_os_str());
+                let mark = if self.marked.contains(path) { "*" } else { " " };
+                let slash = if *is_dir { "/" } else { "" };
+                let (_, logical, _) = self.sizes(path);
+                ListItem::new(format!(
+                    "{} {:>6}  {}{}",
+                    mark,
+                    human_size(logical as f64),
+                    name.to_string_lossy(),
+                    slash
+                ))
+            })
+            .collect();
+        let title = format!("/{}", self.dir.display());
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, main, &mut self.list);
+
+        let line = match &self.screen {
+            Screen::Extract { input } => {
+                format!("extract to: {}_  (enter to confirm, esc to cancel)", input)
+            }
+            _ => {
+                let summary = match self.selected() {
+                    Some((path, _)) => {
+                        let (count, logical, stored) = self.sizes(path);
+                        format!(
+                            "{} files, {} logical, {} stored",
+                            count,
+                            human_size(logical as f64),
+                            human_size(stored as f64)
+                        )
+                    }
+                    None => "empty".to_owned(),
+                };
+                match self.message.is_empty() {
+                    true => format!(
+                        "{}  |  enter open  e extract  d mark  x remove marked  q quit",
+                        summary
+                    ),
+                    false => format!("{}  |  {}", summary, self.message),
+                }
+            }
+        };
+        frame.render_widget(Line::raw(line), status);
+    }
+}
@@ -0,0 +1,112 @@
+//! The chunkers an archive can be cut with. An archive records which one it uses, since the
+//! same data cut by two of them shares no chunks.
+
+use cdchunking::{Chunker, ZPAQ};
+use fastcdc::v2020::FastCDC;
+use std::fmt;
+use std::str::FromStr;
+
+/// Splits data into chunks. The content-defined ones cut at boundaries decided by the data
+/// itself, so an insertion only changes the chunks around it.
+pub(crate) trait Chunking {
+    /// The chunks `data` is cut into, in order and covering all of it.
+    fn cut<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Algorithm {
+    /// The rolling hash from ZPAQ, which archives used before there was a choice.
+    Zpaq,
+    /// FastCDC, which cuts several times faster, with chunk sizes kept closer to the average.
+    FastCdc,
+    /// Blocks of one size, for data that only ever changes in place, such as disk images.
+    Fixed,
+}
+
+impl Algorithm {
+    pub(crate) const ALL: &'static [Algorithm] =
+        &[Algorithm::Zpaq, Algorithm::FastCdc, Algorithm::Fixed];
+
+    /// The names `--chunker` accepts.
+    pub(crate) const NAMES: &'static [&'static str] = &["zpaq", "fastcdc", "fixed"];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zpaq => "zpaq",
+            Algorithm::FastCdc => "fastcdc",
+            Algorithm::Fixed => "fixed",
+        }
+    }
+
+    /// The largest --chunk-bits this chunker can cut to.
+    pub(crate) fn max_bits(self) -> usize {
+        match self {
+            Algorithm::Zpaq | Algorithm::Fixed => 30,
+            // Its largest average chunk is 4 MiB.
+            Algorithm::FastCdc => 22,
+        }
+    }
+
+    /// A chunker aiming for chunks of 2^`bits` bytes on average, or of exactly `size` bytes
+    /// for the fixed one.
+    pub(crate) fn chunker(self, bits: usize, size: usize) -> Box<dyn Chunking> {
+        match self {
+            Algorithm::Zpaq => Box::new(Zpaq { bits }),
+            Algorithm::FastCdc => Box::new(FastCdc { average: 1 << bits }),
+            Algorithm::Fixed => Box::new(Fixed { size }),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name() == s)
+            .ok_or_else(|| format!("unknown chunker '{}'", s))
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+struct Zpaq {
+    bits: usize,
+}
+
+impl Chunking for Zpaq {
+    fn cut<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        Chunker::new(ZPAQ::new(self.bits)).slices(data).collect()
+    }
+}
+
+struct FastCdc {
+    average: usize,
+}
+
+impl Chunking for FastCdc {
+    fn cut<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        // A quarter and four times the average, which keeps every --chunk-bits from 8 to
+        // 22 inside the sizes the crate allows.
+        FastCDC::new(data, self.average / 4, self.average, self.average * 4)
+            .map(|chunk| &data[chunk.offset..chunk.offset + chunk.length])
+            .collect()
+    }
+}
+
+struct Fixed {
+    size: usize,
+}
+
+impl Chunking for Fixed {
+    fn cut<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        // Every chunk is `size` bytes but the last, which is whatever is left over.
+        data.chunks(self.size).collect()
+    }
+}
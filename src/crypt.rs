@@ -0,0 +1,449 @@
+//! Encryption at rest. An archive set up with --encrypt seals every chunk and inline file
+//! with XChaCha20-Poly1305 after compressing it, stored as a random nonce followed by the
+//! ciphertext and tag. Chunks are sealed with their hash as associated data, so one can't be
+//! swapped for another without it being noticed.
+//!
+//! The key is read from --key-file or derived from a passphrase with argon2id. Which one,
+//! with the salt and parameters for argon2id, is kept in the meta table next to a value
+//! sealed at setup, so a wrong key is reported before any chunk is read.
+//!
+//! With --encrypt-names, file names are sealed too, under a nonce that is a keyed hash of
+//! the name. The same name always seals to the same bytes, so looking a file up by name is
+//! still one indexed query, while anything that needs names in order or by prefix decrypts
+//! them all first. The key has to be loaded before any name is, which `run` does as soon as
+//! the archive is opened.
+
+use crate::output::{paint, Stream, Style};
+use crate::{get_meta, set_meta, ChsqlarError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use failure::{format_err, Error};
+use rusqlite::{Connection, NO_PARAMS};
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const SCHEME: &str = "xchacha20-poly1305";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// What the key check value is a sealing of.
+const KEY_CHECK: &[u8] = b"chsqlar key check";
+
+/// Associated data for inline files, which have no hash of their own to use.
+pub(crate) const INLINE_AAD: &[u8] = b"inline";
+
+const NAME_AAD: &[u8] = b"name";
+
+/// Context for deriving the key that name nonces are hashed with from the archive's key.
+const NAME_KEY_CONTEXT: &str = "chsqlar 2026-10 file name nonces";
+
+/// The meta keys that say how an archive is encrypted.
+const SETUP_KEYS: &[&str] = &[
+    "kdf",
+    "kdf_params",
+    "kdf_salt",
+    "key_check",
+    "encryption",
+    "names",
+];
+
+static KEY_FILE: OnceLock<PathBuf> = OnceLock::new();
+static CIPHER: OnceLock<Cipher> = OnceLock::new();
+
+pub(crate) struct Cipher {
+    aead: XChaCha20Poly1305,
+    name_key: [u8; KEY_LEN],
+}
+
+impl Cipher {
+    fn new(key: &[u8; KEY_LEN]) -> Self {
+        Cipher {
+            aead: XChaCha20Poly1305::new(key.into()),
+            name_key: blake3::derive_key(NAME_KEY_CONTEXT, key),
+        }
+    }
+
+    /// `data` sealed under a fresh random nonce, which goes in front of it.
+    pub(crate) fn seal(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        self.seal_with(&nonce, data, aad)
+    }
+
+    /// `name` sealed under a nonce derived from it, so it always seals to the same bytes.
+    fn seal_name(&self, name: &[u8]) -> Result<Vec<u8>, Error> {
+        let hash = blake3::keyed_hash(&self.name_key, name);
+        let nonce = XNonce::from_slice(&hash.as_bytes()[..NONCE_LEN]);
+        self.seal_with(nonce, name, NAME_AAD)
+    }
+
+    fn seal_with(&self, nonce: &XNonce, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let sealed = self
+            .aead
+            .encrypt(nonce, Payload { msg: data, aad })
+            .map_err(|_| format_err!("can't encrypt {} bytes", data.len()))?;
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    /// The data `sealed` was made from, or None if it was changed or sealed with another key.
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, msg) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg, aad })
+            .ok()
+    }
+}
+
+/// Remembers --key-file for when the archive's key is first needed.
+pub(crate) fn set_key_file(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        let _ = KEY_FILE.set(path);
+    }
+}
+
+pub(crate) fn is_encrypted(connection: &Connection) -> Result<bool, Error> {
+    Ok(get_meta::<String>(connection, "encryption")?.is_some())
+}
+
+/// Whether the archive was set up with --encrypt-names.
+pub(crate) fn names_encrypted(connection: &Connection) -> Result<bool, Error> {
+    Ok(get_meta::<String>(connection, "names")?.as_deref() == Some("encrypted"))
+}
+
+/// Sets up a new archive to be encrypted, with the key from --key-file or a passphrase that
+/// is asked for twice, and its file names too if `names`. Does nothing if it already is.
+pub(crate) fn enable(connection: &Connection, names: bool) -> Result<(), Error> {
+    if is_encrypted(connection)? {
+        if names && !names_encrypted(connection)? {
+            return Err(format_err!(
+                "--encrypt-names can only be given when creating an archive, and this one \
+                 was set up without it"
+            ));
+        }
+        return Ok(());
+    }
+
+    let used: i64 = connection.query_row(
+        "SELECT (SELECT COUNT(*) FROM files) + (SELECT COUNT(*) FROM chunks)",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if used > 0 {
+        return Err(format_err!(
+            "--encrypt can only be given when creating an archive, and this one has data"
+        ));
+    }
+
+    let mut meta = Vec::new();
+    let key = match KEY_FILE.get() {
+        Some(path) => {
+            meta.push(("kdf", "key-file".to_owned()));
+            read_key_file(path)?
+        }
+        None => {
+            let passphrase = passphrase(true)?;
+            let mut salt = [0; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let params = Params::default();
+            let params = (params.m_cost(), params.t_cost(), params.p_cost());
+
+            meta.push(("kdf", "argon2id".to_owned()));
+            meta.push(("kdf_params", format_params(params)));
+            meta.push(("kdf_salt", to_hex(&salt)));
+            derive_key(&passphrase, &salt, params)?
+        }
+    };
+
+    let cipher = Cipher::new(&key);
+    meta.push(("key_check", to_hex(&cipher.seal(KEY_CHECK, b"")?)));
+    meta.push(("encryption", SCHEME.to_owned()));
+    if names {
+        meta.push(("names", "encrypted".to_owned()));
+    }
+
+    connection.execute_batch("SAVEPOINT encrypt")?;
+    for (key, value) in meta {
+        set_meta(connection, key, &value)?;
+    }
+    connection.execute_batch("RELEASE encrypt")?;
+
+    let _ = CIPHER.set(cipher);
+
+    Ok(())
+}
+
+/// The archive's cipher, or None if it isn't encrypted. The key is read or asked for the
+/// first time it's needed, and checked against the archive before it's used.
+pub(crate) fn cipher(connection: &Connection) -> Result<Option<&'static Cipher>, Error> {
+    match get_meta::<String>(connection, "encryption")? {
+        None => return Ok(None),
+        Some(ref scheme) if scheme == SCHEME => {}
+        Some(scheme) => return Err(format_err!("unknown encryption scheme '{}'", scheme)),
+    }
+
+    if let Some(cipher) = CIPHER.get() {
+        return Ok(Some(cipher));
+    }
+
+    let kdf = get_meta::<String>(connection, "kdf")?.unwrap_or_default();
+    let key = match (kdf.as_str(), KEY_FILE.get()) {
+        ("key-file", Some(path)) => read_key_file(path)?,
+        ("key-file", None) => {
+            return Err(format_err!(
+                "this archive is encrypted with a key file; give --key-file"
+            ))
+        }
+        ("argon2id", None) => {
+            let params = get_meta::<String>(connection, "kdf_params")?.unwrap_or_default();
+            let salt = get_meta::<String>(connection, "kdf_salt")?.unwrap_or_default();
+            derive_key(
+                &passphrase(false)?,
+                &from_hex(&salt)?,
+                parse_params(&params)?,
+            )?
+        }
+        ("argon2id", Some(_)) => {
+            return Err(format_err!(
+                "this archive is encrypted with a passphrase, not a key file"
+            ))
+        }
+        (kdf, _) => return Err(format_err!("unknown key derivation '{}'", kdf)),
+    };
+
+    let cipher = unlock(connection, &key)?;
+    Ok(Some(CIPHER.get_or_init(|| cipher)))
+}
+
+/// A cipher with `key`, if it opens the value the archive sealed at setup.
+pub(crate) fn unlock(connection: &Connection, key: &[u8; KEY_LEN]) -> Result<Cipher, Error> {
+    let cipher = Cipher::new(key);
+    let check = from_hex(&get_meta::<String>(connection, "key_check")?.unwrap_or_default())?;
+    if cipher.open(&check, b"").as_deref() != Some(KEY_CHECK) {
+        return Err(ChsqlarError::WrongKey.into());
+    }
+
+    Ok(cipher)
+}
+
+/// Sets up `to` to be encrypted the same way as `from`, so what's sealed in one opens in the
+/// other.
+pub(crate) fn copy_setup(from: &Connection, to: &Connection) -> Result<(), Error> {
+    for key in SETUP_KEYS {
+        if let Some(value) = get_meta::<String>(from, key)? {
+            set_meta(to, key, &value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Seals `data` if the archive is encrypted, leaving it as is otherwise.
+pub(crate) fn seal(cipher: Option<&Cipher>, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Error> {
+    match cipher {
+        Some(cipher) => cipher.seal(&data, aad),
+        None => Ok(data),
+    }
+}
+
+/// Opens `data` if the archive is encrypted, failing as `what` being undecryptable if it was
+/// changed or the key is wrong.
+pub(crate) fn open(
+    cipher: Option<&Cipher>,
+    data: Vec<u8>,
+    aad: &[u8],
+    what: impl FnOnce() -> String,
+) -> Result<Vec<u8>, Error> {
+    match cipher {
+        Some(cipher) => cipher
+            .open(&data, aad)
+            .ok_or_else(|| ChsqlarError::Undecryptable { what: what() }.into()),
+        None => Ok(data),
+    }
+}
+
+/// The archive's cipher, for sealing and opening file names, which happens where there's no
+/// connection to load it from. Fails if `cipher` hasn't loaded it yet.
+fn loaded() -> Result<&'static Cipher, Error> {
+    CIPHER
+        .get()
+        .ok_or_else(|| format_err!("the key for the archive's file names hasn't been loaded"))
+}
+
+pub(crate) fn seal_name(name: &[u8]) -> Result<Vec<u8>, Error> {
+    loaded()?.seal_name(name)
+}
+
+pub(crate) fn open_name(sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    loaded()?.open(sealed, NAME_AAD).ok_or_else(|| {
+        ChsqlarError::Undecryptable {
+            what: "a file name".to_owned(),
+        }
+        .into()
+    })
+}
+
+/// Seals the metadata of the file `name` with the name as associated data, so it can't be
+/// moved to another file's row.
+pub(crate) fn seal_attrs(name: &[u8], attrs: &[u8]) -> Result<Vec<u8>, Error> {
+    loaded()?.seal(attrs, name)
+}
+
+pub(crate) fn open_attrs(name: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    loaded()?.open(sealed, name).ok_or_else(|| {
+        ChsqlarError::Undecryptable {
+            what: format!("the metadata of {}", String::from_utf8_lossy(name)),
+        }
+        .into()
+    })
+}
+
+/// For Stats: how the archive is encrypted, and what that leaves readable.
+pub(crate) fn describe(connection: &Connection) -> Result<Option<String>, Error> {
+    let scheme = match get_meta::<String>(connection, "encryption")? {
+        Some(scheme) => scheme,
+        None => return Ok(None),
+    };
+
+    let key = match get_meta::<String>(connection, "kdf")?.as_deref() {
+        Some("argon2id") => format!(
+            "argon2id passphrase ({})",
+            get_meta::<String>(connection, "kdf_params")?.unwrap_or_default()
+        ),
+        _ => "key file".to_owned(),
+    };
+
+    let (names, exposed) = match names_encrypted(connection)? {
+        true => (
+            ", file names and times included",
+            "file sizes and how many files there are",
+        ),
+        false => ("", "file names, sizes and times"),
+    };
+
+    Ok(Some(format!(
+        "encryption: {}, {}{}\n\
+         not encrypted: {}, and chunk hashes, which are of the unencrypted content, so anyone \
+         with the archive can see which files are the same and check whether it holds a file \
+         they have",
+        scheme, key, names, exposed
+    )))
+}
+
+/// Reads a key of 32 bytes, either as is or in base64.
+pub(crate) fn read_key_file(path: &Path) -> Result<[u8; KEY_LEN], Error> {
+    let bytes = fs::read(path)?;
+    let bytes = match bytes.len() {
+        KEY_LEN => bytes,
+        _ => std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|text| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(text.trim())
+                    .ok()
+            })
+            .unwrap_or_default(),
+    };
+
+    bytes.try_into().map_err(|_| {
+        format_err!(
+            "{} must hold a {} byte key, as is or in base64",
+            path.display(),
+            KEY_LEN
+        )
+    })
+}
+
+/// The passphrase from CHSQLAR_PASSPHRASE, or asked for on the terminal without echoing it.
+/// `confirm` asks twice, for when setting one up.
+fn passphrase(confirm: bool) -> Result<String, Error> {
+    if let Ok(passphrase) = std::env::var("CHSQLAR_PASSPHRASE") {
+        let warning = "warning: using the passphrase in CHSQLAR_PASSPHRASE, which other \
+                       processes run as the same user can read";
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, warning));
+        return Ok(passphrase);
+    }
+
+    let asked = rpassword::prompt_password("Passphrase: ").map_err(|e| {
+        format_err!(
+            "can't ask for a passphrase ({}); set CHSQLAR_PASSPHRASE or give --key-file",
+            e
+        )
+    })?;
+    if asked.is_empty() {
+        return Err(format_err!("the passphrase can't be empty"));
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != asked {
+        return Err(format_err!("the passphrases don't match"));
+    }
+
+    Ok(asked)
+}
+
+pub(crate) fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: (u32, u32, u32),
+) -> Result<[u8; KEY_LEN], Error> {
+    let (m, t, p) = params;
+    let params = Params::new(m, t, p, Some(KEY_LEN)).map_err(|e| format_err!("argon2id: {}", e))?;
+
+    let mut key = [0; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format_err!("argon2id: {}", e))?;
+
+    Ok(key)
+}
+
+fn format_params((m, t, p): (u32, u32, u32)) -> String {
+    format!("m={},t={},p={}", m, t, p)
+}
+
+fn parse_params(s: &str) -> Result<(u32, u32, u32), Error> {
+    let mut values = [None; 3];
+    for part in s.split(',') {
+        let (name, value) = part.split_once('=').unwrap_or((part, ""));
+        let slot = match name {
+            "m" => 0,
+            "t" => 1,
+            "p" => 2,
+            _ => continue,
+        };
+        values[slot] = value.parse().ok();
+    }
+
+    match values {
+        [Some(m), Some(t), Some(p)] => Ok((m, t, p)),
+        _ => Err(format_err!(
+            "bad argon2id parameters '{}' in the archive",
+            s
+        )),
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format_err!("bad hex '{}' in the archive", s))
+        })
+        .collect()
+}
@@ -0,0 +1,548 @@
+//! `Daemon` keeps one archive open behind a Unix socket, and `--remote` talks to it.
+//!
+//! Every message is a frame: a big-endian u64 length followed by that many bytes. A request
+//! is a JSON object whose "op" is add-path, add-bytes, list, cat, remove or stat, and
+//! add-bytes is followed by a frame holding the content. Each reply is a JSON object with
+//! "ok" set, or "error" holding the same object as --json-errors. A successful cat is
+//! followed by the content in frames ending with an empty one, then a second reply saying
+//! whether all of it could be read. Writes take turns on the one connection the daemon
+//! opened, while each client reads through a connection of its own.
+//!
+//! The socket is only accessible to its owner, and clients running as another user are
+//! turned away.
+
+use crate::{
+    add_data, add_files, error_json, get_file, list_files, refuse_filtered, remove_files,
+    remove_loose_chunks, stored_name, write_file_to, write_zeros, AddOpt, ChunkCache, CommonOpt,
+    DedupWindow, MemoryBudget, Metrics, NameEncoding, OptCommand, SqliteDatabase, INTERRUPTED,
+};
+use failure::{format_err, Error};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::json;
+use std::env::current_dir;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often idle parts of the daemon check whether it has been told to stop.
+const POLL: Duration = Duration::from_millis(100);
+
+/// The daemon's connection, which every write goes through in turn.
+pub(crate) struct Writer<'a> {
+    pub(crate) db: &'a mut SqliteDatabase,
+    pub(crate) budget: &'a MemoryBudget,
+}
+
+pub(crate) fn daemon_cmd(
+    db: &mut SqliteDatabase,
+    socket: &Path,
+    add_opt: AddOpt,
+    encoding: NameEncoding,
+    opt: &CommonOpt,
+) -> Result<(), Error> {
+    // A socket file nobody answers on is left over from a daemon that didn't exit cleanly.
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format_err!(
+                "a daemon is already listening on {}",
+                socket.display()
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    // Nothing is accepted before this, and a client that connected in between is still
+    // refused by its uid.
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    // Safe as geteuid can't fail and touches no memory.
+    let uid = unsafe { libc::geteuid() };
+
+    // Adds in progress see this between files and commit what they have.
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))?;
+
+    // Shared by the writes and every client's chunk cache.
+    let budget = MemoryBudget::new(opt.memory_limit);
+    let writer = Mutex::new(Writer {
+        db,
+        budget: &budget,
+    });
+    let result = std::thread::scope(|scope| {
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    match peer_uid(&stream) {
+                        Ok(peer) if peer == uid => {}
+                        Ok(peer) => {
+                            eprintln!("refused a client running as uid {}", peer);
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("refused a client whose uid can't be read: {}", e);
+                            continue;
+                        }
+                    }
+                    let (writer, budget, add_opt) = (&writer, &budget, &add_opt);
+                    scope.spawn(move || {
+                        if let Err(e) = serve(stream, writer, budget, add_opt, encoding, opt) {
+                            eprintln!("client dropped: {}", e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+
+        Ok(())
+    });
+
+    fs::remove_file(socket)?;
+    if opt.verbosity > 0 {
+        eprintln!("daemon stopped");
+    }
+
+    result
+}
+
+/// Answers one client's requests until it hangs up or the daemon stops.
+fn serve(
+    mut stream: UnixStream,
+    writer: &Mutex<Writer>,
+    budget: &MemoryBudget,
+    add_opt: &AddOpt,
+    encoding: NameEncoding,
+    opt: &CommonOpt,
+) -> Result<(), Error> {
+    stream.set_nonblocking(false)?;
+    let mut reader = Connection::open_with_flags(&opt.database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    reader.busy_timeout(Duration::from_secs(60))?;
+    let cache = ChunkCache::drawing_on(opt.chunk_cache_mb * 1024 * 1024, budget);
+
+    while let Some(frame) = next_request(&mut stream)? {
+        let request: serde_json::Value = serde_json::from_slice(&frame)?;
+        let data = match request["op"].as_str() {
+            Some("add-bytes") => Some(read_frame(&mut stream)?),
+            _ => None,
+        };
+
+        // Cat streams straight from the chunks, so it writes its own replies.
+        if request["op"] == "cat" {
+            let mut trans = reader.transaction()?;
+            match name_field(&request, "name")
+                .and_then(|name| get_file(&mut trans, name, encoding))
+                .and_then(|f| refuse_filtered(&f, "the daemon").map(|()| f))
+            {
+                Ok(f) => {
+                    write_frame(
+                        &mut stream,
+                        json!({ "ok": true, "size": f.size }).to_string(),
+                    )?;
+                    let written = write_file_to(
+                        &mut trans,
+                        &f,
+                        &cache,
+                        &mut FrameWriter(&mut stream),
+                        write_zeros,
+                    );
+                    write_frame(&mut stream, [])?;
+                    let reply = match written {
+                        Ok(()) => json!({ "ok": true }).to_string(),
+                        Err(e) => error_reply(&e),
+                    };
+                    write_frame(&mut stream, reply)?;
+                }
+                Err(e) => write_frame(&mut stream, error_reply(&e))?,
+            }
+            continue;
+        }
+
+        let reply = match handle(&request, data, &mut reader, writer, add_opt, encoding, opt) {
+            Ok(reply) => reply.to_string(),
+            Err(e) => error_reply(&e),
+        };
+        write_frame(&mut stream, reply)?;
+    }
+
+    Ok(())
+}
+
+/// Answers a request other than cat, which `serve` streams itself.
+pub(crate) fn handle(
+    request: &serde_json::Value,
+    data: Option<Vec<u8>>,
+    reader: &mut Connection,
+    writer: &Mutex<Writer>,
+    add_opt: &AddOpt,
+    encoding: NameEncoding,
+    opt: &CommonOpt,
+) -> Result<serde_json::Value, Error> {
+    match request["op"].as_str().unwrap_or("") {
+        "list" => {
+            let mut trans = reader.transaction()?;
+            let names: Vec<_> = list_files(&mut trans, encoding)?
+                .iter()
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            Ok(json!({ "ok": true, "names": names }))
+        }
+        "stat" => {
+            let mut trans = reader.transaction()?;
+            let f = get_file(&mut trans, name_field(request, "name")?, encoding)?;
+            Ok(json!({
+                "ok": true,
+                "name": f.name.to_string_lossy(),
+                "size": f.size,
+                "chunks": f.chunks.len(),
+                "inline": f.inline.is_some(),
+                "mtime": f.mtime,
+                "added_at": f.added_at,
+                "sha256": f.sha256,
+                "content_hash": f.content_hash,
+                "filter": f.filter,
+            }))
+        }
+        "add-path" => {
+            let cwd = name_field(request, "cwd")?;
+            let paths = names_field(request, "paths")?;
+            let mut writer = writer.lock().unwrap();
+            let Writer { db, budget } = &mut *writer;
+            let summary = add_files(
+                db,
+                &cwd,
+                paths,
+                add_opt.clone(),
+                opt.verbosity,
+                encoding,
+                budget,
+            )?;
+
+            let lossy = |paths: &[PathBuf]| -> Vec<String> {
+                paths
+                    .iter()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .collect()
+            };
+            Ok(json!({
+                "ok": true,
+                "stored": summary.stored,
+                "failed": lossy(&summary.failed),
+                "over_quota": lossy(&summary.over_quota),
+                "interrupted": summary.interrupted,
+                "out_of_time": summary.out_of_time.len(),
+            }))
+        }
+        "add-bytes" => {
+            // Stored the way Add would store a file of that name, so it's extracted inside
+            // the target directory like any other.
+            let sent = name_field(request, "name")?;
+            let name = stored_name(Path::new(""), &sent, add_opt)?;
+            if name.as_os_str().is_empty() {
+                return Err(format_err!("'{}' doesn't name a file", sent.display()));
+            }
+            let mut writer = writer.lock().unwrap();
+            let Writer { db, budget } = &mut *writer;
+            let data = data.unwrap_or_default();
+            // The content and the chunks it's cut into.
+            let _held = budget.acquire(2 * data.len() as u64);
+            let mut trans = db.connection.transaction()?;
+            add_data(
+                &mut trans,
+                data,
+                None,
+                name,
+                add_opt,
+                encoding,
+                budget,
+                &Metrics::default(),
+                &mut DedupWindow::default(),
+            )?;
+            trans.commit()?;
+            Ok(json!({ "ok": true }))
+        }
+        "remove" => {
+            let names = names_field(request, "names")?;
+            let mut writer = writer.lock().unwrap();
+            let mut trans = writer.db.connection.transaction()?;
+            let freed = remove_files(&mut trans, &names, encoding)?;
+            trans.commit()?;
+            remove_loose_chunks(&writer.db.connection, &freed)?;
+            Ok(json!({ "ok": true, "removed": names.len(), "chunks": freed.len() }))
+        }
+        op => Err(format_err!("unknown op '{}'", op)),
+    }
+}
+
+fn error_reply(e: &Error) -> String {
+    json!({ "ok": false, "error": error_json(e) }).to_string()
+}
+
+fn name_field(request: &serde_json::Value, field: &str) -> Result<PathBuf, Error> {
+    match request[field].as_str() {
+        Some(name) => Ok(PathBuf::from(name)),
+        None => Err(format_err!("request needs a string '{}'", field)),
+    }
+}
+
+fn names_field(request: &serde_json::Value, field: &str) -> Result<Vec<PathBuf>, Error> {
+    let names = match request[field].as_array() {
+        Some(names) => names,
+        None => return Err(format_err!("request needs an array '{}'", field)),
+    };
+
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            Some(name) => Ok(PathBuf::from(name)),
+            None => Err(format_err!("'{}' must only hold strings", field)),
+        })
+        .collect()
+}
+
+/// Waits for the client's next request, giving up if the daemon is told to stop first.
+/// Returns None when there won't be one.
+fn next_request(stream: &mut UnixStream) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 8];
+    stream.set_read_timeout(Some(POLL))?;
+    loop {
+        match stream.read(&mut len[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    stream.set_read_timeout(None)?;
+
+    stream.read_exact(&mut len[1..])?;
+    read_body(stream, u64::from_be_bytes(len)).map(Some)
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    read_body(reader, u64::from_be_bytes(len))
+}
+
+fn read_body<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body)?;
+    if (body.len() as u64) < len {
+        return Err(format_err!("connection closed partway through a message"));
+    }
+
+    Ok(body)
+}
+
+fn write_frame<W: Write, B: AsRef<[u8]>>(writer: &mut W, body: B) -> Result<(), Error> {
+    let body = body.as_ref();
+    writer.write_all(&(body.len() as u64).to_be_bytes())?;
+    writer.write_all(body)?;
+
+    Ok(())
+}
+
+/// Sends each write as a frame of its own, so content can be streamed before it's known
+/// whether all of it can be read.
+struct FrameWriter<'a>(&'a mut UnixStream);
+
+impl Write for FrameWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty frame ends the content, so there's nothing to send for an empty write.
+        if !buf.is_empty() {
+            self.0.write_all(&(buf.len() as u64).to_be_bytes())?;
+            self.0.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The uid of the process on the other end of `stream`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Result<u32, Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safe as getsockopt writes at most `len` bytes, the size of `cred`, and the descriptor
+    // stays open while `stream` is borrowed.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(cred.uid)
+}
+
+/// The uid of the process on the other end of `stream`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Result<u32, Error> {
+    let (mut uid, mut gid) = (0, 0);
+    // Safe as getpeereid only writes the two ids, and the descriptor stays open while
+    // `stream` is borrowed.
+    let ret = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(uid)
+}
+
+/// Sends `request` with `data` if given, returning the reply, or its error as an `Error`.
+fn request(
+    stream: &mut UnixStream,
+    request: serde_json::Value,
+    data: Option<&[u8]>,
+) -> Result<serde_json::Value, Error> {
+    write_frame(stream, request.to_string())?;
+    if let Some(data) = data {
+        write_frame(stream, data)?;
+    }
+
+    read_reply(stream)
+}
+
+/// Reads a reply, returning its error as an `Error`.
+fn read_reply(stream: &mut UnixStream) -> Result<serde_json::Value, Error> {
+    let reply: serde_json::Value = serde_json::from_slice(&read_frame(stream)?)?;
+    match reply["ok"].as_bool() {
+        Some(true) => Ok(reply),
+        _ => Err(format_err!(
+            "{}",
+            reply["error"]["message"]
+                .as_str()
+                .unwrap_or("the daemon sent a malformed reply")
+        )),
+    }
+}
+
+/// Runs `cmd` through the daemon listening on `socket`.
+pub(crate) fn remote_cmd(socket: &Path, cmd: OptCommand) -> Result<(), Error> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format_err!("can't reach a daemon on {}: {}", socket.display(), e))?;
+
+    let utf8 = |path: &Path| match path.to_str() {
+        Some(s) => Ok(s.to_owned()),
+        None => Err(format_err!("{} is not valid UTF-8", path.display())),
+    };
+
+    match cmd {
+        OptCommand::List {
+            long: false,
+            dirs: false,
+            added_after: None,
+            modified_since: None,
+            null,
+            ..
+        } => {
+            let reply = request(&mut stream, json!({ "op": "list" }), None)?;
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            for name in reply["names"].as_array().into_iter().flatten() {
+                stdout.write_all(name.as_str().unwrap_or("").as_bytes())?;
+                stdout.write_all(if null { b"\0" } else { b"\n" })?;
+            }
+        }
+        OptCommand::Cat { files, .. } => {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            for f in files {
+                request(&mut stream, json!({ "op": "cat", "name": utf8(&f)? }), None)?;
+                loop {
+                    let frame = read_frame(&mut stream)?;
+                    if frame.is_empty() {
+                        break;
+                    }
+                    stdout.write_all(&frame)?;
+                }
+                read_reply(&mut stream)?;
+            }
+        }
+        OptCommand::Add { files, .. } => {
+            let cwd = current_dir()?;
+            let paths = files
+                .iter()
+                .map(|f| utf8(&cwd.join(f)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let reply = request(
+                &mut stream,
+                json!({ "op": "add-path", "cwd": utf8(&cwd)?, "paths": paths }),
+                None,
+            )?;
+
+            // Report the way a local Add does, with the same exit statuses.
+            let list = |field: &str| reply[field].as_array().cloned().unwrap_or_default();
+            let (failed, over_quota) = (list("failed"), list("over_quota"));
+            if !failed.is_empty() {
+                eprintln!("{} files could not be added:", failed.len());
+                for f in &failed {
+                    eprintln!("  {}", f.as_str().unwrap_or(""));
+                }
+            }
+
+            if !over_quota.is_empty() {
+                eprintln!(
+                    "Archive reached --max-db-size after storing {} files; not added:",
+                    reply["stored"]
+                );
+                for f in &over_quota {
+                    eprintln!("  {}", f.as_str().unwrap_or(""));
+                }
+                std::process::exit(3);
+            }
+
+            if reply["interrupted"] == true {
+                eprintln!(
+                    "The daemon stopped after storing {} files; run the same add again to continue",
+                    reply["stored"]
+                );
+                std::process::exit(130);
+            }
+
+            if reply["out_of_time"].as_u64().unwrap_or(0) > 0 {
+                eprintln!(
+                    "The daemon's time budget ran out after storing {} files, with {} left; \
+                     run the same add again to continue",
+                    reply["stored"], reply["out_of_time"]
+                );
+                std::process::exit(4);
+            }
+
+            if !failed.is_empty() {
+                std::process::exit(2);
+            }
+        }
+        _ => {
+            return Err(format_err!(
+                "only Add, List without options and Cat can be sent with --remote"
+            ))
+        }
+    }
+
+    Ok(())
+}
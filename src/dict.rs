@@ -0,0 +1,156 @@
+//! Zstd dictionaries trained on an archive's own chunks. A small chunk compressed on its own
+//! starts with nothing to refer back to, so similar small files compress poorly one chunk at
+//! a time; a dictionary gives every chunk the same head start.
+//!
+//! Each dictionary is kept in the meta table under "zstd_dict/<id>", and the one Add and
+//! Verify --repair-compression use under "zstd_dict". Training again adds a dictionary
+//! rather than replacing one, so chunks compressed with an older one stay readable. Chunks
+//! compressed with one are stored with the codec "zstd-dict", and zstd writes the id into
+//! each frame, which is how reading finds the dictionary again.
+
+use crate::{get_meta, require_reader_version, set_meta};
+use failure::{format_err, Error};
+use rusqlite::types::ToSql;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// The meta key of the dictionary in use, by id.
+const CURRENT: &str = "zstd_dict";
+
+/// What the meta keys of dictionaries start with, followed by the id.
+const PREFIX: &str = "zstd_dict/";
+
+/// The schema version the "zstd-dict" codec came with, which readers of an archive with a
+/// dictionary have to understand.
+const READER_VERSION: u32 = 5;
+
+/// The dictionaries of every archive opened, by id. Ids are random, so those of different
+/// archives can share the map.
+static LOADED: Mutex<Option<HashMap<u32, Arc<Vec<u8>>>>> = Mutex::new(None);
+
+/// Makes the dictionaries of the archive on `connection` available for decompressing.
+pub(crate) fn load(connection: &Connection) -> Result<(), Error> {
+    let mut stmt = connection.prepare("SELECT key, value FROM meta WHERE key LIKE ?")?;
+    let rows = stmt.query_map(&[&format!("{}%", PREFIX)], |row| {
+        (row.get::<_, String>(0), row.get::<_, Vec<u8>>(1))
+    })?;
+
+    let mut loaded = LOADED.lock().unwrap();
+    let loaded = loaded.get_or_insert_with(HashMap::new);
+    for row in rows {
+        let (key, dict) = row?;
+        let id = key[PREFIX.len()..]
+            .parse()
+            .map_err(|_| format_err!("bad dictionary key '{}' in meta", key))?;
+        loaded.insert(id, Arc::new(dict));
+    }
+
+    Ok(())
+}
+
+/// The id of the dictionary chunks are compressed with, if one has been trained.
+pub(crate) fn current(connection: &Connection) -> Result<Option<u32>, Error> {
+    get_meta(connection, CURRENT)
+}
+
+/// Whether `key` is the meta key of a dictionary, which is binary and not worth printing.
+pub(crate) fn is_dict_key(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+/// Trains a dictionary of at most `max_size` bytes on `samples`, stores it and makes it the
+/// one in use. Returns its id.
+pub(crate) fn train(
+    connection: &Connection,
+    samples: &[Vec<u8>],
+    max_size: usize,
+) -> Result<u32, Error> {
+    let dict = zstd::dict::from_samples(samples, max_size)
+        .map_err(|e| format_err!("training the dictionary failed: {}", e))?;
+    // Safe as it only reads the header, within the `dict.len()` bytes it's given.
+    let id = unsafe { zstd_sys::ZDICT_getDictID(dict.as_ptr() as *const libc::c_void, dict.len()) };
+    if id == 0 {
+        return Err(format_err!("zstd trained a dictionary without an id"));
+    }
+
+    connection.execute(
+        "INSERT OR REPLACE INTO meta VALUES (?,?)",
+        &[&format!("{}{}", PREFIX, id) as &dyn ToSql, &dict],
+    )?;
+    set_meta(connection, CURRENT, &id)?;
+    require_reader_version(connection, READER_VERSION)?;
+
+    LOADED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(id, Arc::new(dict));
+
+    Ok(id)
+}
+
+/// Copies the dictionaries of the attached `source` archive that the main one doesn't have,
+/// for the chunks copied from it.
+pub(crate) fn copy_from_source(connection: &Connection) -> Result<(), Error> {
+    let copied = connection.execute(
+        "INSERT OR IGNORE INTO main.meta SELECT key, value FROM source.meta WHERE key LIKE ?",
+        &[&format!("{}%", PREFIX)],
+    )?;
+    if copied > 0 {
+        require_reader_version(connection, READER_VERSION)?;
+    }
+
+    Ok(())
+}
+
+/// The size of dictionary `id`.
+pub(crate) fn size(id: u32) -> Result<usize, Error> {
+    Ok(get(id)?.len())
+}
+
+fn get(id: u32) -> Result<Arc<Vec<u8>>, Error> {
+    LOADED
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|loaded| loaded.get(&id).cloned())
+        .ok_or_else(|| format_err!("zstd dictionary {} isn't in the archive", id))
+}
+
+/// Compresses `data` at `level` with dictionary `id`.
+pub(crate) fn compress(data: &[u8], level: i32, id: u32) -> Result<Vec<u8>, Error> {
+    let dict = get(id)?;
+    let mut encoder = zstd::stream::Encoder::with_dictionary(Vec::new(), level, &dict)?;
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses a frame compressed with whichever dictionary its header names.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    // Safe as it only reads the header, within the `data.len()` bytes it's given.
+    let id = unsafe {
+        zstd_sys::ZSTD_getDictID_fromFrame(data.as_ptr() as *const libc::c_void, data.len())
+    };
+    let dict = get(id)?;
+
+    let mut result = Vec::new();
+    zstd::stream::Decoder::with_dictionary(data, &dict)?.read_to_end(&mut result)?;
+    Ok(result)
+}
+
+/// How `samples` compress at `level` with dictionary `id` and without one, as the share of
+/// their size each leaves.
+pub(crate) fn ratios(samples: &[Vec<u8>], level: i32, id: u32) -> Result<(f64, f64), Error> {
+    let total: usize = samples.iter().map(Vec::len).sum();
+    let mut with = 0;
+    let mut without = 0;
+    for sample in samples {
+        with += compress(sample, level, id)?.len();
+        without += zstd::encode_all(&sample[..], level)?.len();
+    }
+
+    let total = total.max(1) as f64;
+    Ok((with as f64 / total, without as f64 / total))
+}
@@ -0,0 +1,392 @@
+//! `Doctor` runs every consistency check there is on an archive in turn, and for each
+//! problem found says which command fixes it, or with --fix runs that itself where it's safe.
+//!
+//! The checks run from the outside in: the schema version, SQLite's own structure, the
+//! chunk lists of files and blobs and what they refer to, the chunks themselves, and last
+//! the space VACUUM would give back. A one-line verdict ends the report.
+
+use crate::output::{paint, Stream, Style};
+use crate::{
+    decode_name, delete_chunk, meta_text, migrate_cmd, rebuild_refcounts, remove_loose_chunks,
+    schema_version, split_chunk_list, verify_chunks, ChunkHash, NameEncoding, SqliteDatabase,
+    SCHEMA_VERSION,
+};
+use failure::Error;
+use rusqlite::types::Value;
+use rusqlite::NO_PARAMS;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// How many of the things a check found wrong it lists before leaving out the rest.
+const SHOWN: usize = 10;
+
+/// The share of free pages past which VACUUM is worth its cost.
+const FREE_SHARE: f64 = 0.25;
+
+/// Tallies the problems the checks found and fixed, writing them up to `out`.
+struct Report<'a, W> {
+    out: &'a mut W,
+    problems: usize,
+    fixed: usize,
+    /// Problems left that --fix would have fixed.
+    fixable: usize,
+}
+
+impl<'a, W: Write> Report<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Report {
+            out,
+            problems: 0,
+            fixed: 0,
+            fixable: 0,
+        }
+    }
+
+    fn ok(&mut self, check: &str) -> Result<(), Error> {
+        writeln!(
+            self.out,
+            "{}: {}",
+            check,
+            paint(Stream::Stdout, Style::Good, "ok")
+        )?;
+        Ok(())
+    }
+
+    fn skipped(&mut self, check: &str, why: &str) -> Result<(), Error> {
+        writeln!(
+            self.out,
+            "{}: {}",
+            check,
+            paint(Stream::Stdout, Style::Warning, why)
+        )?;
+        Ok(())
+    }
+
+    /// Writes a problem with the first few things it affects and what to do about it.
+    fn problem(
+        &mut self,
+        check: &str,
+        problem: String,
+        details: &[String],
+        fix: &str,
+    ) -> Result<(), Error> {
+        self.problems += 1;
+        writeln!(
+            self.out,
+            "{}: {}",
+            check,
+            paint(Stream::Stdout, Style::Bad, problem)
+        )?;
+        for detail in details.iter().take(SHOWN) {
+            writeln!(self.out, "    {}", detail)?;
+        }
+        if details.len() > SHOWN {
+            writeln!(self.out, "    and {} more", details.len() - SHOWN)?;
+        }
+        writeln!(self.out, "  fix: {}", fix)?;
+        Ok(())
+    }
+
+    /// Counts a fixable problem as fixed if `fix` is set, and as left to --fix otherwise.
+    /// Returns `fix`, for the caller to go on and fix it.
+    fn fixable(&mut self, fix: bool) -> bool {
+        match fix {
+            true => self.fixed += 1,
+            false => self.fixable += 1,
+        }
+        fix
+    }
+
+    fn verdict(&mut self) -> Result<bool, Error> {
+        let left = self.problems - self.fixed;
+        if self.problems == 0 {
+            writeln!(
+                self.out,
+                "{}",
+                paint(Stream::Stdout, Style::Good, "healthy: every check passed")
+            )?;
+        } else if left == 0 {
+            let msg = format!("healthy: fixed all {} problems found", self.problems);
+            writeln!(self.out, "{}", paint(Stream::Stdout, Style::Good, msg))?;
+        } else {
+            let mut msg = format!("unhealthy: {} of {} problems left", left, self.problems);
+            if self.fixable > 0 {
+                msg += &format!(", of which Doctor --fix can fix {}", self.fixable);
+            }
+            writeln!(self.out, "{}", paint(Stream::Stdout, Style::Bad, msg))?;
+        }
+
+        Ok(left == 0)
+    }
+}
+
+/// Runs every check, with `fix` also running the fixes that can't lose data, and writes the
+/// report to `out`. Returns whether the archive ends up healthy.
+pub(crate) fn doctor_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    fix: bool,
+    encoding: NameEncoding,
+    out: &mut W,
+) -> Result<bool, Error> {
+    let mut report = Report::new(out);
+
+    // Nothing is written to an archive from a newer version, which may keep more than this
+    // one knows to update.
+    let version = schema_version(&db.connection)?;
+    let writable = match version.cmp(&SCHEMA_VERSION) {
+        Ordering::Equal => {
+            report.ok("schema version")?;
+            true
+        }
+        Ordering::Less => {
+            let problem = format!(
+                "archive is at {}, older than the {} this version writes",
+                version, SCHEMA_VERSION
+            );
+            report.problem("schema version", problem, &[], "run Migrate")?;
+            if report.fixable(fix) {
+                migrate_cmd(db)?;
+            }
+            fix
+        }
+        Ordering::Greater => {
+            let problem = format!(
+                "archive is at {}, newer than the {} this version understands",
+                version, SCHEMA_VERSION
+            );
+            let advice = "upgrade chsqlar, as this one won't fix anything in it";
+            report.problem("schema version", problem, &[], advice)?;
+            false
+        }
+    };
+    let fix = fix && writable;
+
+    check_database(db, &mut report)?;
+    check_chunk_lists(db, &mut report, fix, encoding)?;
+    check_chunk_data(db, &mut report)?;
+    check_free_space(db, &mut report, fix)?;
+
+    report.verdict()
+}
+
+/// SQLite's own check of the pages and indexes underneath the tables.
+fn check_database<W: Write>(db: &SqliteDatabase, report: &mut Report<W>) -> Result<(), Error> {
+    let mut messages = Vec::new();
+    {
+        let mut stmt = db.connection.prepare("PRAGMA quick_check")?;
+        for message in stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))? {
+            messages.push(message?);
+        }
+    }
+
+    if messages == ["ok"] {
+        report.ok("database")?;
+    } else {
+        let problem = format!("SQLite found {} problems", messages.len());
+        let advice = "restore the archive from a backup; sqlite3's .recover may save the rest";
+        report.problem("database", problem, &messages, advice)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that every chunk list is made of hashes of stored chunks, that each chunk's
+/// reference count matches the lists, and that every chunk is used by one.
+fn check_chunk_lists<W: Write>(
+    db: &mut SqliteDatabase,
+    report: &mut Report<W>,
+    fix: bool,
+    encoding: NameEncoding,
+) -> Result<(), Error> {
+    let chunk_hash = ChunkHash::load(&db.connection)?;
+
+    let mut stored: HashMap<String, (i64, i64)> = HashMap::new();
+    {
+        let mut stmt = db
+            .connection
+            .prepare("SELECT hash, refcount, COALESCE(size, length(data)) FROM chunks")?;
+        for row in stmt.query_map(NO_PARAMS, |row| {
+            (row.get::<_, String>(0), row.get(1), row.get(2))
+        })? {
+            let (hash, refcount, size) = row?;
+            stored.insert(hash, (refcount, size));
+        }
+    }
+
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    let mut malformed = Vec::new();
+    let mut dangling = Vec::new();
+    for table in &["files", "blobs"] {
+        let key = match *table {
+            "files" => "name",
+            _ => "hash",
+        };
+        let mut stmt = db
+            .connection
+            .prepare(&format!("SELECT {}, chunks FROM {}", key, table))?;
+        for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, Value>(0), row.get(1)))? {
+            let (key, chunks): (Value, Value) = row?;
+            let owner = || -> Result<String, Error> {
+                match *table {
+                    "files" => Ok(decode_name(key.clone(), encoding)?.display().to_string()),
+                    _ => Ok(format!("blob {}", meta_text(key.clone()))),
+                }
+            };
+
+            let chunks = match chunks {
+                Value::Text(chunks) => chunks,
+                _ => {
+                    malformed.push(format!("{}: the chunk list isn't text", owner()?));
+                    continue;
+                }
+            };
+            for hash in split_chunk_list(&chunks) {
+                if hash.len() != chunk_hash.hex_len()
+                    || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+                {
+                    malformed.push(format!("{}: '{}' isn't a chunk hash", owner()?, hash));
+                    continue;
+                }
+                if !stored.contains_key(&hash) {
+                    dangling.push(format!("{}: {}", owner()?, hash));
+                }
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+        }
+    }
+
+    if malformed.is_empty() {
+        report.ok("chunk lists")?;
+    } else {
+        let problem = format!("{} malformed entries", malformed.len());
+        let advice = "Add the files again from their originals, which rewrites their lists";
+        report.problem("chunk lists", problem, &malformed, advice)?;
+    }
+
+    if dangling.is_empty() {
+        report.ok("dangling references")?;
+    } else {
+        let problem = format!("{} references to chunks that aren't stored", dangling.len());
+        let advice = "Add the files (or PutBlob the blobs) again from their originals, which \
+                      stores the missing chunks";
+        report.problem("dangling references", problem, &dangling, advice)?;
+    }
+
+    if schema_version(&db.connection)? < 2 {
+        report.skipped("reference counts", "not kept until the archive is migrated")?;
+    } else {
+        let drifted: Vec<String> = stored
+            .iter()
+            .filter(|(hash, (refcount, _))| counts.get(*hash).copied().unwrap_or(0) != *refcount)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        if drifted.is_empty() {
+            report.ok("reference counts")?;
+        } else {
+            let problem = format!("{} chunks have the wrong count", drifted.len());
+            let advice = "run Compact --rebuild-refcounts";
+            report.problem("reference counts", problem, &drifted, advice)?;
+            if report.fixable(fix) {
+                let trans = db.connection.transaction()?;
+                let (chunks, _) = rebuild_refcounts(&trans)?;
+                trans.commit()?;
+                writeln!(
+                    report.out,
+                    "  rebuilt the reference counts of {} chunks",
+                    chunks
+                )?;
+            }
+        }
+    }
+
+    let unreferenced: Vec<(&String, i64)> = stored
+        .iter()
+        .filter(|(hash, _)| !counts.contains_key(*hash))
+        .map(|(hash, (_, size))| (hash, *size))
+        .collect();
+    if unreferenced.is_empty() {
+        report.ok("unreferenced chunks")?;
+    } else {
+        let bytes: i64 = unreferenced.iter().map(|(_, size)| size).sum();
+        let problem = format!(
+            "{} chunks ({} bytes) that no file or blob uses",
+            unreferenced.len(),
+            bytes
+        );
+        let hashes: Vec<String> = unreferenced
+            .iter()
+            .map(|(hash, _)| hash.to_string())
+            .collect();
+        report.problem(
+            "unreferenced chunks",
+            problem,
+            &hashes,
+            "run Compact, which deletes them",
+        )?;
+        // A malformed entry may still be meant for one of them.
+        if !malformed.is_empty() {
+            writeln!(
+                report.out,
+                "  not deleting them while chunk lists are malformed"
+            )?;
+        } else if report.fixable(fix) {
+            let trans = db.connection.transaction()?;
+            for (hash, _) in &unreferenced {
+                delete_chunk(&trans, hash)?;
+            }
+            trans.commit()?;
+            remove_loose_chunks(&db.connection, unreferenced.iter().map(|(hash, _)| hash))?;
+            writeln!(report.out, "  deleted {} chunks", unreferenced.len())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads, decrypts and decompresses every chunk, as Verify does.
+fn check_chunk_data<W: Write>(db: &SqliteDatabase, report: &mut Report<W>) -> Result<(), Error> {
+    let mut faults = Vec::new();
+    let (checked, _) = verify_chunks(&db.connection, false, |hash, fault| {
+        faults.push(format!("{}: {}", hash, fault));
+    })?;
+
+    if faults.is_empty() {
+        report.ok("chunk data")?;
+    } else {
+        let problem = format!("{} of {} chunks are faulty", faults.len(), checked);
+        let advice = "none can repair them; the files using them have to be restored from \
+                      elsewhere, and Verify lists the chunks again";
+        report.problem("chunk data", problem, &faults, advice)?;
+    }
+
+    Ok(())
+}
+
+/// Pages freed by deletions that only VACUUM gives back to the file system.
+fn check_free_space<W: Write>(
+    db: &SqliteDatabase,
+    report: &mut Report<W>,
+    fix: bool,
+) -> Result<(), Error> {
+    let pages: i64 = db
+        .connection
+        .query_row("PRAGMA page_count", NO_PARAMS, |row| row.get(0))?;
+    let free: i64 = db
+        .connection
+        .query_row("PRAGMA freelist_count", NO_PARAMS, |row| row.get(0))?;
+
+    if free as f64 <= pages as f64 * FREE_SHARE {
+        report.ok("free space")?;
+    } else {
+        let problem = format!("{} of {} pages are free", free, pages);
+        let advice = "run Compact --vacuum, which needs as much free space as the archive takes";
+        report.problem("free space", problem, &[], advice)?;
+        if report.fixable(fix) {
+            db.connection.execute_batch("VACUUM;")?;
+            writeln!(report.out, "  vacuumed")?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,19 +1,43 @@
+use failure::{format_err, Error, Fail};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use globset::{Glob, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use rayon::prelude::*;
-use cdchunking::{Chunker, ZPAQ};
-use crypto::digest::Digest;
-use crypto::sha3::Sha3;
-use failure::Error;
-use rusqlite::types::ToSql;
+use regex::bytes::Regex;
+use rusqlite::types::{ToSql, Value};
 use rusqlite::DropBehavior;
 use rusqlite::Transaction;
-use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use sha2::{Digest, Sha256};
+use sha3::Sha3_512;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env::current_dir;
+use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
 use structopt::StructOpt;
 use zstd::{decode_all, encode_all};
 
+#[cfg(feature = "tui")]
+mod browse;
+mod chunker;
+mod crypt;
+#[cfg(unix)]
+mod daemon;
+mod dict;
+mod doctor;
+mod output;
+#[cfg(test)]
+mod tests;
+mod watch;
+
+use output::{paint, Stream, Style};
+
 #[derive(StructOpt, Debug)]
 struct Opt {
     #[structopt(flatten)]
@@ -28,331 +52,8272 @@ struct CommonOpt {
     database: String,
     #[structopt(short = "v", parse(from_occurrences))]
     verbosity: u8,
+    /// How to store file names. "bytes" keeps names that aren't valid UTF-8 as raw bytes,
+    /// "percent" escapes them as %XX, and "utf8" refuses to store them. Defaults to "bytes"
+    /// for a new archive, which records it; later runs use the recorded one and refuse a
+    /// different one.
+    #[structopt(
+        long = "name-encoding",
+        raw(possible_values = "&[\"utf8\", \"bytes\", \"percent\"]")
+    )]
+    name_encoding: Option<NameEncoding>,
+    /// On failure, print the error to stderr as a JSON object.
+    #[structopt(long = "json-errors")]
+    json_errors: bool,
+    /// Take the archive's lock even from a process that still seems to be running, for a
+    /// lock left behind where that can't be told, as on Windows. Only safe once the
+    /// command that took it has really stopped.
+    #[structopt(long = "break-lock")]
+    break_lock: bool,
+    /// When to colour output. "auto" colours whichever of stdout and stderr is a terminal,
+    /// unless the NO_COLOR environment variable is set.
+    #[structopt(
+        long = "color",
+        default_value = "auto",
+        raw(possible_values = "&[\"auto\", \"always\", \"never\"]")
+    )]
+    color: String,
+    /// Don't print the summary of files, bytes and throughput at the end of Add and Extract.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+    /// Print the summary at the end of Add and Extract to stderr as a JSON object.
+    #[structopt(long = "json-summary")]
+    json_summary: bool,
+    /// How many threads to chunk and compress with. Defaults to one per logical CPU; 1
+    /// does everything on a single thread, in order.
+    #[structopt(long = "threads")]
+    threads: Option<usize>,
+    /// Hold at most this many bytes of file and chunk data at once. The limit is shared by
+    /// the pieces of files Add reads, the output of the chunks being compressed and the
+    /// chunk cache, which gives way to the rest. Under a limit Add reads each file a piece
+    /// at a time, except with --filter or --sparse and for files stored inline or in whole.
+    /// A single chunk or piece always goes ahead, so the real floor is one maximum-size
+    /// chunk per compression thread (see --threads). The limit and the peak are reported
+    /// at -v by Add and Extract.
+    #[structopt(long = "memory-limit")]
+    memory_limit: Option<u64>,
+    /// Keep up to this many MiB of decompressed chunks in memory while reading files back,
+    /// so chunks shared between files are only read and decompressed once.
+    #[structopt(long = "chunk-cache-mb", default_value = "0")]
+    chunk_cache_mb: u64,
+    /// Write new chunk data to files under this directory instead of into the database,
+    /// which then only holds metadata. The directory is remembered by the archive, so it
+    /// only needs giving again to move it.
+    #[structopt(long = "store-dir", parse(from_os_str))]
+    store_dir: Option<PathBuf>,
+    /// Average chunk size as a power of two for a new archive, from 8 to 30; the default is
+    /// 20, about 1 MiB. Smaller chunks find more duplication, in databases and between small
+    /// files, at the cost of more rows. Every later add uses the archive's, and changing it
+    /// once there are chunks takes Compact --chunk-bits, which cuts them all again, or Add
+    /// --force-params, which only cuts new ones.
+    #[structopt(long = "chunk-bits")]
+    chunk_bits: Option<usize>,
+    /// How a new archive finds chunk boundaries. "zpaq" is what archives used before there
+    /// was a choice; "fastcdc" cuts several times faster with about as much dedup, for
+    /// --chunk-bits up to 22; "fixed" cuts every --chunk-size bytes, for already aligned
+    /// data like disk images. Like --chunk-bits, every later add uses the archive's.
+    #[structopt(long = "chunker", raw(possible_values = "chunker::Algorithm::NAMES"))]
+    chunker: Option<chunker::Algorithm>,
+    /// With --chunker fixed, the size of each chunk in bytes, from 512 to 2^30. Defaults to
+    /// 2^--chunk-bits.
+    #[structopt(long = "chunk-size")]
+    chunk_size: Option<usize>,
+    /// Cut chunks at this many bytes even where the chunker found no boundary, which it can
+    /// fail to do for long stretches of repetitive data. Remembered by the archive; defaults
+    /// to 8 times the average chunk size. Chunks already stored keep their size until Compact.
+    #[structopt(
+        long = "max-chunk-bytes",
+        parse(try_from_str = "parse_max_chunk_bytes")
+    )]
+    max_chunk_bytes: Option<usize>,
+    /// What to hash the chunks of a new archive with: "sha3-512", which archives used
+    /// first, or "blake3", many times faster and with shorter keys. An archive keeps the one
+    /// its chunks are stored under; Compact --hash converts it.
+    #[structopt(long = "hash", raw(possible_values = "ChunkHash::NAMES"))]
+    hash: Option<String>,
+    /// Hash the chunks of a new archive with BLAKE3 read out to this many bytes, from 8 to
+    /// 64, instead of the 32 --hash blake3 gives. 16 bytes halves the size of the hashes
+    /// stored for every chunk and stays safe from collisions for any realistic number of
+    /// chunks; Add warns once the archive holds enough that one becomes plausible.
+    #[structopt(long = "hash-bytes", parse(try_from_str = "parse_hash_bytes"))]
+    hash_bytes: Option<usize>,
+    /// Encrypt the content of a new archive, with the key from --key-file or else a
+    /// passphrase taken from CHSQLAR_PASSPHRASE or asked for. File sizes and chunk hashes
+    /// stay readable, and names too without --encrypt-names; Stats says what that gives away.
+    #[structopt(long = "encrypt")]
+    encrypt: bool,
+    /// With --encrypt, also encrypt file names and times, ownership, checksums and --filter
+    /// commands. The key is then needed for every command, and listing in order or by
+    /// prefix decrypts every name. --name-encoding doesn't apply, as names are kept as bytes.
+    #[structopt(long = "encrypt-names", requires = "encrypt")]
+    encrypt_names: bool,
+    /// Read the key of an encrypted archive from this file, holding 32 bytes as is or in
+    /// base64, instead of using a passphrase.
+    #[structopt(long = "key-file", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    #[structopt(flatten)]
+    hooks: HookOpt,
+    /// Send Add, List and Cat to the Daemon listening on this socket instead of opening the
+    /// database, which is then ignored. Add uses the options the daemon was started with.
+    #[structopt(long = "remote", parse(from_os_str))]
+    remote: Option<PathBuf>,
+    #[structopt(flatten)]
+    db_opt: DatabaseOpt,
 }
 
+/// Shell commands to run when Add, Extract or Compact finishes. They get CHSQLAR_OP,
+/// CHSQLAR_DB_PATH and CHSQLAR_EXIT_CODE, plus CHSQLAR_FILES_ADDED after Add and
+/// CHSQLAR_BYTES_WRITTEN, the bytes Add stored after dedup and compression or the bytes
+/// Extract wrote.
 #[derive(StructOpt, Debug)]
-enum OptCommand {
-    Add { files: Vec<PathBuf> },
-    List,
-    Extract { files: Vec<PathBuf> },
+struct HookOpt {
+    /// Run this command through the shell after Add, Extract or Compact succeeds.
+    #[structopt(long = "on-success")]
+    on_success: Option<String>,
+    /// Run this command through the shell after Add, Extract or Compact fails.
+    #[structopt(long = "on-failure")]
+    on_failure: Option<String>,
+    /// Exit with status 1 if --on-success fails. Otherwise a failing hook is only reported.
+    #[structopt(long = "hook-strict")]
+    hook_strict: bool,
 }
 
-#[derive(Debug, Clone)]
-struct File {
-    name: PathBuf,
-    size: i64,
-    chunks: Vec<String>,
+#[derive(StructOpt, Debug, Clone)]
+struct DatabaseOpt {
+    /// SQLite page size in bytes. Only takes effect when creating a new archive.
+    #[structopt(long = "page-size")]
+    page_size: Option<u32>,
+    /// SQLite page cache size, in pages if positive or in KiB if negative.
+    #[structopt(long = "cache-size", allow_hyphen_values = true)]
+    cache_size: Option<i64>,
+    /// How often SQLite syncs to disk. "normal" can lose the last transactions on power
+    /// loss but never corrupts the archive in WAL mode, "full" syncs every commit and "off"
+    /// leaves it to the OS, which is fastest but can corrupt the archive if the machine
+    /// crashes mid-write.
+    #[structopt(
+        long = "sync",
+        default_value = "normal",
+        raw(possible_values = "&[\"off\", \"normal\", \"full\"]")
+    )]
+    sync: String,
+    /// How SQLite returns the space of deleted chunks to the file system. "incremental"
+    /// lets Compact give it back without a full VACUUM. Only takes effect when creating a
+    /// new archive.
+    #[structopt(
+        long = "auto-vacuum",
+        raw(possible_values = "&[\"none\", \"full\", \"incremental\"]")
+    )]
+    auto_vacuum: Option<String>,
+    /// SQLite journal mode. "wal" lets readers work during an add, but keeps -wal and -shm
+    /// files next to the archive and doesn't work on some network file systems; the others
+    /// leave a single file between runs.
+    #[structopt(
+        long = "journal",
+        default_value = "wal",
+        raw(possible_values = "&[\"wal\", \"delete\", \"truncate\", \"memory\"]")
+    )]
+    journal: String,
+    /// Where SQLite writes the scratch files that VACUUM and large sorts need, instead of
+    /// $SQLITE_TMPDIR or $TMPDIR. VACUUM, as run by Compact --vacuum and Doctor --fix, needs
+    /// as much room there as the archive takes.
+    #[structopt(long = "temp-dir", parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
 }
 
-struct PreparedChunk {
-    hash: String,
-    data: Vec<u8>,
+#[derive(StructOpt, Debug)]
+enum OptCommand {
+    /// Store files in the archive.
+    ///
+    /// Ctrl-C finishes and commits the file being added, then exits with status 130. Running
+    /// the same add again continues from there; a second Ctrl-C exits immediately, losing
+    /// only the file in progress. With --atomic, Ctrl-C discards the whole add instead.
+    ///
+    /// By default files are added in order of the names they're stored under, compared one
+    /// path component at a time, so runs over the same tree go through it in the same order.
+    Add {
+        files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        add_opt: AddOpt,
+    },
+    List {
+        /// Also show each file's size and modification time (UTC).
+        #[structopt(short = "l")]
+        long: bool,
+        /// With -l, show modification times to the nanosecond.
+        #[structopt(long = "full-time")]
+        full_time: bool,
+        /// Print the distinct directories that stored files live in instead of the files.
+        #[structopt(long = "dirs", conflicts_with = "long")]
+        dirs: bool,
+        /// With --dirs, only descend this many levels.
+        #[structopt(short = "L", requires = "dirs")]
+        max_depth: Option<usize>,
+        /// Only list files stored after this UTC time, given as YYYY-MM-DD, "YYYY-MM-DD
+        /// HH:MM:SS" or seconds since the Unix epoch.
+        #[structopt(
+            long = "added-after",
+            conflicts_with = "dirs",
+            parse(try_from_str = "parse_time")
+        )]
+        added_after: Option<i64>,
+        /// Instead of the files, print how they differ from those in an older archive: "A"
+        /// for added, then "M" for changed, then "D" for removed, each followed by a tab and
+        /// the name. Files count as changed if their SHA-256 differs, or their size where
+        /// either archive didn't record it.
+        #[structopt(
+            long = "modified-since",
+            raw(conflicts_with_all = "&[\"dirs\", \"long\", \"added_after\"]")
+        )]
+        modified_since: Option<String>,
+        /// End each entry with a NUL byte instead of a newline, like find -print0, so names
+        /// containing newlines survive being piped into xargs -0.
+        #[structopt(short = "0", long = "null")]
+        null: bool,
+        /// Only list files with this tag, given as key=value. Given more than once, files
+        /// must have every one.
+        #[structopt(
+            long = "tag",
+            number_of_values = 1,
+            parse(try_from_str = "parse_tag"),
+            raw(conflicts_with_all = "&[\"dirs\", \"modified_since\"]")
+        )]
+        tags: Vec<(String, String)>,
+    },
+    Extract {
+        files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        extract_opt: ExtractOpt,
+    },
+    /// Write the content of stored files to stdout, in the order given. A directory is
+    /// replaced by the files below it, sorted by name.
+    Cat {
+        files: Vec<PathBuf>,
+        /// Fail on arguments that aren't stored files instead of expanding directories.
+        #[structopt(long = "no-expand-dirs")]
+        no_expand_dirs: bool,
+    },
+    PutBlob,
+    GetBlob {
+        hash: String,
+    },
+    Stats,
+    /// Print every setting the archive keeps, one key and value per line in key order,
+    /// including any written by a newer version.
+    Info,
+    /// Bring an archive written by an older version up to the current schema, which write
+    /// commands require. Read commands work without it.
+    Migrate {
+        /// Also hash the content of every file added by a version that didn't store a
+        /// content hash, reading each one back out of the archive once.
+        #[structopt(long = "compute-file-hashes")]
+        compute_file_hashes: bool,
+    },
+    /// List chunks that no file or blob refers to.
+    Orphans,
+    /// Run every consistency check in turn and say which command fixes each problem found,
+    /// ending with a one-line verdict. Exits with 1 unless the archive ends up healthy.
+    Doctor {
+        /// Also run the fixes that can't lose data: Migrate, rebuilding reference counts,
+        /// deleting unused chunks and VACUUM.
+        #[structopt(long = "fix")]
+        fix: bool,
+    },
+    /// Show how much stored (compressed) data each directory accounts for, largest first.
+    Du {
+        /// How many levels of directories to break the totals down into.
+        #[structopt(long = "depth", default_value = "1")]
+        depth: usize,
+        /// How to count chunks shared by several files. "split" divides them evenly
+        /// between the files, "once" counts them for the first file only.
+        #[structopt(
+            long = "shared",
+            default_value = "split",
+            raw(possible_values = "&[\"split\", \"once\"]")
+        )]
+        shared: String,
+        /// Print exact byte counts instead of human-readable sizes.
+        #[structopt(long = "bytes")]
+        bytes: bool,
+    },
+    /// Print where the chunker cuts a file, one chunk per line as offset, length and hash,
+    /// without storing anything. For seeing why an edit does or doesn't keep chunks shared.
+    ChunkFile {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Average chunk size as a power of two. Defaults to the archive's, if it exists.
+        #[structopt(long = "chunk-bits")]
+        chunk_bits: Option<usize>,
+    },
+    /// Cut files with each chunker and compare how fast they go and how much of the data
+    /// they find duplicated, without storing anything. Uses --chunk-bits and
+    /// --max-chunk-bytes, or the archive's if it exists.
+    Bench {
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        files: Vec<PathBuf>,
+    },
+    /// Re-chunk every stored file and blob with new chunker parameters, then delete the
+    /// chunks that are no longer used.
+    Compact {
+        /// Average chunk size as a power of two, used by this and all later adds. Defaults
+        /// to the archive's.
+        #[structopt(long = "chunk-bits")]
+        chunk_bits: Option<usize>,
+        /// Switch to this chunker, also for all later adds. Defaults to the archive's.
+        #[structopt(long = "chunker", raw(possible_values = "chunker::Algorithm::NAMES"))]
+        chunker: Option<chunker::Algorithm>,
+        /// With --chunker fixed, the size of each chunk in bytes.
+        #[structopt(long = "chunk-size")]
+        chunk_size: Option<usize>,
+        /// Store every chunk again under this hash, also for all later adds. Blobs keep the
+        /// hashes they were put under.
+        #[structopt(long = "hash", raw(possible_values = "ChunkHash::NAMES"))]
+        hash: Option<String>,
+        /// With --hash blake3, how many bytes to read out, as the global --hash-bytes.
+        #[structopt(long = "hash-bytes", parse(try_from_str = "parse_hash_bytes"))]
+        hash_bytes: Option<usize>,
+        /// Afterwards rebuild the whole database with VACUUM, which needs as much free space
+        /// as the archive takes.
+        #[structopt(long = "vacuum")]
+        vacuum: bool,
+        /// First recompute every chunk's reference count from scratch, reporting any that
+        /// had drifted.
+        #[structopt(long = "rebuild-refcounts")]
+        rebuild_refcounts: bool,
+        /// Only report how many chunks, and how many bytes stored and uncompressed, would be
+        /// deleted, broken down by codec. Rechunking is left out, so it can't be combined
+        /// with the chunker options.
+        #[structopt(
+            long = "dry-run",
+            raw(conflicts_with_all = r#"&[
+                "chunk_bits", "chunker", "chunk_size", "hash", "hash_bytes", "vacuum"
+            ]"#)
+        )]
+        dry_run: bool,
+    },
+    /// Copy files and the chunks they use from another archive, without extracting them.
+    Pull {
+        /// The archive to copy from. It must use the same chunker parameters as this one.
+        #[structopt(long = "from")]
+        from: String,
+        /// Globs matched against stored names. Everything is copied if none are given.
+        patterns: Vec<String>,
+    },
+    /// Copy every file from several archives into this one, storing chunks they share once.
+    /// All of them must use the same chunker parameters. Each input is committed as it is
+    /// copied, after every input has been checked.
+    Merge {
+        /// The archives to copy from, in order of precedence.
+        #[structopt(raw(required = "true"))]
+        inputs: Vec<String>,
+        /// What to do when the same name holds different content in more than one place,
+        /// including this archive. "error" copies nothing, "skip" leaves the name as this
+        /// archive had it, "first-wins" keeps the first copy, and "rename" keeps every copy,
+        /// adding .~N~ to the name of the later ones.
+        #[structopt(
+            long = "on-conflict",
+            default_value = "error",
+            raw(possible_values = "&[\"error\", \"skip\", \"first-wins\", \"rename\"]")
+        )]
+        on_conflict: String,
+    },
+    /// Divide the files into new self-contained archives, each holding whole files and the
+    /// chunks they use, and no bigger than --max-size. Chunks shared by files in different
+    /// parts are stored in each of them. Pull every part into one archive to put them back
+    /// together. Blobs aren't copied.
+    Split {
+        /// The size limit for each part, in bytes or with a K, M, G or T suffix. A file too
+        /// big to fit goes into a part of its own, which will be over the limit.
+        #[structopt(long = "max-size", parse(try_from_str = "parse_size"))]
+        max_size: u64,
+        /// Where to write the parts, with {} standing for the part number from 1.
+        #[structopt(long = "out")]
+        out: String,
+    },
+    Verify {
+        /// Only check the stored checksum of each compressed chunk, without decompressing it.
+        #[structopt(long = "fast")]
+        fast: bool,
+        /// Also recompress every chunk stored another way with this one: a zstd level, or
+        /// store, deflate, lz4 or brotli. Chunks already in the same codec are only rewritten
+        /// if they get smaller. Chunks in --store-dir are left as they are.
+        #[structopt(long = "repair-compression", conflicts_with = "fast")]
+        repair_compression: Option<Compression>,
+        /// Instead of checking chunks one by one, read every file back out whole and check it
+        /// against the content hash stored when it was added. Files added by versions that
+        /// didn't store one are skipped until Migrate --compute-file-hashes.
+        #[structopt(
+            long = "files",
+            raw(conflicts_with_all = r#"&["fast", "repair_compression"]"#)
+        )]
+        files: bool,
+        /// With a zstd level for --repair-compression, recompress with the dictionary
+        /// TrainDict last trained.
+        #[structopt(long = "use-dict", requires = "repair_compression")]
+        use_dict: bool,
+    },
+    /// Compare two stored files, exiting with 0 if they're the same, 1 if they differ and 2
+    /// on trouble, like cmp.
+    Cmp {
+        first: PathBuf,
+        second: PathBuf,
+        /// When the files are stored differently, compare their content and report the first
+        /// differing byte, instead of only saying they differ.
+        #[structopt(long = "bytes")]
+        bytes: bool,
+    },
+    /// Print one line per stored file, sorted by name, for auditing and tooling.
+    ///
+    /// The columns are, in this order: name; size in bytes; chunks, the number of chunks
+    /// the content is stored in; sha256, the SHA-256 of the content; and mtime, the
+    /// modification time in RFC 3339 UTC, empty or null if it wasn't stored. CSV and TSV
+    /// output start with a header row. The SHA-256 recorded when a file was added is used
+    /// where there is one.
+    Manifest {
+        /// "csv" quotes fields as RFC 4180 does, "tsv" escapes tabs, newlines and
+        /// backslashes in names with a backslash, and "json-lines" prints one JSON object per
+        /// file, with names that aren't UTF-8 converted lossily and its tags added.
+        /// "sha256sum" only prints the hash and name, for checking a restored tree with
+        /// sha256sum -c.
+        #[structopt(
+            long = "format",
+            default_value = "csv",
+            raw(possible_values = "&[\"csv\", \"tsv\", \"json-lines\", \"sha256sum\"]")
+        )]
+        format: String,
+    },
+    /// Print stored names starting with `partial`, for the shell completion scripts in
+    /// completions/. Prints nothing if the archive can't be read.
+    #[structopt(
+        name = "__complete",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    Complete {
+        subcommand: String,
+        #[structopt(default_value = "")]
+        partial: String,
+    },
+    /// Keep the archive open and serve requests on a Unix socket until SIGTERM or Ctrl-C,
+    /// finishing the requests in flight first. Use --remote to send commands to it. The add
+    /// options given here apply to every file added through the daemon.
+    #[cfg(unix)]
+    Daemon {
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: PathBuf,
+        #[structopt(flatten)]
+        add_opt: AddOpt,
+    },
+    /// Add the given files and directories, then keep adding files under them as they're
+    /// created or modified, until Ctrl-C. Unchanged files are skipped by size and modification
+    /// time, and deleted ones stay in the archive. Keep the archive outside what's watched.
+    Watch {
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+        /// Wait until no changes have come in for this many milliseconds before adding them,
+        /// so a burst of writes is added once.
+        #[structopt(long = "debounce-ms", default_value = "500")]
+        debounce_ms: u64,
+        #[structopt(flatten)]
+        add_opt: AddOpt,
+    },
+    /// Browse the archive interactively: arrows to move, Enter to open a directory or preview
+    /// a file, e to extract the selection, d to mark it and x to remove what's marked.
+    #[cfg(feature = "tui")]
+    Browse,
+    /// Print a digest of each stored file's content in the format of sha256sum and similar
+    /// tools, sorted by name.
+    Checksum {
+        #[structopt(
+            long = "algo",
+            default_value = "sha256",
+            raw(possible_values = "&[\"sha256\", \"sha3-512\", \"blake3\"]")
+        )]
+        algo: String,
+        /// Only the files at or below this path.
+        prefix: Option<PathBuf>,
+    },
+    /// Attach a tag to a stored file, replacing any it already has under `key`. Tags are
+    /// kept apart from the file's own metadata and stay when it's added again.
+    SetTag {
+        file: PathBuf,
+        key: String,
+        value: String,
+    },
+    /// Print the tags of a stored file as key=value, one per line sorted by key.
+    GetTags {
+        file: PathBuf,
+    },
+    /// Train a zstd dictionary on a random sample of the archive's small chunks and make it
+    /// the one Add --use-dict compresses with, printing how much it saves on the sample.
+    /// Earlier dictionaries are kept for the chunks already using them.
+    TrainDict {
+        /// How many chunks to sample.
+        #[structopt(long = "samples", default_value = "1000")]
+        samples: u32,
+        /// The largest dictionary to train, in bytes or with a K or M suffix.
+        #[structopt(
+            long = "max-size",
+            default_value = "110K",
+            parse(try_from_str = "parse_size")
+        )]
+        max_size: u64,
+    },
 }
 
-struct SqliteDatabase {
-    connection: Connection,
+impl OptCommand {
+    /// Whether the command changes the archive, and so needs it at the current schema.
+    fn writes(&self) -> bool {
+        match self {
+            OptCommand::Add { .. }
+            | OptCommand::PutBlob
+            | OptCommand::Compact { .. }
+            | OptCommand::Pull { .. }
+            | OptCommand::Merge { .. }
+            | OptCommand::Daemon { .. }
+            | OptCommand::Watch { .. }
+            | OptCommand::SetTag { .. }
+            | OptCommand::TrainDict { .. } => true,
+            #[cfg(feature = "tui")]
+            OptCommand::Browse => true,
+            OptCommand::Verify {
+                repair_compression, ..
+            } => repair_compression.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Whether the command adds with --force-params.
+    fn forces_params(&self) -> bool {
+        match self {
+            OptCommand::Add { add_opt, .. }
+            | OptCommand::Daemon { add_opt, .. }
+            | OptCommand::Watch { add_opt, .. } => add_opt.force_params,
+            _ => false,
+        }
+    }
 }
 
-impl SqliteDatabase {
-    fn new(fname: &str) -> Result<Self, Error> {
-        let connection = Connection::open(fname)?;
+#[derive(StructOpt, Debug, Clone)]
+struct AddOpt {
+    #[structopt(flatten)]
+    compress_opt: CompressOpt,
+    /// Store files smaller than this many bytes as a single chunk instead of chunking them.
+    #[structopt(long = "whole-file-under")]
+    whole_file_under: Option<u64>,
+    /// Store files smaller than this many bytes directly in the file list, skipping the
+    /// chunk store. 0 turns it off.
+    #[structopt(long = "inline-threshold", default_value = "4096")]
+    inline_threshold: u64,
+    /// Abort on the first file that can't be read, instead of skipping it and carrying on.
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Cut chunks with the --chunker, --chunk-bits and --chunk-size given even though the
+    /// archive's chunks were cut another way, and record them for later adds. Chunks cut the
+    /// two ways won't dedup against each other until Compact cuts them all again. The chunk
+    /// hash can't be forced, as an archive can't hold chunks under two.
+    #[structopt(long = "force-params")]
+    force_params: bool,
+    /// Don't descend into directories on other file systems than the one they were given on.
+    #[structopt(long = "one-file-system")]
+    one_file_system: bool,
+    /// Don't descend into directories given as arguments. A directory can't be stored on its
+    /// own, so each is reported and skipped like a file that can't be read, or fails the add
+    /// with --strict.
+    #[structopt(long = "no-recurse")]
+    no_recurse: bool,
+    /// Stop before committing a file that would make the database and its WAL bigger than
+    /// this many bytes, keeping the files added so far. The limit is saved in the archive
+    /// for later adds; 0 removes it. Chunks in --store-dir don't count.
+    #[structopt(long = "max-db-size")]
+    max_db_size: Option<u64>,
+    /// Remember the hashes of the last N chunks stored or found, so a chunk that comes round
+    /// again within them skips compression and the insert. Chunks seen longer ago still
+    /// dedup, only after being compressed. For big streams whose duplicates sit close
+    /// together, like logs.
+    #[structopt(long = "dedup-window")]
+    dedup_window: Option<usize>,
+    /// Make the database depend only on the files added: don't store modification or add
+    /// times or when the archive was created, and at the end leave WAL mode and VACUUM.
+    /// Adding the same tree into two new archives gives identical files.
+    #[structopt(long = "deterministic")]
+    deterministic: bool,
+    /// Leave files alone that are already stored with the same size and modification time,
+    /// without reading them.
+    #[structopt(long = "skip-unchanged")]
+    skip_unchanged: bool,
+    /// Store the owning user and group of each file, as ids and names, for Extract --owner
+    /// to restore. Does nothing on systems other than Unix.
+    #[structopt(long = "preserve-ownership")]
+    preserve_ownership: bool,
+    /// Add everything in one transaction that is only committed if every file was stored,
+    /// so an error or Ctrl-C leaves the archive as it was. Implies --strict. Without this,
+    /// each file is committed as it's added, so an interrupted add can be resumed.
+    #[structopt(long = "atomic")]
+    atomic: bool,
+    /// Once everything is committed, read each file just added back out of the archive the
+    /// way Extract would and compare it with the file still on disk, failing the add if any
+    /// differ. Every file is read twice and every chunk decoded again, so it's slow.
+    #[structopt(long = "verify-after-commit")]
+    verify_after_commit: bool,
+    /// Skip paths matching the gitignore-style patterns in this file, one per line, relative
+    /// to the current directory. Can be given more than once.
+    #[structopt(long = "exclude-from", number_of_values = 1)]
+    exclude_from: Vec<PathBuf>,
+    /// Store long runs of zeros as holes, which extract recreates without writing them.
+    /// "auto" only looks for them in files that are sparse on disk, "always" in every file.
+    #[structopt(
+        long = "sparse",
+        default_value = "auto",
+        raw(possible_values = "&[\"auto\", \"always\", \"never\"]")
+    )]
+    sparse: String,
+    /// Rename files as they're stored, as REGEX=>REPLACEMENT. The first match is replaced,
+    /// and $1 or ${name} in the replacement insert capture groups. Can be given multiple
+    /// times, applied in order.
+    #[structopt(
+        long = "transform",
+        number_of_values = 1,
+        parse(try_from_str = "parse_transform")
+    )]
+    transforms: Vec<Transform>,
+    /// The order to add files in. "name" sorts them by stored name, "size" adds the largest
+    /// first, "random" shuffles them differently each run and "none" keeps the order the
+    /// file system lists directories in.
+    #[structopt(
+        long = "order",
+        default_value = "name",
+        raw(possible_values = "&[\"name\", \"size\", \"random\", \"none\"]")
+    )]
+    order: String,
+    /// Store what this shell command prints when given each file on stdin, instead of the
+    /// file itself, like a git clean filter. CHSQLAR_FILE holds the stored name. The
+    /// command is recorded with each file, and Extract needs a matching --unfilter.
+    #[structopt(long = "filter")]
+    filter: Option<String>,
+    /// Stop starting new files once this long has passed, like 90s, 45m or 8h, keeping what
+    /// was stored. The same add run again later carries on from where this one stopped.
+    #[structopt(
+        long = "time-budget",
+        conflicts_with = "atomic",
+        parse(try_from_str = "parse_duration")
+    )]
+    time_budget: Option<std::time::Duration>,
+}
 
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS
-            files (
-                name TEXT PRIMARY KEY,
-                size INT,
-                chunks BLOB
-            );
-        ",
-            NO_PARAMS,
-        )?;
+#[derive(StructOpt, Debug)]
+struct ExtractOpt {
+    /// Skip files that already exist with the stored size, and overwrite the rest.
+    #[structopt(long = "resume")]
+    resume: bool,
+    /// Like --resume, but also compare the content of existing files.
+    #[structopt(long = "resume-verify")]
+    resume_verify: bool,
+    /// Create files with the same content as one already extracted as reflinks (copy on
+    /// write clones) of it. "auto" falls back to writing the data where the file system
+    /// doesn't support it, "always" fails instead.
+    #[structopt(
+        long = "reflink",
+        default_value = "never",
+        raw(possible_values = "&[\"auto\", \"always\", \"never\"]")
+    )]
+    reflink: String,
+    /// Write nothing, and print whether each file is missing on disk, identical to the
+    /// stored one or differs from it.
+    #[structopt(long = "list-conflicts", alias = "check-only")]
+    list_conflicts: bool,
+    /// Remove this many leading components from the stored name of each selected file,
+    /// like tar, and write it to what's left, skipping files that don't have more than that.
+    #[structopt(long = "strip-components", default_value = "0")]
+    strip_components: usize,
+    /// Rename files as they're written, as REGEX=>REPLACEMENT, like add's --transform. This
+    /// applies after --strip-components and leaves the archive unchanged.
+    #[structopt(
+        long = "transform",
+        number_of_values = 1,
+        parse(try_from_str = "parse_transform")
+    )]
+    transforms: Vec<Transform>,
+    /// Write files that were added with --filter through this shell command, which gets the
+    /// stored content on stdin and should print the original, like a git smudge filter.
+    #[structopt(long = "unfilter")]
+    unfilter: Option<String>,
+    /// Write the selected files to stdout as a tar stream instead of to disk, for piping into
+    /// tar -x, over ssh or otherwise. Entries get the stored modification time and owner;
+    /// modes aren't stored, so every file is 0644.
+    #[structopt(
+        long = "tar",
+        alias = "to-stdout-tar",
+        raw(conflicts_with_all = r#"&["resume", "resume_verify", "list_conflicts", "unfilter"]"#)
+    )]
+    tar: bool,
+    /// What to do with the owner stored by add's --preserve-ownership. "skip" leaves files
+    /// to the extracting user, "numeric" gives them the stored user and group ids and
+    /// "names" the ids the stored user and group names have here, falling back to the
+    /// stored ids for names that don't exist. Changing the owner usually needs root.
+    #[structopt(
+        long = "owner",
+        default_value = "skip",
+        raw(possible_values = "&[\"skip\", \"numeric\", \"names\"]")
+    )]
+    owner: String,
+}
 
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS
-            chunks (
-                hash BLOB PRIMARY KEY,
-                data BLOB
-            );
-        ",
-            NO_PARAMS,
-        )?;
+#[derive(Debug, Default)]
+struct ExtractSummary {
+    extracted: u64,
+    skipped: u64,
+    rewritten: u64,
+    reflinked: u64,
+    metrics: Metrics,
+    /// The first file extracted with each chunk list, to clone later duplicates from.
+    written: HashMap<String, PathBuf>,
+    /// With --transform, the stored name each output path was written from.
+    transformed: HashMap<PathBuf, PathBuf>,
+    /// How many files couldn't be given their stored owner, and the first error.
+    owner_errors: Option<(u64, String)>,
+    /// With --owner=names, how many files named a user or group this system doesn't have,
+    /// and the first such name.
+    unknown_owners: Option<(u64, String)>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct CompressOpt {
+    /// Compress files matching a glob with the given treatment, as GLOB=store,
+    /// GLOB=deflate, GLOB=lz4, GLOB=brotli or GLOB=<zstd level>. Can be given multiple
+    /// times, the last matching rule wins.
+    #[structopt(
+        long = "compress-rule",
+        number_of_values = 1,
+        parse(try_from_str = "parse_compress_rule")
+    )]
+    compress_rules: Vec<CompressRule>,
+    /// Compress files with extensions that are normally stored as is.
+    #[structopt(long = "compress-all")]
+    compress_all: bool,
+    /// Store files with this extension uncompressed, in addition to the built in list.
+    #[structopt(long = "no-compress-ext", number_of_values = 1)]
+    no_compress_exts: Vec<String>,
+    /// How to compress chunks no rule applies to. "deflate" is zlib, as used by SQLite's
+    /// sqlar format, "lz4" trades ratio for much faster compression and "brotli", only in
+    /// builds with the brotli feature, does the opposite.
+    #[structopt(
+        long = "codec",
+        alias = "algo",
+        default_value = "zstd",
+        raw(possible_values = "CODECS")
+    )]
+    codec: Compression,
+    /// The zstd level for chunks no rule applies to, from 1 (fastest) to 22 (smallest);
+    /// above 19 also turns on long distance matching. The level is saved in the archive, and
+    /// used by later adds that don't give one. 0 is zstd's own default.
+    #[structopt(long = "level", parse(try_from_str = "parse_zstd_level"))]
+    level: Option<i32>,
+    /// Compress zstd chunks with the dictionary TrainDict last trained, which helps most
+    /// with many small, similar files. Chunks a --compress-rule applies to are left without.
+    #[structopt(long = "use-dict")]
+    use_dict: bool,
+    /// Store every chunk and inline file as is, whatever the other options say, for
+    /// archives on storage that compresses by itself or behind a compressing VFS.
+    #[structopt(long = "no-chunk-compression")]
+    no_chunk_compression: bool,
+    /// Store a chunk or inline file as is unless compressing it saves at least this
+    /// percentage of its size, so data that's already compressed is cheap to read back.
+    #[structopt(
+        long = "min-savings",
+        default_value = "3",
+        parse(try_from_str = "parse_percent")
+    )]
+    min_savings: u32,
+}
 
-        connection.execute("PRAGMA journal_mode=WAL;", NO_PARAMS);
+#[derive(Debug)]
+enum ChsqlarError {
+    FileNotFound { path: PathBuf },
+    BlobNotFound { hash: String },
+    ChunkNotFound { hash: String },
+    ChunkCorrupted { hash: String },
+    ArchiveFull { path: PathBuf, limit: u64 },
+    Locked { pid: i64, command: String },
+    WrongKey,
+    Undecryptable { what: String },
+    SchemaTooNew { required: u32 },
+    NeedsMigrate { version: u32 },
+}
 
-        Ok(SqliteDatabase { connection })
+impl ChsqlarError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ChsqlarError::FileNotFound { .. } => "FileNotFound",
+            ChsqlarError::BlobNotFound { .. } => "BlobNotFound",
+            ChsqlarError::ChunkNotFound { .. } => "ChunkNotFound",
+            ChsqlarError::ChunkCorrupted { .. } => "ChunkCorrupted",
+            ChsqlarError::ArchiveFull { .. } => "ArchiveFull",
+            ChsqlarError::Locked { .. } => "Locked",
+            ChsqlarError::WrongKey => "WrongKey",
+            ChsqlarError::Undecryptable { .. } => "Undecryptable",
+            ChsqlarError::SchemaTooNew { .. } => "SchemaTooNew",
+            ChsqlarError::NeedsMigrate { .. } => "NeedsMigrate",
+        }
     }
 }
 
-fn get_file_data(trans: &mut Transaction, name: PathBuf) -> Result<Vec<u8>, Error> {
-    let f = get_file(trans, name)?;
+impl fmt::Display for ChsqlarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChsqlarError::FileNotFound { path } => {
+                write!(f, "{} not found in archive", path.display())
+            }
+            ChsqlarError::BlobNotFound { hash } => write!(f, "no blob with hash {}", hash),
+            ChsqlarError::ChunkNotFound { hash } => write!(f, "chunk {} is missing", hash),
+            ChsqlarError::ChunkCorrupted { hash } => write!(f, "chunk {} is corrupted", hash),
+            ChsqlarError::ArchiveFull { path, limit } => write!(
+                f,
+                "adding {} would grow the archive past {} bytes",
+                path.display(),
+                limit
+            ),
+            ChsqlarError::Locked { pid, command } => {
+                write!(
+                    f,
+                    "archive is locked by pid {} ({}); if that has stopped, --break-lock \
+                     takes the lock anyway",
+                    pid, command
+                )
+            }
+            ChsqlarError::WrongKey => write!(f, "wrong key or passphrase for this archive"),
+            ChsqlarError::Undecryptable { what } => {
+                write!(f, "{} is corrupted or the key is wrong", what)
+            }
+            ChsqlarError::SchemaTooNew { required } => write!(
+                f,
+                "archive requires a chsqlar that understands schema version {}, you have {} \
+                 which understands up to {}",
+                required,
+                env!("CARGO_PKG_VERSION"),
+                SCHEMA_VERSION
+            ),
+            ChsqlarError::NeedsMigrate { version } => write!(
+                f,
+                "archive is at schema version {}, older than the {} this version writes; run \
+                 Migrate before writing to it",
+                version, SCHEMA_VERSION
+            ),
+        }
+    }
+}
 
-    let mut result = Vec::new();
+impl Fail for ChsqlarError {}
 
-    for hash in f.chunks {
-        let chunk = get_chunk(trans, &hash)?;
-        result.extend_from_slice(&chunk);
+/// Maps "no rows" from a lookup query to a more specific error.
+fn or_not_found<T>(
+    result: rusqlite::Result<T>,
+    not_found: impl FnOnce() -> ChsqlarError,
+) -> Result<T, Error> {
+    match result {
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(not_found().into()),
+        other => Ok(other?),
     }
-
-    Ok(result)
 }
 
+fn error_json(e: &Error) -> serde_json::Value {
+    let mut json = serde_json::json!({ "message": e.to_string() });
 
-fn get_chunk(trans: &mut Transaction, hash: &str) -> Result<Vec<u8>, Error> {
-    let data: Vec<u8> =
-        trans.query_row("SELECT data FROM chunks WHERE hash=?", &[&hash], |row| {
-            row.get(0)
-        })?;
+    json["error"] = match e.downcast_ref::<ChsqlarError>() {
+        Some(err) => {
+            match err {
+                ChsqlarError::FileNotFound { path } | ChsqlarError::ArchiveFull { path, .. } => {
+                    json["path"] = path.to_string_lossy().into();
+                }
+                _ => {}
+            }
+            err.kind().into()
+        }
+        None if e.downcast_ref::<std::io::Error>().is_some() => "Io".into(),
+        None if e.downcast_ref::<rusqlite::Error>().is_some() => "Database".into(),
+        None => "Other".into(),
+    };
 
-    let decoded = decode_all(&*data)?;
-    Ok(decoded)
+    json
 }
 
-fn put_chunk(trans: &mut Transaction, chunk: PreparedChunk) -> Result<(), Error> {
-    trans.execute(
-        "INSERT OR IGNORE INTO chunks VALUES (?,?)",
-        &[&chunk.hash, &chunk.data as &ToSql],
-    )?;
-    Ok(())
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NameEncoding {
+    Utf8,
+    Bytes,
+    Percent,
+    /// Sealed with the archive's key, for archives set up with --encrypt-names. Never
+    /// chosen on the command line.
+    Sealed,
 }
 
-fn put_file(trans: &mut Transaction, file: File) -> Result<(), Error> {
-    let chunks = file.chunks.join(";");
-
-    trans.execute(
-        "INSERT OR REPLACE INTO files VALUES (?,?,?)",
-        &[&file.name.to_str().unwrap() as &ToSql, &file.size, &chunks],
-    )?;
+impl FromStr for NameEncoding {
+    type Err = String;
 
-    Ok(())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(NameEncoding::Utf8),
+            "bytes" => Ok(NameEncoding::Bytes),
+            "percent" => Ok(NameEncoding::Percent),
+            _ => Err(format!("unknown name encoding '{}'", s)),
+        }
+    }
 }
 
-fn list_files(trans: &mut Transaction) -> Result<Vec<PathBuf>, Error> {
-    let mut stmt = trans.prepare("SELECT name FROM files")?;
-    let mut results = Vec::<String>::new();
-    for name in stmt.query_map(NO_PARAMS, |row| row.get(0))? {
-        results.push(name?);
+impl NameEncoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            NameEncoding::Utf8 => "utf8",
+            NameEncoding::Bytes => "bytes",
+            NameEncoding::Percent => "percent",
+            NameEncoding::Sealed => "sealed",
+        }
     }
-
-    Ok(results.iter().map(PathBuf::from).collect())
 }
 
-fn chunk_data(data: Vec<u8>) -> Vec<Vec<u8>> {
-    let chunker = Chunker::new(ZPAQ::new(20));
-
-    let mut chunks = Vec::new();
+/// The name encoding the archive was created with, which `given` has to agree with. A new
+/// archive records `given`, or "bytes". Archives from before the encoding was recorded
+/// take whatever is given, as they always did.
+fn archive_name_encoding(
+    connection: &Connection,
+    given: Option<NameEncoding>,
+) -> Result<NameEncoding, Error> {
+    let recorded = match get_meta::<NameEncoding>(connection, "name_encoding")? {
+        Some(recorded) => recorded,
+        None => {
+            let encoding = given.unwrap_or(NameEncoding::Bytes);
+            let files: i64 =
+                connection.query_row("SELECT COUNT(*) FROM files", NO_PARAMS, |row| row.get(0))?;
+            if files == 0 {
+                set_meta(connection, "name_encoding", encoding.as_str())?;
+            }
+            return Ok(encoding);
+        }
+    };
 
-    for chunk in chunker.slices(&data) {
-        chunks.push(chunk.to_owned());
+    match given {
+        Some(given) if given != recorded => Err(format_err!(
+            "the archive stores names with --name-encoding {}, not {}",
+            recorded.as_str(),
+            given.as_str()
+        )),
+        _ => Ok(recorded),
     }
+}
 
-    chunks
+#[cfg(unix)]
+fn path_to_bytes(p: &Path) -> Result<Vec<u8>, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(p.as_os_str().as_bytes().to_vec())
 }
 
-fn hash_chunk(data: &[u8]) -> String {
-    let mut hasher = Sha3::sha3_512();
+#[cfg(not(unix))]
+fn path_to_bytes(p: &Path) -> Result<Vec<u8>, Error> {
+    p.to_str()
+        .map(|s| portable_separators(s).into_bytes())
+        .ok_or_else(|| format_err!("{} is not valid unicode", p.display()))
+}
 
-    hasher.input(&data);
+/// Stored names always use `/`, whichever platform they were added on.
+fn portable_separators(name: &str) -> String {
+    if cfg!(windows) {
+        name.replace('\\', "/")
+    } else {
+        name.to_owned()
+    }
+}
 
-    hasher.result_str()
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
 }
 
-fn get_file(trans: &mut Transaction, name: PathBuf) -> Result<File, Error> {
-    let size: i64;
-    let chunks: String;
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    Ok(PathBuf::from(String::from_utf8(bytes)?))
+}
 
-    let result = trans.query_row(
-        "SELECT size, chunks FROM files WHERE name=?",
-        &[&name.to_str().unwrap()],
-        |row| (row.get(0), row.get(1)),
-    )?;
+/// Converts a file name to the value stored in the `name` column.
+fn encode_name(name: &Path, encoding: NameEncoding) -> Result<Value, Error> {
+    if encoding == NameEncoding::Sealed {
+        return Ok(Value::Blob(crypt::seal_name(&path_to_bytes(name)?)?));
+    }
 
-    size = result.0;
-    chunks = result.1;
+    if let (Some(s), false) = (name.to_str(), encoding == NameEncoding::Percent) {
+        return Ok(Value::Text(portable_separators(s)));
+    }
 
-    let chunks_vec;
+    match encoding {
+        NameEncoding::Utf8 => Err(format_err!(
+            "{} is not valid UTF-8, use --name-encoding bytes or percent to store it",
+            name.display()
+        )),
+        NameEncoding::Bytes | NameEncoding::Sealed => Ok(Value::Blob(path_to_bytes(name)?)),
+        NameEncoding::Percent => {
+            let mut encoded = String::new();
+            for chunk in path_to_bytes(name)?.utf8_chunks() {
+                encoded.push_str(&chunk.valid().replace('%', "%25"));
+                for b in chunk.invalid() {
+                    encoded.push_str(&format!("%{:02X}", b));
+                }
+            }
+            Ok(Value::Text(encoded))
+        }
+    }
+}
 
-    if chunks == "" {
-        chunks_vec = Vec::new();
-    } else {
-        chunks_vec = chunks.split(";").map(|s| s.to_string()).collect();
+/// Converts a stored `name` column back to a path, the inverse of `encode_name`.
+fn decode_name(value: Value, encoding: NameEncoding) -> Result<PathBuf, Error> {
+    match (value, encoding) {
+        (Value::Text(s), NameEncoding::Percent) => {
+            let mut bytes = Vec::new();
+            let mut rest = s.as_bytes();
+            while let Some((&b, tail)) = rest.split_first() {
+                let escaped = tail
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match (b, escaped) {
+                    (b'%', Some(decoded)) => {
+                        bytes.push(decoded);
+                        rest = &tail[2..];
+                    }
+                    _ => {
+                        bytes.push(b);
+                        rest = tail;
+                    }
+                }
+            }
+            path_from_bytes(bytes)
+        }
+        (Value::Blob(b), NameEncoding::Sealed) => path_from_bytes(crypt::open_name(&b)?),
+        (Value::Text(s), _) => Ok(PathBuf::from(s)),
+        (Value::Blob(b), _) => path_from_bytes(b),
+        (other, _) => Err(format_err!("unexpected file name {:?}", other)),
     }
+}
 
-    Ok(File {
-        name,
-        size,
-        chunks: chunks_vec,
-    })
+#[derive(Debug, Clone)]
+struct File {
+    name: PathBuf,
+    size: i64,
+    chunks: Vec<String>,
+    /// Runs of zeros left out of `chunks`, as (offset, length) in the file, in order.
+    holes: Vec<(u64, u64)>,
+    /// The codec and compressed content of a small file stored on its own row instead of
+    /// in chunks.
+    inline: Option<(String, Vec<u8>)>,
+    /// Modification time in nanoseconds since the Unix epoch, if known.
+    mtime: Option<i64>,
+    /// When the file was stored, in nanoseconds since the Unix epoch. None for files added
+    /// with --deterministic or by versions that didn't record it.
+    added_at: Option<i64>,
+    /// SHA-256 of the whole content, in hex. None for files added by versions that didn't
+    /// record it.
+    sha256: Option<String>,
+    /// The whole content hashed the way the archive hashes chunks, in hex. None for files
+    /// added by versions that didn't record it, until Migrate --compute-file-hashes.
+    content_hash: Option<String>,
+    /// The owning user and group ids, for files added with --preserve-ownership on Unix.
+    owner: Option<(u32, u32)>,
+    /// The names of the owning user and group, where the ids had them. None for files added
+    /// by versions that didn't record them.
+    owner_user: Option<String>,
+    owner_group: Option<String>,
+    /// The --filter command the content was stored through, if any.
+    filter: Option<String>,
 }
 
-fn put_file_data(trans: &mut Transaction, name: PathBuf, data: Vec<u8>) -> Result<(), Error> {
-    let mut f = get_file(trans, name)?;
+struct PreparedChunk {
+    hash: String,
+    data: Vec<u8>,
+    compression: Compression,
+    /// Stored as is because `compression` didn't save enough.
+    incompressible: bool,
+    /// The length of the chunk before compression.
+    uncompressed_size: usize,
+}
 
-    let mut chunks = Vec::new();
+/// The names `--codec` accepts.
+const CODECS: &[&str] = &["zstd", "deflate", "lz4", "brotli", "store"];
 
-    let hashed_chunks: Vec<_> = chunk_data(data).into_par_iter().map(|chunk| {
-        let hash = hash_chunk(&chunk);
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Store,
+    Zstd(i32),
+    Deflate(u32),
+    Lz4,
+    /// Brotli at a quality from 0 to 11. Only usable in builds with the brotli feature.
+    Brotli(u32),
+    /// Zstd at a level, with the archive's dictionary of this id.
+    ZstdDict(i32, u32),
+}
 
-        PreparedChunk {data: encode_all(&*chunk, 0).unwrap(), hash: hash}
-    }).collect();
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd(0)
+    }
+}
 
-    for pchunk in hashed_chunks {
-        chunks.push(pchunk.hash.to_string());
+impl FromStr for Compression {
+    type Err = String;
 
-        put_chunk(trans, pchunk)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store" => Ok(Compression::Store),
+            "zstd" => Ok(Compression::default()),
+            "deflate" => Ok(Compression::Deflate(DEFAULT_DEFLATE_LEVEL)),
+            "lz4" => Ok(Compression::Lz4),
+            "brotli" if cfg!(feature = "brotli") => Ok(Compression::Brotli(BROTLI_QUALITY)),
+            "brotli" => Err("this build of chsqlar doesn't include brotli".to_owned()),
+            level => level
+                .parse()
+                .map(Compression::Zstd)
+                .map_err(|_| format!("unknown compression '{}'", s)),
+        }
     }
+}
 
-    f.chunks = chunks;
+const DEFAULT_DEFLATE_LEVEL: u32 = 6;
 
-    put_file(trans, f)?;
+/// Brotli is only worth its time at its highest quality, for archiving.
+const BROTLI_QUALITY: u32 = 11;
 
-    Ok(())
+impl Compression {
+    fn codec(self) -> &'static str {
+        match self {
+            Compression::Store => "store",
+            Compression::Zstd(_) => "zstd",
+            Compression::Deflate(_) => "deflate",
+            Compression::Lz4 => "lz4",
+            Compression::Brotli(_) => "brotli",
+            Compression::ZstdDict(..) => "zstd-dict",
+        }
+    }
+
+    fn level(self) -> Option<i32> {
+        match self {
+            Compression::Store => None,
+            Compression::Zstd(level) => Some(level),
+            Compression::Deflate(level) => Some(level as i32),
+            Compression::Lz4 => None,
+            Compression::Brotli(quality) => Some(quality as i32),
+            Compression::ZstdDict(level, _) => Some(level),
+        }
+    }
+
+    fn from_codec(codec: &str, level: Option<i32>) -> Result<Self, Error> {
+        match codec {
+            "store" => Ok(Compression::Store),
+            "zstd" => Ok(Compression::Zstd(level.unwrap_or(0))),
+            "deflate" => Ok(Compression::Deflate(
+                level.map_or(DEFAULT_DEFLATE_LEVEL, |l| l as u32),
+            )),
+            "lz4" => Ok(Compression::Lz4),
+            "brotli" => Ok(Compression::Brotli(
+                level.map_or(BROTLI_QUALITY, |l| l as u32),
+            )),
+            _ => Err(format_err!("unknown chunk codec '{}'", codec)),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::Store => Ok(data.to_vec()),
+            Compression::Zstd(level) => zstd_compress(data, level),
+            Compression::Deflate(level) => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Brotli(quality) => brotli_compress(data, quality),
+            Compression::ZstdDict(level, id) => dict::compress(data, level, id),
+        }
+    }
 }
 
-fn list_cmd(db: &mut SqliteDatabase) -> Result<(), Error> {
-    let mut trans = db.connection.transaction()?;
+/// What chunks and inline files are kept as is unless compression saves, as a percentage.
+const DEFAULT_MIN_SAVINGS: u32 = 3;
 
-    let files = list_files(&mut trans)?;
+/// Compresses `data`, or keeps it as is if that doesn't save at least `min_savings` percent.
+/// Returns how the data ended up stored, and whether it was kept as is for not compressing.
+fn compress_or_store(
+    compression: Compression,
+    data: &[u8],
+    min_savings: u32,
+    metrics: &Metrics,
+) -> Result<(Compression, Vec<u8>, bool), Error> {
+    let compressed = Metrics::time(&metrics.compress_nanos, || compression.compress(data))?;
+    Metrics::add(&metrics.compress_in, data.len() as u64);
+    Metrics::add(&metrics.compress_out, compressed.len() as u64);
 
-    for file in files {
-        println!("{}", file.to_str().unwrap());
+    let limit = data.len() as u64 * u64::from(100 - min_savings);
+    if compression == Compression::Store || compressed.len() as u64 * 100 <= limit {
+        return Ok((compression, compressed, false));
     }
 
-    Ok(())
+    Ok((Compression::Store, data.to_vec(), true))
 }
 
-fn add_file(trans: &mut Transaction, fpath: PathBuf, fname: PathBuf) -> Result<(), Error> {
-    let mut buf = Vec::new();
-    fs::File::open(&fpath)?.read_to_end(&mut buf)?;
-    let metadata = fs::metadata(&fpath)?;
+/// The highest zstd level before the "ultra" ones, which also get long distance matching.
+const ZSTD_MAX_REGULAR_LEVEL: i32 = 19;
 
-    let f = File {
-        name: fname.clone(),
-        size: metadata.len() as i64,
-        chunks: Vec::new(),
+fn zstd_compress(data: &[u8], level: i32) -> Result<Vec<u8>, Error> {
+    if level <= ZSTD_MAX_REGULAR_LEVEL {
+        return Ok(encode_all(data, level)?);
+    }
+
+    use zstd_sys::ZSTD_cParameter::{ZSTD_c_compressionLevel, ZSTD_c_enableLongDistanceMatching};
+
+    let check = |code: usize| {
+        // Safe as it only looks at the code, and the names of errors are static strings.
+        unsafe {
+            match zstd_sys::ZSTD_isError(code) {
+                0 => Ok(code),
+                _ => {
+                    let name = std::ffi::CStr::from_ptr(zstd_sys::ZSTD_getErrorName(code));
+                    Err(format_err!("zstd: {}", name.to_string_lossy()))
+                }
+            }
+        }
     };
 
-    put_file(trans, f)?;
+    // Safe as the context is checked for null, only used here and freed once, and zstd
+    // writes at most the capacity of `out` it's given, so the length set is within it.
+    unsafe {
+        let cctx = zstd_sys::ZSTD_createCCtx();
+        if cctx.is_null() {
+            return Err(format_err!("zstd: can't allocate a compression context"));
+        }
+        let mut out = Vec::with_capacity(zstd_sys::ZSTD_compressBound(data.len()));
+        let result = check(zstd_sys::ZSTD_CCtx_setParameter(
+            cctx,
+            ZSTD_c_compressionLevel,
+            level,
+        ))
+        .and_then(|_| {
+            check(zstd_sys::ZSTD_CCtx_setParameter(
+                cctx,
+                ZSTD_c_enableLongDistanceMatching,
+                1,
+            ))
+        })
+        .and_then(|_| {
+            check(zstd_sys::ZSTD_compress2(
+                cctx,
+                out.as_mut_ptr() as *mut libc::c_void,
+                out.capacity(),
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+            ))
+        });
+        zstd_sys::ZSTD_freeCCtx(cctx);
+        out.set_len(result?);
 
-    put_file_data(trans, fname, buf)?;
+        Ok(out)
+    }
+}
 
-    Ok(())
+fn decompress(codec: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match codec {
+        "store" => Ok(data),
+        "zstd" => Ok(decode_all(&*data)?),
+        "deflate" => {
+            let mut result = Vec::new();
+            ZlibDecoder::new(&*data).read_to_end(&mut result)?;
+            Ok(result)
+        }
+        "lz4" => {
+            let mut result = Vec::new();
+            lz4_flex::frame::FrameDecoder::new(&*data).read_to_end(&mut result)?;
+            Ok(result)
+        }
+        "brotli" => brotli_decompress(&data),
+        "zstd-dict" => dict::decompress(&data),
+        _ => Err(format_err!("unknown chunk codec '{}'", codec)),
+    }
 }
 
-fn normalise_path<'a>(cwd: &'a Path, p: &'a Path) -> &'a Path {
-    cwd.ancestors()
-        .map(|x| p.strip_prefix(x))
-        .filter(|x| x.is_ok())
-        .next()
-        .unwrap()
-        .unwrap()
+/// The length of `data`, decrypted but still compressed with `codec`, once decompressed. A
+/// zstd frame may record it in its header, which saves decompressing the rest.
+fn uncompressed_len(codec: &str, data: Vec<u8>) -> Result<usize, Error> {
+    if codec == "zstd" {
+        // Safe as it only reads the header, within the `data.len()` bytes it's given.
+        let size = unsafe {
+            zstd_sys::ZSTD_getFrameContentSize(data.as_ptr() as *const libc::c_void, data.len())
+        };
+        // Unknown and error are the two largest values.
+        if size < zstd_sys::ZSTD_CONTENTSIZE_ERROR as u64 {
+            return Ok(size as usize);
+        }
+    }
+
+    Ok(decompress(codec, data)?.len())
 }
 
-fn resolve_files(file: PathBuf) -> Result<Vec<PathBuf>, Error> {
+#[cfg(feature = "brotli")]
+fn brotli_compress(data: &[u8], quality: u32) -> Result<Vec<u8>, Error> {
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: quality as i32,
+        ..Default::default()
+    };
     let mut result = Vec::new();
+    brotli::BrotliCompress(&mut &*data, &mut result, &params)?;
+    Ok(result)
+}
 
-    let file = fs::canonicalize(file)?;
+#[cfg(feature = "brotli")]
+fn brotli_decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::new();
+    brotli::BrotliDecompress(&mut &*data, &mut result)?;
+    Ok(result)
+}
 
-    let meta = fs::metadata(&file)?;
+#[cfg(not(feature = "brotli"))]
+fn brotli_compress(_data: &[u8], _quality: u32) -> Result<Vec<u8>, Error> {
+    Err(format_err!("this build of chsqlar doesn't include brotli"))
+}
 
-    if meta.is_file() {
-        result.push(file);
-    } else if meta.is_dir() {
-        let files: Vec<_> = fs::read_dir(&file)?.map(|x| x.unwrap().path()).collect();
+#[cfg(not(feature = "brotli"))]
+fn brotli_decompress(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(format_err!(
+        "chunk compressed with brotli, which this build of chsqlar doesn't include"
+    ))
+}
 
-        for f in files {
-            let mut pathbuf = PathBuf::new();
-            pathbuf.push(&file);
-            pathbuf.push(f);
-            let resolved_files = resolve_files(pathbuf)?;
-            for resolved in resolved_files {
-                result.push(resolved);
-            }
-        }
-    } else {
-        unimplemented!("Unknown file type");
+#[derive(Debug, Clone)]
+struct CompressRule {
+    glob: GlobMatcher,
+    compression: Compression,
+}
+
+fn parse_zstd_level(s: &str) -> Result<i32, String> {
+    match s.parse() {
+        Ok(level) if (0..=22).contains(&level) => Ok(level),
+        _ => Err(format!("expected a zstd level from 0 to 22, got '{}'", s)),
     }
+}
 
-    Ok(result)
+fn parse_percent(s: &str) -> Result<u32, String> {
+    match s.trim_end_matches('%').parse() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!("expected a percentage from 0 to 100, got '{}'", s)),
+    }
 }
 
-fn add_files_cmd(db: &mut SqliteDatabase, files: Vec<PathBuf>) -> Result<(), Error> {
-    let mut trans = db.connection.transaction()?;
+fn parse_hash_bytes(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(bytes) if HASH_BYTES.contains(&bytes) => Ok(bytes),
+        _ => Err(format!(
+            "expected a number of bytes from {} to {}, got '{}'",
+            HASH_BYTES.start(),
+            HASH_BYTES.end(),
+            s
+        )),
+    }
+}
 
-    let cwd = current_dir()?;
-    for file in files.into_iter() {
-        let resolved = resolve_files(file)?;
-        for f in resolved {
-            let normalised = normalise_path(&cwd, &f).to_path_buf();
-            add_file(&mut trans, f, normalised)?;
-        }
+fn parse_max_chunk_bytes(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(bytes) if bytes >= MIN_MAX_CHUNK_BYTES => Ok(bytes),
+        _ => Err(format!(
+            "expected a number of bytes of at least {}, got '{}'",
+            MIN_MAX_CHUNK_BYTES, s
+        )),
     }
+}
 
-    trans.commit();
+fn parse_compress_rule(s: &str) -> Result<CompressRule, String> {
+    let mut parts = s.rsplitn(2, '=');
+    let compression = parts.next().unwrap().parse()?;
+    let glob = parts
+        .next()
+        .ok_or_else(|| format!("expected GLOB=COMPRESSION, got '{}'", s))?;
+    let glob = Glob::new(glob).map_err(|e| e.to_string())?;
 
-    Ok(())
+    Ok(CompressRule {
+        glob: glob.compile_matcher(),
+        compression,
+    })
 }
 
-fn write_file_data_safe(fname: &Path, data: &[u8]) -> Result<(), Error> {
-    fs::create_dir_all(fname.parent().unwrap())?;
-    let mut f = fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(fname)?;
-    f.write_all(&data)?;
-    Ok(())
+#[derive(Debug, Clone)]
+struct Transform {
+    regex: Regex,
+    replacement: Vec<u8>,
 }
 
-fn extract_file(trans: &mut Transaction, file: PathBuf, ex_to: PathBuf) -> Result<(), Error> {
-    let db_data = get_file_data(trans, file.clone())?;
-
-    let par = ex_to.parent().unwrap();
+fn parse_transform(s: &str) -> Result<Transform, String> {
+    let mut parts = s.rsplitn(2, "=>");
+    let replacement = parts.next().unwrap();
+    let regex = parts
+        .next()
+        .ok_or_else(|| format!("expected REGEX=>REPLACEMENT, got '{}'", s))?;
 
-    let common = file.strip_prefix(par).unwrap();
+    Ok(Transform {
+        regex: Regex::new(regex).map_err(|e| e.to_string())?,
+        replacement: replacement.as_bytes().to_vec(),
+    })
+}
 
-    write_file_data_safe(common, &db_data)?;
+/// Renames `name` with each of `transforms` in turn.
+fn apply_transforms(transforms: &[Transform], name: &Path) -> Result<PathBuf, Error> {
+    let mut bytes = path_to_bytes(name)?;
+    for transform in transforms {
+        bytes = transform
+            .regex
+            .replace(&bytes, &transform.replacement[..])
+            .into_owned();
+    }
 
-    Ok(())
+    path_from_bytes(bytes)
 }
 
-fn extract_path(trans: &mut Transaction, file: PathBuf) -> Result<(), Error> {
-    let db_files = list_files(trans)?;
+/// Extensions of formats that are already compressed, which zstd would only waste time on.
+const NO_COMPRESS_EXTS: &[&str] = &[
+    "7z", "avi", "bz2", "flac", "gif", "gz", "jpeg", "jpg", "lz4", "mkv", "mov", "mp3", "mp4",
+    "ogg", "png", "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+];
 
-    let files: Vec<_> = db_files
-        .iter()
-        .filter(|x| Path::new(x).starts_with(&file))
-        .collect();
+impl CompressOpt {
+    fn compression_for(&self, name: &Path) -> Compression {
+        if self.no_chunk_compression {
+            return Compression::Store;
+        }
 
-    for f in files {
-        extract_file(trans, f.to_path_buf(), file.clone())?;
+        if let Some(rule) = self
+            .compress_rules
+            .iter()
+            .rev()
+            .find(|rule| rule.glob.is_match(name))
+        {
+            return rule.compression;
+        }
+
+        if !self.compress_all && self.is_incompressible(name) {
+            return Compression::Store;
+        }
+
+        match (self.codec, self.level) {
+            (Compression::Zstd(_), Some(level)) => Compression::Zstd(level),
+            (Compression::ZstdDict(_, id), Some(level)) => Compression::ZstdDict(level, id),
+            (codec, _) => codec,
+        }
     }
 
-    Ok(())
-}
+    fn is_incompressible(&self, name: &Path) -> bool {
+        let ext = match name.extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None => return false,
+        };
 
-fn extract_files_cmd(db: &mut SqliteDatabase, files: Vec<PathBuf>) -> Result<(), Error> {
-    let mut trans = db.connection.transaction()?;
-    for file in files {
-        extract_path(&mut trans, file)?;
+        NO_COMPRESS_EXTS.contains(&ext.as_str())
+            || self
+                .no_compress_exts
+                .iter()
+                .any(|e| e.trim_start_matches('.').to_lowercase() == ext)
     }
+}
 
-    Ok(())
+struct SqliteDatabase {
+    connection: Connection,
 }
 
-fn main() -> Result<(), Error> {
-    let app = Opt::from_args();
+impl SqliteDatabase {
+    fn new(fname: &str, opt: &DatabaseOpt) -> Result<Self, Error> {
+        let connection = Connection::open(fname)?;
 
-    let mut db = SqliteDatabase::new(&app.opt.database)?;
+        connection.execute(&format!("PRAGMA synchronous={};", opt.sync), NO_PARAMS)?;
 
-    match app.cmd {
-        OptCommand::List => {
-            list_cmd(&mut db)?;
+        // The page size is fixed once the first table exists, so this has to come first.
+        if let Some(page_size) = opt.page_size {
+            connection.execute(&format!("PRAGMA page_size={};", page_size), NO_PARAMS)?;
+        }
+
+        // Like the page size, this has to be set before any table is created.
+        if let Some(mode) = &opt.auto_vacuum {
+            connection.execute(&format!("PRAGMA auto_vacuum={};", mode), NO_PARAMS)?;
+        }
+
+        if let Some(cache_size) = opt.cache_size {
+            connection.execute(&format!("PRAGMA cache_size={};", cache_size), NO_PARAMS)?;
+        }
+
+        // Checked before the tables below are created, to know whether this is a new archive.
+        let tables: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type='table'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        // And before anything else touches them, as a newer version might have changed them.
+        if tables > 0 {
+            check_readable(&connection)?;
+        }
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            files (
+                name TEXT PRIMARY KEY,
+                size INT,
+                chunks BLOB,
+                holes TEXT,
+                inline_data BLOB,
+                inline_codec TEXT,
+                mtime INT,
+                added_at INT,
+                sha256 TEXT,
+                owner_uid INT,
+                owner_gid INT,
+                owner_user TEXT,
+                owner_group TEXT,
+                filter TEXT,
+                attrs BLOB,
+                content_hash TEXT
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            chunks (
+                hash BLOB PRIMARY KEY,
+                data BLOB,
+                codec TEXT NOT NULL DEFAULT 'zstd',
+                level INT,
+                checksum INT,
+                size INT,
+                incompressible INT NOT NULL DEFAULT 0,
+                refcount INT NOT NULL DEFAULT 0,
+                uncompressed_size INT
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        add_column_if_missing(
+            &connection,
+            "chunks",
+            "codec",
+            "TEXT NOT NULL DEFAULT 'zstd'",
+        )?;
+        add_column_if_missing(&connection, "chunks", "level", "INT")?;
+        add_column_if_missing(&connection, "chunks", "checksum", "INT")?;
+        add_column_if_missing(&connection, "chunks", "size", "INT")?;
+        add_column_if_missing(
+            &connection,
+            "chunks",
+            "incompressible",
+            "INT NOT NULL DEFAULT 0",
+        )?;
+        // Filled in by Migrate for archives from before it existed.
+        add_column_if_missing(&connection, "chunks", "refcount", "INT NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&connection, "chunks", "uncompressed_size", "INT")?;
+        add_column_if_missing(&connection, "files", "holes", "TEXT")?;
+        add_column_if_missing(&connection, "files", "inline_data", "BLOB")?;
+        add_column_if_missing(&connection, "files", "inline_codec", "TEXT")?;
+        add_column_if_missing(&connection, "files", "mtime", "INT")?;
+        add_column_if_missing(&connection, "files", "added_at", "INT")?;
+        add_column_if_missing(&connection, "files", "sha256", "TEXT")?;
+        add_column_if_missing(&connection, "files", "owner_uid", "INT")?;
+        add_column_if_missing(&connection, "files", "owner_gid", "INT")?;
+        add_column_if_missing(&connection, "files", "owner_user", "TEXT")?;
+        add_column_if_missing(&connection, "files", "owner_group", "TEXT")?;
+        add_column_if_missing(&connection, "files", "filter", "TEXT")?;
+        add_column_if_missing(&connection, "files", "attrs", "BLOB")?;
+        add_column_if_missing(&connection, "files", "content_hash", "TEXT")?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            blobs (
+                hash TEXT PRIMARY KEY,
+                size INT,
+                chunks BLOB
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            add_journal (
+                name TEXT PRIMARY KEY,
+                size INT,
+                mtime INT
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            file_meta (
+                file_name TEXT,
+                key TEXT,
+                value TEXT,
+                PRIMARY KEY (file_name, key)
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            meta (
+                key TEXT PRIMARY KEY,
+                value BLOB
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS
+            locks (
+                name TEXT PRIMARY KEY,
+                pid INT,
+                command TEXT
+            );
+        ",
+            NO_PARAMS,
+        )?;
+
+        if tables == 0 {
+            let now = system_time_nanos(std::time::SystemTime::now());
+            set_meta(&connection, "created_at", &now)?;
+            set_meta(
+                &connection,
+                "created_by",
+                concat!("chsqlar ", env!("CARGO_PKG_VERSION")),
+            )?;
+            set_meta(&connection, "schema_version", &SCHEMA_VERSION)?;
+            set_meta(&connection, "min_reader_version", &MIN_READER_VERSION)?;
+        }
+
+        dict::load(&connection)?;
+
+        if let Some(mode) = &opt.auto_vacuum {
+            if auto_vacuum_mode(&connection)? == mode.as_str() {
+                set_meta(&connection, "auto_vacuum", mode)?;
+            } else {
+                eprintln!("--auto-vacuum is ignored, as the archive already exists");
+            }
+        }
+
+        // SQLite answers with the mode it ended up in, which is the old one if it can't
+        // switch, as with WAL on some network file systems.
+        let mode: String = connection.query_row(
+            &format!("PRAGMA journal_mode={};", opt.journal),
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        if !mode.eq_ignore_ascii_case(&opt.journal) {
+            return Err(format_err!(
+                "couldn't set journal mode {} (still {})",
+                opt.journal,
+                mode
+            ));
+        }
+
+        Ok(SqliteDatabase { connection })
+    }
+}
+
+/// Bumped whenever the tables change. An archive records the version it was created or last
+/// migrated at.
+const SCHEMA_VERSION: u32 = 6;
+
+/// The oldest schema version a reader has to understand to read what this version writes.
+/// It lags behind `SCHEMA_VERSION` for changes older readers can ignore, like a new column.
+const MIN_READER_VERSION: u32 = 1;
+
+/// Raises the archive's `min_reader_version` to `version`, for when it starts keeping
+/// something older readers would misread.
+fn require_reader_version(connection: &Connection, version: u32) -> Result<(), Error> {
+    let required: u32 = get_meta(connection, "min_reader_version")?.unwrap_or(0);
+    if required < version {
+        set_meta(connection, "min_reader_version", &version)?;
+    }
+
+    Ok(())
+}
+
+/// The schema version the archive was created or last migrated at, 0 for archives from
+/// before versions were recorded.
+fn schema_version(connection: &Connection) -> Result<u32, Error> {
+    Ok(get_meta(connection, "schema_version")?.unwrap_or(0))
+}
+
+/// Whether the archive has a meta table, which ones from the first versions don't.
+fn has_meta(connection: &Connection) -> Result<bool, Error> {
+    let tables: i64 = connection.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='meta'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    Ok(tables > 0)
+}
+
+/// Refuses an archive written by a version whose changes this one would misread.
+fn check_readable(connection: &Connection) -> Result<(), Error> {
+    if !has_meta(connection)? {
+        return Ok(());
+    }
+
+    match get_meta(connection, "min_reader_version")? {
+        Some(required) if required > SCHEMA_VERSION => {
+            Err(ChsqlarError::SchemaTooNew { required }.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Refuses to write to an archive at any schema version but this one's: an older one has to
+/// be migrated first, and a newer one may need things written this version doesn't know of.
+fn check_writable(connection: &Connection) -> Result<(), Error> {
+    let version = schema_version(connection)?;
+    match version.cmp(&SCHEMA_VERSION) {
+        std::cmp::Ordering::Less => Err(ChsqlarError::NeedsMigrate { version }.into()),
+        std::cmp::Ordering::Greater => Err(ChsqlarError::SchemaTooNew { required: version }.into()),
+        std::cmp::Ordering::Equal => Ok(()),
+    }
+}
+
+/// Opens the archive at `path` that Pull, Merge or --modified-since read from. It's only
+/// read, so unlike the archive being worked on it isn't migrated, given a locks table or
+/// switched to WAL, and has to be at this version's schema already.
+fn open_source(path: &str) -> Result<Connection, Error> {
+    if !Path::new(path).exists() {
+        return Err(format_err!("{} does not exist", path));
+    }
+
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    check_readable(&connection).map_err(|e| format_err!("{}: {}", path, e))?;
+    let version = match has_meta(&connection)? {
+        true => schema_version(&connection)?,
+        false => 0,
+    };
+    if version < SCHEMA_VERSION {
+        return Err(format_err!(
+            "{} is at schema version {}, older than the {} this version reads; run Migrate \
+             on it first",
+            path,
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    Ok(connection)
+}
+
+fn auto_vacuum_mode(connection: &Connection) -> Result<&'static str, Error> {
+    let mode: i64 = connection.query_row("PRAGMA auto_vacuum", NO_PARAMS, |row| row.get(0))?;
+
+    Ok(match mode {
+        1 => "full",
+        2 => "incremental",
+        _ => "none",
+    })
+}
+
+/// Held by a command that deletes or rewrites chunks, so no two of them run on an archive at
+/// once, or by one that adds files, which can run alongside each other but not alongside
+/// one that could delete the chunks they're about to refer to. Dropping it releases the
+/// lock. It's a row in `locks` rather than an OS lock, so it works wherever SQLite does, and
+/// a row left by a process that died is taken over. No connection is kept open for it, as
+/// that would stop the command itself from leaving WAL mode.
+struct ArchiveLock {
+    database: String,
+    name: String,
+}
+
+/// Locks the archive for `command`, which deletes or rewrites chunks.
+fn lock_archive(opt: &CommonOpt, command: &str) -> Result<ArchiveLock, Error> {
+    take_lock(opt, command, "destructive".to_owned())
+}
+
+/// Locks the archive for `command`, which adds files, against commands that delete chunks.
+fn lock_archive_shared(opt: &CommonOpt, command: &str) -> Result<ArchiveLock, Error> {
+    take_lock(opt, command, format!("adding/{}", std::process::id()))
+}
+
+fn take_lock(opt: &CommonOpt, command: &str, name: String) -> Result<ArchiveLock, Error> {
+    let mut connection = lock_connection(&opt.database)?;
+    let me = i64::from(std::process::id());
+
+    // Immediate, so two commands can't both see the lock free and take it.
+    let trans = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    // A destructive command waits for every other holder, one that adds only for those.
+    let blocking = match name.as_str() {
+        "destructive" => "SELECT name, pid, command FROM locks",
+        _ => "SELECT name, pid, command FROM locks WHERE name='destructive'",
+    };
+    let holders = trans
+        .prepare(blocking)?
+        .query_map(NO_PARAMS, |row| {
+            (row.get::<_, String>(0), row.get::<_, i64>(1), row.get(2))
+        })?
+        .collect::<Result<Vec<(String, i64, String)>, _>>()?;
+    for (holder, pid, command) in holders {
+        if pid == me || !process_alive(pid) || opt.break_lock {
+            trans.execute("DELETE FROM locks WHERE name=?", &[&holder])?;
+        } else {
+            return Err(ChsqlarError::Locked { pid, command }.into());
+        }
+    }
+    trans.execute(
+        "INSERT OR REPLACE INTO locks (name, pid, command) VALUES (?, ?, ?)",
+        &[&name as &dyn ToSql, &me, &command],
+    )?;
+    trans.commit()?;
+
+    Ok(ArchiveLock {
+        database: opt.database.clone(),
+        name,
+    })
+}
+
+fn lock_connection(database: &str) -> Result<Connection, Error> {
+    let connection = Connection::open(database)?;
+    connection.busy_timeout(std::time::Duration::from_secs(5))?;
+    // The lock is released after Add --deterministic has vacuumed, and the pid it held
+    // mustn't be left behind in the page.
+    connection.execute_batch("PRAGMA secure_delete=ON")?;
+    Ok(connection)
+}
+
+impl Drop for ArchiveLock {
+    fn drop(&mut self) {
+        let _ = lock_connection(&self.database).and_then(|connection| {
+            connection.execute(
+                "DELETE FROM locks WHERE name=? AND pid=?",
+                &[&self.name as &dyn ToSql, &i64::from(std::process::id())],
+            )?;
+            Ok(())
+        });
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: i64) -> bool {
+    // Signal 0 only checks whether the process exists; EPERM means it does, as someone else's.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a cheap check, a lock is assumed to still be held, until --break-lock.
+#[cfg(not(unix))]
+fn process_alive(_pid: i64) -> bool {
+    true
+}
+
+/// An archive setting, parsed back into the type it was written from.
+fn get_meta<T>(connection: &Connection, key: &str) -> Result<Option<T>, Error>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    let value = connection.query_row("SELECT value FROM meta WHERE key=?", &[&key], |row| {
+        meta_text(row.get(0))
+    });
+    let value = match value {
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        other => other?,
+    };
+
+    match value.parse() {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(e) => Err(format_err!("setting {} holds '{}': {}", key, value, e)),
+    }
+}
+
+fn set_meta<T: fmt::Display + ?Sized>(
+    connection: &Connection,
+    key: &str,
+    value: &T,
+) -> Result<(), Error> {
+    connection.execute(
+        "INSERT OR REPLACE INTO meta VALUES (?,?)",
+        &[&key as &dyn ToSql, &value.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Settings are written as text, but one left by some other version could be anything.
+fn meta_text(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(s) => s,
+        Value::Blob(b) => match String::from_utf8(b) {
+            Ok(s) => s,
+            Err(e) => crypt::to_hex(e.as_bytes()),
+        },
+    }
+}
+
+const DEFAULT_CHUNK_BITS: usize = 20;
+
+/// The chunker parameter the archive's chunks were cut with, which adds must reuse for
+/// dedup to work.
+fn chunk_bits(connection: &Connection) -> Result<usize, Error> {
+    Ok(get_meta(connection, "chunk_bits")?.unwrap_or(DEFAULT_CHUNK_BITS))
+}
+
+/// The chunker the archive's chunks were cut with. Archives from before there was a choice
+/// used ZPAQ.
+fn chunker_algorithm(connection: &Connection) -> Result<chunker::Algorithm, Error> {
+    Ok(get_meta(connection, "chunker")?.unwrap_or(chunker::Algorithm::Zpaq))
+}
+
+/// Sets how a new archive is chunked, keeping its own for whatever isn't given. One that
+/// already has chunks can only be given what it uses, since chunks cut any other way
+/// wouldn't dedup against them, unless `force` says to cut new ones that way anyway.
+fn set_chunking(
+    connection: &Connection,
+    algorithm: Option<chunker::Algorithm>,
+    bits: Option<usize>,
+    size: Option<usize>,
+    force: bool,
+) -> Result<(), Error> {
+    let current = ChunkerParams::load(connection)?;
+    let wanted = current.with(algorithm, bits, size)?;
+
+    let chunks: i64 =
+        connection.query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))?;
+    if chunks > 0 && !force && !wanted.cuts_like(&current) {
+        return Err(format_err!(
+            "this archive's chunks are cut with {}, and chunks cut another way wouldn't dedup \
+             against them; use Compact {} to cut them all again, or Add --force-params to \
+             cut new ones this way anyway",
+            current,
+            wanted
+        ));
+    }
+
+    wanted.save(connection)
+}
+
+/// Unless --max-chunk-bytes says otherwise, chunks are cut at this many times the average
+/// chunk size.
+const DEFAULT_MAX_CHUNK_FACTOR: usize = 8;
+
+/// The smallest --max-chunk-bytes accepted.
+const MIN_MAX_CHUNK_BYTES: usize = 1024;
+
+/// The range of --chunk-size accepted with --chunker fixed.
+const FIXED_CHUNK_SIZES: std::ops::RangeInclusive<usize> = 512..=1 << 30;
+
+/// How data is cut into chunks.
+#[derive(Debug, Clone, Copy)]
+struct ChunkerParams {
+    algorithm: chunker::Algorithm,
+    /// Average chunk size as a power of two.
+    bits: usize,
+    /// With --chunker fixed, the size of every chunk but the last of each file. None cuts
+    /// them at 2^`bits`.
+    size: Option<usize>,
+    /// From --max-chunk-bytes, if given.
+    max_bytes: Option<usize>,
+}
+
+impl ChunkerParams {
+    /// The archive's parameters.
+    fn load(connection: &Connection) -> Result<Self, Error> {
+        let algorithm = chunker_algorithm(connection)?;
+        let size = match algorithm {
+            chunker::Algorithm::Fixed => get_meta(connection, "chunk_size")?,
+            _ => None,
+        };
+
+        Ok(ChunkerParams {
+            algorithm,
+            bits: chunk_bits(connection)?,
+            size,
+            max_bytes: get_meta(connection, "max_chunk_bytes")?,
+        })
+    }
+
+    /// The defaults for an archive that doesn't exist yet.
+    fn new() -> Self {
+        ChunkerParams {
+            algorithm: chunker::Algorithm::Zpaq,
+            bits: DEFAULT_CHUNK_BITS,
+            size: None,
+            max_bytes: None,
+        }
+    }
+
+    /// These parameters with whichever of `algorithm`, `bits` and `size` are given in place
+    /// of their own, checked.
+    fn with(
+        self,
+        algorithm: Option<chunker::Algorithm>,
+        bits: Option<usize>,
+        size: Option<usize>,
+    ) -> Result<Self, Error> {
+        let params = ChunkerParams {
+            algorithm: algorithm.unwrap_or(self.algorithm),
+            bits: bits.unwrap_or(self.bits),
+            size: size.or(self.size),
+            max_bytes: self.max_bytes,
+        };
+        if size.is_some() && params.algorithm != chunker::Algorithm::Fixed {
+            return Err(format_err!(
+                "--chunk-size only applies to --chunker fixed; the others take --chunk-bits"
+            ));
+        }
+
+        params.check()?;
+        Ok(params)
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.algorithm == chunker::Algorithm::Fixed {
+            if !FIXED_CHUNK_SIZES.contains(&self.average()) {
+                return Err(format_err!(
+                    "--chunk-size must be between {} and {}",
+                    FIXED_CHUNK_SIZES.start(),
+                    FIXED_CHUNK_SIZES.end()
+                ));
+            }
+            return Ok(());
+        }
+
+        let max_bits = self.algorithm.max_bits();
+        if !(8..=max_bits).contains(&self.bits) {
+            return Err(format_err!(
+                "--chunk-bits must be between 8 and {} with --chunker {}",
+                max_bits,
+                self.algorithm
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The chunk size aimed for, or with --chunker fixed the size of every chunk.
+    fn average(&self) -> usize {
+        match (self.algorithm, self.size) {
+            (chunker::Algorithm::Fixed, Some(size)) => size,
+            _ => 1 << self.bits,
+        }
+    }
+
+    /// No chunk is longer than this, wherever the chunker would have cut.
+    fn max_bytes(&self) -> usize {
+        self.max_bytes
+            .unwrap_or(DEFAULT_MAX_CHUNK_FACTOR * self.average())
+    }
+
+    fn chunker(&self) -> Box<dyn chunker::Chunking> {
+        self.algorithm.chunker(self.bits, self.average())
+    }
+
+    /// Whether chunks cut with `self` and `other` dedup against each other, apart from
+    /// where --max-chunk-bytes forced a cut.
+    fn cuts_like(&self, other: &Self) -> bool {
+        let size = |params: &Self| match params.algorithm {
+            chunker::Algorithm::Fixed => params.average(),
+            _ => params.bits,
+        };
+        self.algorithm == other.algorithm && size(self) == size(other)
+    }
+
+    /// Records the chunker and chunk size in the archive. --max-chunk-bytes is left as is.
+    fn save(&self, connection: &Connection) -> Result<(), Error> {
+        set_meta(connection, "chunker", self.algorithm.name())?;
+        set_meta(connection, "chunk_bits", &self.bits)?;
+        if self.algorithm == chunker::Algorithm::Fixed {
+            set_meta(connection, "chunk_size", &self.average())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ChunkerParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.algorithm {
+            chunker::Algorithm::Fixed => {
+                write!(f, "--chunker fixed --chunk-size {}", self.average())
+            }
+            _ => write!(f, "--chunker {} --chunk-bits {}", self.algorithm, self.bits),
+        }
+    }
+}
+
+/// The range of --hash-bytes accepted.
+const HASH_BYTES: std::ops::RangeInclusive<usize> = 8..=64;
+
+/// How many bytes --hash blake3 reads out without --hash-bytes.
+const DEFAULT_BLAKE3_BYTES: usize = 32;
+
+/// Add warns once the odds of two chunks sharing a hash pass this.
+const COLLISION_WARNING_ODDS: f64 = 1e-9;
+
+/// Sets a new archive's chunk hash. One that already has chunks keeps the one they were
+/// stored under, so that no archive mixes two.
+fn set_chunk_hash(connection: &Connection, wanted: ChunkHash) -> Result<(), Error> {
+    let current = ChunkHash::load(connection)?;
+
+    let chunks: i64 =
+        connection.query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))?;
+    if chunks > 0 && wanted != current {
+        return Err(format_err!(
+            "this archive's chunks are hashed with {}; --hash and --hash-bytes only apply to a \
+             new archive, even with --force-params, and Compact --hash {} converts this one",
+            current,
+            wanted
+        ));
+    }
+
+    wanted.save(connection)
+}
+
+/// Warns once the archive holds enough chunks for two of them to plausibly share a hash,
+/// which would have Add take one for the other.
+fn warn_of_collisions(connection: &Connection) -> Result<(), Error> {
+    let chunk_hash = ChunkHash::load(connection)?;
+    let chunks: i64 =
+        connection.query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))?;
+
+    let odds = chunk_hash.collision_odds(chunks);
+    if odds > COLLISION_WARNING_ODDS {
+        let warning = format!(
+            "With {} chunks under {} hashes, the odds of two sharing one are about {:.0e}; \
+             a new archive with a larger --hash-bytes would be safer",
+            chunks, chunk_hash, odds
+        );
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, warning));
+    }
+
+    Ok(())
+}
+
+/// What chunks are hashed with, and so stored under.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkHash {
+    /// SHA3-512, which archives used before --hash-bytes.
+    Sha3,
+    /// BLAKE3 read out to this many bytes.
+    Blake3 { bytes: usize },
+}
+
+impl ChunkHash {
+    /// The names `--hash` accepts.
+    const NAMES: &'static [&'static str] = &["sha3-512", "blake3"];
+
+    /// The hash `--hash` and `--hash-bytes` ask for, if either is given.
+    fn chosen(name: Option<&str>, bytes: Option<usize>) -> Result<Option<Self>, Error> {
+        match (name, bytes) {
+            (Some("sha3-512"), Some(_)) => {
+                Err(format_err!("--hash-bytes only applies to --hash blake3"))
+            }
+            (Some("sha3-512"), None) => Ok(Some(ChunkHash::Sha3)),
+            (_, Some(bytes)) => Ok(Some(ChunkHash::Blake3 { bytes })),
+            (Some(_), None) => Ok(Some(ChunkHash::Blake3 {
+                bytes: DEFAULT_BLAKE3_BYTES,
+            })),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn load(connection: &Connection) -> Result<Self, Error> {
+        match get_meta(connection, "hash_bytes")? {
+            Some(bytes) => Ok(ChunkHash::Blake3 { bytes }),
+            None => Ok(ChunkHash::Sha3),
+        }
+    }
+
+    fn save(self, connection: &Connection) -> Result<(), Error> {
+        match self {
+            ChunkHash::Sha3 => {
+                connection.execute("DELETE FROM meta WHERE key='hash_bytes'", NO_PARAMS)?;
+            }
+            ChunkHash::Blake3 { bytes } => set_meta(connection, "hash_bytes", &bytes)?,
+        }
+
+        Ok(())
+    }
+
+    /// The hex digest `data` is stored under.
+    fn of(self, data: &[u8]) -> String {
+        match self {
+            ChunkHash::Sha3 => crypt::to_hex(&hash_chunk(data)),
+            ChunkHash::Blake3 { bytes } => {
+                let mut digest = vec![0; bytes];
+                blake3::Hasher::new()
+                    .update(data)
+                    .finalize_xof()
+                    .fill(&mut digest);
+                crypt::to_hex(&digest)
+            }
+        }
+    }
+
+    /// A digest that hashes whole files the way chunks are, for `File::content_hash`.
+    fn checksum(self) -> Checksum {
+        match self {
+            ChunkHash::Sha3 => Checksum::Sha3(Sha3_512::new()),
+            ChunkHash::Blake3 { bytes } => Checksum::Blake3(Box::new(blake3::Hasher::new()), bytes),
+        }
+    }
+
+    /// The `Checksum --algo` whose digests are the same as this hash's, if there is one.
+    fn checksum_algo(self) -> Option<&'static str> {
+        match self {
+            ChunkHash::Sha3 => Some("sha3-512"),
+            ChunkHash::Blake3 { bytes: 32 } => Some("blake3"),
+            ChunkHash::Blake3 { .. } => None,
+        }
+    }
+
+    /// How many hex digits the hashes of this kind are written with.
+    fn hex_len(self) -> usize {
+        match self {
+            ChunkHash::Sha3 => 128,
+            ChunkHash::Blake3 { bytes } => 2 * bytes,
+        }
+    }
+
+    /// Roughly the odds that two of `chunks` different chunks share a hash, from the
+    /// birthday bound.
+    fn collision_odds(self, chunks: i64) -> f64 {
+        let bits = match self {
+            ChunkHash::Sha3 => 512,
+            ChunkHash::Blake3 { bytes } => 8 * bytes as i32,
+        };
+        let chunks = chunks as f64;
+        chunks * chunks / 2f64.powi(bits + 1)
+    }
+}
+
+impl fmt::Display for ChunkHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkHash::Sha3 => f.write_str("sha3-512"),
+            ChunkHash::Blake3 { bytes } => write!(f, "blake3 --hash-bytes {}", bytes),
+        }
+    }
+}
+
+/// Brings tables created by older versions up to date with the current schema.
+fn add_column_if_missing(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), Error> {
+    let mut stmt = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut columns = Vec::<String>::new();
+    for name in stmt.query_map(NO_PARAMS, |row| row.get(1))? {
+        columns.push(name?);
+    }
+
+    if !columns.iter().any(|c| c == column) {
+        connection.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            NO_PARAMS,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the content of the stored file `name` to `writer`, holding only one chunk in
+/// memory at a time.
+fn extract_file_to<W: Write>(
+    trans: &mut Transaction,
+    name: PathBuf,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+    writer: &mut W,
+) -> Result<(), Error> {
+    let f = get_file(trans, name, encoding)?;
+
+    write_file_to(trans, &f, cache, writer, write_zeros)
+}
+
+/// Writes the content of `f` to `writer`, calling `skip` to fill in each hole.
+fn write_file_to<W, S>(
+    trans: &mut Transaction,
+    f: &File,
+    cache: &ChunkCache,
+    writer: &mut W,
+    mut skip: S,
+) -> Result<(), Error>
+where
+    W: Write,
+    S: FnMut(&mut W, u64) -> Result<(), Error>,
+{
+    let mut holes = f.holes.iter().peekable();
+    let mut pos = 0;
+
+    let pieces = f
+        .inline
+        .as_ref()
+        .map(|(codec, data)| open_inline(trans, codec, data))
+        .into_iter()
+        .chain(f.chunks.iter().map(|hash| cache.get(trans, hash)));
+
+    for chunk in pieces {
+        let chunk = chunk?;
+        let mut data = &chunk[..];
+
+        while !data.is_empty() {
+            let n = match holes.peek() {
+                Some(&&(offset, len)) if offset == pos => {
+                    skip(writer, len)?;
+                    pos += len;
+                    holes.next();
+                    continue;
+                }
+                Some(&&(offset, _)) => std::cmp::min(data.len() as u64, offset - pos) as usize,
+                None => data.len(),
+            };
+
+            writer.write_all(&data[..n])?;
+            data = &data[n..];
+            pos += n as u64;
+        }
+    }
+
+    for &(_, len) in holes {
+        skip(writer, len)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the content of a stored file a chunk at a time. Unlike `write_file_to`, several
+/// can be open on the same connection at once.
+struct StoredReader<'a> {
+    connection: &'a Connection,
+    file: &'a File,
+    cache: &'a ChunkCache<'a>,
+    /// The piece loaded into `data`, counting the inline data as the first if there is any.
+    pieces_read: usize,
+    data: Vec<u8>,
+    used: usize,
+    holes_read: usize,
+    hole_left: u64,
+    pos: u64,
+}
+
+impl<'a> StoredReader<'a> {
+    fn new(connection: &'a Connection, file: &'a File, cache: &'a ChunkCache<'a>) -> Self {
+        StoredReader {
+            connection,
+            file,
+            cache,
+            pieces_read: 0,
+            data: Vec::new(),
+            used: 0,
+            holes_read: 0,
+            hole_left: 0,
+            pos: 0,
+        }
+    }
+
+    /// Loads the next piece of data, returning false at the end.
+    fn next_piece(&mut self) -> Result<bool, Error> {
+        let inline = self.file.inline.is_some() as usize;
+        self.data = match (&self.file.inline, self.pieces_read) {
+            (Some((codec, data)), 0) => open_inline(self.connection, codec, data)?,
+            _ => match self.file.chunks.get(self.pieces_read - inline) {
+                Some(hash) => self.cache.get(self.connection, hash)?,
+                None => return Ok(false),
+            },
+        };
+        self.pieces_read += 1;
+        self.used = 0;
+
+        Ok(true)
+    }
+}
+
+impl<'a> Read for StoredReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(&(offset, len)) = self.file.holes.get(self.holes_read) {
+            if offset == self.pos && self.hole_left == 0 {
+                self.hole_left = len;
+                self.holes_read += 1;
+            }
+        }
+
+        if self.hole_left > 0 {
+            let n = std::cmp::min(buf.len() as u64, self.hole_left) as usize;
+            buf[..n].iter_mut().for_each(|b| *b = 0);
+            self.hole_left -= n as u64;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        while self.used == self.data.len() {
+            let more = self
+                .next_piece()
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            if !more {
+                return Ok(0);
+            }
+        }
+
+        let mut n = std::cmp::min(buf.len(), self.data.len() - self.used);
+        if let Some(&(offset, _)) = self.file.holes.get(self.holes_read) {
+            n = std::cmp::min(n as u64, offset - self.pos) as usize;
+        }
+        buf[..n].copy_from_slice(&self.data[self.used..self.used + n]);
+        self.used += n;
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+fn write_zeros<W: Write>(writer: &mut W, len: u64) -> Result<(), Error> {
+    std::io::copy(&mut std::io::repeat(0).take(len), writer)?;
+    Ok(())
+}
+
+fn get_chunks_data(trans: &mut Transaction, chunks: &[String]) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::new();
+
+    for hash in chunks {
+        let chunk = get_chunk(trans, hash)?;
+        result.extend_from_slice(&chunk);
+    }
+
+    Ok(result)
+}
+
+fn get_chunk(connection: &Connection, hash: &str) -> Result<Vec<u8>, Error> {
+    let (data, codec, checksum): (Option<Vec<u8>>, String, Option<i64>) = or_not_found(
+        connection.query_row(
+            "SELECT data, codec, checksum FROM chunks WHERE hash=?",
+            &[&hash],
+            |row| (row.get(0), row.get(1), row.get(2)),
+        ),
+        || ChsqlarError::ChunkNotFound {
+            hash: hash.to_owned(),
+        },
+    )?;
+
+    let data = match data {
+        Some(data) => data,
+        None => read_loose(connection, hash)?,
+    };
+
+    if checksum.is_some_and(|c| c != checksum_blob(&data)) {
+        return Err(ChsqlarError::ChunkCorrupted {
+            hash: hash.to_owned(),
+        }
+        .into());
+    }
+
+    let cipher = crypt::cipher(connection)?;
+    let data = crypt::open(cipher, data, hash.as_bytes(), || format!("chunk {}", hash))?;
+
+    decompress(&codec, data)
+}
+
+/// Decrypts and decompresses the inline data of a file.
+fn open_inline(connection: &Connection, codec: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = crypt::cipher(connection)?;
+    let data = crypt::open(cipher, data.to_vec(), crypt::INLINE_AAD, || {
+        "inline file data".to_owned()
+    })?;
+
+    decompress(codec, data)
+}
+
+/// A cheap checksum of the stored (compressed) chunk data, to catch bit rot without
+/// decompressing and rehashing.
+fn checksum_blob(data: &[u8]) -> i64 {
+    i64::from(crc32fast::hash(data))
+}
+
+/// Stores `chunk` unless the archive already has it, returning whether it was new.
+fn put_chunk(trans: &mut Transaction, chunk: PreparedChunk) -> Result<bool, Error> {
+    let store_dir = store_dir(trans)?;
+    let inline_data = match store_dir {
+        Some(_) => None,
+        None => Some(&chunk.data),
+    };
+
+    let inserted = trans.execute(
+        "INSERT OR IGNORE INTO chunks
+        (hash, data, codec, level, checksum, size, incompressible, uncompressed_size)
+        VALUES (?,?,?,?,?,?,?,?)",
+        &[
+            &chunk.hash,
+            &inline_data as &dyn ToSql,
+            &chunk.compression.codec(),
+            &chunk.compression.level(),
+            &checksum_blob(&chunk.data),
+            &(chunk.data.len() as i64),
+            &chunk.incompressible,
+            &(chunk.uncompressed_size as i64),
+        ],
+    )?;
+
+    if let (Some(dir), 1) = (store_dir, inserted) {
+        write_loose(&dir, &chunk.hash, &chunk.data)?;
+    }
+
+    Ok(inserted == 1)
+}
+
+/// Where chunk data goes instead of the `data` column, if the archive was set up with
+/// `--store-dir`. Chunks stored before that stay in the database and remain readable.
+fn store_dir(connection: &Connection) -> Result<Option<PathBuf>, Error> {
+    get_meta(connection, "store_dir")
+}
+
+fn set_store_dir(connection: &Connection, dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    let dir = fs::canonicalize(dir)?;
+    let dir = dir
+        .to_str()
+        .ok_or_else(|| format_err!("--store-dir must be valid UTF-8"))?;
+
+    set_meta(connection, "store_dir", dir)
+}
+
+/// Loose chunks are sharded into directories by the first byte of their hash, so no one
+/// directory gets too large.
+fn loose_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(&hash[..2]).join(&hash[2..])
+}
+
+fn write_loose(dir: &Path, hash: &str, data: &[u8]) -> Result<(), Error> {
+    let path = loose_path(dir, hash);
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    // Written under a temporary name first, so a crash never leaves a truncated chunk.
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(())
+}
+
+fn read_loose(connection: &Connection, hash: &str) -> Result<Vec<u8>, Error> {
+    let not_found = || ChsqlarError::ChunkNotFound {
+        hash: hash.to_owned(),
+    };
+
+    let dir = store_dir(connection)?.ok_or_else(not_found)?;
+    match fs::read(loose_path(&dir, hash)) {
+        Ok(data) => Ok(data),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Err(not_found().into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Deletes the row of chunk `hash`. A loose file it has is left to `remove_loose_chunks`
+/// once the transaction has committed, so a rollback never leaves a row without its data.
+fn delete_chunk(connection: &Connection, hash: &str) -> Result<(), Error> {
+    connection.execute("DELETE FROM chunks WHERE hash=?", &[hash])?;
+
+    Ok(())
+}
+
+/// Removes the loose files of chunks whose rows a committed transaction deleted.
+fn remove_loose_chunks<I>(connection: &Connection, hashes: I) -> Result<(), Error>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    if let Some(dir) = store_dir(connection)? {
+        for hash in hashes {
+            match fs::remove_file(loose_path(&dir, hash.as_ref())) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn put_file(trans: &mut Transaction, mut file: File, encoding: NameEncoding) -> Result<(), Error> {
+    let chunks = file.chunks.join(";");
+    let name = encode_name(&file.name, encoding)?;
+    let attrs = match encoding {
+        NameEncoding::Sealed => Some(seal_attrs(&mut file)?),
+        _ => None,
+    };
+    let holes = join_holes(&file.holes);
+    let (inline_codec, inline_data) = match file.inline {
+        Some((codec, data)) => (Some(codec), Some(data)),
+        None => (None, None),
+    };
+    let (uid, gid) = file.owner.unzip();
+
+    adjust_refcounts(trans, &file_chunks(trans, &name)?, -1)?;
+    adjust_refcounts(trans, &file.chunks, 1)?;
+
+    trans.execute(
+        "INSERT OR REPLACE INTO files
+            (name, size, chunks, holes, inline_data, inline_codec, mtime, added_at, sha256,
+             owner_uid, owner_gid, owner_user, owner_group, filter, attrs, content_hash)
+        VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)",
+        &[
+            &name as &dyn ToSql,
+            &file.size,
+            &chunks,
+            &holes,
+            &inline_data,
+            &inline_codec,
+            &file.mtime,
+            &file.added_at,
+            &file.sha256,
+            &uid,
+            &gid,
+            &file.owner_user,
+            &file.owner_group,
+            &file.filter,
+            &attrs,
+            &file.content_hash,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Moves the metadata of `file` that --encrypt-names hides into a sealed value for the
+/// `attrs` column, leaving its own columns empty.
+fn seal_attrs(file: &mut File) -> Result<Vec<u8>, Error> {
+    let attrs = serde_json::json!({
+        "mtime": file.mtime.take(),
+        "added_at": file.added_at.take(),
+        "sha256": file.sha256.take(),
+        "content_hash": file.content_hash.take(),
+        "owner": file.owner.take(),
+        "owner_user": file.owner_user.take(),
+        "owner_group": file.owner_group.take(),
+        "filter": file.filter.take(),
+    });
+
+    crypt::seal_attrs(&path_to_bytes(&file.name)?, attrs.to_string().as_bytes())
+}
+
+/// Fills in the metadata of `file` from its sealed `attrs` column, the inverse of
+/// `seal_attrs`.
+fn open_attrs(file: &mut File, sealed: &[u8]) -> Result<(), Error> {
+    let attrs = crypt::open_attrs(&path_to_bytes(&file.name)?, sealed)?;
+    let attrs: serde_json::Value = serde_json::from_slice(&attrs)?;
+
+    file.mtime = attrs["mtime"].as_i64();
+    file.added_at = attrs["added_at"].as_i64();
+    file.sha256 = attrs["sha256"].as_str().map(str::to_owned);
+    file.content_hash = attrs["content_hash"].as_str().map(str::to_owned);
+    file.owner = serde_json::from_value(attrs["owner"].clone())?;
+    file.owner_user = attrs["owner_user"].as_str().map(str::to_owned);
+    file.owner_group = attrs["owner_group"].as_str().map(str::to_owned);
+    file.filter = attrs["filter"].as_str().map(str::to_owned);
+
+    Ok(())
+}
+
+fn list_files(trans: &mut Transaction, encoding: NameEncoding) -> Result<Vec<PathBuf>, Error> {
+    let mut stmt = trans.prepare("SELECT name FROM files")?;
+    let mut results = Vec::new();
+    for name in stmt.query_map(NO_PARAMS, |row| row.get(0))? {
+        results.push(decode_name(name?, encoding)?);
+    }
+
+    Ok(results)
+}
+
+/// Finds chunks not referenced by any file or blob, returning their hashes and stored sizes.
+fn find_orphans(trans: &mut Transaction) -> Result<Vec<(String, i64)>, Error> {
+    let mut referenced = HashSet::new();
+
+    for table in &["files", "blobs"] {
+        let mut stmt = trans.prepare(&format!("SELECT chunks FROM {}", table))?;
+        for chunks in stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))? {
+            referenced.extend(split_chunk_list(&chunks?));
+        }
+    }
+
+    let mut stmt = trans.prepare("SELECT hash, COALESCE(size, length(data)) FROM chunks")?;
+    let mut orphans = Vec::new();
+    for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+        let (hash, size) = row?;
+        if !referenced.contains(&hash) {
+            orphans.push((hash, size));
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// A chunk Compact deletes.
+struct UnreferencedChunk {
+    hash: String,
+    codec: String,
+    /// As stored, compressed.
+    size: i64,
+    uncompressed_size: Option<i64>,
+}
+
+/// The chunks no file or blob uses any more going by their reference counts, which unlike
+/// `find_orphans` doesn't have to read every chunk list.
+fn unreferenced_chunks(connection: &Connection) -> Result<Vec<UnreferencedChunk>, Error> {
+    let mut stmt = connection.prepare(
+        "SELECT hash, codec, COALESCE(size, length(data)), uncompressed_size FROM chunks
+        WHERE refcount<=0",
+    )?;
+    let mut unreferenced = Vec::new();
+    for row in stmt.query_map(NO_PARAMS, |row| UnreferencedChunk {
+        hash: row.get(0),
+        codec: row.get(1),
+        size: row.get(2),
+        uncompressed_size: row.get(3),
+    })? {
+        unreferenced.push(row?);
+    }
+
+    Ok(unreferenced)
+}
+
+/// Adds `delta` to the reference count of each of `hashes`, once for every time it appears.
+/// Must run in the same transaction as the change to the chunk lists it accounts for.
+fn adjust_refcounts(connection: &Connection, hashes: &[String], delta: i64) -> Result<(), Error> {
+    let mut stmt =
+        connection.prepare_cached("UPDATE chunks SET refcount=refcount+? WHERE hash=?")?;
+    for hash in hashes {
+        stmt.execute(&[&delta as &dyn ToSql, hash])?;
+    }
+
+    Ok(())
+}
+
+/// The chunks the stored file under the already encoded `name` uses, none if there isn't
+/// one.
+fn file_chunks(connection: &Connection, name: &dyn ToSql) -> Result<Vec<String>, Error> {
+    match connection.query_row("SELECT chunks FROM files WHERE name=?", &[name], |row| {
+        row.get::<_, String>(0)
+    }) {
+        Ok(chunks) => Ok(split_chunk_list(&chunks)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Recomputes every chunk's reference count from the chunk lists of files and blobs.
+/// Returns how many chunks there are and how many had a count that was wrong.
+fn rebuild_refcounts(connection: &Connection) -> Result<(usize, usize), Error> {
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    for table in &["files", "blobs"] {
+        let mut stmt = connection.prepare(&format!("SELECT chunks FROM {}", table))?;
+        for chunks in stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))? {
+            for hash in split_chunk_list(&chunks?) {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut stored = Vec::new();
+    {
+        let mut stmt = connection.prepare("SELECT hash, refcount FROM chunks")?;
+        for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+            let (hash, refcount): (String, i64) = row?;
+            stored.push((hash, refcount));
+        }
+    }
+
+    let mut drifted = 0;
+    for (hash, refcount) in &stored {
+        let actual = counts.get(hash).copied().unwrap_or(0);
+        if *refcount != actual {
+            connection.execute(
+                "UPDATE chunks SET refcount=? WHERE hash=?",
+                &[&actual as &dyn ToSql, hash],
+            )?;
+            drifted += 1;
+        }
+    }
+
+    Ok((stored.len(), drifted))
+}
+
+/// Deletes the stored files `names`, then the chunks that only they used. Returns the hashes
+/// of those chunks, for `remove_loose_chunks` once `trans` commits. Chunks that were
+/// orphaned already are left for Compact.
+fn remove_files(
+    trans: &mut Transaction,
+    names: &[PathBuf],
+    encoding: NameEncoding,
+) -> Result<Vec<String>, Error> {
+    let mut released = HashSet::new();
+    for name in names {
+        let stored = get_file(trans, name.clone(), encoding)?;
+        adjust_refcounts(trans, &stored.chunks, -1)?;
+        released.extend(stored.chunks);
+        let name = encode_name(name, encoding)?;
+        trans.execute("DELETE FROM files WHERE name=?", &[&name])?;
+        trans.execute("DELETE FROM file_meta WHERE file_name=?", &[&name])?;
+    }
+
+    let mut freed = Vec::new();
+    for hash in released {
+        let unreferenced: i64 = trans.query_row(
+            "SELECT COUNT(*) FROM chunks WHERE hash=? AND refcount<=0",
+            &[&hash],
+            |row| row.get(0),
+        )?;
+        if unreferenced > 0 {
+            delete_chunk(trans, &hash)?;
+            freed.push(hash);
+        }
+    }
+
+    Ok(freed)
+}
+
+fn chunk_data(data: Vec<u8>, params: ChunkerParams) -> Vec<Vec<u8>> {
+    let chunker = params.chunker();
+
+    let mut chunks = Vec::new();
+
+    for chunk in chunker.cut(&data) {
+        // The cut after a forced one is still wherever the content says, so an edit inside
+        // a long run only changes the chunks up to the next real boundary.
+        chunks.extend(chunk.chunks(params.max_bytes()).map(<[u8]>::to_vec));
+    }
+
+    chunks
+}
+
+/// The SHA3-512 digest of `data`, which `ChunkHash::Sha3` writes out in hex.
+fn hash_chunk(data: &[u8]) -> [u8; 64] {
+    Sha3_512::digest(data).into()
+}
+
+fn get_file(trans: &mut Transaction, name: PathBuf, encoding: NameEncoding) -> Result<File, Error> {
+    or_not_found(
+        trans.query_row(
+            &format!("SELECT {} FROM files WHERE name=?", FILE_COLUMNS),
+            &[&encode_name(&name, encoding)?],
+            |row| file_from_row(name.clone(), row, 0),
+        ),
+        || ChsqlarError::FileNotFound { path: name.clone() },
+    )?
+}
+
+/// The columns of `files` that `file_from_row` reads, in order.
+const FILE_COLUMNS: &str = "size, chunks, holes, inline_codec, inline_data, mtime, added_at, \
+     sha256, owner_uid, owner_gid, filter, attrs, content_hash, owner_user, owner_group";
+
+/// Builds a `File` from `FILE_COLUMNS` selected starting at column `first`.
+fn file_from_row(name: PathBuf, row: &rusqlite::Row, first: usize) -> Result<File, Error> {
+    let col = |i: usize| first + i;
+    let chunks: String = row.get(col(1));
+    let holes: Option<String> = row.get(col(2));
+    let inline_codec: Option<String> = row.get(col(3));
+    let inline_data: Option<Vec<u8>> = row.get(col(4));
+    let uid: Option<u32> = row.get(col(8));
+    let gid: Option<u32> = row.get(col(9));
+    let attrs: Option<Vec<u8>> = row.get(col(11));
+
+    let mut file = File {
+        name,
+        size: row.get(col(0)),
+        chunks: split_chunk_list(&chunks),
+        holes: split_holes(holes.as_ref().map_or("", String::as_str))?,
+        inline: inline_codec.and_then(|codec| Some((codec, inline_data?))),
+        mtime: row.get(col(5)),
+        added_at: row.get(col(6)),
+        sha256: row.get(col(7)),
+        content_hash: row.get(col(12)),
+        owner: uid.zip(gid),
+        owner_user: row.get(col(13)),
+        owner_group: row.get(col(14)),
+        filter: row.get(col(10)),
+    };
+    if let Some(attrs) = attrs {
+        open_attrs(&mut file, &attrs)?;
+    }
+
+    Ok(file)
+}
+
+/// Holes are stored as "offset:length" pairs joined with ";", or NULL if there are none.
+fn join_holes(holes: &[(u64, u64)]) -> Option<String> {
+    if holes.is_empty() {
+        return None;
+    }
+
+    let pairs: Vec<_> = holes
+        .iter()
+        .map(|(offset, len)| format!("{}:{}", offset, len))
+        .collect();
+
+    Some(pairs.join(";"))
+}
+
+fn split_holes(holes: &str) -> Result<Vec<(u64, u64)>, Error> {
+    let mut result = Vec::new();
+
+    for pair in holes.split(';').filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(offset), Some(len)) => result.push((offset.parse()?, len.parse()?)),
+            _ => return Err(format_err!("malformed hole '{}'", pair)),
+        }
+    }
+
+    Ok(result)
+}
+
+fn split_chunk_list(chunks: &str) -> Vec<String> {
+    if chunks == "" {
+        Vec::new()
+    } else {
+        chunks.split(";").map(|s| s.to_string()).collect()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn put_file_data(
+    trans: &mut Transaction,
+    name: PathBuf,
+    chunks: Vec<Vec<u8>>,
+    compression: Compression,
+    min_savings: u32,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<(), Error> {
+    let mut f = get_file(trans, name, encoding)?;
+
+    f.chunks = put_chunks(
+        trans,
+        chunks,
+        compression,
+        min_savings,
+        budget,
+        metrics,
+        window,
+    )?;
+
+    put_file(trans, f, encoding)?;
+
+    Ok(())
+}
+
+fn put_chunks(
+    trans: &mut Transaction,
+    data_chunks: Vec<Vec<u8>>,
+    compression: Compression,
+    min_savings: u32,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<Vec<String>, Error> {
+    let mut chunks = Vec::new();
+    let cipher = crypt::cipher(trans)?;
+    let chunk_hash = ChunkHash::load(trans)?;
+
+    let mut data_chunks = VecDeque::from(data_chunks);
+    while !data_chunks.is_empty() {
+        let batch = budget.next_batch(&mut data_chunks);
+        let input: u64 = batch.iter().map(|c| c.len() as u64).sum();
+        let mut drawn = budget.draw(input);
+
+        let hashed: Vec<_> = batch
+            .into_par_iter()
+            .map(|chunk| {
+                let hash = Metrics::time(&metrics.hash_nanos, || chunk_hash.of(&chunk));
+                (hash, chunk)
+            })
+            .collect();
+
+        // Only chunks the window hasn't seen, earlier in this batch included, are compressed.
+        let hashed: Vec<_> = hashed
+            .into_iter()
+            .map(|(hash, chunk)| match window.contains(&hash) {
+                true => (hash, None),
+                false => {
+                    window.record(hash.clone());
+                    (hash, Some(chunk))
+                }
+            })
+            .collect();
+
+        let hashed_chunks = hashed
+            .into_par_iter()
+            .map(|(hash, chunk)| {
+                let chunk = match chunk {
+                    Some(chunk) => chunk,
+                    None => return Ok((hash, None)),
+                };
+                let (compression, data, incompressible) =
+                    compress_or_store(compression, &chunk, min_savings, metrics)?;
+                let data = crypt::seal(cipher, data, hash.as_bytes())?;
+
+                let pchunk = PreparedChunk {
+                    data,
+                    hash: hash.clone(),
+                    compression,
+                    incompressible,
+                    uncompressed_size: chunk.len(),
+                };
+                Ok((hash, Some(pchunk)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let output: u64 = hashed_chunks
+            .iter()
+            .filter_map(|(_, pchunk)| pchunk.as_ref())
+            .map(|c| c.data.len() as u64)
+            .sum();
+        drawn.shrink(input.saturating_sub(output));
+
+        for (hash, pchunk) in hashed_chunks {
+            chunks.push(hash);
+
+            match pchunk {
+                Some(pchunk) => {
+                    let size = pchunk.data.len() as u64;
+                    if put_chunk(trans, pchunk)? {
+                        Metrics::add(&metrics.chunks_new, 1);
+                        Metrics::add(&metrics.bytes_stored, size);
+                    } else {
+                        Metrics::add(&metrics.chunks_dedup, 1);
+                    }
+                }
+                None => Metrics::add(&metrics.chunks_dedup, 1),
+            }
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// The hashes of the chunks an Add stored or found most recently. Hashes only join once
+/// their transaction commits, so a file rolled back can't leave the window naming chunks
+/// that were never kept. Each Add has its own, which lives only as long as it does, so a
+/// chunk deleted after it can't be skipped as if it were still there. The default one
+/// remembers nothing, for storing outside Add --dedup-window.
+#[derive(Default)]
+struct DedupWindow {
+    committed: RecentHashes,
+    /// Those from the transaction still open.
+    pending: RecentHashes,
+}
+
+impl DedupWindow {
+    fn new(size: usize) -> Self {
+        DedupWindow {
+            committed: RecentHashes::new(size),
+            pending: RecentHashes::new(size),
+        }
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.committed.hashes.contains(hash) || self.pending.hashes.contains(hash)
+    }
+
+    fn record(&mut self, hash: String) {
+        self.pending.insert(hash);
+    }
+
+    /// Called as each transaction starts, forgetting what one that rolled back left behind.
+    fn begin(&mut self) {
+        self.pending = RecentHashes::new(self.pending.size);
+    }
+
+    /// Called once a transaction has committed.
+    fn commit(&mut self) {
+        for hash in self.pending.order.drain(..) {
+            self.committed.insert(hash);
+        }
+        self.pending.hashes.clear();
+    }
+}
+
+/// Up to `size` hashes, forgetting the oldest first.
+#[derive(Default)]
+struct RecentHashes {
+    size: usize,
+    order: VecDeque<String>,
+    hashes: HashSet<String>,
+}
+
+impl RecentHashes {
+    fn new(size: usize) -> Self {
+        RecentHashes {
+            size,
+            order: VecDeque::new(),
+            hashes: HashSet::new(),
+        }
+    }
+
+    fn insert(&mut self, hash: String) {
+        if self.size == 0 || self.hashes.contains(&hash) {
+            return;
+        }
+        if self.order.len() == self.size {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.hashes.insert(hash.clone());
+        self.order.push_back(hash);
+    }
+}
+
+/// Decompressed chunks kept for the rest of the run, evicting the least recently used once
+/// they take up more than `limit` bytes, or more than is left of the memory budget if it
+/// has one. Only used for reading.
+struct ChunkCache<'a> {
+    limit: u64,
+    budget: Option<&'a MemoryBudget>,
+    state: std::cell::RefCell<ChunkCacheState>,
+}
+
+#[derive(Default)]
+struct ChunkCacheState {
+    /// Each chunk's data and when it was last used.
+    chunks: HashMap<String, (Vec<u8>, u64)>,
+    by_use: std::collections::BTreeMap<u64, String>,
+    size: u64,
+    clock: u64,
+}
+
+impl<'a> ChunkCache<'a> {
+    fn new(limit: u64) -> Self {
+        ChunkCache {
+            limit,
+            budget: None,
+            state: Default::default(),
+        }
+    }
+
+    /// A cache whose chunks, and each chunk read through it, are drawn from `budget`.
+    fn drawing_on(limit: u64, budget: &'a MemoryBudget) -> Self {
+        ChunkCache {
+            limit,
+            budget: Some(budget),
+            state: Default::default(),
+        }
+    }
+
+    fn get(&self, connection: &Connection, hash: &str) -> Result<Vec<u8>, Error> {
+        if self.limit == 0 {
+            let data = get_chunk(connection, hash)?;
+            self.note(data.len());
+            return Ok(data);
+        }
+
+        let state = &mut *self.state.borrow_mut();
+        state.clock += 1;
+
+        if let Some((data, used)) = state.chunks.get_mut(hash) {
+            state.by_use.remove(used);
+            *used = state.clock;
+            state.by_use.insert(state.clock, hash.to_owned());
+            return Ok(data.clone());
+        }
+
+        let data = get_chunk(connection, hash)?;
+        self.note(data.len());
+        let size = data.len() as u64;
+        if size > self.limit {
+            return Ok(data);
+        }
+
+        let available = || self.budget.map_or(u64::MAX, MemoryBudget::available);
+        while state.size + size > self.limit || (state.size > 0 && available() < size) {
+            let (_, evicted) = state.by_use.pop_first().unwrap();
+            let (evicted, _) = state.chunks.remove(&evicted).unwrap();
+            state.size -= evicted.len() as u64;
+            if let Some(budget) = self.budget {
+                budget.release_cached(evicted.len() as u64);
+            }
+        }
+        if available() < size {
+            return Ok(data);
+        }
+
+        state.size += size;
+        if let Some(budget) = self.budget {
+            budget.hold_cached(size);
+        }
+        state.by_use.insert(state.clock, hash.to_owned());
+        state
+            .chunks
+            .insert(hash.to_owned(), (data.clone(), state.clock));
+
+        Ok(data)
+    }
+}
+
+impl ChunkCache<'_> {
+    fn note(&self, bytes: usize) {
+        if let Some(budget) = self.budget {
+            budget.note(bytes as u64);
+        }
+    }
+}
+
+impl Drop for ChunkCache<'_> {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget {
+            budget.release_cached(self.state.get_mut().size);
+        }
+    }
+}
+
+/// The bytes of chunk data everything in a run holds at once may draw on: a file or piece
+/// Add has read and the chunks cut from it, the output of the chunks being compressed, and
+/// the chunks kept by the chunk cache. Remembers the most that was drawn at once.
+struct MemoryBudget {
+    limit: Option<u64>,
+    state: Mutex<BudgetUse>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct BudgetUse {
+    used: u64,
+    /// The part of `used` held by chunk caches, which give way rather than be waited for.
+    cached: u64,
+    peak: u64,
+}
+
+impl MemoryBudget {
+    fn new(limit: Option<u64>) -> Self {
+        MemoryBudget {
+            limit,
+            state: Mutex::new(BudgetUse::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes as many chunks from the front of `chunks` as can be compressed together with
+    /// what's left of the budget, and always at least one. Their compressed output is
+    /// budgeted at the size of their input, as it can't be known up front; the input itself
+    /// is drawn by whoever read it.
+    fn next_batch(&self, chunks: &mut VecDeque<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut room = self.available();
+        let mut batch = Vec::new();
+        while let Some(chunk) = chunks.front() {
+            let cost = chunk.len() as u64;
+            if !batch.is_empty() && cost > room {
+                break;
+            }
+            room = room.saturating_sub(cost);
+            batch.extend(chunks.pop_front());
+        }
+
+        batch
+    }
+
+    /// Waits until `bytes` fit under the limit beside what others have drawn, then draws
+    /// them. With nothing else drawn they're granted whatever their size, so a piece bigger
+    /// than the limit still goes ahead on its own. Chunk caches aren't waited for, as they
+    /// only let go of chunks when they next need room. Only for callers holding nothing
+    /// drawn already, who would otherwise wait on themselves.
+    fn acquire(&self, bytes: u64) -> Reservation<'_> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(limit) = self.limit {
+            while state.used > state.cached && state.used + bytes > limit {
+                state = self.released.wait(state).unwrap();
+            }
+        }
+        MemoryBudget::draw_locked(&mut state, bytes);
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+
+    /// Draws `bytes` without waiting, for a caller that already holds some and can't make
+    /// progress without these. They should fit in `available`, unless they're the least
+    /// the caller can do with.
+    fn draw(&self, bytes: u64) -> Reservation<'_> {
+        MemoryBudget::draw_locked(&mut self.state.lock().unwrap(), bytes);
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+
+    fn draw_locked(state: &mut BudgetUse, bytes: u64) {
+        state.used += bytes;
+        state.peak = std::cmp::max(state.peak, state.used);
+    }
+
+    /// How many bytes can still be drawn before reaching the limit.
+    fn available(&self) -> u64 {
+        match self.limit {
+            Some(limit) => limit.saturating_sub(self.state.lock().unwrap().used),
+            None => u64::MAX,
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.state.lock().unwrap().used -= bytes;
+        self.released.notify_all();
+    }
+
+    fn hold_cached(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.cached += bytes;
+        MemoryBudget::draw_locked(&mut state, bytes);
+    }
+
+    fn release_cached(&self, bytes: u64) {
+        self.state.lock().unwrap().cached -= bytes;
+        self.release(bytes);
+    }
+
+    /// Counts `bytes` held only for a moment, such as a chunk just read, towards the peak.
+    fn note(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.peak = std::cmp::max(state.peak, state.used + bytes);
+    }
+
+    /// The most that was drawn at once.
+    fn peak(&self) -> u64 {
+        self.state.lock().unwrap().peak
+    }
+
+    fn report(&self) {
+        match self.limit {
+            Some(limit) => eprintln!(
+                "memory limit {} bytes, peak {} bytes in flight",
+                limit,
+                self.peak()
+            ),
+            None => eprintln!("no memory limit, peak {} bytes in flight", self.peak()),
+        }
+    }
+}
+
+/// Bytes drawn from a `MemoryBudget`, given back when dropped.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Reservation<'_> {
+    /// Gives back `bytes` of the reservation early, once what they were for is freed.
+    fn shrink(&mut self, bytes: u64) {
+        let bytes = std::cmp::min(bytes, self.bytes);
+        self.bytes -= bytes;
+        self.budget.release(bytes);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// Counts and per-stage timings for an Add or Extract, for the summary printed at the end.
+/// Atomic as chunks are hashed and compressed on several threads at once, so the hash and
+/// compress times add up the time spent on every thread.
+#[derive(Debug)]
+struct Metrics {
+    start: std::time::Instant,
+    files: AtomicU64,
+    /// File content read in by Add.
+    bytes_read: AtomicU64,
+    /// File content written out by Extract, leaving out files cloned with reflinks.
+    bytes_written: AtomicU64,
+    chunks_new: AtomicU64,
+    chunks_dedup: AtomicU64,
+    /// Compressed size of the new chunks and inline files.
+    bytes_stored: AtomicU64,
+    /// What went into the compressor and came out, including chunks the archive turned out
+    /// to have already.
+    compress_in: AtomicU64,
+    compress_out: AtomicU64,
+    read_nanos: AtomicU64,
+    hash_nanos: AtomicU64,
+    compress_nanos: AtomicU64,
+    write_nanos: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            start: std::time::Instant::now(),
+            files: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            chunks_new: AtomicU64::new(0),
+            chunks_dedup: AtomicU64::new(0),
+            bytes_stored: AtomicU64::new(0),
+            compress_in: AtomicU64::new(0),
+            compress_out: AtomicU64::new(0),
+            read_nanos: AtomicU64::new(0),
+            hash_nanos: AtomicU64::new(0),
+            compress_nanos: AtomicU64::new(0),
+            write_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, adding how long it took to `stage`.
+    fn time<T>(stage: &AtomicU64, f: impl FnOnce() -> T) -> T {
+        let start = std::time::Instant::now();
+        let result = f();
+        Metrics::add(stage, start.elapsed().as_nanos() as u64);
+        result
+    }
+
+    /// Megabytes per second for `bytes` processed in the time recorded in `stage`.
+    fn rate(bytes: &AtomicU64, stage: &AtomicU64) -> Option<f64> {
+        match stage.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(bytes.load(Ordering::Relaxed) as f64 * 1e3 / nanos as f64),
+        }
+    }
+
+    fn to_json(&self, op: &str) -> serde_json::Value {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        match op {
+            "add" => serde_json::json!({
+                "op": op,
+                "files": get(&self.files),
+                "elapsed_secs": elapsed,
+                "bytes_read": get(&self.bytes_read),
+                "chunks_new": get(&self.chunks_new),
+                "chunks_deduplicated": get(&self.chunks_dedup),
+                "bytes_stored": get(&self.bytes_stored),
+                "compress_bytes_in": get(&self.compress_in),
+                "compress_bytes_out": get(&self.compress_out),
+                "read_mb_per_sec": Metrics::rate(&self.bytes_read, &self.read_nanos),
+                "hash_mb_per_sec": Metrics::rate(&self.bytes_read, &self.hash_nanos),
+                "compress_mb_per_sec":
+                    Metrics::rate(&self.compress_in, &self.compress_nanos),
+            }),
+            _ => serde_json::json!({
+                "op": op,
+                "files": get(&self.files),
+                "elapsed_secs": elapsed,
+                "bytes_written": get(&self.bytes_written),
+                "read_mb_per_sec": Metrics::rate(&self.bytes_written, &self.read_nanos),
+                "write_mb_per_sec": Metrics::rate(&self.bytes_written, &self.write_nanos),
+            }),
+        }
+    }
+
+    /// Prints the summary of an Add or Extract to stderr, as a paragraph or as JSON.
+    fn report(&self, op: &str, json: bool) {
+        let summary = self.to_json(op);
+        if json {
+            eprintln!("{}", summary);
+            return;
+        }
+
+        let mb = |key: &str| summary[key].as_u64().unwrap_or(0) as f64 / 1e6;
+        let rate = |key: &str| match summary[key].as_f64() {
+            Some(rate) => format!("{:.1} MB/s", rate),
+            None => "-".to_owned(),
+        };
+        let elapsed = summary["elapsed_secs"].as_f64().unwrap_or(0.0);
+        if op == "add" {
+            eprintln!(
+                "Added {} files, {:.2} MB read in {:.2}s; {} new chunks and {} deduplicated, \
+                 {:.2} MB stored; {:.2} MB compressed to {:.2} MB. Read {}, hash {}, \
+                 compress {}.",
+                summary["files"],
+                mb("bytes_read"),
+                elapsed,
+                summary["chunks_new"],
+                summary["chunks_deduplicated"],
+                mb("bytes_stored"),
+                mb("compress_bytes_in"),
+                mb("compress_bytes_out"),
+                rate("read_mb_per_sec"),
+                rate("hash_mb_per_sec"),
+                rate("compress_mb_per_sec")
+            );
+        } else {
+            eprintln!(
+                "Extracted {} files, {:.2} MB written in {:.2}s. Read {}, write {}.",
+                summary["files"],
+                mb("bytes_written"),
+                elapsed,
+                rate("read_mb_per_sec"),
+                rate("write_mb_per_sec")
+            );
+        }
+    }
+}
+
+/// Passes writes through to `inner`, adding the time they take to `nanos`.
+struct TimedWriter<'a, W> {
+    inner: W,
+    nanos: &'a AtomicU64,
+}
+
+impl<'a, W: Write> Write for TimedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Metrics::time(self.nanos, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Metrics::time(self.nanos, || self.inner.flush())
+    }
+}
+
+/// Stores `data` in the chunk store without a file name, returning the hash of the whole
+/// blob which can later be passed to `get_blob`.
+fn put_blob(
+    trans: &mut Transaction,
+    data: Vec<u8>,
+    budget: &MemoryBudget,
+) -> Result<String, Error> {
+    let hash = ChunkHash::load(trans)?.of(&data);
+    let size = data.len() as i64;
+    // The blob and the chunks it's cut into.
+    let _held = budget.acquire(2 * size as u64);
+
+    let chunks = chunk_data(data, ChunkerParams::load(trans)?);
+    let compression = Compression::default();
+    let metrics = Metrics::default();
+    let chunks = put_chunks(
+        trans,
+        chunks,
+        compression,
+        DEFAULT_MIN_SAVINGS,
+        budget,
+        &metrics,
+        &mut DedupWindow::default(),
+    )?;
+
+    let inserted = trans.execute(
+        "INSERT OR IGNORE INTO blobs VALUES (?,?,?)",
+        &[&hash as &dyn ToSql, &size, &chunks.join(";")],
+    )?;
+    if inserted > 0 {
+        adjust_refcounts(trans, &chunks, 1)?;
+    }
+
+    Ok(hash)
+}
+
+fn get_blob(trans: &mut Transaction, hash: &str) -> Result<Vec<u8>, Error> {
+    let chunks: String = or_not_found(
+        trans.query_row("SELECT chunks FROM blobs WHERE hash=?", &[&hash], |row| {
+            row.get(0)
+        }),
+        || ChsqlarError::BlobNotFound {
+            hash: hash.to_owned(),
+        },
+    )?;
+
+    get_chunks_data(trans, &split_chunk_list(&chunks))
+}
+
+/// Writes the stored names to `out`, each followed by `end`.
+#[allow(clippy::too_many_arguments)]
+fn list_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    long: bool,
+    full_time: bool,
+    added_after: Option<i64>,
+    tags: &[(String, String)],
+    end: u8,
+    encoding: NameEncoding,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    let tagged = match tags.is_empty() {
+        true => None,
+        false => Some(tagged_files(&trans, tags, encoding)?),
+    };
+
+    if !long && added_after.is_none() && tagged.is_none() {
+        for file in list_files(&mut trans, encoding)? {
+            out.write_all(&path_to_bytes(&file)?)?;
+            out.write_all(&[end])?;
+        }
+
+        return Ok(());
+    }
+
+    // With --encrypt-names the time a file was added is sealed, so only Rust can filter on it.
+    let mut stmt = trans.prepare(&format!(
+        "SELECT name, {} FROM files WHERE ?1 IS NULL OR added_at > ?1 OR attrs IS NOT NULL",
+        FILE_COLUMNS
+    ))?;
+    let rows = stmt.query_map(&[&added_after], |row| {
+        decode_name(row.get(0), encoding).and_then(|name| file_from_row(name, row, 1))
+    })?;
+
+    for file in rows {
+        let file = file??;
+        if added_after.is_some_and(|after| file.added_at.is_none_or(|added| added <= after)) {
+            continue;
+        }
+        if tagged
+            .as_ref()
+            .is_some_and(|tagged| !tagged.contains(&file.name))
+        {
+            continue;
+        }
+
+        if !long {
+            out.write_all(&path_to_bytes(&file.name)?)?;
+            out.write_all(&[end])?;
+            continue;
+        }
+
+        let mtime = match file.mtime {
+            Some(mtime) => format_mtime(mtime, full_time),
+            None => "-".to_owned(),
+        };
+
+        let size = paint(Stream::Stdout, Style::Size, file.size);
+        write!(out, "{:>12} {} ", size, mtime)?;
+        out.write_all(&path_to_bytes(&file.name)?)?;
+        out.write_all(&[end])?;
+    }
+
+    Ok(())
+}
+
+/// Writes the names of files added, changed and removed since the archive `other`, grouped
+/// in that order and each group sorted by name.
+fn list_changes_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    other: &str,
+    end: u8,
+    encoding: NameEncoding,
+    out: &mut W,
+) -> Result<(), Error> {
+    let source = open_source(other)?;
+    // The same name seals differently under another key.
+    if crypt::names_encrypted(&source)? || encoding == NameEncoding::Sealed {
+        return Err(format_err!(
+            "--modified-since can't compare encrypted names"
+        ));
+    }
+    drop(source);
+
+    db.connection
+        .execute("ATTACH DATABASE ? AS source", &[&other])?;
+
+    // Names are compared as bytes, so text and blob names of the same file still match.
+    const CHANGES: &[(&str, &str, Style)] = &[
+        (
+            "A",
+            "SELECT name FROM main.files WHERE CAST(name AS BLOB) NOT IN
+                (SELECT CAST(name AS BLOB) FROM source.files)",
+            Style::Good,
+        ),
+        (
+            "M",
+            "SELECT m.name FROM main.files m
+            JOIN source.files s ON CAST(m.name AS BLOB) = CAST(s.name AS BLOB)
+            WHERE CASE WHEN m.sha256 IS NOT NULL AND s.sha256 IS NOT NULL
+                THEN m.sha256 != s.sha256
+                ELSE m.size != s.size END",
+            Style::Warning,
+        ),
+        (
+            "D",
+            "SELECT name FROM source.files WHERE CAST(name AS BLOB) NOT IN
+                (SELECT CAST(name AS BLOB) FROM main.files)",
+            Style::Bad,
+        ),
+    ];
+
+    for (status, query, style) in CHANGES {
+        let mut stmt = db.connection.prepare(&format!(
+            "SELECT name FROM ({}) ORDER BY CAST(name AS BLOB)",
+            query
+        ))?;
+        for name in stmt.query_map(NO_PARAMS, |row| row.get(0))? {
+            let name = decode_name(name?, encoding)?;
+            write!(out, "{}\t", paint(Stream::Stdout, *style, status))?;
+            out.write_all(&path_to_bytes(&name)?)?;
+            out.write_all(&[end])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes each directory containing stored files to `out` once, with a trailing `/`.
+///
+/// Names sharing a prefix are contiguous when sorted bytewise, so comparing every name
+/// against the previous one is enough to emit each directory exactly once without
+/// remembering the ones already seen.
+fn list_dirs_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    max_depth: Option<usize>,
+    end: u8,
+    encoding: NameEncoding,
+    out: &mut W,
+) -> Result<(), Error> {
+    let trans = db.connection.transaction()?;
+
+    // Casting makes text and blob names sort together by their bytes. Sealed names would
+    // sort by their ciphertext, so they're sorted once decrypted instead.
+    let mut stmt = trans.prepare("SELECT name FROM files ORDER BY CAST(name AS BLOB)")?;
+    let mut names = stmt
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .map(|name| decode_name(name?, encoding))
+        .collect::<Result<Vec<_>, Error>>()?;
+    if encoding == NameEncoding::Sealed {
+        names.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+    }
+
+    let mut previous: Vec<std::ffi::OsString> = Vec::new();
+    for name in names {
+        let mut dirs: Vec<_> = match name.parent() {
+            Some(parent) => parent.iter().map(|c| c.to_owned()).collect(),
+            None => Vec::new(),
+        };
+        if let Some(max_depth) = max_depth {
+            dirs.truncate(max_depth);
+        }
+
+        let common = previous
+            .iter()
+            .zip(&dirs)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for depth in common..dirs.len() {
+            let mut dir = path_to_bytes(&dirs[..=depth].iter().collect::<PathBuf>())?;
+            dir.push(b'/');
+            output::write_painted(out, Stream::Stdout, Style::Dir, &dir)?;
+            out.write_all(&[end])?;
+        }
+
+        previous = dirs;
+    }
+
+    Ok(())
+}
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Converts to nanoseconds since the Unix epoch, negative for earlier times.
+fn system_time_nanos(time: std::time::SystemTime) -> i64 {
+    match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn nanos_system_time(nanos: i64) -> std::time::SystemTime {
+    let offset = std::time::Duration::from_nanos(nanos.unsigned_abs());
+    if nanos >= 0 {
+        std::time::UNIX_EPOCH + offset
+    } else {
+        std::time::UNIX_EPOCH - offset
+    }
+}
+
+fn format_mtime(nanos: i64, full_time: bool) -> String {
+    let secs = nanos.div_euclid(NANOS_PER_SEC);
+    let subsec = nanos.rem_euclid(NANOS_PER_SEC) as u32;
+
+    match chrono::DateTime::from_timestamp(secs, subsec) {
+        Some(time) if full_time => time.format("%Y-%m-%d %H:%M:%S%.9f").to_string(),
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => nanos.to_string(),
+    }
+}
+
+/// Parses a `--tag` given as key=value. The key is everything up to the first "=".
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected key=value, got '{}'", s)),
+    }
+}
+
+/// Parses a UTC time given as a date, a date and time, or seconds since the Unix epoch,
+/// into nanoseconds since the epoch.
+fn parse_time(s: &str) -> Result<i64, String> {
+    let time = if let Ok(secs) = s.parse::<i64>() {
+        chrono::DateTime::from_timestamp(secs, 0).map(|t| t.naive_utc())
+    } else if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        date.and_hms_opt(0, 0, 0)
+    } else {
+        chrono::NaiveDateTime::parse_from_str(&s.replace('T', " "), "%Y-%m-%d %H:%M:%S%.f").ok()
+    };
+
+    time.and_then(|t| t.and_utc().timestamp_nanos_opt())
+        .ok_or_else(|| format!("expected YYYY-MM-DD[ HH:MM:SS] or seconds, got '{}'", s))
+}
+
+/// Compares modification times at full precision, unless one of them has no fractional
+/// part, as on file systems that only keep whole seconds.
+fn same_mtime(a: i64, b: i64) -> bool {
+    let whole = |t: i64| t.rem_euclid(NANOS_PER_SEC) == 0;
+
+    a == b || ((whole(a) || whole(b)) && a.div_euclid(NANOS_PER_SEC) == b.div_euclid(NANOS_PER_SEC))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_file(
+    trans: &mut Transaction,
+    fpath: PathBuf,
+    fname: PathBuf,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<(), Error> {
+    let mut reader = fs::File::open(&fpath)?;
+    let metadata = reader.metadata()?;
+    if let (Some(limit), true) = (budget.limit, can_stream(opt, &metadata)) {
+        return add_streamed(
+            trans,
+            &mut reader,
+            &metadata,
+            fname,
+            opt,
+            encoding,
+            budget,
+            limit,
+            metrics,
+            window,
+        );
+    }
+
+    // Read whole, so the file and the chunks it's cut into are in memory together.
+    let _held = budget.acquire(2 * metadata.len());
+    let mut buf = Vec::new();
+    Metrics::time(&metrics.read_nanos, || reader.read_to_end(&mut buf))?;
+
+    add_data(
+        trans,
+        buf,
+        Some(&metadata),
+        fname,
+        opt,
+        encoding,
+        budget,
+        metrics,
+        window,
+    )
+}
+
+/// A shell command, run by `sh -c` or on Windows `cmd /C`.
+fn shell_command(command: &str) -> std::process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = std::process::Command::new(shell);
+    shell.args([flag, command]);
+    shell
+}
+
+/// `data` passed through the --filter `command` for the file stored as `name`.
+fn filter_data(command: &str, name: &Path, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut child = shell_command(command)
+        .env("CHSQLAR_FILE", name)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    // Fed from another thread, so a command that prints before reading everything can't
+    // fill its stdout pipe and stop while we're still writing.
+    let mut stdin = child.stdin.take().unwrap();
+    let feeder = std::thread::spawn(move || stdin.write_all(&data));
+    let output = child.wait_with_output()?;
+    let fed = feeder.join().unwrap();
+
+    check_filter(command, name, output.status)?;
+    match fed {
+        // The command is allowed to not read all of its input.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        fed => fed?,
+    }
+
+    Ok(output.stdout)
+}
+
+/// A failed filter is an I/O error, so without --strict Add skips the file like one it
+/// can't read.
+fn check_filter(command: &str, name: &Path, status: std::process::ExitStatus) -> Result<(), Error> {
+    if status.success() {
+        return Ok(());
+    }
+
+    let message = format!("'{}' failed on {}: {}", command, name.display(), status);
+    Err(std::io::Error::other(message).into())
+}
+
+/// Stores `buf` as the file `fname`. `metadata` describes where it was read from, if it was
+/// a file, for its mtime and for --sparse auto.
+#[allow(clippy::too_many_arguments)]
+fn add_data(
+    trans: &mut Transaction,
+    buf: Vec<u8>,
+    metadata: Option<&fs::Metadata>,
+    fname: PathBuf,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<(), Error> {
+    Metrics::add(&metrics.files, 1);
+    Metrics::add(&metrics.bytes_read, buf.len() as u64);
+
+    let buf = match &opt.filter {
+        Some(filter) => filter_data(filter, &fname, buf)?,
+        None => buf,
+    };
+    let size = buf.len() as u64;
+
+    let mut sha256 = Sha256::new();
+    let mut content_hash = ChunkHash::load(trans)?.checksum();
+    Metrics::time(&metrics.hash_nanos, || {
+        sha256.update(&buf);
+        content_hash.update(&buf);
+    });
+
+    let holes = match opt.sparse.as_str() {
+        "always" => find_holes(&buf),
+        "auto" if metadata.is_some_and(is_sparse) => find_holes(&buf),
+        _ => Vec::new(),
+    };
+    let buf = remove_holes(buf, &holes);
+
+    let compression = opt.compress_opt.compression_for(&fname);
+
+    let inline = if size < opt.inline_threshold {
+        let min_savings = opt.compress_opt.min_savings;
+        let (compression, data, _) = compress_or_store(compression, &buf, min_savings, metrics)?;
+        let data = crypt::seal(crypt::cipher(trans)?, data, crypt::INLINE_AAD)?;
+        Metrics::add(&metrics.bytes_stored, data.len() as u64);
+        Some((compression.codec().to_owned(), data))
+    } else {
+        None
+    };
+    let is_inline = inline.is_some();
+
+    let mut f = new_file(fname.clone(), size, metadata, opt, sha256, content_hash)?;
+    f.holes = holes;
+    f.inline = inline;
+    put_file(trans, f, encoding)?;
+
+    if is_inline {
+        return Ok(());
+    }
+
+    let params = ChunkerParams::load(trans)?;
+    let chunks = match opt.whole_file_under {
+        Some(limit) if size < limit && buf.len() <= params.max_bytes() => vec![buf],
+        _ => chunk_data(buf, params),
+    };
+
+    let min_savings = opt.compress_opt.min_savings;
+    put_file_data(
+        trans,
+        fname,
+        chunks,
+        compression,
+        min_savings,
+        encoding,
+        budget,
+        metrics,
+        window,
+    )?;
+
+    Ok(())
+}
+
+/// The entry for a file named `fname` of `size` bytes, without its content. `metadata`
+/// describes where it was read from, if it was a file.
+fn new_file(
+    fname: PathBuf,
+    size: u64,
+    metadata: Option<&fs::Metadata>,
+    opt: &AddOpt,
+    sha256: Sha256,
+    content_hash: Checksum,
+) -> Result<File, Error> {
+    let owner = match opt.preserve_ownership {
+        true => metadata.and_then(file_owner),
+        false => None,
+    };
+    let (owner_user, owner_group) = owner_names(owner);
+
+    Ok(File {
+        name: fname,
+        size: size as i64,
+        chunks: Vec::new(),
+        holes: Vec::new(),
+        inline: None,
+        mtime: match (opt.deterministic, metadata) {
+            (false, Some(metadata)) => Some(system_time_nanos(metadata.modified()?)),
+            _ => None,
+        },
+        added_at: match opt.deterministic {
+            true => None,
+            false => Some(system_time_nanos(std::time::SystemTime::now())),
+        },
+        sha256: Some(crypt::to_hex(&sha256.finalize())),
+        content_hash: Some(content_hash.hex()),
+        owner,
+        owner_user,
+        owner_group,
+        filter: opt.filter.clone(),
+    })
+}
+
+/// Whether Add can read the file `metadata` describes a piece at a time, which it can't
+/// when it needs the whole of it at once: for --filter, to find its holes, or to store it
+/// inline or as one chunk.
+fn can_stream(opt: &AddOpt, metadata: &fs::Metadata) -> bool {
+    let size = metadata.len();
+    let sparse = match opt.sparse.as_str() {
+        "always" => true,
+        "auto" => is_sparse(metadata),
+        _ => false,
+    };
+
+    opt.filter.is_none()
+        && !sparse
+        && size >= opt.inline_threshold
+        && opt.whole_file_under.is_none_or(|limit| size >= limit)
+}
+
+/// How much of a file Add reads at a time under --memory-limit: a quarter of the limit,
+/// which leaves room for the chunks cut from it and for their compressed output, but never
+/// less than the largest chunk.
+fn piece_size(limit: u64, params: &ChunkerParams) -> u64 {
+    std::cmp::max(limit / 4, params.max_bytes() as u64)
+}
+
+/// Stores the file `reader` reads a piece at a time, so only a piece and the chunks cut from
+/// it are in memory rather than the whole file. The last chunk of each piece is cut again
+/// with the next one, and every other chunk ends where it would have with the whole file
+/// at once, so the chunks come out the same.
+#[allow(clippy::too_many_arguments)]
+fn add_streamed(
+    trans: &mut Transaction,
+    reader: &mut fs::File,
+    metadata: &fs::Metadata,
+    fname: PathBuf,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    limit: u64,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<(), Error> {
+    Metrics::add(&metrics.files, 1);
+    let params = ChunkerParams::load(trans)?;
+    let chunker = params.chunker();
+    let compression = opt.compress_opt.compression_for(&fname);
+    let piece = piece_size(limit, &params);
+
+    let mut sha256 = Sha256::new();
+    let mut content_hash = ChunkHash::load(trans)?.checksum();
+    let mut size = 0;
+    let mut chunks = Vec::new();
+    // The chunk cut last, which the next piece may add to, followed by that piece.
+    let mut data = Vec::new();
+    loop {
+        // The data and the chunks it's cut into.
+        let _held = budget.acquire(2 * (data.len() as u64 + piece));
+        let carried = data.len();
+        Metrics::time(&metrics.read_nanos, || {
+            Read::by_ref(reader).take(piece).read_to_end(&mut data)
+        })?;
+        let read = (data.len() - carried) as u64;
+        let end = read < piece;
+        Metrics::add(&metrics.bytes_read, read);
+        Metrics::time(&metrics.hash_nanos, || {
+            sha256.update(&data[carried..]);
+            content_hash.update(&data[carried..]);
+        });
+        size += read;
+
+        let mut cut = chunker.cut(&data);
+        let unfinished = match end {
+            true => 0,
+            false => cut.pop().map_or(0, <[u8]>::len),
+        };
+        let data_chunks = cut
+            .iter()
+            .flat_map(|chunk| chunk.chunks(params.max_bytes()).map(<[u8]>::to_vec))
+            .collect();
+        data.drain(..data.len() - unfinished);
+
+        let min_savings = opt.compress_opt.min_savings;
+        chunks.extend(put_chunks(
+            trans,
+            data_chunks,
+            compression,
+            min_savings,
+            budget,
+            metrics,
+            window,
+        )?);
+
+        if end {
+            break;
+        }
+    }
+
+    let mut f = new_file(fname, size, Some(metadata), opt, sha256, content_hash)?;
+    f.chunks = chunks;
+    put_file(trans, f, encoding)?;
+
+    Ok(())
+}
+
+/// Holes are found in whole blocks of this size, as file systems allocate them.
+const SPARSE_BLOCK: usize = 4096;
+
+/// Shorter runs of zeros aren't worth the bookkeeping.
+const SPARSE_MIN_HOLE: u64 = 64 * 1024;
+
+#[cfg(unix)]
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn file_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn file_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Runs `lookup`, a getpw*_r or getgr*_r call given the entry to fill in, a buffer for its
+/// strings and where to point at the result, growing the buffer while it's too small.
+/// `read` gets what's wanted out of the entry while the buffer is still there. None if
+/// there's no such user or group.
+#[cfg(unix)]
+fn lookup_entry<E, T>(
+    mut lookup: impl FnMut(&mut E, &mut [libc::c_char], &mut *mut E) -> libc::c_int,
+    read: impl FnOnce(&E) -> T,
+) -> Option<T> {
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // Safe as it's only used for libc::passwd and libc::group, plain C structs whose
+        // fields are integers and pointers, for which all zeros is a valid value.
+        let mut entry: E = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        match lookup(&mut entry, &mut buf, &mut result) {
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => return Some(read(&entry)),
+            _ => return None,
+        }
+    }
+}
+
+// The unsafe calls below are safe as the functions write only to the entry and to the
+// buffer, within the length they're given, and the strings `read` copies out of the entry
+// point into that buffer, which `lookup_entry` keeps until `read` returns.
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    lookup_entry(
+        |pwd, buf, result| unsafe {
+            libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+        },
+        |pwd: &libc::passwd| {
+            unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) }
+                .to_string_lossy()
+                .into_owned()
+        },
+    )
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    lookup_entry(
+        |grp, buf, result| unsafe {
+            libc::getgrgid_r(gid, grp, buf.as_mut_ptr(), buf.len(), result)
+        },
+        |grp: &libc::group| {
+            unsafe { std::ffi::CStr::from_ptr(grp.gr_name) }
+                .to_string_lossy()
+                .into_owned()
+        },
+    )
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup_entry(
+        |pwd, buf, result| unsafe {
+            libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+        },
+        |pwd: &libc::passwd| pwd.pw_uid,
+    )
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup_entry(
+        |grp, buf, result| unsafe {
+            libc::getgrnam_r(name.as_ptr(), grp, buf.as_mut_ptr(), buf.len(), result)
+        },
+        |grp: &libc::group| grp.gr_gid,
+    )
+}
+
+/// The names of the user and group `owner` has, where they have one.
+#[cfg(unix)]
+fn owner_names(owner: Option<(u32, u32)>) -> (Option<String>, Option<String>) {
+    match owner {
+        Some((uid, gid)) => (user_name(uid), group_name(gid)),
+        None => (None, None),
+    }
+}
+
+#[cfg(not(unix))]
+fn owner_names(_owner: Option<(u32, u32)>) -> (Option<String>, Option<String>) {
+    (None, None)
+}
+
+/// Finds block-aligned runs of zeros in `data` of at least `SPARSE_MIN_HOLE` bytes.
+fn find_holes(data: &[u8]) -> Vec<(u64, u64)> {
+    let mut holes: Vec<(u64, u64)> = Vec::new();
+
+    for (i, block) in data.chunks_exact(SPARSE_BLOCK).enumerate() {
+        if block.iter().any(|&b| b != 0) {
+            continue;
+        }
+
+        let offset = (i * SPARSE_BLOCK) as u64;
+        match holes.last_mut() {
+            Some((start, len)) if *start + *len == offset => *len += SPARSE_BLOCK as u64,
+            _ => holes.push((offset, SPARSE_BLOCK as u64)),
+        }
+    }
+
+    holes.retain(|&(_, len)| len >= SPARSE_MIN_HOLE);
+    holes
+}
+
+/// Cuts `holes` out of `data`, leaving the bytes that need storing.
+fn remove_holes(data: Vec<u8>, holes: &[(u64, u64)]) -> Vec<u8> {
+    if holes.is_empty() {
+        return data;
+    }
+
+    let mut result = Vec::new();
+    let mut pos = 0;
+    for &(offset, len) in holes {
+        result.extend_from_slice(&data[pos..offset as usize]);
+        pos = (offset + len) as usize;
+    }
+    result.extend_from_slice(&data[pos..]);
+
+    result
+}
+
+/// Makes `p` relative to the deepest ancestor of `cwd` it shares. Drive prefixes and the
+/// root are dropped, so the result is relative even when `p` is on another drive.
+fn normalise_path(cwd: &Path, p: &Path) -> PathBuf {
+    let common = cwd
+        .components()
+        .zip(p.components())
+        .take_while(|&(a, b)| same_component(a, b))
+        .count();
+
+    p.components()
+        .skip(common)
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Compares path components, treating `C:` and the `\\?\C:` that `canonicalize` returns on
+/// Windows as the same drive.
+fn same_component(a: Component, b: Component) -> bool {
+    fn drive(prefix: Prefix) -> Option<u8> {
+        match prefix {
+            Prefix::Disk(d) | Prefix::VerbatimDisk(d) => Some(d.to_ascii_uppercase()),
+            _ => None,
+        }
+    }
+
+    match (a, b) {
+        (Component::Prefix(a), Component::Prefix(b)) => match (drive(a.kind()), drive(b.kind())) {
+            (Some(a), Some(b)) => a == b,
+            _ => a == b,
+        },
+        _ => a == b,
+    }
+}
+
+/// Collects per-file I/O errors during add so one unreadable file doesn't abort the run,
+/// unless `strict` is set.
+struct AddFailures {
+    strict: bool,
+    failed: Vec<PathBuf>,
+}
+
+impl AddFailures {
+    fn record(&mut self, path: &Path, err: Error) -> Result<(), Error> {
+        if self.strict || err.downcast_ref::<std::io::Error>().is_none() {
+            return Err(err);
+        }
+
+        eprintln!("{}: {}", path.display(), err);
+        self.failed.push(path.to_path_buf());
+
+        Ok(())
+    }
+}
+
+/// Settings and accumulated state for walking the paths given to `add`.
+struct Walk<'a> {
+    opt: &'a AddOpt,
+    verbosity: u8,
+    failures: AddFailures,
+    excludes: Gitignore,
+    /// With --transform, the file each stored name was taken from in this run.
+    transformed: HashMap<PathBuf, PathBuf>,
+}
+
+impl<'a> Walk<'a> {
+    /// Fails if --transform gives `f` the same stored name as another file in this run.
+    fn check_transformed(&mut self, cwd: &Path, f: &Path) -> Result<(), Error> {
+        if self.opt.transforms.is_empty() {
+            return Ok(());
+        }
+
+        let name = stored_name(cwd, f, self.opt)?;
+        if let Some(other) = self.transformed.get(&name) {
+            return Err(format_err!(
+                "{} and {} would both be stored as {}",
+                other.display(),
+                f.display(),
+                name.display()
+            ));
+        }
+        self.transformed.insert(name, f.to_path_buf());
+
+        Ok(())
+    }
+}
+
+fn load_excludes(root: &Path, files: &[PathBuf]) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    for file in files {
+        if let Some(e) = builder.add(file) {
+            return Err(e.into());
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+#[cfg(unix)]
+fn device_id(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Expands `file` into the files below it. `device` is the file system the walk started on,
+/// for `--one-file-system`.
+fn resolve_files(
+    file: PathBuf,
+    device: Option<u64>,
+    walk: &mut Walk,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut result = Vec::new();
+
+    let resolved = fs::canonicalize(&file).and_then(|f| fs::metadata(&f).map(|meta| (f, meta)));
+    let (file, meta) = match resolved {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            walk.failures.record(&file, e.into())?;
+            return Ok(result);
+        }
+    };
+
+    if walk.excludes.matched(&file, meta.is_dir()).is_ignore() {
+        if walk.verbosity > 0 {
+            eprintln!("excluding {}", file.display());
+        }
+        return Ok(result);
+    }
+
+    let mut device = device;
+    if walk.opt.one_file_system {
+        match device {
+            Some(root) if device_id(&meta) != Some(root) => {
+                if walk.verbosity > 0 {
+                    eprintln!("not crossing into {}", file.display());
+                }
+                return Ok(result);
+            }
+            Some(_) => {}
+            None => device = device_id(&meta),
+        }
+    }
+
+    if meta.is_file() {
+        result.push(file);
+    } else if meta.is_dir() && walk.opt.no_recurse {
+        let e = std::io::Error::other("is a directory, which --no-recurse leaves out");
+        walk.failures.record(&file, e.into())?;
+    } else if meta.is_dir() {
+        let entries = fs::read_dir(&file).and_then(|dir| {
+            dir.map(|x| x.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let mut files = match entries {
+            Ok(files) => files,
+            Err(e) => {
+                walk.failures.record(&file, e.into())?;
+                return Ok(result);
+            }
+        };
+
+        if walk.opt.order != "none" {
+            files.sort();
+        }
+
+        for f in files {
+            let mut pathbuf = PathBuf::new();
+            pathbuf.push(&file);
+            pathbuf.push(f);
+            let resolved_files = resolve_files(pathbuf, device, walk)?;
+            for resolved in resolved_files {
+                result.push(resolved);
+            }
+        }
+    } else {
+        // FIFOs, sockets and device nodes have no content to store.
+        let e = std::io::Error::other("not a regular file or directory");
+        walk.failures.record(&file, e.into())?;
+    }
+
+    Ok(result)
+}
+
+/// Set by the Ctrl-C handler; `add` checks it between files.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn install_interrupt_handler() -> Result<(), Error> {
+    let installed = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing the current file (Ctrl-C again to abort it)");
+    });
+
+    match installed {
+        // By an earlier command run in the same process, as in tests.
+        Ok(()) | Err(ctrlc::Error::MultipleHandlers) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What an `add` run got through.
+struct AddSummary {
+    stored: usize,
+    /// The size of the files this run read in, leaving out any a previous run had stored.
+    bytes: u64,
+    failed: Vec<PathBuf>,
+    interrupted: bool,
+    /// Paths left out because the archive reached --max-db-size.
+    over_quota: Vec<PathBuf>,
+    /// Paths not started before --time-budget ran out.
+    out_of_time: Vec<PathBuf>,
+    metrics: Metrics,
+}
+
+/// Adds every file under `files`, stopping early after a Ctrl-C, when the archive is full or
+/// when the time budget runs out.
+fn add_files_cmd(
+    db: &mut SqliteDatabase,
+    files: Vec<PathBuf>,
+    opt: AddOpt,
+    verbosity: u8,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+) -> Result<AddSummary, Error> {
+    install_interrupt_handler()?;
+
+    add_files(db, &current_dir()?, files, opt, verbosity, encoding, budget)
+}
+
+/// Adds every file under `files`, resolving relative paths and stored names against `cwd`.
+/// Stops early once `INTERRUPTED` is set.
+fn add_files(
+    db: &mut SqliteDatabase,
+    cwd: &Path,
+    files: Vec<PathBuf>,
+    mut opt: AddOpt,
+    verbosity: u8,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+) -> Result<AddSummary, Error> {
+    // Walking a huge tree takes a while too, so it counts against the time budget.
+    let deadline = opt.time_budget.map(|time| std::time::Instant::now() + time);
+
+    opt.max_db_size = match opt.max_db_size {
+        Some(0) => {
+            db.connection
+                .execute("DELETE FROM meta WHERE key='max_db_size'", NO_PARAMS)?;
+            None
+        }
+        Some(limit) => {
+            set_meta(&db.connection, "max_db_size", &limit)?;
+            Some(limit)
+        }
+        None => get_meta(&db.connection, "max_db_size")?,
+    };
+
+    if opt.compress_opt.level.is_some() && opt.compress_opt.codec.codec() != "zstd" {
+        return Err(format_err!("--level only applies to --codec zstd"));
+    }
+    let stored_level = get_meta(&db.connection, "zstd_level")?;
+    opt.compress_opt.level = match (opt.compress_opt.level, stored_level) {
+        (Some(level), _) => {
+            set_meta(&db.connection, "zstd_level", &level)?;
+            Some(level)
+        }
+        (None, level) => level,
+    };
+
+    if opt.compress_opt.use_dict {
+        let level = opt.compress_opt.level.unwrap_or(0);
+        opt.compress_opt.codec = match (opt.compress_opt.codec, dict::current(&db.connection)?) {
+            (Compression::Zstd(_), Some(id)) => Compression::ZstdDict(level, id),
+            (Compression::Zstd(_), None) => {
+                return Err(format_err!(
+                    "--use-dict needs a dictionary, which TrainDict trains"
+                ))
+            }
+            _ => return Err(format_err!("--use-dict only applies to --codec zstd")),
+        };
+    }
+
+    let mut window = opt.dedup_window.map(DedupWindow::new).unwrap_or_default();
+
+    let mut walk = Walk {
+        opt: &opt,
+        verbosity,
+        failures: AddFailures {
+            strict: opt.strict || opt.atomic,
+            failed: Vec::new(),
+        },
+        excludes: load_excludes(&fs::canonicalize(cwd)?, &opt.exclude_from)?,
+        transformed: HashMap::new(),
+    };
+
+    let mut resolved = Vec::new();
+    for file in files {
+        resolved.extend(resolve_files(file, None, &mut walk)?);
+    }
+    order_files(&mut resolved, cwd, &opt.order);
+
+    let metrics = Metrics::default();
+    let mut stored = 0;
+    let mut bytes = 0;
+    let mut added = Vec::new();
+    let mut over_quota = Vec::new();
+    let mut out_of_time = Vec::new();
+    if opt.atomic {
+        let (count, size, files) = add_files_atomic(
+            db,
+            resolved,
+            &mut walk,
+            cwd,
+            encoding,
+            budget,
+            &metrics,
+            &mut window,
+        )?;
+        stored = count;
+        bytes = size;
+        added = files;
+    } else {
+        let mut resolved = resolved.into_iter();
+        while let Some(f) = resolved.next() {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                break;
+            }
+            if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                out_of_time.push(f);
+                out_of_time.extend(resolved);
+                break;
+            }
+            walk.check_transformed(cwd, &f)?;
+            match add_committed_file(db, cwd, &f, &opt, encoding, budget, &metrics, &mut window) {
+                Ok(size) => {
+                    stored += 1;
+                    if let Some(size) = size {
+                        bytes += size;
+                        added.push(f);
+                    }
+                }
+                Err(e) => match e.downcast::<ChsqlarError>() {
+                    Ok(ChsqlarError::ArchiveFull { .. }) => {
+                        over_quota.push(f);
+                        over_quota.extend(resolved);
+                        break;
+                    }
+                    Ok(e) => return Err(e.into()),
+                    Err(e) => walk.failures.record(&f, e)?,
+                },
+            }
+        }
+    }
+
+    let interrupted = INTERRUPTED.load(Ordering::SeqCst);
+
+    // Keep the journal if anything failed or was cut short, so a re-run only redoes what's
+    // missing.
+    let cut_short = interrupted || !over_quota.is_empty() || !out_of_time.is_empty();
+    if walk.failures.failed.is_empty() && !cut_short {
+        db.connection
+            .execute("DELETE FROM add_journal", NO_PARAMS)?;
+    }
+
+    if opt.deterministic {
+        db.connection.execute_batch(
+            "DELETE FROM meta WHERE key='created_at'; PRAGMA journal_mode=DELETE; VACUUM;",
+        )?;
+    }
+
+    if opt.verify_after_commit {
+        verify_added(db, cwd, &added, &opt, encoding)?;
+    }
+
+    if verbosity > 0 {
+        budget.report();
+    }
+
+    Ok(AddSummary {
+        stored,
+        bytes,
+        failed: walk.failures.failed,
+        interrupted,
+        over_quota,
+        out_of_time,
+        metrics,
+    })
+}
+
+/// How much of an add cut short by --time-budget is left, with a guess at how long it'll take
+/// from the rate this run read files at.
+fn remaining_work(summary: &AddSummary) -> String {
+    let left: u64 = summary
+        .out_of_time
+        .iter()
+        .map(|f| fs::metadata(f).map_or(0, |meta| meta.len()))
+        .sum();
+    let elapsed = summary.metrics.start.elapsed().as_secs_f64();
+
+    let mut remaining = format!(
+        "{} files, {:.2} MB left",
+        summary.out_of_time.len(),
+        left as f64 / 1e6
+    );
+    if summary.bytes > 0 {
+        let estimate = left as f64 * elapsed / summary.bytes as f64;
+        remaining += &format!(", about {:.0}s more at this rate", estimate);
+    }
+    remaining += "; run the same add again to continue";
+
+    remaining
+}
+
+/// Sorts resolved files into the order `--order` asks for.
+fn order_files(files: &mut [PathBuf], cwd: &Path, order: &str) {
+    match order {
+        "name" => files.sort_by_cached_key(|f| normalise_path(cwd, f)),
+        "size" => files.sort_by_cached_key(|f| {
+            std::cmp::Reverse(fs::metadata(f).map_or(0, |meta| meta.len()))
+        }),
+        // Each RandomState hashes with new random keys, which is as good as a shuffle.
+        "random" => {
+            use std::hash::BuildHasher;
+            let state = std::collections::hash_map::RandomState::new();
+            files.sort_by_cached_key(|f| state.hash_one(f));
+        }
+        _ => {}
+    }
+}
+
+/// Adds all of `files` in one transaction, committing only if every one was stored.
+/// Returns how many were, their total size and the ones not skipped as already stored,
+/// which is none of them if it was interrupted.
+#[allow(clippy::too_many_arguments)]
+fn add_files_atomic(
+    db: &mut SqliteDatabase,
+    files: Vec<PathBuf>,
+    walk: &mut Walk,
+    cwd: &Path,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<(usize, u64, Vec<PathBuf>), Error> {
+    let mut trans = db.connection.transaction()?;
+    window.begin();
+
+    let mut stored = 0;
+    let mut bytes = 0;
+    let mut added = Vec::new();
+    for f in files {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Ok((0, 0, Vec::new()));
+        }
+        walk.check_transformed(cwd, &f)?;
+        let opt = walk.opt;
+        let size = add_journaled_file(&mut trans, cwd, &f, opt, encoding, budget, metrics, window)?;
+        stored += 1;
+        if let Some(size) = size {
+            bytes += size;
+            added.push(f);
+        }
+    }
+
+    trans.commit()?;
+    window.commit();
+
+    Ok((stored, bytes, added))
+}
+
+/// Adds `f` in a transaction of its own, so an interrupted add keeps what it finished.
+/// Returns how many bytes it read, like `add_journaled_file`.
+#[allow(clippy::too_many_arguments)]
+fn add_committed_file(
+    db: &mut SqliteDatabase,
+    cwd: &Path,
+    f: &Path,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<Option<u64>, Error> {
+    // Returning early drops the transaction, rolling back this file's chunks.
+    let mut trans = db.connection.transaction()?;
+    window.begin();
+
+    let size = add_journaled_file(&mut trans, cwd, f, opt, encoding, budget, metrics, window)?;
+
+    trans.commit()?;
+    window.commit();
+
+    Ok(size)
+}
+
+/// Adds `f` unless the journal shows an earlier run already did. Returns its size, or None
+/// if it was skipped.
+#[allow(clippy::too_many_arguments)]
+fn add_journaled_file(
+    trans: &mut Transaction,
+    cwd: &Path,
+    f: &Path,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    budget: &MemoryBudget,
+    metrics: &Metrics,
+    window: &mut DedupWindow,
+) -> Result<Option<u64>, Error> {
+    let normalised = stored_name(cwd, f, opt)?;
+    let name = encode_name(&normalised, encoding)?;
+
+    let (size, mtime) = journal_key(f)?;
+    if is_journaled(trans, &name, size, mtime)? {
+        return Ok(None);
+    }
+    if opt.skip_unchanged && is_unchanged(trans, &name, &normalised, size, mtime)? {
+        return Ok(None);
+    }
+
+    add_file(
+        trans,
+        f.to_path_buf(),
+        normalised,
+        opt,
+        encoding,
+        budget,
+        metrics,
+        window,
+    )?;
+
+    trans.execute(
+        "INSERT OR REPLACE INTO add_journal VALUES (?,?,?)",
+        &[&name as &dyn ToSql, &size, &mtime],
+    )?;
+
+    if let Some(limit) = opt.max_db_size {
+        if database_size(trans)? > limit {
+            return Err(ChsqlarError::ArchiveFull {
+                path: f.to_path_buf(),
+                limit,
+            }
+            .into());
+        }
+    }
+
+    Ok(Some(size as u64))
+}
+
+/// Reads each of `added` back out of the archive through the path Extract takes, and
+/// compares it with the file still on disk. Reports every one that differs, then fails.
+fn verify_added(
+    db: &mut SqliteDatabase,
+    cwd: &Path,
+    added: &[PathBuf],
+    opt: &AddOpt,
+    encoding: NameEncoding,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+    // Nothing is cached, so every chunk is read and decoded again.
+    let cache = ChunkCache::new(0);
+
+    let mut mismatched = 0;
+    for f in added {
+        let problem = match read_back(&mut trans, cwd, f, opt, encoding, &cache) {
+            Ok(None) => continue,
+            Ok(Some(problem)) => problem,
+            Err(e) => format!("can't be read back: {}", e),
+        };
+        eprintln!(
+            "{}: {}",
+            f.display(),
+            paint(Stream::Stderr, Style::Bad, problem)
+        );
+        mismatched += 1;
+    }
+
+    if mismatched > 0 {
+        return Err(format_err!(
+            "{} of {} files added read back differently from the files on disk",
+            mismatched,
+            added.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// What's wrong with the stored copy of the added file `f`, if anything.
+fn read_back(
+    trans: &mut Transaction,
+    cwd: &Path,
+    f: &Path,
+    opt: &AddOpt,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<Option<String>, Error> {
+    let name = stored_name(cwd, f, opt)?;
+    let stored = get_file(trans, name.clone(), encoding)?;
+
+    let mut original = Vec::new();
+    fs::File::open(f)?.read_to_end(&mut original)?;
+    if let Some(filter) = &opt.filter {
+        original = filter_data(filter, &name, original)?;
+    }
+
+    if stored.size != original.len() as i64 {
+        return Ok(Some(format!(
+            "stored with {} bytes, but {} on disk",
+            stored.size,
+            original.len()
+        )));
+    }
+
+    let mut compare = Compare {
+        reader: &original[..],
+        matches: true,
+    };
+    write_file_to(trans, &stored, cache, &mut compare, write_zeros)?;
+
+    Ok(match compare.matches {
+        true => None,
+        false => Some("content differs from the file on disk".to_owned()),
+    })
+}
+
+/// The name `f` is stored under, after any --transform.
+fn stored_name(cwd: &Path, f: &Path, opt: &AddOpt) -> Result<PathBuf, Error> {
+    let normalised = normalise_path(cwd, f);
+    if opt.transforms.is_empty() {
+        return Ok(normalised);
+    }
+
+    apply_transforms(&opt.transforms, &normalised)
+}
+
+/// The size of the database including uncommitted changes, plus its WAL file.
+fn database_size(connection: &Connection) -> Result<u64, Error> {
+    let pages: i64 = connection.query_row("PRAGMA page_count", NO_PARAMS, |row| row.get(0))?;
+    let page_size: i64 = connection.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+
+    let mut stmt = connection.prepare("PRAGMA database_list")?;
+    let mut wal = 0;
+    for row in stmt.query_map(NO_PARAMS, |row| {
+        (row.get::<_, String>(1), row.get::<_, String>(2))
+    })? {
+        let (name, file) = row?;
+        if name == "main" && !file.is_empty() {
+            wal = fs::metadata(format!("{}-wal", file)).map_or(0, |m| m.len());
+        }
+    }
+
+    Ok((pages * page_size) as u64 + wal)
+}
+
+/// The size and mtime of a source file, used to tell whether an interrupted add already
+/// stored its current content.
+fn journal_key(fpath: &Path) -> Result<(i64, i64), Error> {
+    let metadata = fs::metadata(fpath)?;
+    let mtime = system_time_nanos(metadata.modified()?);
+
+    Ok((metadata.len() as i64, mtime))
+}
+
+/// Whether the archive already holds `name` with this size and mtime, so --skip-unchanged
+/// can leave it alone. Files stored through --filter only have the mtime to go by, as the
+/// stored size is of the filter's output.
+fn is_unchanged(
+    trans: &Transaction,
+    name: &Value,
+    path: &Path,
+    size: i64,
+    mtime: i64,
+) -> Result<bool, Error> {
+    let stored = trans.query_row(
+        &format!("SELECT {} FROM files WHERE name=?", FILE_COLUMNS),
+        &[name],
+        |row| file_from_row(path.to_owned(), row, 0),
+    );
+
+    match stored {
+        Ok(stored) => {
+            let stored = stored?;
+            Ok((stored.size == size || stored.filter.is_some())
+                && stored.mtime.is_some_and(|stored| same_mtime(stored, mtime)))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_journaled(trans: &Transaction, name: &Value, size: i64, mtime: i64) -> Result<bool, Error> {
+    let journaled = trans.query_row(
+        "SELECT mtime FROM add_journal WHERE name=? AND size=?",
+        &[name as &dyn ToSql, &size],
+        |row| row.get(0),
+    );
+
+    match journaled {
+        Ok(journaled) => Ok(same_mtime(journaled, mtime)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn create_file_safe(fname: &Path) -> Result<fs::File, Error> {
+    if let Some(parent) = fname.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(fname)?;
+    Ok(f)
+}
+
+/// Checks whether a previous extract already wrote `stored` to `fname` in full.
+fn is_extracted(
+    trans: &mut Transaction,
+    stored: &File,
+    fname: &Path,
+    verify: bool,
+    cache: &ChunkCache,
+) -> Result<bool, Error> {
+    if fs::metadata(fname)?.len() != stored.size as u64 {
+        return Ok(false);
+    }
+
+    if !verify {
+        return Ok(true);
+    }
+
+    let mut on_disk = Compare {
+        reader: fs::File::open(fname)?,
+        matches: true,
+    };
+    write_file_to(trans, stored, cache, &mut on_disk, write_zeros)?;
+
+    Ok(on_disk.matches)
+}
+
+/// A writer that checks what's written against the content of `reader`.
+struct Compare<R> {
+    reader: R,
+    matches: bool,
+}
+
+impl<R: Read> Write for Compare<R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.matches {
+            let mut theirs = vec![0; buf.len()];
+            self.matches = self.reader.read_exact(&mut theirs).is_ok() && theirs == buf;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where `file`, selected by the argument `ex_to`, is extracted to after --strip-components
+/// and --transform, or None if it's skipped.
+fn extract_name(
+    file: &Path,
+    ex_to: &Path,
+    opt: &ExtractOpt,
+    summary: &mut ExtractSummary,
+) -> Result<Option<PathBuf>, Error> {
+    // Like tar, --strip-components counts from the stored name, whatever was selected.
+    // Without it, files are written relative to the parent of the selection, and the
+    // archive root has none.
+    let stripped;
+    let common = if opt.strip_components > 0 {
+        match strip_components(file, opt.strip_components) {
+            Ok(path) => {
+                stripped = path;
+                stripped.as_path()
+            }
+            Err(reason) => {
+                eprintln!("skipping {}: {}", file.display(), reason);
+                return Ok(None);
+            }
+        }
+    } else {
+        match ex_to.parent() {
+            Some(par) => file.strip_prefix(par).unwrap(),
+            None => file,
+        }
+    };
+
+    // A tar stream can hold names that this system can't.
+    if cfg!(windows) && !opt.tar {
+        if let Err(reason) = check_windows_name(common) {
+            eprintln!("skipping {}: {}", common.display(), reason);
+            return Ok(None);
+        }
+    }
+
+    let renamed;
+    let common = if opt.transforms.is_empty() {
+        common
+    } else {
+        renamed = apply_transforms(&opt.transforms, common)?;
+        if renamed.as_os_str().is_empty() || !stays_inside(&renamed) {
+            eprintln!(
+                "skipping {}: --transform renamed it to '{}'",
+                common.display(),
+                renamed.display()
+            );
+            return Ok(None);
+        }
+        if let Some(other) = summary.transformed.get(&renamed) {
+            return Err(format_err!(
+                "{} and {} would both be extracted to {}",
+                other.display(),
+                file.display(),
+                renamed.display()
+            ));
+        }
+        summary
+            .transformed
+            .insert(renamed.clone(), file.to_path_buf());
+        renamed.as_path()
+    };
+
+    // Add only stores plain relative names, but an archive written some other way needn't.
+    if !stays_inside(common) {
+        eprintln!(
+            "skipping {}: it would be extracted outside the current directory",
+            file.display()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(common.to_path_buf()))
+}
+
+/// How extracting `stored` to `target` would go: "missing" if nothing is there yet,
+/// "identical" if the same content already is, and "differs" if it would be overwritten.
+fn conflict(
+    trans: &mut Transaction,
+    stored: &File,
+    target: &Path,
+    cache: &ChunkCache,
+) -> Result<&'static str, Error> {
+    Ok(if !target.exists() {
+        "missing"
+    } else if is_extracted(trans, stored, target, true, cache)? {
+        "identical"
+    } else {
+        "differs"
+    })
+}
+
+fn extract_file(
+    trans: &mut Transaction,
+    file: PathBuf,
+    ex_to: PathBuf,
+    opt: &ExtractOpt,
+    summary: &mut ExtractSummary,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    let common = match extract_name(&file, &ex_to, opt, summary)? {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+
+    let target = long_path(&common)?;
+    let common = target.as_path();
+
+    if opt.list_conflicts {
+        let stored = get_file(trans, file, encoding)?;
+        let state = conflict(trans, &stored, common, cache)?;
+        let style = match state {
+            "missing" => Style::Warning,
+            "identical" => Style::Good,
+            _ => Style::Bad,
+        };
+        println!(
+            "{}\t{}",
+            paint(Stream::Stdout, style, state),
+            common.display()
+        );
+
+        return Ok(());
+    }
+
+    if (opt.resume || opt.resume_verify) && common.exists() {
+        let stored = get_file(trans, file.clone(), encoding)?;
+
+        if is_extracted(trans, &stored, common, opt.resume_verify, cache)? {
+            summary.skipped += 1;
+        } else {
+            unfilter_command(&stored, opt)?;
+            let mut f = fs::File::create(common)?;
+            fill_file(trans, &stored, &mut f, common, opt, summary, cache)?;
+            restore_mtime(&f, &stored)?;
+            restore_owner(&f, &stored, opt, summary);
+            summary.rewritten += 1;
+        }
+
+        return Ok(());
+    }
+
+    let stored = get_file(trans, file, encoding)?;
+    // Checked first so a missing --unfilter doesn't leave an empty file behind.
+    unfilter_command(&stored, opt)?;
+    let mut f = create_file_safe(common)?;
+    fill_file(trans, &stored, &mut f, common, opt, summary, cache)?;
+    restore_mtime(&f, &stored)?;
+    restore_owner(&f, &stored, opt, summary);
+    summary.extracted += 1;
+
+    Ok(())
+}
+
+/// Drops the first `n` components of `path`, like tar's `--strip-components`. What's left
+/// must stay inside the directory it's extracted to.
+fn strip_components(path: &Path, n: usize) -> Result<PathBuf, String> {
+    let mut components = path.components();
+    if components.by_ref().take(n).count() < n || components.clone().next().is_none() {
+        return Err(format!("fewer than {} path components", n + 1));
+    }
+
+    let path: PathBuf = components.collect();
+    if !stays_inside(&path) {
+        return Err("would be written outside the output directory".to_owned());
+    }
+
+    Ok(path)
+}
+
+/// Whether `path` names something inside the directory it's relative to.
+fn stays_inside(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Writes the content of `stored` into `f`, cloning it from an earlier identical file if
+/// `--reflink` allows. Holes are seeked over, leaving them unallocated.
+fn fill_file(
+    trans: &mut Transaction,
+    stored: &File,
+    f: &mut fs::File,
+    target: &Path,
+    opt: &ExtractOpt,
+    summary: &mut ExtractSummary,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    let key = format!(
+        "{}|{}|{}",
+        stored.chunks.join(";"),
+        join_holes(&stored.holes).unwrap_or_default(),
+        stored
+            .inline
+            .as_ref()
+            .map_or_else(String::new, |(_, data)| crypt::to_hex(&hash_chunk(data)))
+    );
+
+    Metrics::add(&summary.metrics.files, 1);
+
+    if opt.reflink != "never" {
+        if let Some(source) = summary.written.get(&key) {
+            match reflink(source, f) {
+                Ok(()) => {
+                    summary.reflinked += 1;
+                    return Ok(());
+                }
+                Err(e) if opt.reflink == "always" => return Err(e),
+                Err(_) => {}
+            }
+        }
+    }
+
+    if let Some(unfilter) = unfilter_command(stored, opt)? {
+        unfilter_to(trans, stored, cache, unfilter, f)?;
+        Metrics::add(&summary.metrics.bytes_written, f.metadata()?.len());
+        summary
+            .written
+            .entry(key)
+            .or_insert_with(|| target.to_path_buf());
+        return Ok(());
+    }
+
+    // Everything but the writes is reading and decompressing chunks.
+    let metrics = &summary.metrics;
+    let writing = metrics.write_nanos.load(Ordering::Relaxed);
+    let start = std::time::Instant::now();
+    let mut timed = TimedWriter {
+        inner: &mut *f,
+        nanos: &metrics.write_nanos,
+    };
+    write_file_to(trans, stored, cache, &mut timed, |f, len| {
+        f.inner.seek(SeekFrom::Current(len as i64))?;
+        Ok(())
+    })?;
+    let written = metrics.write_nanos.load(Ordering::Relaxed) - writing;
+    Metrics::add(
+        &metrics.read_nanos,
+        start.elapsed().as_nanos() as u64 - written,
+    );
+    Metrics::add(&metrics.bytes_written, stored.size as u64);
+    f.set_len(stored.size as u64)?;
+    summary
+        .written
+        .entry(key)
+        .or_insert_with(|| target.to_path_buf());
+
+    Ok(())
+}
+
+/// The --unfilter command to write `stored` through, if it was added with --filter.
+fn unfilter_command<'a>(stored: &File, opt: &'a ExtractOpt) -> Result<Option<&'a str>, Error> {
+    match (&stored.filter, &opt.unfilter) {
+        (None, _) => Ok(None),
+        (Some(_), Some(unfilter)) => Ok(Some(unfilter)),
+        (Some(filter), None) => Err(format_err!(
+            "{} was added with --filter '{}'; give --unfilter to extract it",
+            stored.name.display(),
+            filter
+        )),
+    }
+}
+
+/// Refuses `stored` if it was added with --filter, for `reader`s that have no --unfilter
+/// to undo it with and would hand out the filtered content.
+fn refuse_filtered(stored: &File, reader: &str) -> Result<(), Error> {
+    match &stored.filter {
+        Some(filter) => Err(format_err!(
+            "{} was added with --filter '{}', which {} can't undo; use Extract --unfilter",
+            stored.name.display(),
+            filter,
+            reader
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Writes the content of `stored` through the --unfilter `command` into `f`.
+fn unfilter_to(
+    trans: &mut Transaction,
+    stored: &File,
+    cache: &ChunkCache,
+    command: &str,
+    f: &fs::File,
+) -> Result<(), Error> {
+    let mut child = shell_command(command)
+        .env("CHSQLAR_FILE", &stored.name)
+        .stdin(std::process::Stdio::piped())
+        .stdout(f.try_clone()?)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let fed = write_file_to(trans, stored, cache, &mut stdin, write_zeros);
+    drop(stdin);
+
+    // A command that fails early makes the writes fail too, but its status says why.
+    check_filter(command, &stored.name, child.wait()?)?;
+    fed
+}
+
+/// Gives `f` the stored owner as --owner says, if there is one. Changing it usually needs
+/// root, so failing to is only counted, for `extract_files_cmd` to warn about once.
+#[cfg(unix)]
+fn restore_owner(f: &fs::File, stored: &File, opt: &ExtractOpt, summary: &mut ExtractSummary) {
+    let (mut uid, mut gid) = match stored.owner {
+        Some(owner) if opt.owner != "skip" => owner,
+        _ => return,
+    };
+    if opt.owner == "names" {
+        // A name this system doesn't know leaves the stored id in its place.
+        let mut unknown = None;
+        if let Some(user) = &stored.owner_user {
+            match user_id(user) {
+                Some(id) => uid = id,
+                None => unknown = Some(format!("user {}", user)),
+            }
+        }
+        if let Some(group) = &stored.owner_group {
+            match group_id(group) {
+                Some(id) => gid = id,
+                None => unknown = unknown.or_else(|| Some(format!("group {}", group))),
+            }
+        }
+        if let Some(name) = unknown {
+            summary.unknown_owners.get_or_insert((0, name)).0 += 1;
+        }
+    }
+
+    if let Err(e) = std::os::unix::fs::fchown(f, Some(uid), Some(gid)) {
+        summary
+            .owner_errors
+            .get_or_insert_with(|| (0, e.to_string()))
+            .0 += 1;
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_owner(_f: &fs::File, _stored: &File, _opt: &ExtractOpt, _summary: &mut ExtractSummary) {}
+
+/// Sets the stored modification time on `f`, to the nanosecond where the platform allows.
+fn restore_mtime(f: &fs::File, stored: &File) -> Result<(), Error> {
+    if let Some(mtime) = stored.mtime {
+        f.set_modified(nanos_system_time(mtime))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &fs::File) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = fs::File::open(source)?;
+
+    // Safe as both descriptors are open for the duration of the call.
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _dest: &fs::File) -> Result<(), Error> {
+    Err(format_err!("reflinks are not supported on this platform"))
+}
+
+/// Names Windows refuses to create, regardless of extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Explains why `name` can't be created on Windows, if it can't.
+fn check_windows_name(name: &Path) -> Result<(), String> {
+    for component in name.components() {
+        let part = match component {
+            Component::Normal(part) => part.to_string_lossy(),
+            _ => continue,
+        };
+
+        if let Some(c) = part
+            .chars()
+            .find(|&c| "<>:\"|?*".contains(c) || (c as u32) < 32)
+        {
+            return Err(format!(
+                "'{}' contains {:?}, which Windows doesn't allow",
+                part, c
+            ));
+        }
+
+        if part.ends_with('.') || part.ends_with(' ') {
+            return Err(format!("'{}' ends with a dot or space", part));
+        }
+
+        let stem = part.split('.').next().unwrap().to_uppercase();
+        if WINDOWS_RESERVED_NAMES.contains(&stem.as_str()) {
+            return Err(format!("'{}' is a reserved device name on Windows", part));
+        }
+    }
+
+    Ok(())
+}
+
+/// On Windows, turns paths longer than MAX_PATH into `\\?\` extended-length paths, which
+/// aren't subject to the limit.
+fn long_path(p: &Path) -> Result<PathBuf, Error> {
+    const MAX_PATH: usize = 260;
+
+    if !cfg!(windows) {
+        return Ok(p.to_path_buf());
+    }
+
+    let absolute: PathBuf = current_dir()?.join(p).components().collect();
+    if absolute.as_os_str().len() < MAX_PATH {
+        return Ok(p.to_path_buf());
+    }
+
+    match absolute.components().next() {
+        Some(Component::Prefix(prefix)) if prefix.kind().is_verbatim() => Ok(absolute),
+        _ => {
+            let mut extended = std::ffi::OsString::from(r"\\?\");
+            extended.push(absolute.as_os_str());
+            Ok(PathBuf::from(extended))
+        }
+    }
+}
+
+/// The stored files at or below `prefix`, sorted by name.
+fn files_under(
+    trans: &mut Transaction,
+    prefix: &Path,
+    encoding: NameEncoding,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files: Vec<_> = list_files(trans, encoding)?
+        .into_iter()
+        .filter(|x| x.starts_with(prefix))
+        .collect();
+
+    if files.is_empty() {
+        return Err(ChsqlarError::FileNotFound {
+            path: prefix.to_path_buf(),
+        }
+        .into());
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn extract_path(
+    trans: &mut Transaction,
+    file: PathBuf,
+    opt: &ExtractOpt,
+    summary: &mut ExtractSummary,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    for f in files_under(trans, &file, encoding)? {
+        extract_file(trans, f, file.clone(), opt, summary, encoding, cache)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the files at or below each of `files` to `out` as a tar stream, named as they
+/// would be extracted. Like Cat, only one chunk is held in memory at a time.
+fn extract_tar<W: Write>(
+    trans: &mut Transaction,
+    files: Vec<PathBuf>,
+    opt: &ExtractOpt,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+    out: &mut W,
+) -> Result<ExtractSummary, Error> {
+    let mut summary = ExtractSummary::default();
+    let start = std::time::Instant::now();
+    let write_nanos = AtomicU64::new(0);
+
+    let mut tar = tar::Builder::new(TimedWriter {
+        inner: out,
+        nanos: &write_nanos,
+    });
+
+    for arg in files {
+        for file in files_under(trans, &arg, encoding)? {
+            let name = match extract_name(&file, &arg, opt, &mut summary)? {
+                Some(name) => name,
+                None => continue,
+            };
+            let stored = get_file(trans, file, encoding)?;
+            refuse_filtered(&stored, "--tar")?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(stored.size as u64);
+            header.set_mode(0o644);
+            if let Some(mtime) = stored.mtime {
+                header.set_mtime(mtime.div_euclid(NANOS_PER_SEC).max(0) as u64);
+            }
+            if let Some((uid, gid)) = stored.owner {
+                header.set_uid(uid.into());
+                header.set_gid(gid.into());
+            }
+            if let Some(user) = &stored.owner_user {
+                header.set_username(user)?;
+            }
+            if let Some(group) = &stored.owner_group {
+                header.set_groupname(group)?;
+            }
+
+            tar.append_data(&mut header, &name, StoredReader::new(trans, &stored, cache))?;
+            Metrics::add(&summary.metrics.files, 1);
+            Metrics::add(&summary.metrics.bytes_written, stored.size as u64);
+            summary.extracted += 1;
+        }
+    }
+    tar.into_inner()?.flush()?;
+
+    // Everything but the writes is reading and decompressing chunks.
+    let written = write_nanos.into_inner();
+    Metrics::add(&summary.metrics.write_nanos, written);
+    Metrics::add(
+        &summary.metrics.read_nanos,
+        start.elapsed().as_nanos() as u64 - written,
+    );
+
+    Ok(summary)
+}
+
+fn extract_files_cmd(
+    db: &mut SqliteDatabase,
+    files: Vec<PathBuf>,
+    opt: ExtractOpt,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<ExtractSummary, Error> {
+    let mut trans = db.connection.transaction()?;
+    if opt.tar {
+        let stdout = std::io::stdout();
+        return extract_tar(&mut trans, files, &opt, encoding, cache, &mut stdout.lock());
+    }
+
+    let mut summary = ExtractSummary::default();
+
+    for file in files {
+        extract_path(&mut trans, file, &opt, &mut summary, encoding, cache)?;
+    }
+
+    if opt.resume || opt.resume_verify {
+        println!(
+            "{} extracted, {} already correct, {} rewritten",
+            summary.extracted, summary.skipped, summary.rewritten
+        );
+    }
+
+    if opt.reflink != "never" {
+        println!("{} files cloned with reflinks", summary.reflinked);
+    }
+
+    if let Some((count, name)) = &summary.unknown_owners {
+        let warning = format!(
+            "{} files are owned by names this system doesn't have, like {}; they were given \
+             their stored ids instead",
+            count, name
+        );
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, warning));
+    }
+    if let Some((count, error)) = &summary.owner_errors {
+        let warning = format!(
+            "Couldn't restore the owner of {} files ({}); they belong to the extracting user",
+            count, error
+        );
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, warning));
+    }
+
+    Ok(summary)
+}
+
+/// Writes the contents of `files` to `out` one after another, in the order given.
+fn cat_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    files: Vec<PathBuf>,
+    expand_dirs: bool,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    for file in files {
+        let stored = match get_file(&mut trans, file.clone(), encoding) {
+            Ok(stored) => stored,
+            Err(e) => match e.downcast::<ChsqlarError>() {
+                Ok(ChsqlarError::FileNotFound { .. }) if expand_dirs => {
+                    for f in files_under(&mut trans, &file, encoding)? {
+                        extract_file_to(&mut trans, f, encoding, cache, out)?;
+                    }
+                    continue;
+                }
+                Ok(e) => return Err(e.into()),
+                Err(e) => return Err(e),
+            },
+        };
+
+        write_file_to(&mut trans, &stored, cache, out, write_zeros)?;
+    }
+
+    Ok(())
+}
+
+/// Compares two stored files, printing where they differ like cmp. Files stored with the
+/// same chunks are the same without reading anything, and without --bytes so are files
+/// with the same content hash.
+fn cmp_cmd(
+    db: &mut SqliteDatabase,
+    first: &Path,
+    second: &Path,
+    bytes: bool,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<bool, Error> {
+    let mut trans = db.connection.transaction()?;
+    let a = get_file(&mut trans, first.to_path_buf(), encoding)?;
+    let b = get_file(&mut trans, second.to_path_buf(), encoding)?;
+
+    // Encrypted inline data is sealed with a random nonce, so only compares equal opened.
+    let same_inline = match (&a.inline, &b.inline) {
+        (Some((a_codec, a_data)), Some((b_codec, b_data))) => {
+            open_inline(&trans, a_codec, a_data)? == open_inline(&trans, b_codec, b_data)?
+        }
+        (a_inline, b_inline) => a_inline == b_inline,
+    };
+    if a.size == b.size && a.chunks == b.chunks && a.holes == b.holes && same_inline {
+        return Ok(true);
+    }
+
+    let differ = paint(Stream::Stdout, Style::Bad, "differ");
+
+    // The same content can still be stored in other chunks, cut with other parameters or
+    // inline, so only the content can say the files differ. Without --bytes, the hashes
+    // of it will do where both files have one, which older versions didn't store.
+    let same_hash = match (&a.content_hash, &b.content_hash, &a.sha256, &b.sha256) {
+        _ if a.size != b.size => Some(false),
+        (Some(a_hash), Some(b_hash), _, _) => Some(a_hash == b_hash),
+        (_, _, Some(a_hash), Some(b_hash)) => Some(a_hash == b_hash),
+        _ => None,
+    };
+    match same_hash {
+        Some(same) if !bytes => {
+            if !same {
+                println!("{} {} {}", first.display(), second.display(), differ);
+            }
+            return Ok(same);
+        }
+        _ => {}
+    }
+
+    let mut a_reader = std::io::BufReader::new(StoredReader::new(&trans, &a, cache));
+    let mut b_reader = std::io::BufReader::new(StoredReader::new(&trans, &b, cache));
+    let mut offset = 0;
+    loop {
+        let (a_buf, b_buf) = (a_reader.fill_buf()?, b_reader.fill_buf()?);
+        let n = std::cmp::min(a_buf.len(), b_buf.len());
+        if n == 0 {
+            if a_buf.len() == b_buf.len() {
+                return Ok(true);
+            }
+            let shorter = if a_buf.is_empty() { first } else { second };
+            eprintln!("cmp: EOF on {} after byte {}", shorter.display(), offset);
+            return Ok(false);
+        }
+
+        if let Some(i) = (0..n).find(|&i| a_buf[i] != b_buf[i]) {
+            match bytes {
+                true => println!(
+                    "{} {} {}: byte {}",
+                    first.display(),
+                    second.display(),
+                    differ,
+                    offset + i as u64 + 1
+                ),
+                false => println!("{} {} {}", first.display(), second.display(), differ),
+            }
+            return Ok(false);
+        }
+
+        a_reader.consume(n);
+        b_reader.consume(n);
+        offset += n as u64;
+    }
+}
+
+/// A digest of file content, fed by writing the content to it.
+enum Checksum {
+    Sha256(Sha256),
+    Sha3(Sha3_512),
+    /// BLAKE3 read out to this many bytes.
+    Blake3(Box<blake3::Hasher>, usize),
+}
+
+impl Checksum {
+    fn new(algo: &str) -> Result<Self, Error> {
+        match algo {
+            "sha256" => Ok(Checksum::Sha256(Sha256::new())),
+            "sha3-512" => Ok(Checksum::Sha3(Sha3_512::new())),
+            "blake3" => Ok(Checksum::Blake3(Box::new(blake3::Hasher::new()), 32)),
+            _ => Err(format_err!("unknown checksum algorithm '{}'", algo)),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Sha256(hasher) => hasher.update(data),
+            Checksum::Sha3(hasher) => hasher.update(data),
+            Checksum::Blake3(hasher, _) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn hex(self) -> String {
+        match self {
+            Checksum::Sha256(hasher) => crypt::to_hex(&hasher.finalize()),
+            Checksum::Sha3(hasher) => crypt::to_hex(&hasher.finalize()),
+            Checksum::Blake3(hasher, bytes) => {
+                let mut digest = vec![0; bytes];
+                hasher.finalize_xof().fill(&mut digest);
+                crypt::to_hex(&digest)
+            }
+        }
+    }
+}
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+const MANIFEST_COLUMNS: &[&str] = &["name", "size", "chunks", "sha256", "mtime"];
+
+/// Writes the manifest described in the help for `Manifest` to `out`, a file at a time.
+fn manifest_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    format: &str,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+    out: &mut W,
+) -> Result<(), Error> {
+    let trans = db.connection.transaction()?;
+
+    let separator = if format == "tsv" { b'\t' } else { b',' };
+    if format == "csv" || format == "tsv" {
+        let header = MANIFEST_COLUMNS.join(&(separator as char).to_string());
+        writeln!(out, "{}", header)?;
+    }
+
+    let mut stmt = trans.prepare(&format!(
+        "SELECT name, {} FROM files ORDER BY name",
+        FILE_COLUMNS
+    ))?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        decode_name(row.get(0), encoding).and_then(|name| file_from_row(name, row, 1))
+    })?;
+    let mut files: Box<dyn Iterator<Item = Result<File, Error>>> = Box::new(rows.map(|file| file?));
+
+    // Sealed names come out in the order of their ciphertext, so they're sorted here.
+    if encoding == NameEncoding::Sealed {
+        let mut sorted = files.collect::<Result<Vec<_>, Error>>()?;
+        sorted.sort_by(|a, b| a.name.as_os_str().cmp(b.name.as_os_str()));
+        files = Box::new(sorted.into_iter().map(Ok));
+    }
+
+    for file in files {
+        let file = file?;
+
+        let sha256 = match &file.sha256 {
+            Some(sha256) => sha256.clone(),
+            None => {
+                let mut sha256 = Checksum::new("sha256")?;
+                std::io::copy(&mut StoredReader::new(&trans, &file, cache), &mut sha256)?;
+                sha256.hex()
+            }
+        };
+
+        let mtime = file.mtime.map(|mtime| {
+            let time = chrono::DateTime::from_timestamp_nanos(mtime);
+            time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+        });
+
+        if format == "sha256sum" {
+            write!(out, "{}  ", sha256)?;
+            out.write_all(&path_to_bytes(&file.name)?)?;
+            out.write_all(b"\n")?;
+            continue;
+        }
+
+        if format == "json-lines" {
+            let line = serde_json::json!({
+                "name": file.name.to_string_lossy(),
+                "size": file.size,
+                "chunks": file.chunks.len(),
+                "sha256": sha256,
+                "mtime": mtime,
+                "tags": file_tags(&trans, &encode_name(&file.name, encoding)?)?,
+            });
+            writeln!(out, "{}", line)?;
+            continue;
+        }
+
+        let name = path_to_bytes(&file.name)?;
+        let fields = [
+            name,
+            file.size.to_string().into_bytes(),
+            file.chunks.len().to_string().into_bytes(),
+            sha256.into_bytes(),
+            mtime.unwrap_or_default().into_bytes(),
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.write_all(&[separator])?;
+            }
+            match format {
+                "tsv" => out.write_all(&escape_tsv(field))?,
+                _ => out.write_all(&escape_csv(field))?,
+            }
+        }
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Quotes `field` if it contains anything CSV gives a meaning to, doubling quotes inside.
+fn escape_csv(field: &[u8]) -> Vec<u8> {
+    if !field.iter().any(|b| b",\"\r\n".contains(b)) {
+        return field.to_vec();
+    }
+
+    let mut escaped = vec![b'"'];
+    for &b in field {
+        if b == b'"' {
+            escaped.push(b'"');
+        }
+        escaped.push(b);
+    }
+    escaped.push(b'"');
+
+    escaped
+}
+
+fn escape_tsv(field: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(field.len());
+    for &b in field {
+        match b {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            _ => escaped.push(b),
+        }
+    }
+
+    escaped
+}
+
+/// Subcommands whose arguments are stored names.
+const COMPLETE_NAMES_FOR: &[&str] = &["Extract", "Cat", "Cmp", "Checksum", "SetTag", "GetTags"];
+
+/// At most this many completions are printed.
+const COMPLETE_LIMIT: usize = 200;
+
+/// Writes the stored names starting with `partial` to `out`, up to and including the next
+/// `/`, so directories complete one level at a time.
+fn complete_cmd<W: Write>(database: &str, partial: &str, out: &mut W) -> Result<(), Error> {
+    let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    // Names are sorted, so everything with the prefix follows it in one run.
+    let mut stmt = connection.prepare("SELECT name FROM files WHERE name >= ? ORDER BY name")?;
+    let mut previous = String::new();
+    let mut printed = 0;
+    for name in stmt.query_map(&[&partial], |row| row.get::<_, Value>(0))? {
+        let name = match name? {
+            Value::Text(name) => name,
+            _ => continue,
+        };
+        if !name.starts_with(partial) {
+            break;
+        }
+
+        let rest = &name[partial.len()..];
+        let candidate = match rest.find('/') {
+            Some(i) => &name[..partial.len() + i + 1],
+            None => &name[..],
+        };
+        if candidate == previous {
+            continue;
+        }
+
+        writeln!(out, "{}", candidate)?;
+        previous = candidate.to_owned();
+        printed += 1;
+        if printed == COMPLETE_LIMIT {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a digest of each stored file under `prefix` to `out`, as sha256sum prints them.
+fn checksum_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    algo: &str,
+    prefix: Option<PathBuf>,
+    verbosity: u8,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    let files = match prefix {
+        Some(prefix) => files_under(&mut trans, &prefix, encoding)?,
+        None => {
+            let mut files = list_files(&mut trans, encoding)?;
+            files.sort();
+            files
+        }
+    };
+
+    let same_as_content_hash = ChunkHash::load(&trans)?.checksum_algo() == Some(algo);
+
+    let mut reused = 0;
+    for name in files {
+        let stored = get_file(&mut trans, name.clone(), encoding)?;
+        let hex = match (&stored.sha256, &stored.content_hash, algo) {
+            (Some(sha256), _, "sha256") => {
+                reused += 1;
+                sha256.clone()
+            }
+            (_, Some(content_hash), _) if same_as_content_hash => {
+                reused += 1;
+                content_hash.clone()
+            }
+            _ => {
+                let mut checksum = Checksum::new(algo)?;
+                write_file_to(&mut trans, &stored, cache, &mut checksum, write_zeros)?;
+                checksum.hex()
+            }
+        };
+
+        write!(out, "{}  ", hex)?;
+        out.write_all(&path_to_bytes(&name)?)?;
+        out.write_all(b"\n")?;
+    }
+
+    if verbosity > 0 && reused > 0 {
+        eprintln!(
+            "{} digests were taken from the hashes stored when adding",
+            reused
+        );
+    }
+
+    Ok(())
+}
+
+fn set_tag_cmd(
+    db: &mut SqliteDatabase,
+    file: PathBuf,
+    key: &str,
+    value: &str,
+    encoding: NameEncoding,
+) -> Result<(), Error> {
+    // Tags are kept in plain text, which would give away what the names hide.
+    if encoding == NameEncoding::Sealed {
+        return Err(format_err!(
+            "SetTag can't tag files in an archive with encrypted names"
+        ));
+    }
+    if key.is_empty() || key.contains('=') {
+        return Err(format_err!("a tag key can't be empty or contain '='"));
+    }
+
+    let mut trans = db.connection.transaction()?;
+
+    // Only stored files can be tagged.
+    get_file(&mut trans, file.clone(), encoding)?;
+    trans.execute(
+        "INSERT OR REPLACE INTO file_meta VALUES (?,?,?)",
+        &[&encode_name(&file, encoding)? as &dyn ToSql, &key, &value],
+    )?;
+
+    trans.commit()?;
+
+    Ok(())
+}
+
+fn get_tags_cmd<W: Write>(
+    db: &mut SqliteDatabase,
+    file: PathBuf,
+    encoding: NameEncoding,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    get_file(&mut trans, file.clone(), encoding)?;
+    for (key, value) in file_tags(&trans, &encode_name(&file, encoding)?)? {
+        writeln!(out, "{}={}", key, value)?;
+    }
+
+    Ok(())
+}
+
+/// The tags of the file stored as `name`, by key.
+fn file_tags(
+    connection: &Connection,
+    name: &dyn ToSql,
+) -> Result<std::collections::BTreeMap<String, String>, Error> {
+    let mut stmt =
+        connection.prepare_cached("SELECT key, value FROM file_meta WHERE file_name=?")?;
+    let mut tags = std::collections::BTreeMap::new();
+    for row in stmt.query_map(&[name], |row| (row.get(0), row.get(1)))? {
+        let (key, value) = row?;
+        tags.insert(key, value);
+    }
+
+    Ok(tags)
+}
+
+/// The names of the stored files that have every one of `tags`.
+fn tagged_files(
+    connection: &Connection,
+    tags: &[(String, String)],
+    encoding: NameEncoding,
+) -> Result<HashSet<PathBuf>, Error> {
+    let mut tagged: Option<HashSet<PathBuf>> = None;
+    for (key, value) in tags {
+        let mut stmt = connection.prepare_cached(
+            "SELECT file_name FROM file_meta WHERE key=? AND value=?
+                AND file_name IN (SELECT name FROM files)",
+        )?;
+        let mut names = HashSet::new();
+        for name in stmt.query_map(&[key, value], |row| row.get(0))? {
+            names.insert(decode_name(name?, encoding)?);
+        }
+        tagged = Some(match tagged {
+            Some(tagged) => tagged.intersection(&names).cloned().collect(),
+            None => names,
+        });
+    }
+
+    Ok(tagged.unwrap_or_default())
+}
+
+/// Chunks larger than this aren't sampled for a dictionary, which only helps small ones.
+const DICT_SAMPLE_MAX: i64 = 128 * 1024;
+
+fn train_dict_cmd(db: &mut SqliteDatabase, samples: u32, max_size: usize) -> Result<(), Error> {
+    // A dictionary is made of pieces of the content, and isn't sealed.
+    if crypt::is_encrypted(&db.connection)? {
+        return Err(format_err!(
+            "TrainDict can't store a dictionary in an encrypted archive"
+        ));
+    }
+
+    let trans = db.connection.transaction()?;
+
+    let mut hashes = Vec::new();
+    {
+        let mut stmt = trans.prepare(
+            "SELECT hash FROM chunks WHERE COALESCE(uncompressed_size, size) <= ?
+            ORDER BY random() LIMIT ?",
+        )?;
+        let params = [&DICT_SAMPLE_MAX as &dyn ToSql, &samples];
+        for hash in stmt.query_map(&params, |row| row.get::<_, String>(0))? {
+            hashes.push(hash?);
+        }
+    }
+    let sampled = hashes
+        .iter()
+        .map(|hash| get_chunk(&trans, hash))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let id = dict::train(&trans, &sampled, max_size)?;
+    println!(
+        "trained dictionary {} of {} bytes on {} chunks",
+        id,
+        dict::size(id)?,
+        sampled.len()
+    );
+
+    let level = get_meta(&trans, "zstd_level")?.unwrap_or(0);
+    let (with, without) = dict::ratios(&sampled, level, id)?;
+    println!(
+        "zstd compresses them to {:.1}% of their size with it, and {:.1}% without",
+        with * 100.0,
+        without * 100.0
+    );
+
+    trans.commit()?;
+
+    Ok(())
+}
+
+fn put_blob_cmd(db: &mut SqliteDatabase, budget: &MemoryBudget) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    let mut buf = Vec::new();
+    std::io::stdin().read_to_end(&mut buf)?;
+
+    let hash = put_blob(&mut trans, buf, budget)?;
+
+    trans.commit()?;
+
+    println!("{}", hash);
+
+    Ok(())
+}
+
+fn get_blob_cmd(db: &mut SqliteDatabase, hash: String) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    let data = get_blob(&mut trans, &hash)?;
+
+    let stdout = std::io::stdout();
+    stdout.lock().write_all(&data)?;
+
+    Ok(())
+}
+
+fn migrate_cmd(db: &mut SqliteDatabase) -> Result<(), Error> {
+    let version = schema_version(&db.connection)?;
+    match version.cmp(&SCHEMA_VERSION) {
+        std::cmp::Ordering::Greater => {
+            return Err(ChsqlarError::SchemaTooNew { required: version }.into())
+        }
+        std::cmp::Ordering::Equal => println!("already at schema version {}", version),
+        // Opening the archive already added whatever tables and columns it was missing.
+        std::cmp::Ordering::Less => {
+            // Reference counts weren't kept before version 2.
+            if version < 2 {
+                let trans = db.connection.transaction()?;
+                rebuild_refcounts(&trans)?;
+                trans.commit()?;
+            }
+            // Nor uncompressed sizes before version 3.
+            if version < 3 {
+                fill_uncompressed_sizes(db)?;
+            }
+            // Version 4 added content hashes. Files without one are fine, and
+            // Migrate --compute-file-hashes fills them in on request. Version 5 added
+            // tags, which no older file has. Version 6 added owner names, which older files
+            // added with --preserve-ownership lack; Extract --owner=names uses their ids.
+            set_meta(&db.connection, "schema_version", &SCHEMA_VERSION)?;
+            set_meta(&db.connection, "min_reader_version", &MIN_READER_VERSION)?;
+            println!(
+                "migrated from schema version {} to {}",
+                version, SCHEMA_VERSION
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the uncompressed size of every chunk that has none, a batch per transaction.
+/// Chunks that can't be read are left without one and reported.
+fn fill_uncompressed_sizes(db: &mut SqliteDatabase) -> Result<(), Error> {
+    let cipher = crypt::cipher(&db.connection)?;
+    let mut hashes = Vec::new();
+    {
+        let mut stmt = db
+            .connection
+            .prepare("SELECT hash FROM chunks WHERE uncompressed_size IS NULL")?;
+        for hash in stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))? {
+            hashes.push(hash?);
+        }
+    }
+
+    let mut unreadable = 0;
+    for batch in hashes.chunks(RECOMPRESS_BATCH) {
+        let trans = db.connection.transaction()?;
+
+        let mut stored = Vec::new();
+        for hash in batch {
+            let (data, codec): (Option<Vec<u8>>, String) = trans.query_row(
+                "SELECT data, codec FROM chunks WHERE hash=?",
+                &[hash],
+                |row| (row.get(0), row.get(1)),
+            )?;
+            stored.push((
+                hash,
+                data.map_or_else(|| read_loose(&trans, hash), Ok),
+                codec,
+            ));
+        }
+
+        let sizes: Vec<_> = stored
+            .into_par_iter()
+            .map(|(hash, data, codec)| {
+                let size = data
+                    .and_then(|data| crypt::open(cipher, data, hash.as_bytes(), String::new))
+                    .and_then(|data| uncompressed_len(&codec, data));
+                (hash, size)
+            })
+            .collect();
+
+        for (hash, size) in sizes {
+            match size {
+                Ok(size) => {
+                    trans.execute(
+                        "UPDATE chunks SET uncompressed_size=? WHERE hash=?",
+                        &[&(size as i64) as &dyn ToSql, hash],
+                    )?;
+                }
+                Err(_) => unreadable += 1,
+            }
+        }
+
+        trans.commit()?;
+    }
+
+    if unreadable > 0 {
+        let msg = format!(
+            "{} chunks couldn't be read and have no uncompressed size; Verify lists them",
+            unreadable
+        );
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, msg));
+    }
+
+    Ok(())
+}
+
+/// Hashes the content of every file with no content hash, or with `all` of every file, a
+/// batch per transaction. Files that can't be read are left without one and reported.
+/// Returns how many were hashed.
+fn fill_content_hashes(
+    db: &mut SqliteDatabase,
+    all: bool,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<usize, Error> {
+    let chunk_hash = ChunkHash::load(&db.connection)?;
+    let names = list_files(&mut db.connection.transaction()?, encoding)?;
+
+    let mut filled = 0;
+    let mut unreadable = 0;
+    for batch in names.chunks(RECOMPRESS_BATCH) {
+        let mut trans = db.connection.transaction()?;
+
+        for name in batch {
+            let mut file = get_file(&mut trans, name.clone(), encoding)?;
+            if file.content_hash.is_some() && !all {
+                continue;
+            }
+
+            let mut checksum = chunk_hash.checksum();
+            match write_file_to(&mut trans, &file, cache, &mut checksum, write_zeros) {
+                Ok(()) => {
+                    file.content_hash = Some(checksum.hex());
+                    filled += 1;
+                }
+                // A hash from before the archive's hash changed would no longer match.
+                Err(_) => {
+                    file.content_hash = None;
+                    unreadable += 1;
+                }
+            }
+            put_file(&mut trans, file, encoding)?;
+        }
+
+        trans.commit()?;
+    }
+
+    if unreadable > 0 {
+        let msg = format!(
+            "{} files couldn't be read and have no content hash; Verify lists the faulty chunks",
+            unreadable
+        );
+        eprintln!("{}", paint(Stream::Stderr, Style::Warning, msg));
+    }
+
+    Ok(filled)
+}
+
+fn info_cmd(db: &SqliteDatabase) -> Result<(), Error> {
+    let mut stmt = db
+        .connection
+        .prepare("SELECT key, value FROM meta ORDER BY key")?;
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+        let (key, value) = row?;
+        let value = match value {
+            Value::Blob(dict) if dict::is_dict_key(&key) => format!("({} bytes)", dict.len()),
+            value => meta_text(value),
+        };
+        writeln!(stdout, "{}\t{}", key, value)?;
+    }
+
+    Ok(())
+}
+
+fn stats_cmd<W: Write>(db: &mut SqliteDatabase, out: &mut W) -> Result<(), Error> {
+    let trans = db.connection.transaction()?;
+
+    let files: i64 = trans.query_row("SELECT COUNT(*) FROM files", NO_PARAMS, |row| row.get(0))?;
+    let chunks: i64 =
+        trans.query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))?;
+
+    let (inlined, inlined_bytes): (i64, Option<i64>) = trans.query_row(
+        "SELECT COUNT(*), SUM(length(inline_data)) FROM files WHERE inline_data IS NOT NULL",
+        NO_PARAMS,
+        |row| (row.get(0), row.get(1)),
+    )?;
+
+    writeln!(out, "files: {}", files)?;
+    writeln!(out, "chunks: {}", chunks)?;
+    writeln!(
+        out,
+        "inlined: {} files, {} bytes, saving at least {} chunk rows",
+        inlined,
+        inlined_bytes.unwrap_or(0),
+        inlined
+    )?;
+    writeln!(out, "auto_vacuum: {}", auto_vacuum_mode(&trans)?)?;
+    if let Some(encryption) = crypt::describe(&trans)? {
+        writeln!(out, "{}", encryption)?;
+    }
+    if let Some(level) = get_meta::<i32>(&trans, "zstd_level")? {
+        writeln!(out, "default zstd level: {}", level)?;
+    }
+    let params = ChunkerParams::load(&trans)?;
+    writeln!(
+        out,
+        "chunking: {} (about {} bytes on average), at most {} bytes",
+        params,
+        params.average(),
+        params.max_bytes()
+    )?;
+    writeln!(out, "chunk hash: {}", ChunkHash::load(&trans)?)?;
+    if let Some(id) = dict::current(&trans)? {
+        writeln!(out, "zstd dictionary: {} ({} bytes)", id, dict::size(id)?)?;
+    }
+
+    let mut stmt = trans.prepare(
+        "SELECT codec, level, COUNT(*), SUM(COALESCE(size, length(data))),
+            SUM(uncompressed_size), COUNT(uncompressed_size)
+        FROM chunks GROUP BY codec, level ORDER BY codec, level",
+    )?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        let codec: String = row.get(0);
+        let level: Option<i32> = row.get(1);
+        let count: i64 = row.get(2);
+        let bytes: i64 = row.get(3);
+        let uncompressed: Option<i64> = row.get(4);
+        let known: i64 = row.get(5);
+        (codec, level, count, bytes, uncompressed, known)
+    })?;
+
+    for row in rows {
+        let (codec, level, count, bytes, uncompressed, known) = row?;
+        // Archives not yet migrated may only know some of the sizes.
+        let from = match (uncompressed, known == count) {
+            (Some(uncompressed), true) => format!(" from {}", uncompressed),
+            _ => String::new(),
+        };
+        match (codec.as_str(), level) {
+            ("store", _) => writeln!(out, "stored raw: {} chunks, {} bytes", count, bytes)?,
+            (_, Some(level)) => writeln!(
+                out,
+                "compressed ({} level {}): {} chunks, {} bytes{}",
+                codec, level, count, bytes, from
+            )?,
+            (_, None) => writeln!(
+                out,
+                "compressed ({}): {} chunks, {} bytes{}",
+                codec, count, bytes, from
+            )?,
+        }
+    }
+
+    let incompressible: i64 = trans.query_row(
+        "SELECT COUNT(*) FROM chunks WHERE incompressible",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    writeln!(
+        out,
+        "stored raw as incompressible: {} chunks",
+        incompressible
+    )?;
+
+    // What a dictionary saves shows in how much of their size chunks compressed with one
+    // keep, next to those without.
+    let kept = |codec: &str| -> Result<Option<f64>, Error> {
+        let (bytes, uncompressed): (Option<i64>, Option<i64>) = trans.query_row(
+            "SELECT SUM(COALESCE(size, length(data))), SUM(uncompressed_size)
+            FROM chunks WHERE codec=? AND uncompressed_size IS NOT NULL",
+            &[&codec],
+            |row| (row.get(0), row.get(1)),
+        )?;
+        Ok(bytes
+            .zip(uncompressed.filter(|&uncompressed| uncompressed > 0))
+            .map(|(bytes, uncompressed)| bytes as f64 / uncompressed as f64))
+    };
+    if let Some(with) = kept("zstd-dict")? {
+        let without = match kept("zstd")? {
+            Some(without) => format!(", against {:.1}% for zstd without one", without * 100.0),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "zstd with a dictionary: compressed to {:.1}% of their size{}",
+            with * 100.0,
+            without
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Looks up how a stored chunk was compressed.
+fn chunk_compression(trans: &mut Transaction, hash: &str) -> Result<Compression, Error> {
+    let (codec, level): (String, Option<i32>) = trans.query_row(
+        "SELECT codec, level FROM chunks WHERE hash=?",
+        &[&hash],
+        |row| (row.get(0), row.get(1)),
+    )?;
+
+    // Data stored again is compressed with the dictionary in use now, which needn't be the
+    // one the chunk was.
+    if codec == "zstd-dict" {
+        return match dict::current(trans)? {
+            Some(id) => Ok(Compression::ZstdDict(level.unwrap_or(0), id)),
+            None => Ok(Compression::Zstd(level.unwrap_or(0))),
+        };
+    }
+
+    Compression::from_codec(&codec, level)
+}
+
+/// Reassembles the content of `chunks` and stores it again cut with `params`, keeping the
+/// compression of the first original chunk.
+fn rechunk(
+    trans: &mut Transaction,
+    chunks: &[String],
+    params: ChunkerParams,
+    budget: &MemoryBudget,
+) -> Result<Vec<String>, Error> {
+    let compression = match chunks.first() {
+        Some(hash) => chunk_compression(trans, hash)?,
+        None => Compression::default(),
+    };
+
+    let data = get_chunks_data(trans, chunks)?;
+    let _held = budget.acquire(2 * data.len() as u64);
+
+    let chunks = chunk_data(data, params);
+    put_chunks(
+        trans,
+        chunks,
+        compression,
+        DEFAULT_MIN_SAVINGS,
+        budget,
+        &Metrics::default(),
+        &mut DedupWindow::default(),
+    )
+}
+
+/// The chunker parameters of `database` if it exists, or the defaults, with whatever the
+/// command line gives in their place. For commands that only look at how data is cut.
+/// Left unchecked, as Bench tries every chunker with them.
+fn chunker_params_for(opt: &CommonOpt, bits: Option<usize>) -> Result<ChunkerParams, Error> {
+    let mut params = match Path::new(&opt.database).exists() {
+        true => ChunkerParams::load(&Connection::open_with_flags(
+            &opt.database,
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?)?,
+        false => ChunkerParams::new(),
+    };
+
+    params.algorithm = opt.chunker.unwrap_or(params.algorithm);
+    params.bits = bits.or(opt.chunk_bits).unwrap_or(params.bits);
+    params.size = opt.chunk_size.or(params.size);
+    params.max_bytes = opt.max_chunk_bytes.or(params.max_bytes);
+
+    Ok(params)
+}
+
+/// Writes the offset, length and hash of each chunk `params` cut `path` into to `out`.
+fn chunk_file_cmd<W: Write>(
+    path: &Path,
+    params: ChunkerParams,
+    hash: ChunkHash,
+    out: &mut W,
+) -> Result<(), Error> {
+    params.check()?;
+
+    let mut offset = 0;
+    for chunk in chunk_data(fs::read(path)?, params) {
+        writeln!(out, "{}\t{}\t{}", offset, chunk.len(), hash.of(&chunk))?;
+        offset += chunk.len();
+    }
+
+    Ok(())
+}
+
+/// Cuts `files` with every chunker at `params`, printing how fast each went, how many chunks
+/// it made and how much of the data was left once chunks cut twice were only counted once.
+fn bench_cmd(files: &[PathBuf], params: ChunkerParams) -> Result<(), Error> {
+    let mut contents = Vec::new();
+    for file in files {
+        contents.push(fs::read(file)?);
+    }
+    let total: usize = contents.iter().map(Vec::len).sum();
+    let mb = |bytes: usize| bytes as f64 / 1e6;
+
+    for &algorithm in chunker::Algorithm::ALL {
+        let params = ChunkerParams {
+            algorithm,
+            ..params
+        };
+        if let Err(e) = params.check() {
+            println!("{}: skipped, {}", algorithm, e);
+            continue;
+        }
+
+        let mut nanos = 0;
+        let mut chunks = 0;
+        let mut seen = HashSet::new();
+        let mut unique = 0;
+        for data in &contents {
+            let data = data.clone();
+            let start = std::time::Instant::now();
+            let cut = chunk_data(data, params);
+            nanos += start.elapsed().as_nanos() as u64;
+
+            chunks += cut.len();
+            for chunk in cut {
+                if seen.insert(hash_chunk(&chunk)) {
+                    unique += chunk.len();
+                }
+            }
+        }
+
+        println!(
+            "{}: {} chunks averaging {} bytes, {:.2} of {:.2} MB unique, {:.1} MB/s",
+            algorithm,
+            chunks,
+            total.checked_div(chunks).unwrap_or(0),
+            mb(unique),
+            mb(total),
+            mb(total) / (nanos.max(1) as f64 / 1e9)
+        );
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compact_cmd(
+    db: &mut SqliteDatabase,
+    algorithm: Option<chunker::Algorithm>,
+    chunk_bits: Option<usize>,
+    chunk_size: Option<usize>,
+    chunk_hash: Option<ChunkHash>,
+    vacuum: bool,
+    rebuild: bool,
+    verbosity: u8,
+    budget: &MemoryBudget,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    if rebuild {
+        let (chunks, drifted) = rebuild_refcounts(&trans)?;
+        println!(
+            "rebuilt the reference counts of {} chunks, {} had drifted",
+            chunks, drifted
+        );
+        if drifted > 0 {
+            let msg = "reference counts should never drift, so this is a bug in chsqlar";
+            eprintln!("{}", paint(Stream::Stderr, Style::Warning, msg));
+        }
+    }
+
+    let params = ChunkerParams::load(&trans)?.with(algorithm, chunk_bits, chunk_size)?;
+    params.save(&trans)?;
+
+    // Rechunking stores every chunk again, under whatever hash the archive now uses.
+    let old_hash = ChunkHash::load(&trans)?;
+    let rehash = chunk_hash.filter(|&chunk_hash| chunk_hash != old_hash);
+    if let Some(chunk_hash) = rehash {
+        chunk_hash.save(&trans)?;
+        println!("rehashing chunks from {} to {}", old_hash, chunk_hash);
+    }
+
+    let mut rechunked = 0;
+    for table in &["files", "blobs"] {
+        let mut rows = Vec::new();
+        {
+            let mut stmt = trans.prepare(&format!("SELECT rowid, chunks FROM {}", table))?;
+            for row in stmt.query_map(NO_PARAMS, |row| {
+                (row.get::<_, i64>(0), row.get::<_, String>(1))
+            })? {
+                rows.push(row?);
+            }
+        }
+
+        for (rowid, chunks) in rows {
+            let old_chunks = split_chunk_list(&chunks);
+            let new_chunks = rechunk(&mut trans, &old_chunks, params, budget)?;
+
+            adjust_refcounts(&trans, &old_chunks, -1)?;
+            adjust_refcounts(&trans, &new_chunks, 1)?;
+            trans.execute(
+                &format!("UPDATE {} SET chunks=? WHERE rowid=?", table),
+                &[&new_chunks.join(";") as &dyn ToSql, &rowid],
+            )?;
+
+            rechunked += 1;
+            if verbosity > 0 {
+                eprintln!("rechunked {} of {} rows", rechunked, table);
+            }
+        }
+    }
+
+    let orphans = unreferenced_chunks(&trans)?;
+    for chunk in &orphans {
+        delete_chunk(&trans, &chunk.hash)?;
+    }
+
+    trans.commit()?;
+    remove_loose_chunks(&db.connection, orphans.iter().map(|chunk| &chunk.hash))?;
+
+    // Content hashes are taken with the chunk hash, so they have to be taken again.
+    if rehash.is_some() {
+        let filled = fill_content_hashes(db, true, encoding, cache)?;
+        println!("rehashed the content of {} files", filled);
+    }
+
+    // With incremental auto_vacuum the freed pages can be given back without rebuilding.
+    if vacuum {
+        db.connection.execute_batch("VACUUM;")?;
+    } else if auto_vacuum_mode(&db.connection)? == "incremental" {
+        db.connection.execute_batch("PRAGMA incremental_vacuum;")?;
+    }
+
+    if verbosity > 0 {
+        budget.report();
+    }
+
+    let bytes: i64 = orphans.iter().map(|chunk| chunk.size).sum();
+    println!(
+        "rechunked {} entries, removed {} old chunks ({} bytes)",
+        rechunked,
+        orphans.len(),
+        bytes
+    );
+
+    Ok(())
+}
+
+/// Writes what Compact would delete without the chunker changing to `out`, by codec, and
+/// deletes nothing. With `rebuild` the reference counts are recomputed first, but not saved.
+fn compact_dry_run<W: Write>(
+    db: &mut SqliteDatabase,
+    rebuild: bool,
+    out: &mut W,
+) -> Result<(), Error> {
+    // Dropped without committing.
+    let trans = db.connection.transaction()?;
+
+    if rebuild {
+        let (chunks, drifted) = rebuild_refcounts(&trans)?;
+        writeln!(
+            out,
+            "would rebuild the reference counts of {} chunks, {} have drifted",
+            chunks, drifted
+        )?;
+    }
+
+    let orphans = unreferenced_chunks(&trans)?;
+
+    let mut by_codec = std::collections::BTreeMap::new();
+    for chunk in &orphans {
+        let entry = by_codec.entry(chunk.codec.as_str()).or_insert((0, 0, 0));
+        entry.0 += 1;
+        entry.1 += chunk.size;
+        entry.2 += chunk.uncompressed_size.unwrap_or(chunk.size);
+    }
+
+    let bytes: i64 = by_codec.values().map(|(_, bytes, _)| bytes).sum();
+    let uncompressed: i64 = by_codec
+        .values()
+        .map(|(_, _, uncompressed)| uncompressed)
+        .sum();
+    writeln!(
+        out,
+        "would remove {} old chunks ({} bytes, {} uncompressed)",
+        orphans.len(),
+        bytes,
+        uncompressed
+    )?;
+    for (codec, (count, bytes, uncompressed)) in by_codec {
+        writeln!(
+            out,
+            "  {}: {} chunks, {} bytes, {} uncompressed",
+            codec, count, bytes, uncompressed
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Copies the file `name` and the chunks it uses from the attached `source` archive into
+/// the main one, storing it as `as_name`. Returns the chunks that weren't there already.
+fn copy_from_source(
+    connection: &Connection,
+    name: &Value,
+    as_name: &Value,
+    chunks: &str,
+) -> Result<Vec<String>, Error> {
+    let mut copied = Vec::new();
+    for hash in split_chunk_list(chunks) {
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO main.chunks
+                (hash, data, codec, level, checksum, size, incompressible, uncompressed_size)
+            SELECT hash, data, codec, level, checksum, size, incompressible, uncompressed_size
+            FROM source.chunks WHERE hash=?",
+            &[&hash],
+        )?;
+        if inserted > 0 {
+            copied.push(hash);
+        }
+    }
+    if !copied.is_empty() {
+        dict::copy_from_source(connection)?;
+    }
+
+    adjust_refcounts(connection, &file_chunks(connection, as_name)?, -1)?;
+    adjust_refcounts(connection, &split_chunk_list(chunks), 1)?;
+
+    connection.execute(
+        "INSERT OR REPLACE INTO main.files
+            (name, size, chunks, holes, inline_data, inline_codec, mtime, added_at, sha256,
+             owner_uid, owner_gid, owner_user, owner_group, filter, attrs, content_hash)
+        SELECT ?, size, chunks, holes, inline_data, inline_codec, mtime, added_at, sha256,
+            owner_uid, owner_gid, owner_user, owner_group, filter, attrs, content_hash
+        FROM source.files WHERE name=?",
+        &[as_name, name],
+    )?;
+
+    Ok(copied)
+}
+
+/// A part being filled by `split_cmd`, inside an open transaction.
+struct SplitPart {
+    path: String,
+    db: SqliteDatabase,
+    files: usize,
+}
+
+fn split_cmd(
+    db: &mut SqliteDatabase,
+    max_size: u64,
+    out: &str,
+    db_opt: &DatabaseOpt,
+    verbosity: u8,
+) -> Result<(), Error> {
+    if !out.contains("{}") {
+        return Err(format_err!("--out needs a {{}} for the part number"));
+    }
+
+    // Copying rows in SQL would leave loose chunks behind.
+    if store_dir(&db.connection)?.is_some() {
+        return Err(format_err!(
+            "Split can't copy from an archive that uses --store-dir"
+        ));
+    }
+
+    let source: String = db.connection.query_row(
+        "SELECT file FROM pragma_database_list WHERE name='main'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let params = ChunkerParams::load(&db.connection)?;
+    let chunk_hash = ChunkHash::load(&db.connection)?;
+
+    let mut chunk_sizes = HashMap::new();
+    let mut rows = Vec::new();
+    {
+        let mut stmt = db
+            .connection
+            .prepare("SELECT hash, COALESCE(size, length(data)) FROM chunks")?;
+        for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+            let (hash, size): (String, Option<i64>) = row?;
+            chunk_sizes.insert(hash, size.unwrap_or(0) as u64);
+        }
+
+        let mut stmt = db
+            .connection
+            .prepare("SELECT name, chunks FROM files ORDER BY name")?;
+        for row in stmt.query_map(NO_PARAMS, |row| {
+            (row.get::<_, Value>(0), row.get::<_, String>(1))
+        })? {
+            rows.push(row?);
+        }
+    }
+
+    // Parts are written as single files, which is the point of splitting.
+    let part_opt = DatabaseOpt {
+        journal: "delete".to_owned(),
+        ..db_opt.clone()
+    };
+
+    let mut parts = 0;
+    let mut part: Option<SplitPart> = None;
+    let mut seen = HashSet::new();
+    let mut copied_bytes = 0;
+    let mut unique_bytes = 0;
+
+    let mut rows = rows.into_iter().peekable();
+    while let Some((name, chunks)) = rows.peek() {
+        let current = match &mut part {
+            Some(current) => current,
+            None => {
+                parts += 1;
+                let path = out.replace("{}", &parts.to_string());
+                if Path::new(&path).exists() {
+                    return Err(format_err!("{} already exists", path));
+                }
+                let part_db = SqliteDatabase::new(&path, &part_opt)?;
+                params.save(&part_db.connection)?;
+                chunk_hash.save(&part_db.connection)?;
+                for key in &["max_chunk_bytes", "name_encoding"] {
+                    if let Some(value) = get_meta::<String>(&db.connection, key)? {
+                        set_meta(&part_db.connection, key, &value)?;
+                    }
+                }
+                // Chunks are copied still sealed, so each part opens with the same key.
+                crypt::copy_setup(&db.connection, &part_db.connection)?;
+                part_db
+                    .connection
+                    .execute("ATTACH DATABASE ? AS source", &[&source])?;
+                part_db.connection.execute_batch("BEGIN")?;
+                part.get_or_insert(SplitPart {
+                    path,
+                    db: part_db,
+                    files: 0,
+                })
+            }
+        };
+
+        let connection = &current.db.connection;
+        connection.execute_batch("SAVEPOINT file")?;
+        let copied = copy_from_source(connection, name, name, chunks)?;
+
+        if current.files > 0 && database_size(connection)? > max_size {
+            connection.execute_batch("ROLLBACK TO file; RELEASE file; COMMIT")?;
+            if verbosity > 0 {
+                eprintln!("{}: {} files", current.path, current.files);
+            }
+            part = None;
+            continue;
+        }
+        connection.execute_batch("RELEASE file")?;
+        current.files += 1;
+
+        for hash in copied {
+            let size = chunk_sizes.get(&hash).cloned().unwrap_or(0);
+            copied_bytes += size;
+            if seen.insert(hash) {
+                unique_bytes += size;
+            }
+        }
+        rows.next();
+    }
+
+    if let Some(current) = part {
+        current.db.connection.execute_batch("COMMIT")?;
+        if verbosity > 0 {
+            eprintln!("{}: {} files", current.path, current.files);
+        }
+    }
+
+    println!(
+        "split into {} parts, {} bytes of chunks stored in more than one part",
+        parts,
+        copied_bytes - unique_bytes
+    );
+
+    Ok(())
+}
+
+/// Parses a byte count, optionally followed by K, M, G or T for powers of 1024.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("expected a size like 4096, 512M or 4G, got '{}'", s))
+}
+
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let unit = match unit {
+        "" | "s" => Some(1),
+        "m" => Some(60),
+        "h" => Some(60 * 60),
+        "d" => Some(24 * 60 * 60),
+        _ => None,
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .zip(unit)
+        .and_then(|(n, unit)| n.checked_mul(unit))
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("expected a duration like 90s, 45m or 8h, got '{}'", s))
+}
+
+/// How an archive's chunks are cut and hashed, and which archive that is, for errors.
+struct Chunking {
+    archive: String,
+    params: ChunkerParams,
+    hash: ChunkHash,
+}
+
+impl Chunking {
+    fn load(connection: &Connection, archive: &str) -> Result<Self, Error> {
+        Ok(Chunking {
+            archive: archive.to_owned(),
+            params: ChunkerParams::load(connection)?,
+            hash: ChunkHash::load(connection)?,
+        })
+    }
+
+    fn save(&self, connection: &Connection) -> Result<(), Error> {
+        self.params.save(connection)?;
+        self.hash.save(connection)
+    }
+}
+
+/// Checks that `command` can copy files and their chunks from `source`, called `name`, into
+/// `dest` with SQL, for Pull and Merge. Returns the chunking `dest` is to have: its own once
+/// it has chunks, otherwise `expected` from an earlier source, or else `source`'s.
+fn check_copyable(
+    command: &str,
+    source: &Connection,
+    name: &str,
+    dest: &Connection,
+    expected: Option<Chunking>,
+) -> Result<Chunking, Error> {
+    // Copying rows would leave loose chunks behind.
+    if store_dir(dest)?.is_some() {
+        return Err(format_err!(
+            "{} can't copy into an archive that uses --store-dir",
+            command
+        ));
+    }
+    if store_dir(source)?.is_some() {
+        return Err(format_err!(
+            "{} can't copy from {}, which uses --store-dir",
+            command,
+            name
+        ));
+    }
+    // Nor can chunks sealed with one key be opened with another.
+    if crypt::is_encrypted(dest)? {
+        return Err(format_err!(
+            "{} can't copy into an encrypted archive",
+            command
+        ));
+    }
+    if crypt::is_encrypted(source)? {
+        return Err(format_err!(
+            "{} can't copy from {}, which is encrypted",
+            command,
+            name
+        ));
+    }
+
+    // Chunks cut with different parameters would never dedup against each other, so an
+    // empty archive takes on the source's and anything else has to match.
+    let chunks: i64 = dest.query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))?;
+    let expected = match chunks {
+        0 => expected,
+        _ => Some(Chunking::load(dest, "this archive")?),
+    };
+    let source = Chunking::load(source, name)?;
+    match expected {
+        Some(expected) if !expected.params.cuts_like(&source.params) => Err(format_err!(
+            "{} uses {} but {} uses {}; Compact one of them to match first",
+            source.archive,
+            source.params,
+            expected.archive,
+            expected.params
+        )),
+        Some(expected) if expected.hash != source.hash => Err(format_err!(
+            "{} stores chunks under {} hashes but {} uses {}",
+            source.archive,
+            source.hash,
+            expected.archive,
+            expected.hash
+        )),
+        Some(expected) => Ok(expected),
+        None => Ok(source),
+    }
+}
+
+fn pull_cmd(
+    db: &mut SqliteDatabase,
+    from: &str,
+    patterns: Vec<String>,
+    encoding: NameEncoding,
+    verbosity: u8,
+) -> Result<(), Error> {
+    let source = open_source(from)?;
+    let chunking = check_copyable("Pull", &source, from, &db.connection, None)?;
+    drop(source);
+
+    let matchers = patterns
+        .iter()
+        .map(|p| Ok(Glob::new(p)?.compile_matcher()))
+        .collect::<Result<Vec<GlobMatcher>, Error>>()?;
+
+    db.connection
+        .execute("ATTACH DATABASE ? AS source", &[&from])?;
+
+    let trans = db.connection.transaction()?;
+    chunking.save(&trans)?;
+
+    let mut rows = Vec::new();
+    {
+        let mut stmt = trans.prepare("SELECT name, chunks FROM source.files")?;
+        for row in stmt.query_map(NO_PARAMS, |row| {
+            (row.get::<_, Value>(0), row.get::<_, String>(1))
+        })? {
+            rows.push(row?);
+        }
+    }
+
+    let mut pulled = 0;
+    for (name, chunks) in rows {
+        let path = decode_name(name.clone(), encoding)?;
+        if !matchers.is_empty() && !matchers.iter().any(|m| m.is_match(&path)) {
+            continue;
+        }
+
+        copy_from_source(&trans, &name, &name, &chunks)?;
+
+        pulled += 1;
+        if verbosity > 0 {
+            eprintln!("pulled {}", path.display());
+        }
+    }
+
+    trans.commit()?;
+
+    println!("{} files pulled", pulled);
+
+    Ok(())
+}
+
+/// What identifies a file's content for `merge_cmd`: its chunks, holes and inline data.
+type ContentKey = (String, Option<String>, Option<Vec<u8>>);
+
+fn merge_cmd(
+    db: &mut SqliteDatabase,
+    inputs: &[String],
+    on_conflict: &str,
+    verbosity: u8,
+) -> Result<(), Error> {
+    const FILE_KEYS: &str = "SELECT CAST(name AS BLOB), chunks, holes, inline_data FROM files";
+
+    // Which names hold more than one content across this archive and every input.
+    let mut contents: HashMap<Vec<u8>, ContentKey> = HashMap::new();
+    let mut conflicts = HashSet::new();
+    let mut record = |connection: &Connection| -> Result<(), Error> {
+        let mut stmt = connection.prepare(FILE_KEYS)?;
+        for row in stmt.query_map(NO_PARAMS, |row| {
+            (
+                row.get::<_, Vec<u8>>(0),
+                (row.get(1), row.get(2), row.get(3)),
+            )
+        })? {
+            let (name, key) = row?;
+            match contents.get(&name) {
+                Some(first) if *first != key => {
+                    conflicts.insert(name);
+                }
+                Some(_) => {}
+                None => {
+                    contents.insert(name, key);
+                }
+            }
+        }
+        Ok(())
+    };
+
+    record(&db.connection)?;
+    // An empty archive takes on the first input's chunking, which the rest have to match.
+    let mut chunking = None;
+    for input in inputs {
+        let source = open_source(input)?;
+        chunking = Some(check_copyable(
+            "Merge",
+            &source,
+            input,
+            &db.connection,
+            chunking,
+        )?);
+        record(&source)?;
+    }
+
+    if on_conflict == "error" && !conflicts.is_empty() {
+        let mut names: Vec<_> = conflicts.iter().collect();
+        names.sort();
+        return Err(format_err!(
+            "{} names hold different content in different archives, such as {}; \
+             choose what to do with --on-conflict",
+            names.len(),
+            String::from_utf8_lossy(names[0])
+        ));
+    }
+
+    if let Some(chunking) = chunking {
+        chunking.save(&db.connection)?;
+    }
+
+    let (mut merged, mut renamed, mut skipped) = (0, 0, 0);
+    for input in inputs {
+        db.connection
+            .execute("ATTACH DATABASE ? AS source", &[input])?;
+        let trans = db.connection.transaction()?;
+
+        let mut rows = Vec::new();
+        {
+            let mut stmt = trans.prepare(
+                "SELECT name, CAST(name AS BLOB), chunks, holes, inline_data FROM source.files",
+            )?;
+            for row in stmt.query_map(NO_PARAMS, |row| {
+                let name: Value = row.get(0);
+                let bytes: Vec<u8> = row.get(1);
+                let key: ContentKey = (row.get(2), row.get(3), row.get(4));
+                (name, bytes, key)
+            })? {
+                rows.push(row?);
+            }
+        }
+
+        for (name, bytes, key) in rows {
+            let stored = trans.query_row(
+                "SELECT chunks, holes, inline_data FROM main.files WHERE name=?",
+                &[&name],
+                |row| (row.get(0), row.get(1), row.get(2)),
+            );
+            let stored: Option<ContentKey> = match stored {
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                other => Some(other?),
+            };
+
+            let as_name = match stored {
+                None if on_conflict == "skip" && conflicts.contains(&bytes) => {
+                    skipped += 1;
+                    continue;
+                }
+                None => name.clone(),
+                Some(ref stored) if *stored == key => continue,
+                Some(_) if on_conflict == "rename" => {
+                    renamed += 1;
+                    free_name(&trans, &name, &contents)?
+                }
+                Some(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            copy_from_source(&trans, &name, &as_name, &key.0)?;
+            merged += 1;
+            if verbosity > 0 {
+                eprintln!("merged {} from {}", String::from_utf8_lossy(&bytes), input);
+            }
+        }
+
+        trans.commit()?;
+        db.connection.execute("DETACH DATABASE source", NO_PARAMS)?;
+    }
+
+    println!(
+        "{} files merged from {} archives, {} renamed, {} conflicting copies skipped",
+        merged,
+        inputs.len(),
+        renamed,
+        skipped
+    );
+
+    Ok(())
+}
+
+/// The first of `name.~1~`, `name.~2~` and so on that neither this archive nor any input
+/// to the merge already uses.
+fn free_name(
+    trans: &Transaction,
+    name: &Value,
+    taken: &HashMap<Vec<u8>, ContentKey>,
+) -> Result<Value, Error> {
+    for n in 1.. {
+        let suffix = format!(".~{}~", n);
+        let candidate = match name {
+            Value::Text(s) => Value::Text(format!("{}{}", s, suffix)),
+            Value::Blob(b) => Value::Blob([b.as_slice(), suffix.as_bytes()].concat()),
+            other => return Err(format_err!("unexpected file name {:?}", other)),
+        };
+        let bytes = match &candidate {
+            Value::Text(s) => s.as_bytes().to_vec(),
+            Value::Blob(b) => b.clone(),
+            _ => unreachable!(),
+        };
+
+        let used: i64 = trans.query_row(
+            "SELECT COUNT(*) FROM main.files WHERE name=?",
+            &[&candidate],
+            |row| row.get(0),
+        )?;
+        if used == 0 && !taken.contains_key(&bytes) {
+            return Ok(candidate);
+        }
+    }
+
+    unreachable!()
+}
+
+fn orphans_cmd(db: &mut SqliteDatabase) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+
+    let orphans = find_orphans(&mut trans)?;
+
+    for (hash, _) in &orphans {
+        println!("{}", hash);
+    }
+
+    let bytes: i64 = orphans.iter().map(|(_, size)| size).sum();
+    println!(
+        "{} orphaned chunks, {} bytes reclaimable",
+        orphans.len(),
+        bytes
+    );
+
+    Ok(())
+}
+
+fn du_cmd(
+    db: &mut SqliteDatabase,
+    depth: usize,
+    shared: &str,
+    bytes: bool,
+    encoding: NameEncoding,
+) -> Result<(), Error> {
+    let trans = db.connection.transaction()?;
+    let totals = du_totals(&trans, depth, shared, encoding)?;
+    let total: f64 = totals.iter().map(|(_, size)| size).sum();
+
+    let show = |size: f64| match bytes {
+        true => format!("{}", size.round()),
+        false => human_size(size),
+    };
+    for (dir, size) in &totals {
+        let size = paint(Stream::Stdout, Style::Size, show(*size));
+        println!("{}\t{}", size, dir.display());
+    }
+    println!("{}\ttotal", paint(Stream::Stdout, Style::Size, show(total)));
+
+    Ok(())
+}
+
+/// The stored bytes under each directory `depth` components deep, largest first, with
+/// chunks shared between files split between them or charged `once`.
+fn du_totals(
+    trans: &Connection,
+    depth: usize,
+    shared: &str,
+    encoding: NameEncoding,
+) -> Result<Vec<(PathBuf, f64)>, Error> {
+    let mut chunk_sizes = HashMap::new();
+    {
+        let mut stmt = trans.prepare("SELECT hash, COALESCE(size, length(data)) FROM chunks")?;
+        for row in stmt.query_map(NO_PARAMS, |row| (row.get::<_, String>(0), row.get(1)))? {
+            let (hash, size): (String, Option<i64>) = row?;
+            chunk_sizes.insert(hash, size.unwrap_or(0) as f64);
+        }
+    }
+
+    let mut files = Vec::new();
+    {
+        let mut stmt = trans.prepare("SELECT name, chunks, length(inline_data) FROM files")?;
+        for row in stmt.query_map(NO_PARAMS, |row| {
+            let name: Value = row.get(0);
+            let chunks: String = row.get(1);
+            let inline: Option<i64> = row.get(2);
+            (name, chunks, inline)
+        })? {
+            let (name, chunks, inline) = row?;
+            files.push((
+                decode_name(name, encoding)?,
+                split_chunk_list(&chunks),
+                inline,
+            ));
+        }
+    }
+
+    let mut refs = HashMap::new();
+    for (_, chunks, _) in &files {
+        for hash in chunks {
+            *refs.entry(hash.as_str()).or_insert(0) += 1;
+        }
+    }
+
+    let mut totals = HashMap::<PathBuf, f64>::new();
+    let mut counted = HashSet::new();
+    for (name, chunks, inline) in &files {
+        let mut size = inline.unwrap_or(0) as f64;
+        for hash in chunks {
+            let chunk_size = chunk_sizes.get(hash).cloned().unwrap_or(0.0);
+            size += match shared {
+                "once" if counted.insert(hash.as_str()) => chunk_size,
+                "once" => 0.0,
+                _ => chunk_size / f64::from(refs[hash.as_str()]),
+            };
+        }
+
+        let mut dir: PathBuf = name.parent().into_iter().flatten().take(depth).collect();
+        if dir.as_os_str().is_empty() {
+            dir = PathBuf::from(".");
+        }
+        *totals.entry(dir).or_insert(0.0) += size;
+    }
+
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+
+    Ok(totals)
+}
+
+/// Formats a byte count the way `du -h` does.
+fn human_size(bytes: f64) -> String {
+    let mut size = bytes;
+    for unit in &["", "K", "M", "G", "T"] {
+        if size < 1024.0 {
+            return match *unit {
+                "" => format!("{}", size.round()),
+                _ if size < 10.0 => format!("{:.1}{}", size, unit),
+                _ => format!("{:.0}{}", size, unit),
+            };
+        }
+        size /= 1024.0;
+    }
+
+    format!("{:.0}P", size)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkFault {
+    BlobCorrupted,
+    ContentMismatch,
+    LengthMismatch,
+    Undecryptable,
+}
+
+impl ChunkFault {
+    fn describe(self) -> &'static str {
+        match self {
+            ChunkFault::BlobCorrupted => "compressed blob corrupted",
+            ChunkFault::ContentMismatch => "decompressed content doesn't match hash",
+            ChunkFault::LengthMismatch => "decompressed length doesn't match the recorded one",
+            ChunkFault::Undecryptable => "corrupted or the key is wrong",
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn verify_chunk(
+    hash: &str,
+    data: Vec<u8>,
+    codec: &str,
+    checksum: Option<i64>,
+    uncompressed_size: Option<i64>,
+    cipher: Option<&crypt::Cipher>,
+    chunk_hash: ChunkHash,
+    fast: bool,
+) -> Option<ChunkFault> {
+    if checksum.is_some_and(|c| c != checksum_blob(&data)) {
+        return Some(ChunkFault::BlobCorrupted);
+    }
+
+    if fast {
+        return None;
+    }
+
+    let data = match crypt::open(cipher, data, hash.as_bytes(), String::new) {
+        Ok(data) => data,
+        Err(_) => return Some(ChunkFault::Undecryptable),
+    };
+
+    match decompress(codec, data) {
+        Ok(ref decoded) if chunk_hash.of(decoded) != hash => Some(ChunkFault::ContentMismatch),
+        Ok(decoded) if uncompressed_size.is_some_and(|size| size != decoded.len() as i64) => {
+            Some(ChunkFault::LengthMismatch)
+        }
+        Ok(_) => None,
+        Err(_) => Some(ChunkFault::BlobCorrupted),
+    }
+}
+
+/// Checks every chunk, passing each faulty one to `fault` with what's wrong with it.
+/// Returns how many were checked, and how many --fast skipped for having no checksum.
+fn verify_chunks(
+    connection: &Connection,
+    fast: bool,
+    mut fault: impl FnMut(&str, &dyn fmt::Display),
+) -> Result<(usize, usize), Error> {
+    // --fast only looks at checksums of the stored data, so doesn't need the key.
+    let cipher = match fast {
+        true => None,
+        false => crypt::cipher(connection)?,
+    };
+    let chunk_hash = ChunkHash::load(connection)?;
+
+    let mut stmt =
+        connection.prepare("SELECT hash, data, codec, checksum, uncompressed_size FROM chunks")?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        let hash: String = row.get(0);
+        let data: Option<Vec<u8>> = row.get(1);
+        let codec: String = row.get(2);
+        let checksum: Option<i64> = row.get(3);
+        let uncompressed_size: Option<i64> = row.get(4);
+        (hash, data, codec, checksum, uncompressed_size)
+    })?;
+
+    let mut checked = 0;
+    let mut unchecked = 0;
+
+    for row in rows {
+        let (hash, data, codec, checksum, uncompressed_size) = row?;
+
+        if fast && checksum.is_none() {
+            unchecked += 1;
+            continue;
+        }
+
+        checked += 1;
+
+        let data = match data.map_or_else(|| read_loose(connection, &hash), Ok) {
+            Ok(data) => data,
+            Err(e) => {
+                fault(&hash, &e);
+                continue;
+            }
+        };
+
+        if let Some(found) = verify_chunk(
+            &hash,
+            data,
+            &codec,
+            checksum,
+            uncompressed_size,
+            cipher,
+            chunk_hash,
+            fast,
+        ) {
+            fault(&hash, &found.describe());
+        }
+    }
+
+    Ok((checked, unchecked))
+}
+
+fn verify_cmd(db: &mut SqliteDatabase, fast: bool) -> Result<(), Error> {
+    let trans = db.connection.transaction()?;
+
+    let mut faults = 0;
+    let (checked, unchecked) = verify_chunks(&trans, fast, |hash, fault| {
+        println!("{}: {}", hash, paint(Stream::Stdout, Style::Bad, fault));
+        faults += 1;
+    })?;
+
+    let style = if faults > 0 { Style::Bad } else { Style::Good };
+    println!(
+        "{} chunks checked, {} faulty",
+        checked,
+        paint(Stream::Stdout, style, faults)
+    );
+    if unchecked > 0 {
+        println!(
+            "{} chunks have no stored checksum and were skipped",
+            unchecked
+        );
+    }
+
+    if faults > 0 {
+        return Err(format_err!("{} chunks failed verification", faults));
+    }
+
+    Ok(())
+}
+
+/// Reads back each file that has a content hash and checks that it still hashes to it.
+fn verify_files_cmd(
+    db: &mut SqliteDatabase,
+    encoding: NameEncoding,
+    cache: &ChunkCache,
+) -> Result<(), Error> {
+    let mut trans = db.connection.transaction()?;
+    let chunk_hash = ChunkHash::load(&trans)?;
+
+    let mut names = list_files(&mut trans, encoding)?;
+    names.sort();
+
+    let mut checked = 0;
+    let mut unchecked = 0;
+    let mut faults = 0;
+    for name in names {
+        let file = get_file(&mut trans, name, encoding)?;
+        let expected = match &file.content_hash {
+            Some(expected) => expected,
+            None => {
+                unchecked += 1;
+                continue;
+            }
+        };
+
+        checked += 1;
+        let mut checksum = chunk_hash.checksum();
+        let fault = match write_file_to(&mut trans, &file, cache, &mut checksum, write_zeros) {
+            Ok(()) if checksum.hex() == *expected => continue,
+            Ok(()) => "content doesn't match its hash".to_owned(),
+            Err(e) => format!("can't be read: {}", e),
+        };
+        println!(
+            "{}: {}",
+            file.name.display(),
+            paint(Stream::Stdout, Style::Bad, fault)
+        );
+        faults += 1;
+    }
+
+    let style = if faults > 0 { Style::Bad } else { Style::Good };
+    println!(
+        "{} files checked, {} faulty",
+        checked,
+        paint(Stream::Stdout, style, faults)
+    );
+    if unchecked > 0 {
+        println!(
+            "{} files have no content hash and were skipped; Migrate --compute-file-hashes \
+             stores one",
+            unchecked
+        );
+    }
+
+    if faults > 0 {
+        return Err(format_err!("{} files failed verification", faults));
+    }
+
+    Ok(())
+}
+
+/// How many chunks `--repair-compression` rewrites per transaction.
+const RECOMPRESS_BATCH: usize = 256;
+
+/// Checks every zstd chunk not already at `level` and recompresses it at `level`, replacing
+/// the stored data only if it gets smaller. The hash is of the uncompressed content, so
+/// nothing referring to the chunk changes. Chunks Add found incompressible are left as is.
+fn repair_compression_cmd(
+    db: &mut SqliteDatabase,
+    target: Compression,
+    verbosity: u8,
+) -> Result<(), Error> {
+    let cipher = crypt::cipher(&db.connection)?;
+    let chunk_hash = ChunkHash::load(&db.connection)?;
+    let mut hashes = Vec::new();
+    {
+        let mut stmt = db.connection.prepare(
+            "SELECT hash FROM chunks
+            WHERE data IS NOT NULL AND NOT incompressible AND NOT (codec=? AND level IS ?)",
+        )?;
+        let params = [&target.codec() as &dyn ToSql, &target.level()];
+        for hash in stmt.query_map(&params, |row| row.get::<_, String>(0))? {
+            hashes.push(hash?);
+        }
+    }
+
+    let mut checked = 0;
+    let mut rewritten = 0;
+    let mut reclaimed = 0;
+    let mut faults = 0;
+
+    for batch in hashes.chunks(RECOMPRESS_BATCH) {
+        let trans = db.connection.transaction()?;
+
+        let mut stored = Vec::new();
+        for hash in batch {
+            let (data, codec, checksum): (Vec<u8>, String, Option<i64>) = trans.query_row(
+                "SELECT data, codec, checksum FROM chunks WHERE hash=?",
+                &[hash],
+                |row| (row.get(0), row.get(1), row.get(2)),
+            )?;
+            stored.push((hash, data, codec, checksum));
+        }
+
+        let results = stored
+            .into_par_iter()
+            .map(|(hash, data, codec, checksum)| {
+                // Switching codec is what was asked for, even if it takes more space.
+                let convert = codec != target.codec();
+                if checksum.is_some_and(|c| c != checksum_blob(&data)) {
+                    return Ok((hash, data.len(), convert, Err(ChunkFault::BlobCorrupted)));
+                }
+                let opened = match crypt::open(cipher, data.clone(), hash.as_bytes(), String::new) {
+                    Ok(opened) => opened,
+                    Err(_) => {
+                        return Ok((hash, data.len(), convert, Err(ChunkFault::Undecryptable)))
+                    }
+                };
+                let decoded = match decompress(&codec, opened) {
+                    Ok(decoded) => decoded,
+                    Err(_) => {
+                        return Ok((hash, data.len(), convert, Err(ChunkFault::BlobCorrupted)))
+                    }
+                };
+                if chunk_hash.of(&decoded) != *hash {
+                    return Ok((hash, data.len(), convert, Err(ChunkFault::ContentMismatch)));
+                }
+
+                let compressed = target.compress(&decoded)?;
+                let sealed = crypt::seal(cipher, compressed, hash.as_bytes())?;
+                Ok((hash, data.len(), convert, Ok(sealed)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        for (hash, old_len, convert, result) in results {
+            checked += 1;
+            match result {
+                Ok(data) if convert || data.len() < old_len => {
+                    trans.execute(
+                        "UPDATE chunks SET data=?, codec=?, level=?, checksum=?, size=?
+                        WHERE hash=?",
+                        &[
+                            &data as &dyn ToSql,
+                            &target.codec(),
+                            &target.level(),
+                            &checksum_blob(&data),
+                            &(data.len() as i64),
+                            hash,
+                        ],
+                    )?;
+                    rewritten += 1;
+                    reclaimed += old_len as i64 - data.len() as i64;
+                }
+                Ok(_) => {}
+                Err(fault) => {
+                    println!("{}: {}", hash, fault.describe());
+                    faults += 1;
+                }
+            }
+        }
+
+        trans.commit()?;
+
+        if verbosity > 0 {
+            eprintln!("checked {} of {} chunks", checked, hashes.len());
+        }
+    }
+
+    if auto_vacuum_mode(&db.connection)? == "incremental" {
+        db.connection.execute_batch("PRAGMA incremental_vacuum;")?;
+    }
+
+    let target = match target.level() {
+        Some(level) => format!("{} level {}", target.codec(), level),
+        None => target.codec().to_owned(),
+    };
+    println!(
+        "{} of {} chunks recompressed as {}, {} bytes reclaimed",
+        rewritten, checked, target, reclaimed
+    );
+
+    if faults > 0 {
+        return Err(format_err!("{} chunks failed verification", faults));
+    }
+
+    Ok(())
+}
+
+/// Runs --on-success or --on-failure, whichever an `op` that finished with exit status `code`
+/// calls for, with `vars` added to its environment. A failing hook is only an error with
+/// --hook-strict, and only after a success, so it never hides why the operation failed.
+fn run_hook(opt: &CommonOpt, op: &str, code: i32, vars: &[(&str, u64)]) -> Result<(), Error> {
+    let hook = match (code, &opt.hooks.on_success, &opt.hooks.on_failure) {
+        (0, Some(hook), _) => hook,
+        (0, None, _) | (_, _, None) => return Ok(()),
+        (_, _, Some(hook)) => hook,
+    };
+
+    let mut command = shell_command(hook);
+    command
+        .env("CHSQLAR_OP", op)
+        .env("CHSQLAR_DB_PATH", &opt.database)
+        .env("CHSQLAR_EXIT_CODE", code.to_string());
+    for (name, value) in vars {
+        command.env(name, value.to_string());
+    }
+
+    let failure = match command.status() {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => format!("{} hook failed: {}", op, status),
+        Err(e) => format!("{} hook could not be run: {}", op, e),
+    };
+
+    if opt.hooks.hook_strict && code == 0 {
+        return Err(format_err!("{}", failure));
+    }
+    eprintln!("{}", paint(Stream::Stderr, Style::Bad, failure));
+
+    Ok(())
+}
+
+/// Hands --temp-dir to SQLite through SQLITE_TMPDIR, which it looks up once, when it first
+/// needs a scratch file. Called from `main` before the thread pool is built, as changing
+/// the environment while other threads may read it isn't safe.
+fn set_sqlite_temp_dir(opt: &DatabaseOpt) -> Result<(), Error> {
+    if let Some(dir) = &opt.temp_dir {
+        // SQLite would silently move on to the next place if the directory weren't usable.
+        if !dir.is_dir() {
+            return Err(format_err!(
+                "--temp-dir {} is not a directory",
+                dir.display()
+            ));
+        }
+        std::env::set_var("SQLITE_TMPDIR", dir);
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    let app = Opt::from_args();
+    let json_errors = app.opt.json_errors;
+    // Cmp exits with 1 for files that differ, so like cmp it uses 2 for errors.
+    let error_status = match app.cmd {
+        OptCommand::Cmp { .. } => 2,
+        _ => 1,
+    };
+    output::init(&app.opt.color);
+
+    // Everything runs inside the pool so that rayon's parallel iterators use it.
+    let result = set_sqlite_temp_dir(&app.opt.db_opt)
+        .and_then(|()| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(app.opt.threads.unwrap_or(0))
+                .build()
+                .map_err(Error::from)
+        })
+        .and_then(|pool| pool.install(|| run(app)));
+
+    match result {
+        Err(e) if json_errors => {
+            eprintln!("{}", error_json(&e));
+            std::process::exit(error_status);
+        }
+        // Returning the error prints it the same way, only without colour.
+        Err(e) if output::colored(Stream::Stderr) || error_status != 1 => {
+            eprintln!("{} {:?}", paint(Stream::Stderr, Style::Bad, "Error:"), e);
+            std::process::exit(error_status);
+        }
+        result => result,
+    }
+}
+
+fn run(app: Opt) -> Result<(), Error> {
+    // Completion must not create or lock the archive, so it never gets the normal open.
+    if let OptCommand::Complete {
+        subcommand,
+        partial,
+    } = &app.cmd
+    {
+        if COMPLETE_NAMES_FOR.contains(&subcommand.as_str()) {
+            let stdout = std::io::stdout();
+            let _ = complete_cmd(&app.opt.database, partial, &mut stdout.lock());
+        }
+        return Ok(());
+    }
+
+    // Nor does looking at how a file would be chunked.
+    if let OptCommand::ChunkFile { path, chunk_bits } = &app.cmd {
+        let hash = match ChunkHash::chosen(app.opt.hash.as_deref(), app.opt.hash_bytes)? {
+            Some(hash) => hash,
+            None if Path::new(&app.opt.database).exists() => ChunkHash::load(
+                &Connection::open_with_flags(&app.opt.database, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+            )?,
+            None => ChunkHash::Sha3,
+        };
+        let params = chunker_params_for(&app.opt, *chunk_bits)?;
+        let stdout = std::io::stdout();
+        return chunk_file_cmd(path, params, hash, &mut stdout.lock());
+    }
+    if let OptCommand::Bench { files } = &app.cmd {
+        return bench_cmd(files, chunker_params_for(&app.opt, None)?);
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(socket) = &app.opt.remote {
+            return daemon::remote_cmd(socket, app.cmd);
+        }
+    }
+
+    let mut db = SqliteDatabase::new(&app.opt.database, &app.opt.db_opt)?;
+    if app.cmd.writes() {
+        check_writable(&db.connection)?;
+    }
+    let budget = MemoryBudget::new(app.opt.memory_limit);
+    let cache = ChunkCache::drawing_on(app.opt.chunk_cache_mb * 1024 * 1024, &budget);
+
+    if let Some(dir) = &app.opt.store_dir {
+        set_store_dir(&db.connection, dir)?;
+    }
+    if app.opt.chunker.is_some() || app.opt.chunk_bits.is_some() || app.opt.chunk_size.is_some() {
+        let opt = &app.opt;
+        let force = app.cmd.forces_params();
+        set_chunking(
+            &db.connection,
+            opt.chunker,
+            opt.chunk_bits,
+            opt.chunk_size,
+            force,
+        )?;
+    }
+    if let Some(max_bytes) = app.opt.max_chunk_bytes {
+        set_meta(&db.connection, "max_chunk_bytes", &max_bytes)?;
+    }
+    if let Some(hash) = ChunkHash::chosen(app.opt.hash.as_deref(), app.opt.hash_bytes)? {
+        set_chunk_hash(&db.connection, hash)?;
+    }
+
+    let mut encoding = archive_name_encoding(&db.connection, app.opt.name_encoding)?;
+    crypt::set_key_file(app.opt.key_file.clone());
+    if app.opt.encrypt {
+        crypt::enable(&db.connection, app.opt.encrypt_names)?;
+    }
+    // Names are sealed and opened far from any connection, so the key is loaded up front.
+    if crypt::names_encrypted(&db.connection)? {
+        crypt::cipher(&db.connection)?;
+        encoding = NameEncoding::Sealed;
+    }
+
+    match app.cmd {
+        OptCommand::List {
+            long,
+            full_time,
+            dirs,
+            max_depth,
+            added_after,
+            modified_since,
+            null,
+            tags,
+        } => {
+            let end = if null { b'\0' } else { b'\n' };
+            if dirs {
+                let stdout = std::io::stdout();
+                list_dirs_cmd(&mut db, max_depth, end, encoding, &mut stdout.lock())?;
+            } else if let Some(other) = modified_since {
+                let stdout = std::io::stdout();
+                list_changes_cmd(&mut db, &other, end, encoding, &mut stdout.lock())?;
+            } else {
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                list_cmd(
+                    &mut db,
+                    long,
+                    full_time,
+                    added_after,
+                    &tags,
+                    end,
+                    encoding,
+                    &mut out,
+                )?;
+            }
+        }
+        OptCommand::Add { files, add_opt } => {
+            let verbosity = app.opt.verbosity;
+            let result = lock_archive_shared(&app.opt, "Add").and_then(|_lock| {
+                add_files_cmd(&mut db, files, add_opt, verbosity, encoding, &budget)
+            });
+            let summary = match result {
+                Ok(summary) => summary,
+                Err(e) => {
+                    run_hook(&app.opt, "add", 1, &[])?;
+                    return Err(e);
+                }
+            };
+
+            if !summary.failed.is_empty() {
+                let failed = format!("{} files could not be added:", summary.failed.len());
+                eprintln!("{}", paint(Stream::Stderr, Style::Bad, failed));
+                for f in &summary.failed {
+                    eprintln!("  {}", f.display());
+                }
+            }
+
+            if !summary.over_quota.is_empty() {
+                let full = format!(
+                    "Archive reached --max-db-size after storing {} files; not added:",
+                    summary.stored
+                );
+                eprintln!("{}", paint(Stream::Stderr, Style::Warning, full));
+                for f in &summary.over_quota {
+                    eprintln!("  {}", f.display());
+                }
+            } else if summary.interrupted {
+                let interrupted = format!(
+                    "Interrupted after storing {} files; run the same add again to continue",
+                    summary.stored
+                );
+                eprintln!("{}", paint(Stream::Stderr, Style::Warning, interrupted));
+            } else if !summary.out_of_time.is_empty() {
+                let out_of_time = format!(
+                    "Time budget used up after storing {} files; {}",
+                    summary.stored,
+                    remaining_work(&summary)
+                );
+                eprintln!("{}", paint(Stream::Stderr, Style::Warning, out_of_time));
+            }
+            warn_of_collisions(&db.connection)?;
+
+            let code = if !summary.over_quota.is_empty() {
+                3
+            } else if summary.interrupted {
+                130
+            } else if !summary.out_of_time.is_empty() {
+                4
+            } else if !summary.failed.is_empty() {
+                2
+            } else {
+                0
+            };
+            if !app.opt.quiet {
+                summary.metrics.report("add", app.opt.json_summary);
+            }
+
+            let vars = [
+                ("CHSQLAR_FILES_ADDED", summary.stored as u64),
+                (
+                    "CHSQLAR_BYTES_WRITTEN",
+                    summary.metrics.bytes_stored.load(Ordering::Relaxed),
+                ),
+            ];
+            run_hook(&app.opt, "add", code, &vars)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        OptCommand::Extract { files, extract_opt } => {
+            let result = extract_files_cmd(&mut db, files, extract_opt, encoding, &cache);
+            let (code, bytes) = match &result {
+                Ok(summary) => (0, summary.metrics.bytes_written.load(Ordering::Relaxed)),
+                Err(_) => (1, 0),
+            };
+            if let (Ok(summary), false) = (&result, app.opt.quiet) {
+                summary.metrics.report("extract", app.opt.json_summary);
+            }
+            if app.opt.verbosity > 0 {
+                budget.report();
+            }
+            run_hook(
+                &app.opt,
+                "extract",
+                code,
+                &[("CHSQLAR_BYTES_WRITTEN", bytes)],
+            )?;
+            result?;
+        }
+        OptCommand::Cat {
+            files,
+            no_expand_dirs,
+        } => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            cat_cmd(&mut db, files, !no_expand_dirs, encoding, &cache, &mut out)?;
+        }
+        OptCommand::PutBlob => {
+            put_blob_cmd(&mut db, &budget)?;
+        }
+        OptCommand::GetBlob { hash } => {
+            get_blob_cmd(&mut db, hash)?;
+        }
+        OptCommand::Stats => {
+            let stdout = std::io::stdout();
+            stats_cmd(&mut db, &mut stdout.lock())?;
+        }
+        OptCommand::Info => {
+            info_cmd(&db)?;
+        }
+        OptCommand::Migrate {
+            compute_file_hashes,
+        } => {
+            migrate_cmd(&mut db)?;
+            if compute_file_hashes {
+                let _lock = lock_archive(&app.opt, "Migrate --compute-file-hashes")?;
+                let filled = fill_content_hashes(&mut db, false, encoding, &cache)?;
+                println!("computed the content hashes of {} files", filled);
+            }
+        }
+        OptCommand::Orphans => {
+            orphans_cmd(&mut db)?;
+        }
+        OptCommand::Doctor { fix } => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            let healthy = match fix {
+                true => {
+                    let _lock = lock_archive(&app.opt, "Doctor --fix")?;
+                    doctor::doctor_cmd(&mut db, true, encoding, &mut out)?
+                }
+                false => doctor::doctor_cmd(&mut db, false, encoding, &mut out)?,
+            };
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        OptCommand::Du {
+            depth,
+            shared,
+            bytes,
+        } => {
+            du_cmd(&mut db, depth, &shared, bytes, encoding)?;
+        }
+        OptCommand::Compact {
+            rebuild_refcounts,
+            dry_run: true,
+            ..
+        } => {
+            let stdout = std::io::stdout();
+            compact_dry_run(&mut db, rebuild_refcounts, &mut stdout.lock())?;
+        }
+        OptCommand::Compact {
+            chunk_bits,
+            chunker,
+            chunk_size,
+            hash,
+            hash_bytes,
+            vacuum,
+            rebuild_refcounts,
+            dry_run: false,
+        } => {
+            let chunk_hash = ChunkHash::chosen(hash.as_deref(), hash_bytes)?;
+            let verbosity = app.opt.verbosity;
+            let result = lock_archive(&app.opt, "Compact").and_then(|_lock| {
+                compact_cmd(
+                    &mut db,
+                    chunker,
+                    chunk_bits,
+                    chunk_size,
+                    chunk_hash,
+                    vacuum,
+                    rebuild_refcounts,
+                    verbosity,
+                    &budget,
+                    encoding,
+                    &cache,
+                )
+            });
+            run_hook(&app.opt, "compact", result.is_err() as i32, &[])?;
+            result?;
+        }
+        OptCommand::Split { max_size, out } => {
+            split_cmd(&mut db, max_size, &out, &app.opt.db_opt, app.opt.verbosity)?;
+        }
+        OptCommand::Merge {
+            inputs,
+            on_conflict,
+        } => {
+            let _lock = lock_archive_shared(&app.opt, "Merge")?;
+            let verbosity = app.opt.verbosity;
+            merge_cmd(&mut db, &inputs, &on_conflict, verbosity)?;
+        }
+        OptCommand::Pull { from, patterns } => {
+            let _lock = lock_archive_shared(&app.opt, "Pull")?;
+            pull_cmd(&mut db, &from, patterns, encoding, app.opt.verbosity)?;
+        }
+        OptCommand::Verify {
+            fast,
+            repair_compression: None,
+            files: false,
+            ..
+        } => {
+            verify_cmd(&mut db, fast)?;
+        }
+        OptCommand::Verify { files: true, .. } => {
+            verify_files_cmd(&mut db, encoding, &cache)?;
+        }
+        OptCommand::Verify {
+            repair_compression: Some(target),
+            use_dict,
+            ..
+        } => {
+            let target = match (use_dict, target, dict::current(&db.connection)?) {
+                (false, target, _) => target,
+                (true, Compression::Zstd(level), Some(id)) => Compression::ZstdDict(level, id),
+                (true, Compression::Zstd(_), None) => {
+                    return Err(format_err!(
+                        "--use-dict needs a dictionary, which TrainDict trains"
+                    ))
+                }
+                (true, _, _) => return Err(format_err!("--use-dict only applies to a zstd level")),
+            };
+            let _lock = lock_archive(&app.opt, "Verify --repair-compression")?;
+            repair_compression_cmd(&mut db, target, app.opt.verbosity)?;
+        }
+        OptCommand::Cmp {
+            first,
+            second,
+            bytes,
+        } => {
+            if !cmp_cmd(&mut db, &first, &second, bytes, encoding, &cache)? {
+                std::process::exit(1);
+            }
+        }
+        OptCommand::Manifest { format } => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            manifest_cmd(&mut db, &format, encoding, &cache, &mut out)?;
+        }
+        OptCommand::Complete { .. } | OptCommand::ChunkFile { .. } | OptCommand::Bench { .. } => {
+            unreachable!()
+        }
+        OptCommand::Checksum { algo, prefix } => {
+            let verbosity = app.opt.verbosity;
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            checksum_cmd(
+                &mut db, &algo, prefix, verbosity, encoding, &cache, &mut out,
+            )?;
+        }
+        OptCommand::SetTag { file, key, value } => {
+            set_tag_cmd(&mut db, file, &key, &value, encoding)?;
+        }
+        OptCommand::GetTags { file } => {
+            let stdout = std::io::stdout();
+            get_tags_cmd(&mut db, file, encoding, &mut stdout.lock())?;
+        }
+        OptCommand::TrainDict { samples, max_size } => {
+            let _lock = lock_archive(&app.opt, "TrainDict")?;
+            train_dict_cmd(&mut db, samples, max_size as usize)?;
+        }
+        #[cfg(unix)]
+        OptCommand::Daemon { socket, add_opt } => {
+            let _lock = lock_archive(&app.opt, "Daemon")?;
+            // Asking for a passphrase has to happen now, not on the first request.
+            crypt::cipher(&db.connection)?;
+            daemon::daemon_cmd(&mut db, &socket, add_opt, encoding, &app.opt)?;
         }
-        OptCommand::Add { files } => {
-            add_files_cmd(&mut db, files)?;
+        OptCommand::Watch {
+            paths,
+            debounce_ms,
+            add_opt,
+        } => {
+            // Held for as long as it watches, as each change it sees is an Add.
+            let _lock = lock_archive_shared(&app.opt, "Watch")?;
+            let debounce = std::time::Duration::from_millis(debounce_ms);
+            crypt::cipher(&db.connection)?;
+            watch::watch_cmd(&mut db, paths, debounce, add_opt, encoding, &app.opt)?;
         }
-        OptCommand::Extract { files } => {
-            extract_files_cmd(&mut db, files)?;
+        #[cfg(feature = "tui")]
+        OptCommand::Browse => {
+            let _lock = lock_archive(&app.opt, "Browse")?;
+            // Nor can it once the terminal belongs to the browser.
+            crypt::cipher(&db.connection)?;
+            browse::browse_cmd(&mut db, encoding, &cache)?;
         }
     }
 
@@ -0,0 +1,106 @@
+//! Colour for output read by people. Each stream gets colour only if `--color` says so, or
+//! with the default "auto" if it's a terminal and `NO_COLOR` isn't set, so output going to
+//! a pipe or file stays exactly as it is without colour.
+
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STDOUT_COLOR: AtomicBool = AtomicBool::new(false);
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Decides once per stream whether to colour it, from the `--color` choice.
+pub(crate) fn init(choice: &str) {
+    let auto =
+        |terminal: bool| terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    let (stdout, stderr) = match choice {
+        "always" => (true, true),
+        "never" => (false, false),
+        _ => (
+            auto(std::io::stdout().is_terminal()),
+            auto(std::io::stderr().is_terminal()),
+        ),
+    };
+
+    STDOUT_COLOR.store(stdout, Ordering::Relaxed);
+    STDERR_COLOR.store(stderr, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+pub(crate) fn colored(stream: Stream) -> bool {
+    match stream {
+        Stream::Stdout => STDOUT_COLOR.load(Ordering::Relaxed),
+        Stream::Stderr => STDERR_COLOR.load(Ordering::Relaxed),
+    }
+}
+
+/// What a piece of output is, which decides its colour.
+#[derive(Clone, Copy)]
+pub(crate) enum Style {
+    Size,
+    Dir,
+    Good,
+    Bad,
+    Warning,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Size => "36",
+            Style::Dir => "1;34",
+            Style::Good => "32",
+            Style::Bad => "31",
+            Style::Warning => "33",
+        }
+    }
+}
+
+/// `value` displayed in `style` if `stream` is coloured, and as is otherwise.
+pub(crate) struct Painted<T> {
+    value: T,
+    style: Style,
+    stream: Stream,
+}
+
+pub(crate) fn paint<T: fmt::Display>(stream: Stream, style: Style, value: T) -> Painted<T> {
+    Painted {
+        value,
+        style,
+        stream,
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !colored(self.stream) {
+            return self.value.fmt(f);
+        }
+
+        // Padding applies to the text, not the escape codes around it.
+        write!(f, "\x1b[{}m", self.style.code())?;
+        self.value.fmt(f)?;
+        write!(f, "\x1b[0m")
+    }
+}
+
+/// Writes raw `bytes`, such as a file name that may not be UTF-8, in `style`.
+pub(crate) fn write_painted<W: Write>(
+    out: &mut W,
+    stream: Stream,
+    style: Style,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    if !colored(stream) {
+        return out.write_all(bytes);
+    }
+
+    write!(out, "\x1b[{}m", style.code())?;
+    out.write_all(bytes)?;
+    write!(out, "\x1b[0m")
+}
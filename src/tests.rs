@@ -0,0 +1,3890 @@
+//! Tests that run commands against archives in scratch directories, the way the command
+//! line would.
+
+use crate::{
+    add_files, archive_name_encoding, cat_cmd, check_windows_name, checksum_blob, checksum_cmd,
+    chunk_data, chunk_file_cmd, chunker, chunker_params_for, cmp_cmd, compact_dry_run,
+    complete_cmd, conflict, crypt, decompress, doctor::doctor_cmd, du_totals, error_json,
+    extract_file_to, extract_files_cmd, extract_tar, find_orphans, format_mtime, get_blob,
+    get_chunk, get_file, get_meta, get_tags_cmd, human_size, install_interrupt_handler,
+    list_changes_cmd, list_cmd, list_dirs_cmd, manifest_cmd, normalise_path, order_files,
+    parse_time, put_blob, rebuild_refcounts, remove_files, run, same_mtime, set_meta,
+    set_sqlite_temp_dir, stats_cmd, verify_chunks, AddSummary, Checksum, ChsqlarError, ChunkCache,
+    ChunkHash, Compression, ExtractSummary, File, MemoryBudget, NameEncoding, Opt, OptCommand,
+    SqliteDatabase, INTERRUPTED, SCHEMA_VERSION,
+};
+use failure::{format_err, Error};
+use rusqlite::types::ToSql;
+use rusqlite::NO_PARAMS;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, MutexGuard};
+use structopt::StructOpt;
+
+/// Commands resolve names against the working directory, which all tests share, so tests
+/// with a scratch directory take turns.
+static CWD: Mutex<()> = Mutex::new(());
+
+/// A directory of one test's own, which is the working directory while it lives. The
+/// archive is "a.db" in it.
+struct Scratch {
+    dir: PathBuf,
+    _cwd: MutexGuard<'static, ()>,
+}
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        // A test that panicked poisons the lock, which says nothing about the next one.
+        let cwd = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir =
+            std::env::temp_dir().join(format!("chsqlar-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+
+        Scratch { dir, _cwd: cwd }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Creates the file `name` holding `data`, and any directories it's in.
+    fn write(&self, name: &str, data: &[u8]) {
+        let path = self.path(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    fn read(&self, name: &str) -> Vec<u8> {
+        fs::read(self.path(name)).unwrap()
+    }
+
+    fn parse(&self, args: &[&str]) -> Result<Opt, Error> {
+        self.parse_on("a.db", args)
+    }
+
+    fn parse_on(&self, archive: &str, args: &[&str]) -> Result<Opt, Error> {
+        let head = ["chsqlar", archive];
+        let args = head.iter().chain(args);
+        Opt::from_iter_safe(args).map_err(|e| format_err!("{}", e))
+    }
+
+    /// Runs the command line `args` on the archive.
+    fn run(&self, args: &[&str]) -> Result<(), Error> {
+        run(self.parse(args)?)
+    }
+
+    /// Runs the command line `args` on another archive in the directory.
+    fn run_on(&self, archive: &str, args: &[&str]) -> Result<(), Error> {
+        run(self.parse_on(archive, args)?)
+    }
+
+    /// Adds with the Add options `args`, returning what happened. Add itself isn't run, as
+    /// it exits on partial failure.
+    fn add(&self, args: &[&str]) -> Result<AddSummary, Error> {
+        self.add_within(&MemoryBudget::new(None), args)
+    }
+
+    /// Adds like `add`, drawing on `budget`.
+    fn add_within(&self, budget: &MemoryBudget, args: &[&str]) -> Result<AddSummary, Error> {
+        let app = self.parse(&[&["Add"], args].concat())?;
+        let (files, add_opt) = match app.cmd {
+            OptCommand::Add { files, add_opt } => (files, add_opt),
+            _ => unreachable!(),
+        };
+
+        let mut db = self.open();
+        let encoding = archive_name_encoding(&db.connection, app.opt.name_encoding)?;
+        add_files(&mut db, &self.dir, files, add_opt, 0, encoding, budget)
+    }
+
+    /// Extracts with the Extract options `args`, returning what happened.
+    fn extract(&self, args: &[&str]) -> Result<ExtractSummary, Error> {
+        let app = self.parse(&[&["Extract"], args].concat())?;
+        let (files, extract_opt) = match app.cmd {
+            OptCommand::Extract { files, extract_opt } => (files, extract_opt),
+            _ => unreachable!(),
+        };
+
+        let mut db = self.open();
+        let encoding = archive_name_encoding(&db.connection, app.opt.name_encoding)?;
+        let cache = ChunkCache::new(0);
+        extract_files_cmd(&mut db, files, extract_opt, encoding, &cache)
+    }
+
+    fn open(&self) -> SqliteDatabase {
+        let app = self.parse(&["Info"]).unwrap();
+        SqliteDatabase::new(&app.opt.database, &app.opt.db_opt).unwrap()
+    }
+
+    /// The value of PRAGMA `name` with the archive opened with the options `args`.
+    fn pragma(&self, args: &[&str], name: &str) -> i64 {
+        let app = self.parse(&[args, &["Info"]].concat()).unwrap();
+        let db = SqliteDatabase::new(&app.opt.database, &app.opt.db_opt).unwrap();
+        let sql = format!("PRAGMA {}", name);
+        db.connection
+            .query_row(&sql, NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    /// The row of the stored file `name`.
+    fn stored(&self, name: &str) -> File {
+        let mut db = self.open();
+        let mut trans = db.connection.transaction().unwrap();
+        get_file(&mut trans, name.into(), NameEncoding::Bytes).unwrap()
+    }
+
+    /// The stored content of `name`, as Cat would print it.
+    fn cat(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let mut db = self.open();
+        let mut trans = db.connection.transaction()?;
+        let mut out = Vec::new();
+        let cache = ChunkCache::new(0);
+        extract_file_to(
+            &mut trans,
+            name.into(),
+            NameEncoding::Bytes,
+            &cache,
+            &mut out,
+        )?;
+
+        Ok(out)
+    }
+
+    /// The codec and level of each chunk of the stored file `name`.
+    fn treatments(&self, name: &str) -> Vec<(String, Option<i32>)> {
+        let db = self.open();
+        let sql = "SELECT codec, level FROM chunks WHERE hash=?";
+        let chunks = self.stored(name).chunks;
+        let rows = chunks.iter().map(|hash| {
+            db.connection
+                .query_row(sql, &[hash], |row| (row.get(0), row.get(1)))
+                .unwrap()
+        });
+        rows.collect()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(std::env::temp_dir());
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Some bytes that compress, and that differ between seeds.
+fn sample(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i / 7) as u8 ^ (i % 251) as u8 ^ seed)
+        .collect()
+}
+
+/// Bytes that don't compress.
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    // Xorshift never leaves zero, and distinct seeds should give distinct bytes.
+    let mut state = seed * 2 + 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+#[test]
+fn filter_cat_round_trips() {
+    let s = Scratch::new("filter-cat");
+    let data = sample(100_000, 1);
+    s.write("f", &data);
+    s.add(&["--filter", "cat", "f"]).unwrap();
+    assert_eq!(s.cat("f").unwrap(), data);
+
+    fs::remove_file(s.path("f")).unwrap();
+    s.run(&["Extract", "--unfilter", "cat", "f"]).unwrap();
+    assert_eq!(s.read("f"), data);
+}
+
+#[test]
+fn reversible_filter_round_trips() {
+    let s = Scratch::new("filter-rot13");
+    let data = b"the quick brown fox jumps over the lazy dog\n".repeat(100);
+    s.write("f", &data);
+    let rot13 = "tr a-z n-za-m";
+    s.add(&["--filter", rot13, "f"]).unwrap();
+
+    // What's stored is the filter's output.
+    let stored = s.cat("f").unwrap();
+    assert_eq!(
+        stored,
+        b"gur dhvpx oebja sbk whzcf bire gur ynml qbt\n".repeat(100)
+    );
+
+    fs::remove_file(s.path("f")).unwrap();
+    assert!(s.run(&["Extract", "f"]).is_err());
+    assert!(!exists(&s.path("f")));
+
+    s.run(&["Extract", "--unfilter", rot13, "f"]).unwrap();
+    assert_eq!(s.read("f"), data);
+}
+
+const FOX: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+#[test]
+fn hashes_match_known_digests() {
+    let sha3 = "01dedd5de4ef14642445ba5f5b97c15e47b9ad931326e4b0727cd94cefc44fff\
+                23f07bf543139939b49128caf436dc1bdee54fcb24023a08d9403f9b4bf0d450";
+    let sha256 = "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592";
+
+    assert_eq!(ChunkHash::Sha3.of(FOX), sha3);
+    for (algo, digest) in &[("sha3-512", sha3), ("sha256", sha256)] {
+        let mut checksum = Checksum::new(algo).unwrap();
+        checksum.update(&FOX[..10]);
+        checksum.update(&FOX[10..]);
+        assert_eq!(checksum.hex(), *digest);
+    }
+
+    let s = Scratch::new("digests");
+    s.write("fox", FOX);
+    s.add(&["fox"]).unwrap();
+    let stored = s.stored("fox");
+    assert_eq!(stored.sha256.as_deref(), Some(sha256));
+    assert_eq!(stored.content_hash.as_deref(), Some(sha3));
+}
+
+#[test]
+fn memory_limit_batches_chunks() {
+    // A batch costs the size of its compressed output, taken to be its input's.
+    let budget = MemoryBudget::new(Some(1200));
+    let mut chunks = VecDeque::from(vec![vec![0; 300]; 10]);
+    assert_eq!(budget.next_batch(&mut chunks).len(), 4);
+    assert_eq!(chunks.len(), 6);
+
+    // What others have drawn leaves less room.
+    let held = budget.draw(600);
+    assert_eq!(budget.next_batch(&mut chunks).len(), 2);
+    drop(held);
+    assert_eq!(budget.available(), 1200);
+
+    // A chunk over the limit still goes, on its own.
+    let budget = MemoryBudget::new(Some(100));
+    let mut chunks = VecDeque::from(vec![vec![0; 300], vec![0; 10]]);
+    assert_eq!(budget.next_batch(&mut chunks).len(), 1);
+    assert_eq!(budget.next_batch(&mut chunks).len(), 1);
+
+    let mut chunks = VecDeque::from(vec![vec![0; 300]; 10]);
+    assert_eq!(MemoryBudget::new(None).next_batch(&mut chunks).len(), 10);
+}
+
+#[test]
+fn memory_limit_reads_files_a_piece_at_a_time() {
+    let data = noise(300_000, 1);
+    for chunking in [
+        &["--chunk-bits", "12"][..],
+        &["--chunker", "zpaq", "--chunk-bits", "12"],
+        &["--chunker", "fixed", "--chunk-size", "3000"],
+    ] {
+        let s = Scratch::new("memory-limit-pieces");
+        s.run(&[chunking, &["Migrate"]].concat()).unwrap();
+        s.write("whole", &data);
+        s.write("pieces", &data);
+        s.add(&["whole"]).unwrap();
+        s.add_within(&MemoryBudget::new(Some(100_000)), &["pieces"])
+            .unwrap();
+
+        // Every chunk but the last of a piece ends where it would have, so the rest are cut
+        // again with the next piece and the chunks come out the same.
+        let (whole, pieces) = (s.stored("whole"), s.stored("pieces"));
+        assert!(whole.chunks.len() > 4, "{:?}", chunking);
+        assert_eq!(whole.chunks, pieces.chunks, "{:?}", chunking);
+        assert_eq!(whole.sha256, pieces.sha256);
+        assert_eq!(whole.content_hash, pieces.content_hash);
+        assert_eq!(s.cat("pieces").unwrap(), data);
+    }
+}
+
+#[test]
+fn memory_limit_bounds_a_file_bigger_than_it() {
+    let s = Scratch::new("memory-limit-peak");
+    s.run(&["--chunk-bits", "12", "Migrate"]).unwrap();
+    s.write("big", &noise(1_000_000, 1));
+    s.write("other", &noise(1_000_000, 2));
+
+    let budget = MemoryBudget::new(Some(200_000));
+    s.add_within(&budget, &["big"]).unwrap();
+    assert!(budget.peak() > 0);
+    assert!(budget.peak() <= 200_000, "peak {}", budget.peak());
+    assert_eq!(budget.available(), 200_000);
+
+    // Without a limit the whole file is read at once.
+    let budget = MemoryBudget::new(None);
+    s.add_within(&budget, &["other"]).unwrap();
+    assert!(budget.peak() >= 1_000_000, "peak {}", budget.peak());
+}
+
+#[test]
+fn chunk_cache_gives_way_to_the_memory_limit() {
+    let s = Scratch::new("memory-limit-cache");
+    s.run(&["--chunker", "fixed", "--chunk-size", "4096", "Migrate"])
+        .unwrap();
+    s.write("f", &noise(5 * 4096, 1));
+    s.add(&["f"]).unwrap();
+    let chunks = s.stored("f").chunks;
+    let db = s.open();
+
+    let budget = MemoryBudget::new(Some(10_000));
+    let cache = ChunkCache::drawing_on(1 << 20, &budget);
+    for hash in &chunks {
+        cache.get(&db.connection, hash).unwrap();
+    }
+    // Only two chunks fit beside each other.
+    assert_eq!(budget.available(), 10_000 - 2 * 4096);
+
+    // Room others draw on is taken back from the cache when it next reads a chunk.
+    let held = budget.draw(5000);
+    cache.get(&db.connection, &chunks[0]).unwrap();
+    assert_eq!(budget.available(), 10_000 - 5000 - 4096);
+    drop(held);
+    drop(cache);
+    assert_eq!(budget.available(), 10_000);
+}
+
+#[test]
+fn name_encoding_is_recorded_at_creation() {
+    let s = Scratch::new("name-encoding");
+    s.write("100%", b"x");
+    s.run(&["--name-encoding", "percent", "Migrate"]).unwrap();
+    assert!(s.run(&["--name-encoding", "bytes", "Migrate"]).is_err());
+
+    // Later runs use the recorded encoding without being told.
+    s.add(&["100%"]).unwrap();
+    let name: String = s
+        .open()
+        .connection
+        .query_row("SELECT name FROM files", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    assert_eq!(name, "100%25");
+}
+
+#[test]
+fn name_encoding_of_older_archives_is_not_recorded() {
+    let s = Scratch::new("name-encoding-old");
+    s.write("f", b"x");
+    s.add(&["f"]).unwrap();
+    let db = s.open();
+    db.connection
+        .execute("DELETE FROM meta WHERE key='name_encoding'", NO_PARAMS)
+        .unwrap();
+
+    s.run(&["--name-encoding", "utf8", "Migrate"]).unwrap();
+    s.run(&["--name-encoding", "percent", "Migrate"]).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn owner_is_restored_as_asked() {
+    use crate::{user_id, user_name};
+    use std::os::unix::fs::MetadataExt;
+
+    let s = Scratch::new("owner");
+    s.write("f", b"x");
+    s.add(&["--preserve-ownership", "f"]).unwrap();
+    let uid = fs::metadata(s.path("f")).unwrap().uid();
+    let stored = s.stored("f");
+    assert_eq!(stored.owner.map(|(uid, _)| uid), Some(uid));
+    assert_eq!(stored.owner_user, user_name(uid));
+    assert_eq!(stored.owner_user.as_deref().and_then(user_id), Some(uid));
+
+    // As though added on a machine where the same user has another id.
+    let other = uid + 12345;
+    s.open()
+        .connection
+        .execute("UPDATE files SET owner_uid=?", &[&other])
+        .unwrap();
+    let extract = |owner: &str| {
+        fs::remove_file(s.path("f")).unwrap();
+        s.run(&["Extract", "--owner", owner, "f"]).unwrap();
+        fs::metadata(s.path("f")).unwrap().uid()
+    };
+    assert_eq!(extract("skip"), uid);
+    assert_eq!(extract("names"), uid);
+    // Only root can give a file away.
+    let numeric = extract("numeric");
+    assert_eq!(numeric == other, unsafe { libc::geteuid() } == 0);
+
+    // A name this system doesn't have falls back to the stored id, and is counted.
+    s.open()
+        .connection
+        .execute("UPDATE files SET owner_user='no-such-user-here'", NO_PARAMS)
+        .unwrap();
+    fs::remove_file(s.path("f")).unwrap();
+    let summary = s.extract(&["--owner", "names", "f"]).unwrap();
+    let unknown = Some((1, "user no-such-user-here".to_owned()));
+    assert_eq!(summary.unknown_owners, unknown);
+    let uid = fs::metadata(s.path("f")).unwrap().uid();
+    assert_eq!(uid == other, unsafe { libc::geteuid() } == 0);
+    fs::remove_file(s.path("f")).unwrap();
+    let summary = s.extract(&["--owner", "numeric", "f"]).unwrap();
+    assert_eq!(summary.unknown_owners, None);
+}
+
+#[test]
+fn copies_keep_chunks_incompressible() {
+    let s = Scratch::new("copy-incompressible");
+    s.write("f", &noise(100_000, 1));
+    s.add(&["f"]).unwrap();
+    let count = |archive: &str| -> i64 {
+        let connection = rusqlite::Connection::open(s.path(archive)).unwrap();
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM chunks WHERE incompressible",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    let stored = count("a.db");
+    assert!(stored > 0);
+
+    s.run_on("pulled.db", &["Pull", "--from", "a.db"]).unwrap();
+    s.run_on("merged.db", &["Merge", "a.db"]).unwrap();
+    assert_eq!(count("pulled.db"), stored);
+    assert_eq!(count("merged.db"), stored);
+}
+
+#[test]
+fn cmp_looks_past_chunk_lists() {
+    let s = Scratch::new("cmp");
+    let data = sample(3000, 1);
+    let mut other = data.clone();
+    other[1000] ^= 1;
+    s.write("inline", &data);
+    s.write("chunked", &data);
+    s.write("other", &other);
+    s.add(&["inline"]).unwrap();
+    s.add(&["--inline-threshold", "0", "chunked", "other"])
+        .unwrap();
+
+    let cmp = |first: &str, second: &str, bytes: bool| {
+        let mut db = s.open();
+        let (first, second) = (Path::new(first), Path::new(second));
+        let cache = ChunkCache::new(0);
+        cmp_cmd(&mut db, first, second, bytes, NameEncoding::Bytes, &cache).unwrap()
+    };
+    for &bytes in &[false, true] {
+        assert!(cmp("inline", "chunked", bytes));
+        assert!(!cmp("inline", "other", bytes));
+    }
+
+    // Without hashes, it reads the content.
+    s.open()
+        .connection
+        .execute("UPDATE files SET sha256=NULL, content_hash=NULL", NO_PARAMS)
+        .unwrap();
+    assert!(cmp("inline", "chunked", false));
+    assert!(!cmp("inline", "other", false));
+}
+
+#[test]
+fn watch_stores_changes_and_skips_the_unchanged() {
+    use crate::watch::{ingest, watch_add_opt};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let s = Scratch::new("watch-ingest");
+    let write = |name: &str, data: &[u8]| {
+        s.write(name, data);
+        let f = fs::File::options().write(true).open(s.path(name)).unwrap();
+        f.set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .unwrap();
+    };
+    write("d/f", b"first");
+    write("d/g", b"kept");
+    let Opt { opt, cmd } = s.parse(&["Watch", "d"]).unwrap();
+    let (paths, add_opt) = match cmd {
+        OptCommand::Watch { paths, add_opt, .. } => (paths, watch_add_opt(add_opt)),
+        _ => unreachable!(),
+    };
+    let mut db = s.open();
+    let budget = MemoryBudget::new(None);
+    let mut ingest = |changed: Vec<PathBuf>| {
+        let encoding = NameEncoding::Bytes;
+        ingest(&mut db, &s.dir, changed, &add_opt, encoding, &opt, &budget).unwrap()
+    };
+
+    // The first pass over the watched directory.
+    ingest(paths);
+    assert_eq!(s.cat("d/f").unwrap(), b"first");
+    assert_eq!(s.cat("d/g").unwrap(), b"kept");
+
+    // A modify that changes the size is stored.
+    write("d/f", b"second!");
+    ingest(vec![PathBuf::from("d/f")]);
+    assert_eq!(s.cat("d/f").unwrap(), b"second!");
+
+    // One that leaves the size and mtime as they were isn't read at all.
+    write("d/g", b"lost");
+    ingest(vec![PathBuf::from("d/g"), PathBuf::from("d")]);
+    assert_eq!(s.cat("d/g").unwrap(), b"kept");
+}
+
+#[test]
+fn watch_ignores_only_the_archive() {
+    use crate::watch::{archive_paths, is_watched};
+
+    let s = Scratch::new("watch");
+    for name in &[
+        "a.db",
+        "a.db-wal",
+        "a.db-notes.txt",
+        "store/chunk",
+        "stored",
+        "f",
+    ] {
+        s.write(name, b"x");
+    }
+    // As the archive records it.
+    let store = fs::canonicalize(s.path("store")).unwrap();
+    let ignored = archive_paths(Path::new("a.db"), Some(store)).unwrap();
+
+    for name in &["a.db", "a.db-wal", "store", "store/chunk"] {
+        assert!(!is_watched(Path::new(name), &ignored), "{}", name);
+    }
+    for name in &["a.db-notes.txt", "stored", "f"] {
+        assert!(is_watched(Path::new(name), &ignored), "{}", name);
+    }
+    // Gone by the time the change is handled.
+    assert!(!is_watched(Path::new("missing"), &ignored));
+}
+
+#[test]
+fn zstd_level_is_saved_each_time() {
+    let s = Scratch::new("zstd-level");
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        s.write(name, &sample(200_000, i as u8));
+    }
+    let saved = || -> Option<i32> { get_meta(&s.open().connection, "zstd_level").unwrap() };
+    let chunk_levels = || -> Vec<i32> {
+        let db = s.open();
+        let mut stmt = db
+            .connection
+            .prepare("SELECT DISTINCT level FROM chunks ORDER BY level")
+            .unwrap();
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    };
+
+    s.add(&["--level", "22", "a"]).unwrap();
+    assert_eq!(saved(), Some(22));
+    assert_eq!(chunk_levels(), [22]);
+    assert_eq!(s.cat("a").unwrap(), sample(200_000, 0));
+
+    s.add(&["--level", "5", "b"]).unwrap();
+    s.add(&["c"]).unwrap();
+    assert_eq!(saved(), Some(5));
+    assert_eq!(chunk_levels(), [5, 22]);
+    assert_eq!(s.cat("c").unwrap(), sample(200_000, 2));
+}
+
+#[test]
+fn ultra_zstd_levels_round_trip() {
+    // Levels above 19 go through their own context with long distance matching on, and
+    // should still find the repeat and read back with the plain decoder.
+    let half = noise(300_000, 1);
+    let data = [&half[..], FOX, &half[..]].concat();
+    for level in [19, 20, 22] {
+        let compressed = Compression::Zstd(level).compress(&data).unwrap();
+        assert!(compressed.len() < 400_000, "level {}", level);
+        assert_eq!(decompress("zstd", compressed).unwrap(), data);
+    }
+}
+
+#[test]
+fn strip_components_counts_from_the_stored_name() {
+    let s = Scratch::new("strip-components");
+    for name in &[
+        "backups/2024/host/etc/x",
+        "backups/2024/host/y",
+        "backups/z",
+    ] {
+        s.write(name, name.as_bytes());
+    }
+    s.add(&["backups"]).unwrap();
+    fs::remove_dir_all(s.path("backups")).unwrap();
+
+    let strip = &["Extract", "--strip-components", "3"];
+    s.run(&[strip, &["backups/2024"][..]].concat()).unwrap();
+    assert_eq!(s.read("etc/x"), b"backups/2024/host/etc/x");
+    assert_eq!(s.read("y"), b"backups/2024/host/y");
+    // Too short to strip, so skipped.
+    assert!(!exists(&s.path("z")));
+    assert!(!exists(&s.path("backups")));
+
+    // The same for a file selected on its own.
+    fs::remove_file(s.path("y")).unwrap();
+    s.run(&[strip, &["backups/2024/host/y"][..]].concat())
+        .unwrap();
+    assert_eq!(s.read("y"), b"backups/2024/host/y");
+}
+
+#[test]
+fn locks_keep_deleting_apart_from_adding() {
+    let s = Scratch::new("locks");
+    s.write("f", b"x");
+    s.add(&["f"]).unwrap();
+    s.run_on("other.db", &["Migrate"]).unwrap();
+    let hold = |name: &str, pid: i64| {
+        s.open()
+            .connection
+            .execute(
+                "INSERT INTO locks VALUES (?, ?, 'Test')",
+                &[&name as &dyn ToSql, &pid],
+            )
+            .unwrap();
+    };
+    let held = || -> i64 {
+        let db = s.open();
+        db.connection
+            .query_row("SELECT COUNT(*) FROM locks", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    };
+    let locked = |args: &[&str]| match s.run(args).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::Locked { pid: 1, .. })) => true,
+        Ok(()) => false,
+        Err(e) => panic!("{:?}", e),
+    };
+    let pull = &["Pull", "--from", "other.db"];
+
+    // Pid 1 is always running.
+    hold("destructive", 1);
+    assert!(locked(&["Add", "f"]));
+    assert!(locked(pull));
+    // Refused before it starts watching, rather than only once a change comes in.
+    assert!(locked(&["Watch", "f"]));
+    assert!(locked(&["Compact"]));
+    assert!(!locked(&["--break-lock", "Compact"]));
+    assert_eq!(held(), 0);
+
+    hold("adding/1", 1);
+    assert!(!locked(pull));
+    assert!(locked(&["Compact"]));
+    assert_eq!(held(), 1);
+
+    // The lock of a process that's gone is taken over.
+    if cfg!(unix) {
+        s.open()
+            .connection
+            .execute("UPDATE locks SET pid=?", &[&i64::from(i32::MAX)])
+            .unwrap();
+        assert!(!locked(&["Compact"]));
+        assert_eq!(held(), 0);
+    }
+}
+
+#[test]
+fn files_are_added_in_the_order_asked() {
+    let s = Scratch::new("order");
+    for (name, len) in &[("d/b", 30), ("a", 10), ("d/a", 20), ("c", 40)] {
+        s.write(name, &vec![1; *len]);
+    }
+    let added = |order: &str| -> Vec<String> {
+        let _ = fs::remove_file(s.path("a.db"));
+        s.add(&["--order", order, "d", "c", "a"]).unwrap();
+        let db = s.open();
+        let mut stmt = db
+            .connection
+            .prepare("SELECT name FROM files ORDER BY rowid")
+            .unwrap();
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    };
+    assert_eq!(added("name"), ["a", "c", "d/a", "d/b"]);
+    assert_eq!(added("size"), ["c", "d/b", "d/a", "a"]);
+
+    let mut files: Vec<PathBuf> = (0..100).map(|i| format!("{:03}", i).into()).collect();
+    let sorted = files.clone();
+    order_files(&mut files, &s.dir, "random");
+    assert_ne!(files, sorted);
+    files.sort();
+    assert_eq!(files, sorted);
+}
+
+#[test]
+fn files_are_added_in_stored_name_order_by_default() {
+    let s = Scratch::new("order-default");
+    for name in &["a.txt", "a-b/f", "a/c/d", "a/b", "a/a"] {
+        s.write(name, name.as_bytes());
+    }
+    s.add(&["a.txt", "a-b", "a"]).unwrap();
+    // Compared a component at a time, so everything in a comes before a-b, though "-"
+    // sorts before "/".
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT name FROM files ORDER BY rowid"),
+        ["a/a", "a/b", "a/c/d", "a-b/f", "a.txt"]
+    );
+}
+
+#[test]
+fn chunk_cache_keeps_recently_used_chunks() {
+    let s = Scratch::new("chunk-cache");
+    s.run(&["--chunker", "fixed", "--chunk-size", "4096", "Migrate"])
+        .unwrap();
+    s.write("f", &noise(8192, 1));
+    s.add(&["f"]).unwrap();
+    let chunks = s.stored("f").chunks;
+    assert_eq!(chunks.len(), 2);
+    let (first, second) = (&chunks[0], &chunks[1]);
+
+    let db = s.open();
+    let connection = &db.connection;
+    // Room for one chunk.
+    let cache = ChunkCache::new(6000);
+    let uncached = ChunkCache::new(0);
+    assert_eq!(
+        cache.get(connection, first).unwrap(),
+        get_chunk(connection, first).unwrap()
+    );
+    cache.get(connection, first).unwrap();
+    cache.get(connection, second).unwrap();
+
+    connection.execute("DELETE FROM chunks", NO_PARAMS).unwrap();
+    assert!(uncached.get(connection, second).is_err());
+    assert_eq!(cache.get(connection, second).unwrap().len(), 4096);
+    // Evicted to make room for the second.
+    assert!(cache.get(connection, first).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn add_hook_gets_the_bytes_stored() {
+    let s = Scratch::new("hook");
+    let data = sample(100_000, 1);
+    s.write("f", &data);
+    s.write("g", &data);
+    let hook = "echo $CHSQLAR_FILES_ADDED $CHSQLAR_BYTES_WRITTEN > hook.txt";
+    s.run(&["--on-success", hook, "Add", "f", "g"]).unwrap();
+
+    let vars = String::from_utf8(s.read("hook.txt")).unwrap();
+    let vars: Vec<u64> = vars
+        .split_whitespace()
+        .map(|v| v.parse().unwrap())
+        .collect();
+    let stored: i64 = s
+        .open()
+        .connection
+        .query_row("SELECT SUM(LENGTH(data)) FROM chunks", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(vars, [2, stored as u64]);
+    // Deduplicated and compressed, so well under the 200,000 bytes read.
+    assert!(stored < 100_000);
+}
+
+#[test]
+fn blobs_are_stored_by_hash() {
+    let s = Scratch::new("blobs");
+    let data = sample(50_000, 1);
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let budget = MemoryBudget::new(None);
+    let hash = put_blob(&mut trans, data.clone(), &budget).unwrap();
+    assert_eq!(hash, ChunkHash::Sha3.of(&data));
+    assert_eq!(put_blob(&mut trans, data.clone(), &budget).unwrap(), hash);
+    assert_eq!(get_blob(&mut trans, &hash).unwrap(), data);
+
+    // The blob's chunks are the file's, referred to once more.
+    let count = |sql: &str| -> i64 { trans.query_row(sql, NO_PARAMS, |row| row.get(0)).unwrap() };
+    assert_eq!(count("SELECT COUNT(*) FROM blobs"), 1);
+    assert_eq!(count("SELECT COUNT(*) FROM chunks WHERE refcount != 2"), 0);
+
+    let missing = get_blob(&mut trans, &ChunkHash::Sha3.of(b"other"));
+    match missing.map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::BlobNotFound { .. })) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+/// The key of every encrypted test archive: bytes 0 to 31, in base64. The key is loaded
+/// once per process, so the tests can't each use their own.
+const KEY: &[u8] = b"AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+/// Sets the scratch archive up to be encrypted with `KEY`, and its names too if `names`.
+fn encrypt(s: &Scratch, names: bool) {
+    s.write("key", KEY);
+    let mut args = vec!["--encrypt", "--key-file", "key"];
+    if names {
+        args.push("--encrypt-names");
+    }
+    args.push("Migrate");
+    s.run(&args).unwrap();
+}
+
+#[test]
+fn key_is_checked_before_use() {
+    let s = Scratch::new("key-check");
+    encrypt(&s, false);
+
+    let raw: Vec<u8> = (0..32).collect();
+    s.write("raw", &raw);
+    let key = crypt::read_key_file(&s.path("raw")).unwrap();
+    assert_eq!(key[..], raw[..]);
+    assert_eq!(crypt::read_key_file(&s.path("key")).unwrap(), key);
+    s.write("short", &raw[..31]);
+    assert!(crypt::read_key_file(&s.path("short")).is_err());
+
+    let db = s.open();
+    assert!(crypt::unlock(&db.connection, &key).is_ok());
+    let mut wrong = key;
+    wrong[0] ^= 1;
+    match crypt::unlock(&db.connection, &wrong).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::WrongKey)) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    // A passphrase gives the same key each time, and another with another salt.
+    let params = (256, 1, 1);
+    let derived = crypt::derive_key("passphrase", &[1; 16], params).unwrap();
+    assert_eq!(
+        crypt::derive_key("passphrase", &[1; 16], params).unwrap(),
+        derived
+    );
+    assert_ne!(
+        crypt::derive_key("passphrase", &[2; 16], params).unwrap(),
+        derived
+    );
+}
+
+#[test]
+fn chunks_are_encrypted_at_rest() {
+    let s = Scratch::new("encrypt-chunks");
+    s.write("key", KEY);
+    s.run(&[
+        "--encrypt",
+        "--key-file",
+        "key",
+        "--chunker",
+        "fixed",
+        "--chunk-size",
+        "4096",
+        "Migrate",
+    ])
+    .unwrap();
+    let data = noise(3 * 4096, 1);
+    s.write("f", &data);
+    s.write("g", &data);
+    s.write("small", FOX);
+    s.add(&["f", "g", "small"]).unwrap();
+
+    // Hashes are still of the plaintext, so the copy shares the chunks.
+    let chunks = s.stored("f").chunks;
+    let hashes: Vec<_> = data.chunks(4096).map(|c| ChunkHash::Sha3.of(c)).collect();
+    assert_eq!(chunks, hashes);
+    assert_eq!(s.stored("g").chunks, chunks);
+    let refcounts = "SELECT CAST(refcount AS TEXT) FROM chunks";
+    assert_eq!(rows(&s.path("a.db"), refcounts), ["2", "2", "2"]);
+
+    // Neither chunks nor inline files hold their plaintext.
+    let db = s.open();
+    let blobs = |sql: &str| -> Vec<Vec<u8>> {
+        let mut stmt = db.connection.prepare(sql).unwrap();
+        let blobs = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        blobs.map(Result::unwrap).collect()
+    };
+    for blob in blobs("SELECT data FROM chunks") {
+        assert!(!blob.windows(64).any(|w| data.windows(64).any(|d| d == w)));
+    }
+    let inline = blobs("SELECT inline_data FROM files WHERE inline_data IS NOT NULL");
+    assert_eq!(inline.len(), 1);
+    assert!(!inline[0].windows(FOX.len()).any(|w| w == FOX));
+    assert_eq!(s.cat("f").unwrap(), data);
+    assert_eq!(s.cat("small").unwrap(), FOX);
+
+    // Moving one chunk's data and checksum to another's row is caught, as is changing a byte.
+    let undecryptable = |name| match s.cat(name).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::Undecryptable { .. })) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    };
+    let swap = "UPDATE chunks SET (data, checksum) =
+                (SELECT data, checksum FROM chunks WHERE hash != ?1 LIMIT 1) WHERE hash = ?1";
+    db.connection.execute(swap, &[&chunks[0]]).unwrap();
+    undecryptable("f");
+    let flip = "UPDATE files SET inline_data =
+                CAST(substr(inline_data, 1, 30) || x'00' || substr(inline_data, 32) AS BLOB)";
+    db.connection.execute(flip, NO_PARAMS).unwrap();
+    undecryptable("small");
+
+    let describe = crypt::describe(&db.connection).unwrap().unwrap();
+    assert!(
+        describe.contains("xchacha20-poly1305, key file"),
+        "{}",
+        describe
+    );
+    assert!(describe.contains("chunk hashes"), "{}", describe);
+}
+
+#[test]
+fn names_and_metadata_are_sealed() {
+    let s = Scratch::new("encrypt-names");
+    encrypt(&s, true);
+    s.write("dir/a", b"first");
+    s.write("dir/b", FOX);
+    s.write("c", &noise(10_000, 1));
+    s.run(&["Add", "dir", "c"]).unwrap();
+
+    // The files table holds no names or times in the clear.
+    let db = s.open();
+    let mut stmt = db.connection.prepare("SELECT name FROM files").unwrap();
+    let names = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+    for name in names {
+        let name: Vec<u8> = name.unwrap();
+        assert!(!name.windows(3).any(|w| w == b"dir"), "{:?}", name);
+    }
+    let clear = "SELECT CAST(count(*) AS TEXT) FROM files
+                 WHERE mtime IS NOT NULL OR added_at IS NOT NULL OR attrs IS NULL";
+    assert_eq!(rows(&s.path("a.db"), clear), ["0"]);
+
+    // Listing decrypts and sorts them, the same as for a plain archive.
+    assert!(crypt::names_encrypted(&db.connection).unwrap());
+    let encoding = NameEncoding::Sealed;
+    let list = |long| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        list_cmd(&mut db, long, false, None, &[], b' ', encoding, &mut out).map(|()| out)
+    };
+    assert_eq!(list(false).unwrap(), b"c dir/a dir/b ");
+    let mut out = Vec::new();
+    list_dirs_cmd(&mut s.open(), None, b' ', encoding, &mut out).unwrap();
+    assert_eq!(out, b"dir/ ");
+
+    // Extracting by prefix finds the directory's files.
+    fs::remove_dir_all(s.path("dir")).unwrap();
+    s.run(&["Extract", "dir"]).unwrap();
+    assert_eq!(s.read("dir/a"), b"first");
+    assert_eq!(s.read("dir/b"), FOX);
+
+    // Metadata moved to another file's row doesn't open.
+    let swap = "UPDATE files SET attrs = (SELECT attrs FROM files ORDER BY rowid LIMIT 1)
+                WHERE rowid = (SELECT max(rowid) FROM files)";
+    db.connection.execute(swap, NO_PARAMS).unwrap();
+    match list(true).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::Undecryptable { .. })) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn extract_creates_parent_directories() {
+    let s = Scratch::new("extract-parents");
+    s.write("f", b"root");
+    s.write("d/e/g", b"nested");
+    s.add(&["f", "d"]).unwrap();
+    fs::remove_file(s.path("f")).unwrap();
+    fs::remove_dir_all(s.path("d")).unwrap();
+
+    s.run(&["Extract", "f", "d"]).unwrap();
+    assert_eq!(s.read("f"), b"root");
+    assert_eq!(s.read("d/e/g"), b"nested");
+
+    // Files are written relative to the parent of what was selected.
+    s.run(&["Extract", "d/e"]).unwrap();
+    assert_eq!(s.read("e/g"), b"nested");
+}
+
+#[test]
+fn compress_rules_pick_each_files_treatment() {
+    let s = Scratch::new("compress-rules");
+    s.write("a.txt", &sample(100_000, 1));
+    s.write("b.log", &sample(100_000, 2));
+    s.write("c.log.txt", &sample(100_000, 2));
+    s.add(&[
+        "--compress-rule",
+        "*.txt=store",
+        "--compress-rule",
+        "*.log=19",
+        "--compress-rule",
+        "*.log=3",
+        "a.txt",
+        "b.log",
+        "c.log.txt",
+    ])
+    .unwrap();
+
+    // The last rule that matches wins.
+    assert_eq!(s.treatments("a.txt"), [("store".to_owned(), None)]);
+    assert_eq!(s.treatments("b.log"), [("zstd".to_owned(), Some(3))]);
+    // The same content was already stored under another rule, and stays as it was.
+    assert_eq!(s.treatments("c.log.txt"), s.treatments("b.log"));
+}
+
+#[test]
+fn page_size_is_set_at_creation() {
+    let s = Scratch::new("page-size");
+    let created = ["--page-size", "8192", "--cache-size", "-4000"];
+    assert_eq!(s.pragma(&created, "page_size"), 8192);
+    assert_eq!(s.pragma(&created, "cache_size"), -4000);
+
+    // An existing archive keeps the page size it was created with.
+    assert_eq!(s.pragma(&["--page-size", "16384"], "page_size"), 8192);
+}
+
+#[test]
+fn compressed_formats_are_stored_as_is() {
+    let s = Scratch::new("no-compress-ext");
+    for (i, name) in ["a.JPG", "b.txt", "c.dat"].iter().enumerate() {
+        s.write(name, &sample(100_000, i as u8));
+    }
+    s.add(&["--no-compress-ext", ".dat", "a.JPG", "b.txt", "c.dat"])
+        .unwrap();
+    let store = [("store".to_owned(), None)];
+    assert_eq!(s.treatments("a.JPG"), store);
+    assert_eq!(s.treatments("b.txt")[0].0, "zstd");
+    assert_eq!(s.treatments("c.dat"), store);
+
+    s.write("d.jpg", &sample(100_000, 3));
+    s.add(&["--compress-all", "d.jpg"]).unwrap();
+    assert_eq!(s.treatments("d.jpg")[0].0, "zstd");
+}
+
+#[test]
+fn verify_tells_corrupted_blobs_from_wrong_content() {
+    let s = Scratch::new("verify");
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        s.write(name, &sample(100_000, i as u8));
+    }
+    s.add(&["a", "b", "c"]).unwrap();
+    let [a, b, c] = ["a", "b", "c"].map(|name| s.stored(name).chunks.remove(0));
+
+    let db = s.open();
+    let connection = &db.connection;
+    let update = |sql: &str, params: &[&dyn ToSql]| connection.execute(sql, params).unwrap();
+    update(
+        "UPDATE chunks SET data = CAST(data || x'00' AS BLOB) WHERE hash=?",
+        &[&a],
+    );
+    let other = zstd::encode_all(&b"other"[..], 3).unwrap();
+    let sql = "UPDATE chunks SET data=?, checksum=? WHERE hash=?";
+    update(sql, &[&other, &checksum_blob(&other), &b]);
+    update("UPDATE chunks SET checksum=NULL WHERE hash=?", &[&c]);
+
+    let verify = |fast| {
+        let mut faults = Vec::new();
+        let counts = verify_chunks(connection, fast, |hash, fault| {
+            faults.push((hash.to_owned(), fault.to_string()))
+        })
+        .unwrap();
+        faults.sort();
+        (counts, faults)
+    };
+    let corrupted = (a.clone(), "compressed blob corrupted".to_owned());
+    let mismatched = (b, "decompressed content doesn't match hash".to_owned());
+    let mut both = vec![corrupted.clone(), mismatched];
+    both.sort();
+    assert_eq!(verify(false), ((3, 0), both));
+    // --fast only compares checksums, and c has none.
+    assert_eq!(verify(true), ((2, 1), vec![corrupted]));
+
+    match get_chunk(connection, &a).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::ChunkCorrupted { .. })) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn sync_mode_is_set_on_open() {
+    let s = Scratch::new("sync");
+    assert_eq!(s.pragma(&[], "synchronous"), 1);
+    for (i, mode) in ["off", "normal", "full"].iter().enumerate() {
+        assert_eq!(s.pragma(&["--sync", mode], "synchronous"), i as i64);
+    }
+    assert!(s.parse(&["--sync", "fast", "Info"]).is_err());
+}
+
+#[test]
+fn resume_keeps_only_correct_files() {
+    let s = Scratch::new("resume");
+    let names = ["a", "b", "c", "d"];
+    for (i, name) in names.iter().enumerate() {
+        s.write(name, &sample(10_000, i as u8));
+    }
+    s.add(&names).unwrap();
+
+    // b was cut short, c has the right size but not the right content, d is missing.
+    let damage = |s: &Scratch| {
+        s.write("b", &sample(5_000, 1));
+        s.write("c", &sample(10_000, 9));
+        fs::remove_file(s.path("d")).unwrap();
+    };
+    let counts = |summary: ExtractSummary| (summary.extracted, summary.skipped, summary.rewritten);
+
+    damage(&s);
+    assert!(s.extract(&["a"]).is_err());
+    assert_eq!(
+        counts(s.extract(&["--resume", "a", "b", "c", "d"]).unwrap()),
+        (1, 2, 1)
+    );
+    assert_eq!(s.read("b"), sample(10_000, 1));
+    assert_eq!(s.read("c"), sample(10_000, 9));
+    assert_eq!(s.read("d"), sample(10_000, 3));
+
+    damage(&s);
+    let verified = s.extract(&["--resume-verify", "a", "b", "c", "d"]).unwrap();
+    assert_eq!(counts(verified), (1, 1, 2));
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(s.read(name), sample(10_000, i as u8));
+    }
+}
+
+#[test]
+fn small_files_can_be_stored_whole() {
+    let s = Scratch::new("whole-file");
+    s.run(&["--chunk-bits", "16", "Migrate"]).unwrap();
+    s.write("chunked", &noise(300_000, 1));
+    s.write("whole", &noise(300_000, 2));
+    s.write("copy", &noise(300_000, 2));
+    s.add(&["chunked"]).unwrap();
+    s.add(&["--whole-file-under", "300001", "whole", "copy"])
+        .unwrap();
+
+    assert!(s.stored("chunked").chunks.len() > 1);
+    assert_eq!(s.stored("whole").chunks.len(), 1);
+    assert_eq!(s.stored("copy").chunks, s.stored("whole").chunks);
+    assert_eq!(s.cat("whole").unwrap(), noise(300_000, 2));
+
+    // The limit is exclusive.
+    s.write("large", &noise(300_000, 4));
+    s.add(&["--whole-file-under", "300000", "large"]).unwrap();
+    assert!(s.stored("large").chunks.len() > 1);
+}
+
+/// Each row `sql` returns as text, for comparing tables between archives.
+fn rows(archive: &Path, sql: &str) -> Vec<String> {
+    let connection = rusqlite::Connection::open(archive).unwrap();
+    let mut stmt = connection.prepare(sql).unwrap();
+    let rows = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+    rows.map(Result::unwrap).collect()
+}
+
+#[test]
+fn interrupted_add_resumes_from_the_journal() {
+    let s = Scratch::new("add-journal");
+    let names = ["a", "b", "c"];
+    for (i, name) in names.iter().enumerate() {
+        s.write(name, &sample(20_000, i as u8));
+    }
+    s.add(&names).unwrap();
+    fs::rename(s.path("a.db"), s.path("whole.db")).unwrap();
+
+    // The first run can't read b, and keeps the journal of what it did store.
+    fs::rename(s.path("b"), s.path("b.away")).unwrap();
+    let first = s.add(&names).unwrap();
+    assert_eq!((first.stored, first.failed), (2, vec![PathBuf::from("b")]));
+    let journal = "SELECT name FROM add_journal ORDER BY name";
+    assert_eq!(rows(&s.path("a.db"), journal), ["a", "c"]);
+
+    fs::rename(s.path("b.away"), s.path("b")).unwrap();
+    let second = s.add(&names).unwrap();
+    assert_eq!(second.bytes, 20_000);
+    assert!(rows(&s.path("a.db"), journal).is_empty());
+
+    for sql in &[
+        "SELECT quote(name) || size || quote(chunks) FROM files ORDER BY name",
+        "SELECT hash || quote(data) || refcount FROM chunks ORDER BY hash",
+    ] {
+        assert_eq!(rows(&s.path("a.db"), sql), rows(&s.path("whole.db"), sql));
+    }
+}
+
+#[test]
+fn orphans_are_chunks_nothing_refers_to() {
+    let s = Scratch::new("orphans");
+    s.write("f", &noise(20_000, 1));
+    s.write("g", &noise(20_000, 2));
+    s.add(&["f", "g"]).unwrap();
+    let replaced = s.stored("f").chunks;
+
+    s.write("f", &noise(30_000, 3));
+    s.add(&["f"]).unwrap();
+
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let orphans = find_orphans(&mut trans).unwrap();
+    let hashes: Vec<_> = orphans.iter().map(|(hash, _)| hash.clone()).collect();
+    assert_eq!(hashes, replaced);
+    // Stored as is, as noise doesn't compress.
+    assert_eq!(orphans.iter().map(|(_, size)| size).sum::<i64>(), 20_000);
+}
+
+#[test]
+fn add_skips_files_it_cant_read() {
+    let s = Scratch::new("keep-going");
+    for name in &["a", "c", "d"] {
+        s.write(name, name.as_bytes());
+    }
+
+    let summary = s.add(&["a", "b", "c"]).unwrap();
+    assert_eq!(summary.stored, 2);
+    assert_eq!(summary.failed, [PathBuf::from("b")]);
+    assert_eq!(s.cat("c").unwrap(), b"c");
+
+    assert!(s.add(&["--strict", "b", "d"]).is_err());
+    assert!(s.cat("d").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn add_skips_fifos_and_sockets() {
+    let s = Scratch::new("special-files");
+    s.write("d/a", b"a");
+    let fifo = std::ffi::CString::new(s.path("d/fifo").to_str().unwrap()).unwrap();
+    // Safe as mkfifo only reads the NUL-terminated path, which outlives the call.
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    let _socket = std::os::unix::net::UnixListener::bind(s.path("d/socket")).unwrap();
+
+    // Neither is opened, which for the FIFO would wait for a writer forever.
+    let summary = s.add(&["d"]).unwrap();
+    assert_eq!(summary.stored, 1);
+    assert_eq!(summary.failed, [s.path("d/fifo"), s.path("d/socket")]);
+    assert_eq!(s.cat("d/a").unwrap(), b"a");
+    assert!(s.add(&["--strict", "d"]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn one_file_system_stays_on_the_first_device() {
+    use std::os::unix::fs::MetadataExt;
+
+    let s = Scratch::new("one-file-system");
+    // Any other mounted file system will do.
+    let other = Path::new("/dev/shm").join(format!("chsqlar-test-{}", std::process::id()));
+    let device = |path: &Path| fs::metadata(path).unwrap().dev();
+    if fs::create_dir_all(&other).is_err() || device(&other) == device(&s.dir) {
+        return;
+    }
+    fs::write(other.join("x"), b"x").unwrap();
+    s.write("d/f", b"f");
+    std::os::unix::fs::symlink(&other, s.path("d/mnt")).unwrap();
+
+    let one = s.add(&["--one-file-system", "d"]);
+    let all = s.add(&["d"]);
+    fs::remove_dir_all(&other).unwrap();
+    assert_eq!(one.unwrap().stored, 1);
+    assert_eq!(all.unwrap().stored, 2);
+}
+
+#[test]
+fn extract_streams_one_chunk_at_a_time() {
+    /// Remembers how much it was given at once, and how much in all.
+    #[derive(Default)]
+    struct Counting {
+        largest: usize,
+        total: usize,
+    }
+
+    impl std::io::Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.total += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let s = Scratch::new("stream");
+    s.run(&["--chunk-bits", "16", "Migrate"]).unwrap();
+    s.write("f", &noise(2_000_000, 1));
+    s.add(&["f"]).unwrap();
+    assert!(s.stored("f").chunks.len() > 4);
+
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let mut out = Counting::default();
+    let cache = ChunkCache::new(0);
+    extract_file_to(
+        &mut trans,
+        "f".into(),
+        NameEncoding::Bytes,
+        &cache,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(out.total, 2_000_000);
+    // No chunk is over eight times the average of 64 KiB.
+    assert!(out.largest <= 8 << 16);
+}
+
+#[test]
+fn errors_are_described_in_json() {
+    let s = Scratch::new("json-errors");
+    let e = s.run(&["--json-errors", "Extract", "missing"]).unwrap_err();
+    let printed: serde_json::Value = serde_json::from_str(&error_json(&e).to_string()).unwrap();
+    assert_eq!(printed["error"], "FileNotFound");
+    assert_eq!(printed["path"], "missing");
+    assert_eq!(printed["message"], e.to_string());
+
+    let e = Error::from(fs::read("missing").unwrap_err());
+    assert_eq!(error_json(&e)["error"], "Io");
+    assert_eq!(error_json(&format_err!("other"))["error"], "Other");
+}
+
+#[test]
+fn windows_names_are_checked() {
+    assert!(check_windows_name(Path::new("dir/file.txt")).is_ok());
+    for bad in &[
+        "a:b",
+        "dir/what?",
+        "tab\tbed",
+        "dots.",
+        "space ",
+        "CON",
+        "dir/nul.txt",
+    ] {
+        assert!(check_windows_name(Path::new(bad)).is_err(), "{}", bad);
+    }
+
+    let normalised = |cwd: &str, p: &str| normalise_path(Path::new(cwd), Path::new(p));
+    assert_eq!(normalised("/home/u", "/home/u/d/f"), Path::new("d/f"));
+    assert_eq!(normalised("/home/u", "/home/v/f"), Path::new("v/f"));
+    assert_eq!(normalised("/home/u", "/tmp/f"), Path::new("tmp/f"));
+    if cfg!(windows) {
+        // canonicalize gives verbatim paths, which are on the same drive as plain ones.
+        assert_eq!(
+            normalised(r"C:\Users\u", r"\\?\c:\Users\u\f"),
+            Path::new("f")
+        );
+        assert_eq!(normalised(r"C:\Users\u", r"D:\data\f"), Path::new("data/f"));
+    }
+}
+
+#[test]
+fn compact_rechunks_every_file() {
+    let s = Scratch::new("compact");
+    s.run(&["--chunk-bits", "16", "Migrate"]).unwrap();
+    let files: Vec<_> = (0..3).map(|i| noise(400_000, i)).collect();
+    for (i, data) in files.iter().enumerate() {
+        s.write(&i.to_string(), data);
+    }
+    s.add(&["0", "1", "2"]).unwrap();
+    let before = s.stored("0").chunks;
+
+    s.run(&["Compact", "--chunk-bits", "13"]).unwrap();
+    assert_eq!(
+        get_meta::<usize>(&s.open().connection, "chunk_bits").unwrap(),
+        Some(13)
+    );
+    let after = s.stored("0").chunks;
+    assert!(after.len() > before.len());
+    for (i, data) in files.iter().enumerate() {
+        assert_eq!(&s.cat(&i.to_string()).unwrap(), data);
+    }
+
+    // The old chunks are gone.
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    assert!(find_orphans(&mut trans).unwrap().is_empty());
+    let chunks: i64 = trans
+        .query_row("SELECT COUNT(*) FROM chunks", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    let referenced: usize = (0..3).map(|i| s.stored(&i.to_string()).chunks.len()).sum();
+    assert_eq!(chunks as usize, referenced);
+}
+
+#[test]
+fn reflink_falls_back_to_writing() {
+    let s = Scratch::new("reflink");
+    let data = noise(100_000, 1);
+    s.write("a", &data);
+    s.write("b", &data);
+    s.add(&["a", "b"]).unwrap();
+    let remove = || {
+        ["a", "b"]
+            .iter()
+            .for_each(|f| fs::remove_file(s.path(f)).unwrap())
+    };
+
+    remove();
+    let auto = s.extract(&["--reflink", "auto", "a", "b"]).unwrap();
+    assert_eq!((s.read("a"), s.read("b")), (data.clone(), data.clone()));
+
+    // Whether the file system can clone decides between the two.
+    remove();
+    match s.extract(&["--reflink", "always", "a", "b"]) {
+        Ok(always) => {
+            assert_eq!((auto.reflinked, always.reflinked), (1, 1));
+            assert_eq!(s.read("b"), data);
+        }
+        Err(_) => assert_eq!(auto.reflinked, 0),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_stops_add_after_the_current_file() {
+    let s = Scratch::new("interrupt");
+    for name in &["a", "b", "c"] {
+        s.write(name, name.as_bytes());
+    }
+
+    // The filter interrupts the test process while a is being added.
+    install_interrupt_handler().unwrap();
+    let filter = r#"[ "$CHSQLAR_FILE" = a ] && kill -INT $PPID; sleep 0.2; cat"#;
+    let summary = s.add(&["--filter", filter, "a", "b", "c"]);
+    INTERRUPTED.store(false, Ordering::SeqCst);
+
+    let summary = summary.unwrap();
+    assert!(summary.interrupted);
+    assert_eq!(summary.stored, 1);
+    assert_eq!(s.cat("a").unwrap(), b"a");
+    assert!(s.cat("b").is_err());
+    let journal = rows(&s.path("a.db"), "SELECT name FROM add_journal");
+    assert_eq!(journal, ["a"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn zero_runs_are_stored_and_extracted_as_holes() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let s = Scratch::new("sparse");
+    let head = noise(10_000, 1);
+    let tail = noise(10_000, 2);
+    let mut f = fs::File::create(s.path("f")).unwrap();
+    f.write_all(&head).unwrap();
+    f.seek(SeekFrom::Current(1 << 20)).unwrap();
+    f.write_all(&tail).unwrap();
+    drop(f);
+    let data = s.read("f");
+    s.write("dense", &data);
+
+    s.add(&["f", "dense"]).unwrap();
+    // Whole 4 KiB blocks of zeros, from the first after the head to the last before the tail.
+    assert_eq!(s.stored("f").holes, [(12_288, (1 << 20) - 4096)]);
+    assert!(s.stored("dense").holes.is_empty());
+    s.add(&["--sparse", "always", "dense"]).unwrap();
+    assert_eq!(s.stored("dense").holes, s.stored("f").holes);
+    assert_eq!(s.cat("f").unwrap(), data);
+    // Only the data around the hole takes much space.
+    let sql = "SELECT CAST(SUM(length(data)) AS TEXT) FROM chunks";
+    let stored: usize = rows(&s.path("a.db"), sql)[0].parse().unwrap();
+    assert!(stored < data.len() / 20, "{}", stored);
+    // Shorter runs of zeros are left in the chunks.
+    s.write("short", &[&head[..], &[0; 32 << 10], &tail[..]].concat());
+    s.add(&["--sparse", "always", "short"]).unwrap();
+    assert!(s.stored("short").holes.is_empty());
+
+    fs::remove_file(s.path("f")).unwrap();
+    s.run(&["Extract", "f"]).unwrap();
+    assert_eq!(s.read("f"), data);
+    let meta = fs::metadata(s.path("f")).unwrap();
+    assert!(meta.blocks() * 512 < meta.size() / 2);
+}
+
+#[test]
+fn pull_copies_files_and_their_chunks() {
+    let s = Scratch::new("pull");
+    s.write("d/x", &noise(20_000, 1));
+    s.write("d/y", &noise(20_000, 2));
+    s.write("z", &noise(20_000, 1));
+    s.run_on("src.db", &["Add", "d"]).unwrap();
+    s.add(&["z"]).unwrap();
+
+    s.run(&["Pull", "--from", "src.db", "d/x"]).unwrap();
+    assert_eq!(s.cat("d/x").unwrap(), noise(20_000, 1));
+    assert!(s.cat("d/y").is_err());
+    // d/x has the same content as z, so brought no chunks of its own.
+    let chunks = rows(&s.path("a.db"), "SELECT hash FROM chunks");
+    assert_eq!(chunks, s.stored("z").chunks);
+
+    s.run(&["Pull", "--from", "src.db"]).unwrap();
+    assert_eq!(s.cat("d/y").unwrap(), noise(20_000, 2));
+
+    s.run_on("other.db", &["--chunk-bits", "16", "Add", "z"])
+        .unwrap();
+    assert!(s.run(&["Pull", "--from", "other.db"]).is_err());
+}
+
+#[test]
+fn pull_and_merge_refuse_the_same_sources() {
+    let s = Scratch::new("copyable");
+    s.write("f", &noise(50_000, 1));
+    s.write("key", KEY);
+    let sources: &[(&str, &[&str], &str)] = &[
+        ("stored.db", &["--store-dir", "store"], "uses --store-dir"),
+        (
+            "sealed.db",
+            &["--encrypt", "--key-file", "key"],
+            "is encrypted",
+        ),
+        ("other.db", &["--chunk-bits", "16"], "Compact one of them"),
+        ("blake.db", &["--hash", "blake3"], "hashes but this archive"),
+    ];
+    for (archive, args, _) in sources {
+        s.run_on(archive, &[*args, &["Add", "f"]].concat()).unwrap();
+    }
+    s.add(&["f"]).unwrap();
+
+    for (archive, _, reason) in sources {
+        let pulled = s.run(&["Pull", "--from", archive]).unwrap_err();
+        let merged = s.run(&["Merge", archive]).unwrap_err();
+        assert!(pulled.to_string().contains(reason), "{}", pulled);
+        assert_eq!(
+            pulled.to_string().replacen("Pull", "Merge", 1),
+            merged.to_string()
+        );
+    }
+
+    for dest in &["stored.db", "sealed.db"] {
+        let pulled = s.run_on(dest, &["Pull", "--from", "a.db"]).unwrap_err();
+        let merged = s.run_on(dest, &["Merge", "a.db"]).unwrap_err();
+        assert!(pulled.to_string().contains("can't copy into"), "{}", pulled);
+        assert!(merged.to_string().contains("can't copy into"), "{}", merged);
+    }
+
+    // An empty archive takes on the first input's chunking, which the rest have to match.
+    let err = s
+        .run_on("merged.db", &["Merge", "a.db", "blake.db"])
+        .unwrap_err();
+    assert!(err.to_string().contains("but a.db uses"), "{}", err);
+    s.run_on("pulled.db", &["Pull", "--from", "blake.db"])
+        .unwrap();
+    let err = s
+        .run_on("pulled.db", &["Pull", "--from", "a.db"])
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("hashes but this archive"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn archives_read_from_are_left_as_they_are() {
+    let s = Scratch::new("read-only-source");
+    s.write("f", &noise(20_000, 1));
+    s.run_on("src.db", &["--journal", "delete", "Add", "f"])
+        .unwrap();
+    let source = rusqlite::Connection::open(s.path("src.db")).unwrap();
+    source.execute_batch("DROP TABLE locks").unwrap();
+    let commands: &[&[&str]] = &[
+        &["Pull", "--from", "src.db"],
+        &["Merge", "src.db"],
+        &["List", "--modified-since", "src.db"],
+    ];
+
+    // Not given a locks table or switched to WAL.
+    let before = s.read("src.db");
+    for args in commands {
+        s.run(args).unwrap();
+    }
+    assert_eq!(s.read("src.db"), before);
+    assert!(!exists(&s.path("src.db-wal")));
+    assert_eq!(s.cat("f").unwrap(), noise(20_000, 1));
+
+    // Nor migrated behind its owner's back.
+    set_meta(&source, "schema_version", &(SCHEMA_VERSION - 1)).unwrap();
+    let before = s.read("src.db");
+    for args in commands {
+        let err = s.run(args).unwrap_err().to_string();
+        assert!(err.contains("run Migrate on it first"), "{}", err);
+    }
+    assert_eq!(s.read("src.db"), before);
+}
+
+#[test]
+fn threads_dont_change_what_is_stored() {
+    let s = Scratch::new("threads");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("a", &sample(500_000, 1));
+    s.write("b", &noise(500_000, 2));
+
+    let mut stored = Vec::new();
+    for threads in &[1, 4] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(*threads)
+            .build()
+            .unwrap();
+        pool.install(|| s.add(&["a", "b"])).unwrap();
+        fs::rename(s.path("a.db"), s.path(&format!("{}.db", threads))).unwrap();
+        s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+
+        let archive = s.path(&format!("{}.db", threads));
+        stored.push((
+            rows(
+                &archive,
+                "SELECT quote(name) || size || chunks FROM files ORDER BY name",
+            ),
+            rows(
+                &archive,
+                "SELECT hash || quote(data) FROM chunks ORDER BY hash",
+            ),
+        ));
+    }
+    assert!(stored[0].1.len() > 10);
+    assert_eq!(stored[0], stored[1]);
+}
+
+#[test]
+fn exclude_from_skips_listed_paths() {
+    let s = Scratch::new("exclude-from");
+    for name in &[
+        "t/a.log",
+        "t/keep.log",
+        "t/x.txt",
+        "t/build/y.txt",
+        "t/sub/build",
+    ] {
+        s.write(name, name.as_bytes());
+    }
+    s.write("logs", b"# not kept\n*.log\n!keep.log\n");
+    s.write("dirs", b"build/\n");
+
+    let args = ["--exclude-from", "logs", "--exclude-from", "dirs", "t"];
+    assert_eq!(s.add(&args).unwrap().stored, 3);
+    let names = rows(&s.path("a.db"), "SELECT name FROM files ORDER BY name");
+    // build/ only matches directories.
+    assert_eq!(names, ["t/keep.log", "t/sub/build", "t/x.txt"]);
+}
+
+#[test]
+fn store_dir_keeps_chunks_in_loose_files() {
+    let s = Scratch::new("store-dir");
+    s.run(&["--store-dir", "store", "Migrate"]).unwrap();
+    s.write("f", &noise(100_000, 1));
+    s.add(&["f"]).unwrap();
+
+    let loose = |hash: &str| s.path("store").join(&hash[..2]).join(&hash[2..]);
+    let old = s.stored("f").chunks.remove(0);
+    assert_eq!(fs::read(loose(&old)).unwrap().len(), 100_000);
+    let in_database = rows(
+        &s.path("a.db"),
+        "SELECT hash FROM chunks WHERE data IS NOT NULL",
+    );
+    assert!(in_database.is_empty());
+    assert_eq!(s.cat("f").unwrap(), noise(100_000, 1));
+
+    // Compact deletes the loose file of the chunk the new content replaced.
+    s.write("f", &noise(100_000, 2));
+    s.add(&["f"]).unwrap();
+    s.run(&["Compact"]).unwrap();
+    let new = s.stored("f").chunks.remove(0);
+    assert!(!exists(&loose(&old)));
+    assert_eq!(s.cat("f").unwrap(), noise(100_000, 2));
+
+    fs::remove_file(loose(&new)).unwrap();
+    match s.cat("f").map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(ChsqlarError::ChunkNotFound { .. })) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn small_files_are_stored_inline() {
+    let s = Scratch::new("inline");
+    s.write("small", &sample(4095, 1));
+    s.write("large", &sample(4096, 2));
+    s.write("off", &sample(100, 3));
+    s.add(&["small", "large"]).unwrap();
+    s.add(&["--inline-threshold", "0", "off"]).unwrap();
+
+    let small = s.stored("small");
+    assert!(small.chunks.is_empty());
+    assert_eq!(small.inline.unwrap().0, "zstd");
+    for name in &["large", "off"] {
+        let f = s.stored(name);
+        assert!(f.inline.is_none());
+        assert_eq!(f.chunks.len(), 1);
+    }
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT COUNT(*) || '' FROM chunks"),
+        ["2"]
+    );
+
+    fs::remove_file(s.path("small")).unwrap();
+    s.run(&["Extract", "small"]).unwrap();
+    assert_eq!(s.read("small"), sample(4095, 1));
+}
+
+#[test]
+fn max_db_size_stops_add_when_full() {
+    let s = Scratch::new("max-db-size");
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        s.write(name, &noise(100_000, i as u64));
+    }
+
+    let summary = s.add(&["--max-db-size", "200000", "a", "b", "c"]).unwrap();
+    assert_eq!(summary.stored, 1);
+    assert_eq!(summary.over_quota, [s.path("b"), s.path("c")]);
+    // b's chunk was rolled back with it.
+    let mut chunks = s.stored("a").chunks;
+    chunks.sort();
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT hash FROM chunks ORDER BY hash"),
+        chunks
+    );
+    let size = fs::metadata(s.path("a.db")).unwrap().len();
+    assert!(size <= 200_000, "{}", size);
+}
+
+#[test]
+fn max_db_size_is_kept_for_later_adds() {
+    let s = Scratch::new("max-db-size-kept");
+    for (i, name) in ["a", "b", "c"].iter().enumerate() {
+        s.write(name, &noise(100_000, i as u64));
+    }
+    let quota = "SELECT CAST(value AS TEXT) FROM meta WHERE key='max_db_size'";
+
+    s.add(&["--max-db-size", "200000", "a"]).unwrap();
+    assert_eq!(rows(&s.path("a.db"), quota), ["200000"]);
+    // Without the flag the saved limit still applies.
+    let summary = s.add(&["b"]).unwrap();
+    assert_eq!((summary.stored, summary.over_quota), (0, vec![s.path("b")]));
+
+    // With --atomic, going over fails the whole add.
+    let err = match s.add(&["--atomic", "--max-db-size", "300000", "b", "c"]) {
+        Err(err) => err,
+        Ok(_) => panic!("an atomic add went over --max-db-size"),
+    };
+    match err.downcast_ref() {
+        Some(ChsqlarError::ArchiveFull { limit: 300_000, .. }) => {}
+        _ => panic!("{}", err),
+    }
+    assert_eq!(rows(&s.path("a.db"), "SELECT name FROM files"), ["a"]);
+
+    s.add(&["--max-db-size", "0", "b", "c"]).unwrap();
+    assert!(rows(&s.path("a.db"), quota).is_empty());
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT name FROM files ORDER BY name"),
+        ["a", "b", "c"]
+    );
+}
+
+#[test]
+fn deterministic_archives_are_identical() {
+    let s = Scratch::new("deterministic");
+    for (i, name) in ["t/a", "t/b/c", "t/b/d", "t/e"].iter().enumerate() {
+        s.write(name, &sample(20_000 * i + 100, i as u8));
+    }
+
+    s.run_on("one.db", &["Add", "--deterministic", "t"])
+        .unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+    fs::File::options()
+        .write(true)
+        .open(s.path("t/a"))
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+    s.run_on("two.db", &["Add", "--deterministic", "t"])
+        .unwrap();
+
+    assert!(!exists(&s.path("one.db-wal")));
+    assert_eq!(s.read("one.db"), s.read("two.db"));
+}
+
+#[test]
+fn atomic_add_stores_all_or_nothing() {
+    let s = Scratch::new("atomic");
+    s.write("a", &noise(20_000, 1));
+    s.write("c", &noise(20_000, 2));
+    s.write("before", b"before");
+    s.add(&["before"]).unwrap();
+    let files = "SELECT name FROM files ORDER BY name";
+
+    assert!(s.add(&["--atomic", "a", "b", "c"]).is_err());
+    assert_eq!(rows(&s.path("a.db"), files), ["before"]);
+    assert!(rows(&s.path("a.db"), "SELECT hash FROM chunks").is_empty());
+
+    s.write("b", b"b");
+    assert_eq!(s.add(&["--atomic", "a", "b", "c"]).unwrap().stored, 3);
+    assert_eq!(rows(&s.path("a.db"), files), ["a", "b", "before", "c"]);
+}
+
+#[test]
+fn mtimes_keep_their_nanoseconds() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let s = Scratch::new("mtime");
+    let nanos: i64 = 1_500_000_000_123_456_789;
+    let set_mtime = |name: &str| {
+        let f = fs::File::options().write(true).open(s.path(name)).unwrap();
+        f.set_modified(UNIX_EPOCH + Duration::from_nanos(nanos as u64))
+            .unwrap();
+    };
+    s.write("f", b"f");
+    set_mtime("f");
+    s.add(&["f"]).unwrap();
+    assert_eq!(s.stored("f").mtime, Some(nanos));
+
+    fs::remove_file(s.path("f")).unwrap();
+    s.run(&["Extract", "f"]).unwrap();
+    let modified = fs::metadata(s.path("f")).unwrap().modified().unwrap();
+    assert_eq!(
+        modified.duration_since(UNIX_EPOCH).unwrap().as_nanos(),
+        nanos as u128
+    );
+
+    assert_eq!(format_mtime(nanos, false), "2017-07-14 02:40:00");
+    assert_eq!(format_mtime(nanos, true), "2017-07-14 02:40:00.123456789");
+    assert_eq!(format_mtime(-1, true), "1969-12-31 23:59:59.999999999");
+
+    // A file system that keeps whole seconds matches, one with other nanoseconds doesn't.
+    let second = nanos - nanos % 1_000_000_000;
+    assert!(same_mtime(nanos, second) && same_mtime(second, nanos));
+    assert!(!same_mtime(nanos, nanos + 1));
+    assert!(!same_mtime(second, second + 1_000_000_000));
+}
+
+#[test]
+fn du_splits_shared_chunks_between_directories() {
+    let s = Scratch::new("du");
+    fs::create_dir_all(s.path("a/deep")).unwrap();
+    fs::create_dir_all(s.path("b")).unwrap();
+    let shared = noise(100_000, 1);
+    s.write("a/x", &shared);
+    s.write("a/deep/z", &noise(50_000, 2));
+    s.write("r", b"tiny");
+    s.add(&["a/x", "a/deep/z", "r"]).unwrap();
+    s.write("b/y", &shared);
+    s.add(&["b/y"]).unwrap();
+
+    let db = s.open();
+    let du = |depth, shared| -> Vec<(String, f64)> {
+        du_totals(&db.connection, depth, shared, NameEncoding::Bytes)
+            .unwrap()
+            .into_iter()
+            .map(|(dir, size)| (dir.display().to_string(), size))
+            .collect()
+    };
+    let size = |name: &str| -> f64 {
+        let sql = "SELECT CAST(COALESCE(size, length(data)) AS TEXT) FROM chunks WHERE hash = ";
+        s.stored(name)
+            .chunks
+            .iter()
+            .map(|hash| rows(&s.path("a.db"), &format!("{}'{}'", sql, hash))[0].parse::<f64>())
+            .map(Result::unwrap)
+            .sum()
+    };
+    let x = size("a/x");
+    let z = size("a/deep/z");
+
+    // The file in b shares every chunk with one in a, so each gets half.
+    let split = du(1, "split");
+    assert_eq!(
+        split,
+        [
+            ("a".into(), x / 2.0 + z),
+            ("b".into(), x / 2.0),
+            (".".into(), 4.0)
+        ]
+    );
+    // Counted once, they're charged to a, which was stored first.
+    let once = du(1, "once");
+    assert_eq!(
+        once,
+        [("a".into(), x + z), (".".into(), 4.0), ("b".into(), 0.0)]
+    );
+
+    // Equal totals come in name order.
+    let deep = du(2, "split");
+    assert_eq!(
+        deep,
+        [
+            ("a".into(), x / 2.0),
+            ("a/deep".into(), z),
+            ("b".into(), x / 2.0),
+            (".".into(), 4.0)
+        ]
+    );
+}
+
+#[test]
+fn human_sizes_read_like_du_h() {
+    assert_eq!(human_size(0.0), "0");
+    assert_eq!(human_size(1023.0), "1023");
+    assert_eq!(human_size(1536.0), "1.5K");
+    assert_eq!(human_size(20.0 * 1024.0), "20K");
+    assert_eq!(human_size(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0G");
+}
+
+#[test]
+fn list_conflicts_classifies_targets_and_writes_nothing() {
+    let s = Scratch::new("conflicts");
+    s.write("same", &sample(10_000, 1));
+    s.write("size", &sample(10_000, 2));
+    s.write("bytes", &sample(10_000, 3));
+    s.write("gone", &sample(10_000, 4));
+    s.add(&["same", "size", "bytes", "gone"]).unwrap();
+
+    s.write("size", &sample(9_999, 2));
+    let mut bytes = sample(10_000, 3);
+    bytes[5000] ^= 1;
+    s.write("bytes", &bytes);
+    fs::remove_file(s.path("gone")).unwrap();
+
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let cache = ChunkCache::new(0);
+    let mut state = |name: &str| {
+        let stored = get_file(&mut trans, name.into(), NameEncoding::Bytes).unwrap();
+        conflict(&mut trans, &stored, &s.path(name), &cache).unwrap()
+    };
+    assert_eq!(state("same"), "identical");
+    assert_eq!(state("size"), "differs");
+    assert_eq!(state("bytes"), "differs");
+    assert_eq!(state("gone"), "missing");
+    drop(trans);
+    drop(db);
+
+    s.run(&["Extract", "--list-conflicts"]).unwrap();
+    assert!(!exists(&s.path("gone")));
+    assert_eq!(s.read("bytes"), bytes);
+    assert_eq!(s.read("size"), sample(9_999, 2));
+}
+
+#[test]
+fn incremental_auto_vacuum_gives_space_back_on_compact() {
+    let s = Scratch::new("auto-vacuum");
+    assert_eq!(
+        s.pragma(&["--auto-vacuum", "incremental"], "auto_vacuum"),
+        2
+    );
+    let mode = "SELECT value FROM meta WHERE key='auto_vacuum'";
+    assert_eq!(rows(&s.path("a.db"), mode), ["incremental"]);
+    // It can't be changed once the archive exists.
+    assert_eq!(s.pragma(&["--auto-vacuum", "none"], "auto_vacuum"), 2);
+
+    s.write("f", &noise(1_000_000, 1));
+    s.add(&["f"]).unwrap();
+    s.write("f", b"small now");
+    s.add(&["f"]).unwrap();
+    let full = fs::metadata(s.path("a.db")).unwrap().len();
+
+    s.run(&["Compact"]).unwrap();
+    assert_eq!(s.pragma(&[], "freelist_count"), 0);
+    let compacted = fs::metadata(s.path("a.db")).unwrap().len();
+    assert!(compacted < full / 2, "{} of {}", compacted, full);
+}
+
+#[test]
+fn deflate_chunks_round_trip_and_are_plain_zlib() {
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    let s = Scratch::new("deflate");
+    // Sixteen letters of noise: cut like noise, but compressible.
+    let data: Vec<u8> = noise(3_000_000, 1).iter().map(|b| b'a' + b % 16).collect();
+    s.write("f", &data);
+    s.add(&["--codec", "deflate", "f"]).unwrap();
+    let deflate = s.treatments("f");
+    assert!(deflate.len() > 1);
+    assert!(deflate.iter().all(|t| t.0 == "deflate"), "{:?}", deflate);
+    assert_eq!(s.cat("f").unwrap(), data);
+
+    let db = s.open();
+    let mut unzipped = Vec::new();
+    let mut stored = 0;
+    for hash in s.stored("f").chunks {
+        let blob: Vec<u8> = db
+            .connection
+            .query_row("SELECT data FROM chunks WHERE hash=?", &[&hash], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        stored += blob.len();
+        ZlibDecoder::new(&blob[..])
+            .read_to_end(&mut unzipped)
+            .unwrap();
+    }
+    assert_eq!(unzipped, data);
+    assert!(stored < data.len() * 2 / 3, "{}", stored);
+
+    s.run(&["Verify"]).unwrap();
+    s.run(&["Compact", "--chunk-bits", "19"]).unwrap();
+    assert!(s.treatments("f").len() > deflate.len());
+    assert!(s.treatments("f").iter().all(|t| *t == deflate[0]));
+    assert_eq!(s.cat("f").unwrap(), data);
+}
+
+#[test]
+fn list_dirs_prints_each_directory_once() {
+    let s = Scratch::new("list-dirs");
+    // "." sorts before "/", so a/b.x is listed before a/b.
+    for name in &[
+        "a/b/c/f", "a/b/g", "a/b.x/f", "a/bb/h", "a/f", "d/e/f", "top",
+    ] {
+        s.write(name, name.as_bytes());
+    }
+    s.add(&["a", "d", "top"]).unwrap();
+
+    let dirs = |max_depth| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        list_dirs_cmd(&mut db, max_depth, b'\n', NameEncoding::Bytes, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(dirs(None), "a/\na/b.x/\na/b/\na/b/c/\na/bb/\nd/\nd/e/\n");
+    assert_eq!(dirs(Some(1)), "a/\nd/\n");
+    assert_eq!(dirs(Some(2)), "a/\na/b.x/\na/b/\na/bb/\nd/\nd/e/\n");
+}
+
+#[test]
+fn adding_with_other_chunk_parameters_is_refused() {
+    let s = Scratch::new("params");
+    s.write("f", &noise(200_000, 1));
+    s.run(&["--chunk-bits", "14", "Add", "f"]).unwrap();
+    let chunks = s.stored("f").chunks;
+
+    let err = s.run(&["--chunk-bits", "16", "Add", "f"]).unwrap_err();
+    assert!(err.to_string().contains("Compact"), "{}", err);
+    let err = s.run(&["--hash", "blake3", "Add", "f"]).unwrap_err();
+    assert!(err.to_string().contains("Compact --hash"), "{}", err);
+    let bits = "SELECT value FROM meta WHERE key='chunk_bits'";
+    assert_eq!(rows(&s.path("a.db"), bits), ["14"]);
+
+    // Repeating the archive's own, or leaving them out, dedups against what's there.
+    s.write("g", &noise(200_000, 1));
+    s.run(&["--chunk-bits", "14", "Add", "g"]).unwrap();
+    s.add(&["f", "g"]).unwrap();
+    assert_eq!(s.stored("g").chunks, chunks);
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT hash FROM chunks").len(),
+        chunks.len()
+    );
+
+    // Forced, new chunks are cut the new way and later adds keep to it.
+    s.write("h", &noise(200_000, 1));
+    s.run(&["--chunk-bits", "16", "Add", "--force-params", "h"])
+        .unwrap();
+    assert_eq!(rows(&s.path("a.db"), bits), ["16"]);
+    assert_ne!(s.stored("h").chunks, chunks);
+    assert_eq!(s.cat("f").unwrap(), s.cat("h").unwrap());
+    s.run(&["--chunk-bits", "16", "Add", "g"]).unwrap();
+    assert_eq!(s.stored("g").chunks, s.stored("h").chunks);
+
+    // The hash can't be.
+    let forced = s.run(&["--hash", "blake3", "Add", "--force-params", "f"]);
+    assert!(forced.unwrap_err().to_string().contains("Compact --hash"));
+}
+
+#[test]
+fn chunk_bits_set_the_new_archives_chunk_size() {
+    let s = Scratch::new("chunk-bits");
+    let stats = || {
+        let mut out = Vec::new();
+        stats_cmd(&mut s.open(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert!(s.run(&["--chunk-bits", "7", "Migrate"]).is_err());
+    assert!(s.run(&["--chunk-bits", "40", "Migrate"]).is_err());
+
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let chunking = "chunking: --chunker zpaq --chunk-bits 14 (about 16384 bytes on average)";
+    assert!(stats().contains(chunking), "{}", stats());
+
+    // Later adds cut at that size without being told again.
+    let data = noise(400_000, 1);
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+    let chunks = s.stored("f").chunks.len();
+    assert!(chunks > 400_000 / 16384 / 3 && chunks < 400_000 / 16384 * 3);
+}
+
+#[test]
+fn transform_renames_on_add_and_extract() {
+    let s = Scratch::new("transform");
+    s.write("build/notes.txt", b"notes");
+    s.write("build/bin/tool", b"tool");
+    s.write("other", b"other");
+    let rules = [
+        "--transform",
+        "^build/=>release/",
+        "--transform",
+        r"(\w+)\.txt$=>${1}.md",
+    ];
+    s.add(&[&rules[..], &["build", "other"]].concat()).unwrap();
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT name FROM files ORDER BY name"),
+        ["other", "release/bin/tool", "release/notes.md"]
+    );
+
+    // Two files renamed to one name is an error, on add and on extract.
+    s.write("x/f", b"x");
+    s.write("y/f", b"y");
+    match s.add(&["--transform", "^[xy]/=>z/", "x", "y"]) {
+        Err(err) => assert!(err.to_string().contains("z/f"), "{}", err),
+        Ok(_) => panic!("x/f and y/f were both stored as z/f"),
+    }
+    s.add(&["x", "y"]).unwrap();
+    match s.extract(&["--transform", "^[xy]/=>z/", "x", "y"]) {
+        Err(err) => assert!(err.to_string().contains("both"), "{}", err),
+        Ok(_) => panic!("x/f and y/f were both extracted to z/f"),
+    }
+
+    s.extract(&["--transform", "^release/=>out/", "release"])
+        .unwrap();
+    assert_eq!(s.read("out/notes.md"), b"notes");
+    assert_eq!(s.read("out/bin/tool"), b"tool");
+    assert_eq!(s.stored("release/notes.md").size, 5);
+
+    // Names that would end up outside the output directory are skipped.
+    s.extract(&["--transform", "^other$=>../other", "other"])
+        .unwrap();
+    assert!(!exists(&s.path("../other")));
+}
+
+#[test]
+fn added_at_is_recorded_and_filters_list() {
+    let s = Scratch::new("added-at");
+    s.write("a", &noise(10_000, 1));
+    s.write("b", &noise(10_000, 2));
+    s.write("c", &noise(10_000, 3));
+    s.add(&["a"]).unwrap();
+    s.add(&["b"]).unwrap();
+    s.add(&["--deterministic", "c"]).unwrap();
+
+    let a = s.stored("a").added_at.unwrap();
+    let b = s.stored("b").added_at.unwrap();
+    assert!(a < b, "{} {}", a, b);
+    assert_eq!(s.stored("c").added_at, None);
+
+    let list = |added_after| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        let encoding = NameEncoding::Bytes;
+        list_cmd(
+            &mut db,
+            false,
+            false,
+            added_after,
+            &[],
+            b' ',
+            encoding,
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(list(None), "a b c ");
+    assert_eq!(list(Some(a - 1)), "a b ");
+    assert_eq!(list(Some(a)), "b ");
+    assert_eq!(list(Some(b)), "");
+
+    // Rechunking keeps the time each file was added.
+    s.run(&["Compact", "--chunk-bits", "12"]).unwrap();
+    assert_eq!(s.stored("a").added_at, Some(a));
+
+    let day = 1_700_006_400 * 1_000_000_000;
+    assert_eq!(parse_time("2023-11-15"), Ok(day));
+    assert_eq!(parse_time("1700006400"), Ok(day));
+    assert_eq!(parse_time("2023-11-15 00:00:01"), Ok(day + 1_000_000_000));
+    assert_eq!(parse_time("2023-11-15T00:00:00.5"), Ok(day + 500_000_000));
+    assert!(parse_time("yesterday").is_err());
+}
+
+#[test]
+fn journal_delete_leaves_no_wal_file() {
+    let s = Scratch::new("journal");
+    s.write("f", &noise(10_000, 1));
+    s.write("g", &noise(10_000, 2));
+    let journal_mode = |args: &[&str]| -> String {
+        let app = s.parse(&[args, &["Info"]].concat()).unwrap();
+        let db = SqliteDatabase::new(&app.opt.database, &app.opt.db_opt).unwrap();
+        db.connection
+            .query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    };
+    // The file format versions in the header are 2 in WAL mode and 1 otherwise.
+    let versions = || s.read("a.db")[18..20].to_vec();
+
+    assert_eq!(journal_mode(&["--journal", "delete"]), "delete");
+    s.run(&["--journal", "delete", "Add", "f"]).unwrap();
+    assert!(!exists(&s.path("a.db-wal")));
+    assert_eq!(versions(), [1, 1]);
+
+    assert_eq!(journal_mode(&[]), "wal");
+    s.run(&["Add", "g"]).unwrap();
+    assert_eq!(versions(), [2, 2]);
+}
+
+#[test]
+fn cat_expands_directories_in_name_order() {
+    let s = Scratch::new("cat-dirs");
+    s.write("head", b"<");
+    s.write("tail", b">");
+    // Stored out of order, one add at a time.
+    for part in &["002", "000", "010", "001"] {
+        let name = format!("parts/{}", part);
+        s.write(&name, part.as_bytes());
+        s.add(&[&name]).unwrap();
+    }
+    s.write("parts-old/000", b"old");
+    s.add(&["head", "tail", "parts-old"]).unwrap();
+
+    let cat = |files: &[&str], expand_dirs| -> Result<String, Error> {
+        let mut db = s.open();
+        let files = files.iter().map(PathBuf::from).collect();
+        let mut out = Vec::new();
+        let cache = ChunkCache::new(0);
+        cat_cmd(
+            &mut db,
+            files,
+            expand_dirs,
+            NameEncoding::Bytes,
+            &cache,
+            &mut out,
+        )?;
+        Ok(String::from_utf8(out).unwrap())
+    };
+    assert_eq!(cat(&["tail", "head"], true).unwrap(), "><");
+    assert_eq!(
+        cat(&["head", "parts", "tail"], true).unwrap(),
+        "<000001002010>"
+    );
+    assert_eq!(cat(&["parts/"], true).unwrap(), "000001002010");
+
+    match cat(&["head", "parts"], false).unwrap_err().downcast() {
+        Ok(ChsqlarError::FileNotFound { path }) => assert_eq!(path, Path::new("parts")),
+        other => panic!("{:?}", other.map(|e| e.to_string())),
+    }
+    assert!(cat(&["missing"], true).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn special_bits_are_never_restored() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let s = Scratch::new("special-bits");
+    s.write("tool", b"#!/bin/sh\n");
+    let setuid = fs::Permissions::from_mode(0o6755);
+    fs::set_permissions(s.path("tool"), setuid).unwrap();
+    s.add(&["tool"]).unwrap();
+
+    fs::remove_file(s.path("tool")).unwrap();
+    s.run(&["Extract", "tool"]).unwrap();
+    let mode = fs::metadata(s.path("tool")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7000, 0, "{:o}", mode);
+}
+
+#[test]
+fn checksum_prints_sha256sum_lines_in_name_order() {
+    let s = Scratch::new("checksum");
+    s.write("fox", FOX);
+    s.write("d/fox", FOX);
+    s.write("d/empty", b"");
+    s.add(&["fox", "d"]).unwrap();
+
+    let checksum = |algo: &str, prefix: Option<&str>| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        let cache = ChunkCache::new(0);
+        let prefix = prefix.map(PathBuf::from);
+        checksum_cmd(
+            &mut db,
+            algo,
+            prefix,
+            0,
+            NameEncoding::Bytes,
+            &cache,
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let fox = "2f1514181aadccd913abd94cfa592701a5686ab23f8df1dff1b74710febc6d4a";
+    let empty = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+    assert_eq!(
+        checksum("blake3", None),
+        format!("{}  d/empty\n{}  d/fox\n{}  fox\n", empty, fox, fox)
+    );
+    assert_eq!(
+        checksum("blake3", Some("d")),
+        format!("{}  d/empty\n{}  d/fox\n", empty, fox)
+    );
+
+    // The sha256 stored when adding is used rather than reading the file again.
+    let planted = "0".repeat(64);
+    s.open()
+        .connection
+        .execute("UPDATE files SET sha256=? WHERE name='fox'", &[&planted])
+        .unwrap();
+    assert!(checksum("sha256", Some("fox")).starts_with(&planted));
+}
+
+#[test]
+fn repair_compression_keeps_hashes_and_content() {
+    let s = Scratch::new("repair-compression");
+    s.run(&["--chunk-bits", "16", "Migrate"]).unwrap();
+    // Numbered lines of words, which level 19 packs tighter than level 1.
+    let words: Vec<&str> = std::str::from_utf8(FOX).unwrap().split(' ').collect();
+    let data: Vec<u8> = noise(100_000, 1)
+        .iter()
+        .enumerate()
+        .map(|(i, b)| format!("{} {}\n", i, words[*b as usize % words.len()]))
+        .collect::<String>()
+        .into_bytes();
+    s.write("f", &data);
+    s.add(&["--level", "1", "f"]).unwrap();
+    let chunks = s.stored("f").chunks;
+    assert!(chunks.len() > 1);
+    let stored = || -> usize {
+        let sql = "SELECT CAST(SUM(length(data)) AS TEXT) FROM chunks";
+        rows(&s.path("a.db"), sql)[0].parse().unwrap()
+    };
+    let before = stored();
+
+    s.run(&["Verify", "--repair-compression", "19"]).unwrap();
+    assert!(s
+        .treatments("f")
+        .iter()
+        .all(|t| *t == ("zstd".to_owned(), Some(19))));
+    assert!(stored() < before, "{} {}", stored(), before);
+    assert_eq!(s.stored("f").chunks, chunks);
+    assert_eq!(s.cat("f").unwrap(), data);
+
+    s.run(&["Verify", "--repair-compression", "store"]).unwrap();
+    assert!(s.treatments("f").iter().all(|t| t.0 == "store"));
+    assert_eq!(s.cat("f").unwrap(), data);
+    s.run(&["Verify"]).unwrap();
+}
+
+// Quotes and tabs aren't allowed in Windows names.
+#[cfg(unix)]
+#[test]
+fn manifest_escapes_names_in_each_format() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let s = Scratch::new("manifest");
+    let write = |name: &str, data: &[u8], mtime: Duration| {
+        s.write(name, data);
+        let f = fs::File::options().write(true).open(s.path(name)).unwrap();
+        f.set_modified(UNIX_EPOCH + mtime).unwrap();
+    };
+    write("a,\"b\"", FOX, Duration::from_secs(1_000_000_000));
+    write("tab\there", b"x", Duration::new(1_000_000_000, 500_000_000));
+    s.add(&["a,\"b\"", "tab\there"]).unwrap();
+
+    let manifest = |format: &str| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        let cache = ChunkCache::new(0);
+        manifest_cmd(&mut db, format, NameEncoding::Bytes, &cache, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let fox = "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592";
+    let x = "2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881";
+
+    assert_eq!(
+        manifest("csv"),
+        format!(
+            "name,size,chunks,sha256,mtime\n\
+             \"a,\"\"b\"\"\",43,0,{},2001-09-09T01:46:40Z\n\
+             tab\there,1,0,{},2001-09-09T01:46:40.500Z\n",
+            fox, x
+        )
+    );
+    assert_eq!(
+        manifest("tsv").lines().nth(2).unwrap(),
+        format!("tab\\there\t1\t0\t{}\t2001-09-09T01:46:40.500Z", x)
+    );
+    let line: serde_json::Value =
+        serde_json::from_str(manifest("json-lines").lines().next().unwrap()).unwrap();
+    assert_eq!(line["name"], "a,\"b\"");
+    assert_eq!(line["sha256"], fox);
+    assert_eq!(line["size"], 43);
+}
+
+#[test]
+fn sha256sum_manifest_verifies_an_extracted_tree() {
+    let s = Scratch::new("manifest-sha256");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("d/big", &noise(100_000, 1));
+    s.write("d/small", FOX);
+    s.write("e", b"");
+    s.add(&["d", "e"]).unwrap();
+
+    let manifest = || {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        let cache = ChunkCache::new(0);
+        manifest_cmd(&mut db, "sha256sum", NameEncoding::Bytes, &cache, &mut out).unwrap();
+        out
+    };
+    let stored = manifest();
+    let sha256 = s.stored("d/big").sha256;
+    assert!(sha256.is_some());
+    s.run(&["Compact", "--chunk-bits", "12"]).unwrap();
+    assert_eq!(s.stored("d/big").sha256, sha256);
+
+    // Rows without a stored hash, as older archives have, are hashed from their content.
+    s.open()
+        .connection
+        .execute("UPDATE files SET sha256=NULL", NO_PARAMS)
+        .unwrap();
+    assert_eq!(manifest(), stored);
+
+    fs::remove_dir_all(s.path("d")).unwrap();
+    fs::remove_file(s.path("e")).unwrap();
+    s.extract(&["d", "e"]).unwrap();
+    fs::write(s.path("SHA256SUMS"), &stored).unwrap();
+    let check = std::process::Command::new("sha256sum")
+        .args(["--check", "--quiet", "SHA256SUMS"])
+        .current_dir(&s.dir)
+        .status()
+        .unwrap();
+    assert!(check.success());
+}
+
+#[test]
+fn complete_offers_stored_names_a_level_at_a_time() {
+    let s = Scratch::new("complete");
+    for name in &["d/a/x", "d/a/y", "d/b", "dz", "e"] {
+        s.write(name, name.as_bytes());
+    }
+    s.add(&["d", "dz", "e"]).unwrap();
+    for i in 0..250 {
+        s.write(&format!("many/{:03}", i), b"");
+    }
+    s.add(&["many"]).unwrap();
+
+    let complete = |database: &str, partial: &str| -> Result<String, Error> {
+        let mut out = Vec::new();
+        complete_cmd(database, partial, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    };
+    let archive = s.path("a.db").display().to_string();
+    assert_eq!(complete(&archive, "d").unwrap(), "d/\ndz\n");
+    assert_eq!(complete(&archive, "d/").unwrap(), "d/a/\nd/b\n");
+    assert_eq!(complete(&archive, "d/a/").unwrap(), "d/a/x\nd/a/y\n");
+    assert_eq!(complete(&archive, "x").unwrap(), "");
+    assert_eq!(complete(&archive, "many/").unwrap().lines().count(), 200);
+
+    // A missing archive is an error, which __complete swallows, and isn't created.
+    let missing = s.path("missing.db").display().to_string();
+    assert!(complete(&missing, "").is_err());
+    s.run_on("missing.db", &["__complete", "Extract", ""])
+        .unwrap();
+    assert!(!exists(&s.path("missing.db")));
+}
+
+#[test]
+fn split_parts_merge_back_into_the_same_files() {
+    let s = Scratch::new("split");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+        s.write(name, &noise(100_000, i as u64));
+    }
+    s.write("copy-of-a", &noise(100_000, 0));
+    s.write("huge", &noise(400_000, 9));
+    s.add(&["a", "b", "c", "d", "copy-of-a", "huge"]).unwrap();
+
+    s.run(&["Split", "--max-size", "250K", "--out", "part-{}.db"])
+        .unwrap();
+    let parts: Vec<String> = (1..)
+        .map(|i| format!("part-{}.db", i))
+        .take_while(|part| exists(&s.path(part)))
+        .collect();
+    assert!(parts.len() >= 3, "{:?}", parts);
+    let mut names = Vec::new();
+    for part in &parts {
+        let files = rows(&s.path(part), "SELECT name FROM files");
+        let size = fs::metadata(s.path(part)).unwrap().len();
+        // Only a part holding a file too big for any part can be over.
+        assert!(size <= 250 * 1024 || files == ["huge"], "{} {}", part, size);
+        names.extend(files);
+    }
+    names.sort();
+    assert_eq!(names, ["a", "b", "c", "copy-of-a", "d", "huge"]);
+
+    let mut merge = vec!["Merge"];
+    merge.extend(parts.iter().map(String::as_str));
+    s.run_on("merged.db", &merge).unwrap();
+    let manifest = "SELECT name || ' ' || sha256 FROM files ORDER BY name";
+    assert_eq!(
+        rows(&s.path("merged.db"), manifest),
+        rows(&s.path("a.db"), manifest)
+    );
+    s.run_on("merged.db", &["Verify"]).unwrap();
+}
+
+#[test]
+fn removing_files_frees_only_the_chunks_they_alone_used() {
+    let s = Scratch::new("remove");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("d/a", &noise(100_000, 1));
+    s.write("d/b", &noise(100_000, 2));
+    s.write("keep", &noise(100_000, 1));
+    s.write("old", &noise(100_000, 3));
+    s.add(&["d", "keep", "old"]).unwrap();
+    // Replacing old orphans its chunks, which removing other files leaves to Compact.
+    s.write("old", b"replaced");
+    s.add(&["old"]).unwrap();
+    let orphans = rows(&s.path("a.db"), "SELECT hash FROM chunks WHERE refcount<=0");
+    assert!(!orphans.is_empty());
+
+    let mut b = s.stored("d/b").chunks;
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let names = [PathBuf::from("d/a"), PathBuf::from("d/b")];
+    let mut freed = remove_files(&mut trans, &names, NameEncoding::Bytes).unwrap();
+    trans.commit().unwrap();
+    drop(db);
+
+    // d/a's chunks are still used by keep.
+    freed.sort();
+    b.sort();
+    assert_eq!(freed, b);
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT name FROM files ORDER BY name"),
+        ["keep", "old"]
+    );
+    assert_eq!(s.cat("keep").unwrap(), noise(100_000, 1));
+    let mut left = s.stored("keep").chunks;
+    left.extend(orphans);
+    left.sort();
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT hash FROM chunks ORDER BY hash"),
+        left
+    );
+}
+
+#[cfg(feature = "tui")]
+#[test]
+fn browse_counts_shared_chunks_once() {
+    use crate::browse::Browser;
+
+    let s = Scratch::new("browse");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("d/a", &noise(100_000, 1));
+    s.write("d/b", &noise(100_000, 1));
+    s.write("d/small", b"small");
+    s.add(&["d"]).unwrap();
+
+    let stored: u64 = rows(
+        &s.path("a.db"),
+        "SELECT CAST(SUM(length(data)) AS TEXT) FROM chunks",
+    )[0]
+    .parse()
+    .unwrap();
+    let mut db = s.open();
+    let cache = ChunkCache::new(0);
+    let browser = Browser::new(&mut db, NameEncoding::Bytes, &cache).unwrap();
+    assert_eq!(browser.sizes(Path::new("d")), (3, 200_005, stored + 5));
+    assert_eq!(browser.sizes(Path::new("d/a")), (1, 100_000, stored));
+}
+
+#[test]
+fn merge_dedups_chunks_and_resolves_conflicts() {
+    let s = Scratch::new("merge");
+    let big = noise(300_000, 1);
+    for (archive, seed) in &[("x.db", 2), ("y.db", 3)] {
+        s.write("big", &big);
+        s.write("same", FOX);
+        s.write("clash", &noise(10_000, *seed));
+        s.run_on(
+            archive,
+            &["--chunk-bits", "14", "Add", "big", "same", "clash"],
+        )
+        .unwrap();
+    }
+    let chunks = |archive: &str| rows(&s.path(archive), "SELECT hash FROM chunks").len();
+    let names = |archive: &str| rows(&s.path(archive), "SELECT name FROM files ORDER BY name");
+    let content = |archive: &str, name: &str| {
+        let sql = format!("SELECT sha256 FROM files WHERE name='{}'", name);
+        rows(&s.path(archive), &sql)
+    };
+
+    // By default a name with different content in two inputs stops the merge.
+    let merge = |archive: &str, policy: &str| {
+        s.run_on(
+            archive,
+            &[
+                "--chunk-bits",
+                "14",
+                "Merge",
+                "x.db",
+                "y.db",
+                "--on-conflict",
+                policy,
+            ],
+        )
+    };
+    assert!(merge("error.db", "error").is_err());
+    assert!(rows(&s.path("error.db"), "SELECT name FROM files").is_empty());
+
+    merge("first.db", "first-wins").unwrap();
+    assert_eq!(names("first.db"), ["big", "clash", "same"]);
+    assert_eq!(content("first.db", "clash"), content("x.db", "clash"));
+    // Only clash differs between x and y, so y brings nothing else.
+    assert_eq!(chunks("first.db"), chunks("x.db"));
+
+    merge("skip.db", "skip").unwrap();
+    assert_eq!(names("skip.db"), ["big", "same"]);
+
+    merge("rename.db", "rename").unwrap();
+    assert_eq!(names("rename.db"), ["big", "clash", "clash.~1~", "same"]);
+    assert_eq!(content("rename.db", "clash.~1~"), content("y.db", "clash"));
+    s.run_on("rename.db", &["Verify"]).unwrap();
+
+    // Inputs cut with other chunk parameters wouldn't dedup, and are refused.
+    s.run_on("z.db", &["--chunk-bits", "16", "Add", "same"])
+        .unwrap();
+    assert!(s.run_on("first.db", &["Merge", "z.db"]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn list_null_ends_names_with_nul_bytes() {
+    let s = Scratch::new("list-null");
+    s.write("two\nlines/f", b"f");
+    s.write("plain", b"p");
+    s.add(&["two\nlines", "plain"]).unwrap();
+
+    let list = |long| {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        let encoding = NameEncoding::Bytes;
+        list_cmd(&mut db, long, false, None, &[], b'\0', encoding, &mut out).unwrap();
+        out
+    };
+    assert_eq!(list(false), b"plain\0two\nlines/f\0");
+    let long = list(true);
+    assert_eq!(long.iter().filter(|b| **b == 0).count(), 2);
+    assert!(long.ends_with(b" two\nlines/f\0"));
+
+    let mut db = s.open();
+    let mut out = Vec::new();
+    list_dirs_cmd(&mut db, None, b'\0', NameEncoding::Bytes, &mut out).unwrap();
+    assert_eq!(out, b"two\nlines/\0");
+
+    for flag in &["-0", "--null"] {
+        match s.parse(&["List", flag]).unwrap().cmd {
+            OptCommand::List { null, .. } => assert!(null),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn no_chunk_compression_stores_raw_chunks() {
+    let s = Scratch::new("no-compression");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let data = [noise(100_000, 1), sample(100_000, 2)].concat();
+    s.write("f", &data);
+    s.write("small", FOX);
+    let rule = "--compress-rule=*=19";
+    s.add(&[
+        "--no-chunk-compression",
+        rule,
+        "--codec",
+        "deflate",
+        "f",
+        "small",
+    ])
+    .unwrap();
+
+    assert!(s.treatments("f").iter().all(|t| t.0 == "store"));
+    let db = s.open();
+    let mut raw = Vec::new();
+    for hash in s.stored("f").chunks {
+        let blob: Vec<u8> = db
+            .connection
+            .query_row("SELECT data FROM chunks WHERE hash=?", &[&hash], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        raw.extend(blob);
+    }
+    assert_eq!(raw, data);
+    assert_eq!(
+        s.stored("small").inline,
+        Some(("store".to_owned(), FOX.to_vec()))
+    );
+
+    assert_eq!(s.cat("f").unwrap(), data);
+    assert_eq!(s.cat("small").unwrap(), FOX);
+}
+
+#[test]
+fn run_summaries_count_what_was_done() {
+    let s = Scratch::new("metrics");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("a", &noise(200_000, 1));
+    s.write("copy", &noise(200_000, 1));
+    let summary = s.add(&["a", "copy"]).unwrap().metrics.to_json("add");
+    let chunks = s.stored("a").chunks.len() as u64;
+    let stored = rows(
+        &s.path("a.db"),
+        "SELECT CAST(SUM(length(data)) AS TEXT) FROM chunks",
+    );
+
+    assert_eq!(summary["op"], "add");
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["bytes_read"], 400_000);
+    assert_eq!(summary["chunks_new"], chunks);
+    assert_eq!(summary["chunks_deduplicated"], chunks);
+    assert_eq!(summary["bytes_stored"].to_string(), stored[0]);
+    assert!(summary["read_mb_per_sec"].as_f64().unwrap() > 0.0);
+
+    fs::remove_file(s.path("a")).unwrap();
+    fs::remove_file(s.path("copy")).unwrap();
+    let summary = s
+        .extract(&["a", "copy"])
+        .unwrap()
+        .metrics
+        .to_json("extract");
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["bytes_written"], 400_000);
+    assert!(summary.get("chunks_new").is_none());
+}
+
+#[test]
+fn colour_only_when_asked_or_on_a_terminal() {
+    use crate::output::{init, paint, Stream, Style};
+
+    // Colour is process-wide, so this holds the scratch lock the tests reading output take.
+    let s = Scratch::new("colour");
+    s.write("d/f", b"f");
+    s.add(&["d"]).unwrap();
+    let dirs = || {
+        let mut db = s.open();
+        let mut out = Vec::new();
+        list_dirs_cmd(&mut db, None, b'\n', NameEncoding::Bytes, &mut out).unwrap();
+        out
+    };
+
+    init("always");
+    let size = format!("{:>4}", paint(Stream::Stdout, Style::Size, 12));
+    let listed = dirs();
+    init("never");
+    assert_eq!(size, "\x1b[36m  12\x1b[0m");
+    assert_eq!(listed, b"\x1b[1;34md/\x1b[0m\n");
+
+    assert_eq!(
+        format!("{:>4}", paint(Stream::Stdout, Style::Size, 12)),
+        "  12"
+    );
+    assert_eq!(dirs(), b"d/\n");
+}
+
+#[test]
+fn no_chunk_is_over_the_cap() {
+    let s = Scratch::new("max-chunk-bytes");
+    // A short pattern over and over gives the chunker nowhere to cut.
+    let data: Vec<u8> = b"abcdefg".iter().cycle().take(2_000_000).cloned().collect();
+    let chunks = |args: &[&str]| {
+        let app = s.parse(&[args, &["Info"]].concat()).unwrap();
+        chunk_data(data.clone(), chunker_params_for(&app.opt, None).unwrap())
+    };
+    assert_eq!(chunks(&[]).len(), 1);
+    let capped = chunks(&["--max-chunk-bytes", "65536"]);
+    assert!(capped.len() >= data.len() / 65536);
+    assert!(capped.iter().all(|chunk| chunk.len() <= 65536));
+    assert_eq!(capped.concat(), data);
+
+    // The cap is kept in the archive, and applies to later adds.
+    s.run(&["--max-chunk-bytes", "65536", "Migrate"]).unwrap();
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+    let sizes = "SELECT CAST(max(uncompressed_size) AS TEXT) FROM chunks";
+    assert_eq!(rows(&s.path("a.db"), sizes), ["65536"]);
+    assert_eq!(s.cat("f").unwrap(), data);
+
+    assert!(s.parse(&["--max-chunk-bytes", "1000", "Info"]).is_err());
+}
+
+#[test]
+fn chunk_file_offsets_cover_the_file_and_match_add() {
+    let s = Scratch::new("chunk-file");
+    let data = noise(300_000, 1);
+    s.write("f", &data);
+
+    let cuts = |args: &[&str], bits| -> Vec<(usize, usize, String)> {
+        let app = s.parse(&[args, &["Info"]].concat()).unwrap();
+        let params = chunker_params_for(&app.opt, bits).unwrap();
+        let mut out = Vec::new();
+        chunk_file_cmd(&s.path("f"), params, ChunkHash::Sha3, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                let offset = fields[0].parse().unwrap();
+                (offset, fields[1].parse().unwrap(), fields[2].to_owned())
+            })
+            .collect()
+    };
+    let at_12 = cuts(&[], Some(12));
+    assert!(at_12.len() > 10);
+    let mut offset = 0;
+    for (at, len, _) in &at_12 {
+        assert_eq!(*at, offset);
+        offset += len;
+    }
+    assert_eq!(offset, data.len());
+    // Looking never creates the archive.
+    s.run(&["ChunkFile", "f"]).unwrap();
+    assert!(!exists(&s.path("a.db")));
+
+    // Without --chunk-bits it cuts the way the archive does.
+    s.run(&["--chunk-bits", "14", "Add", "f"]).unwrap();
+    let hashes: Vec<String> = cuts(&[], None).into_iter().map(|cut| cut.2).collect();
+    assert_eq!(hashes, s.stored("f").chunks);
+    assert!(cuts(&["--chunk-bits", "12"], None).len() > hashes.len());
+}
+
+#[test]
+fn lz4_chunks_round_trip_and_codecs_go_by_the_tag() {
+    use std::io::Read;
+
+    let s = Scratch::new("lz4");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let data: Vec<u8> = noise(200_000, 1).iter().map(|b| b'a' + b % 4).collect();
+    s.write("f", &data);
+    s.add(&["--algo", "lz4", "f"]).unwrap();
+    assert!(s.treatments("f").iter().all(|t| t.0 == "lz4"));
+    assert_eq!(s.cat("f").unwrap(), data);
+
+    let db = s.open();
+    let mut unpacked = Vec::new();
+    for hash in s.stored("f").chunks {
+        let blob: Vec<u8> = db
+            .connection
+            .query_row("SELECT data FROM chunks WHERE hash=?", &[&hash], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        lz4_flex::frame::FrameDecoder::new(&blob[..])
+            .read_to_end(&mut unpacked)
+            .unwrap();
+    }
+    assert_eq!(unpacked, data);
+
+    // A file that happens to be a zstd frame, stored as is, comes back as is.
+    let frame = zstd::encode_all(&data[..], 3).unwrap();
+    s.write("frame.zst", &frame);
+    s.add(&["--codec", "store", "frame.zst"]).unwrap();
+    assert_eq!(s.cat("frame.zst").unwrap(), frame);
+}
+
+#[test]
+fn a_file_can_mix_every_codec() {
+    let s = Scratch::new("mixed-codecs");
+    s.run(&["--chunker", "fixed", "--chunk-size", "4096", "Migrate"])
+        .unwrap();
+    let mut codecs = vec!["store", "lz4", "deflate", "zstd"];
+    if cfg!(feature = "brotli") {
+        codecs.push("brotli");
+    }
+
+    // Each part is stored with its own codec, and the whole file reuses their chunks.
+    let mut whole = Vec::new();
+    for (i, codec) in codecs.iter().enumerate() {
+        let part = sample(8192, i as u8);
+        let name = format!("part-{}", codec);
+        s.write(&name, &part);
+        s.add(&["--codec", codec, &name]).unwrap();
+        whole.extend(part);
+    }
+    s.write("whole", &whole);
+    s.add(&["whole"]).unwrap();
+    let used: Vec<String> = s.treatments("whole").into_iter().map(|t| t.0).collect();
+    let expected: Vec<String> = codecs
+        .iter()
+        .flat_map(|codec| vec![codec.to_string(); 2])
+        .collect();
+    assert_eq!(used, expected);
+    assert_eq!(s.cat("whole").unwrap(), whole);
+    s.run(&["Verify"]).unwrap();
+
+    s.run(&["Verify", "--repair-compression", "lz4"]).unwrap();
+    assert!(s.treatments("whole").iter().all(|t| t.0 == "lz4"));
+    assert_eq!(s.cat("whole").unwrap(), whole);
+    s.run(&["Verify", "--repair-compression", "3"]).unwrap();
+    let zstd = ("zstd".to_owned(), Some(3));
+    assert!(s.treatments("whole").iter().all(|t| *t == zstd));
+    assert_eq!(s.cat("whole").unwrap(), whole);
+}
+
+#[test]
+fn a_tiny_time_budget_stops_and_resumes() {
+    let s = Scratch::new("time-budget");
+    let names: Vec<String> = (0..8).map(|i| format!("f{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        s.write(name, &sample(100, i as u8));
+    }
+    let mut args = vec!["--time-budget", "1", "--filter", "sleep 0.3; cat"];
+    args.extend(names.iter().map(String::as_str));
+
+    // Files not started before the deadline are left for later, and the journal is kept.
+    let first = s.add(&args).unwrap();
+    assert!(first.stored > 0 && first.stored < names.len());
+    assert_eq!(first.stored + first.out_of_time.len(), names.len());
+    let journal = "SELECT name FROM add_journal ORDER BY name";
+    assert_eq!(rows(&s.path("a.db"), journal), names[..first.stored]);
+
+    // The same add without a budget only reads the rest, and clears the journal.
+    let second = s.add(&args[2..]).unwrap();
+    assert!(second.out_of_time.is_empty());
+    assert_eq!(second.bytes, 100 * (names.len() - first.stored) as u64);
+    assert!(rows(&s.path("a.db"), journal).is_empty());
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(s.cat(name).unwrap(), sample(100, i as u8));
+    }
+
+    assert!(s.parse(&["Add", "--time-budget", "2d", "f0"]).is_ok());
+    assert!(s.parse(&["Add", "--time-budget", "45x", "f0"]).is_err());
+}
+
+#[test]
+fn modified_since_diffs_two_archives() {
+    let s = Scratch::new("modified-since");
+    s.write("same", b"kept");
+    s.write("changed", b"before");
+    s.write("gone", b"removed");
+    s.add(&["same", "changed", "gone"]).unwrap();
+    fs::copy(s.path("a.db"), s.path("old.db")).unwrap();
+
+    // The same size, so only the sha256 shows the change.
+    s.write("changed", b"after!");
+    s.write("new", b"added");
+    s.add(&["changed", "new"]).unwrap();
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    remove_files(&mut trans, &[PathBuf::from("gone")], NameEncoding::Bytes).unwrap();
+    trans.commit().unwrap();
+
+    let diff = |db: &mut SqliteDatabase| {
+        let mut out = Vec::new();
+        let old = s.path("old.db");
+        let (old, encoding) = (old.to_str().unwrap(), NameEncoding::Bytes);
+        list_changes_cmd(db, old, b'\n', encoding, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(diff(&mut db), "A\tnew\nM\tchanged\nD\tgone\n");
+
+    // Without hashes, a change of the same size goes unseen.
+    let mut db = s.open();
+    db.connection
+        .execute("UPDATE files SET sha256 = NULL", NO_PARAMS)
+        .unwrap();
+    assert_eq!(diff(&mut db), "A\tnew\nD\tgone\n");
+}
+
+#[test]
+fn fastcdc_is_kept_for_the_archive() {
+    let s = Scratch::new("fastcdc");
+    let data = noise(400_000, 1);
+    let chunker = chunker::Algorithm::FastCdc.chunker(14, 0);
+    let chunks = chunker.cut(&data);
+    assert_eq!(chunks.concat(), data);
+    let (last, rest) = chunks.split_last().unwrap();
+    assert!(rest.iter().all(|c| (4096..=65536).contains(&c.len())));
+    assert!(last.len() <= 65536);
+
+    // An insertion only changes the chunks around it.
+    let mut inserted = data.clone();
+    inserted.splice(200_000..200_000, b"inserted".iter().cloned());
+    let after = chunker.cut(&inserted);
+    let shared = after.iter().filter(|c| chunks.contains(c)).count();
+    assert!(shared >= chunks.len() - 3, "{} of {}", shared, chunks.len());
+
+    s.run(&["--chunker", "fastcdc", "--chunk-bits", "14", "Migrate"])
+        .unwrap();
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+    assert_eq!(s.stored("f").chunks.len(), chunks.len());
+    let mut out = Vec::new();
+    stats_cmd(&mut s.open(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("--chunker fastcdc --chunk-bits 14"), "{}", out);
+
+    // Another chunker is refused, short of Compact cutting everything again.
+    let err = s.run(&["--chunker", "zpaq", "Add", "f"]).unwrap_err();
+    assert!(err.to_string().contains("Compact"), "{}", err);
+    s.run(&["Compact", "--chunker", "zpaq"]).unwrap();
+    let chunker = "SELECT value FROM meta WHERE key='chunker'";
+    assert_eq!(rows(&s.path("a.db"), chunker), ["zpaq"]);
+    assert_eq!(s.cat("f").unwrap(), data);
+}
+
+#[test]
+fn no_recurse_leaves_directory_contents_out() {
+    let s = Scratch::new("no-recurse");
+    s.write("d/a", b"a");
+    s.write("d/sub/b", b"b");
+    s.write("top", b"top");
+
+    // The directory itself is reported and skipped, and only what's named is stored.
+    let summary = s.add(&["--no-recurse", "d", "top", "d/a"]).unwrap();
+    assert_eq!(summary.failed, [s.path("d")]);
+    let names = "SELECT name FROM files ORDER BY name";
+    assert_eq!(rows(&s.path("a.db"), names), ["d/a", "top"]);
+    assert!(s.add(&["--no-recurse", "--strict", "d"]).is_err());
+
+    s.add(&["d"]).unwrap();
+    assert_eq!(rows(&s.path("a.db"), names), ["d/a", "d/sub/b", "top"]);
+}
+
+#[test]
+fn fixed_chunks_leave_the_remainder_last() {
+    let s = Scratch::new("fixed");
+    let lengths = |len: usize, size| -> Vec<usize> {
+        let data = noise(len, 1);
+        let chunks = chunker::Algorithm::Fixed.chunker(12, size).cut(&data);
+        assert_eq!(chunks.concat(), data);
+        chunks.iter().map(|c| c.len()).collect()
+    };
+    assert_eq!(lengths(10_000, 4096), [4096, 4096, 1808]);
+    assert_eq!(lengths(8192, 4096), [4096, 4096]);
+    assert_eq!(lengths(8193, 4096), [4096, 4096, 1]);
+    assert_eq!(lengths(1000, 4096), [1000]);
+    assert_eq!(lengths(1001, 1000), [1000, 1]);
+    assert!(lengths(0, 4096).is_empty());
+
+    assert!(s.run(&["--chunk-size", "4096", "Migrate"]).is_err());
+    let fixed = ["--chunker", "fixed", "--chunk-size"];
+    assert!(s.run(&[&fixed[..], &["511", "Migrate"]].concat()).is_err());
+
+    // Without --chunk-size, blocks are 2^--chunk-bits.
+    let app = s
+        .parse(&["--chunker", "fixed", "--chunk-bits", "12", "Info"])
+        .unwrap();
+    let params = chunker_params_for(&app.opt, None).unwrap();
+    let cuts: Vec<usize> = chunk_data(noise(10_000, 1), params)
+        .iter()
+        .map(Vec::len)
+        .collect();
+    assert_eq!(cuts, [4096, 4096, 1808]);
+
+    s.run(&[&fixed[..], &["3000", "Migrate"]].concat()).unwrap();
+    let data = noise(10_000, 2);
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+    let sizes = "SELECT CAST(uncompressed_size AS TEXT) FROM chunks ORDER BY uncompressed_size";
+    assert_eq!(
+        rows(&s.path("a.db"), sizes),
+        ["1000", "3000", "3000", "3000"]
+    );
+    assert_eq!(s.cat("f").unwrap(), data);
+}
+
+#[test]
+fn truncated_hashes_have_their_length_and_dedup() {
+    let s = Scratch::new("hash-bytes");
+    assert!(s.run(&["--hash-bytes", "7", "Migrate"]).is_err());
+    assert!(s.run(&["--hash-bytes", "65", "Migrate"]).is_err());
+    s.run(&["--hash-bytes", "16", "--chunk-bits", "14", "Migrate"])
+        .unwrap();
+
+    let data = noise(300_000, 1);
+    s.write("f", &data);
+    let mut appended = data.clone();
+    appended.extend(noise(1000, 2));
+    s.write("g", &appended);
+    s.add(&["f"]).unwrap();
+    let f = s.stored("f").chunks;
+    let hash = ChunkHash::Blake3 { bytes: 16 };
+    assert_eq!(hash.of(&data[..0]).len(), 32);
+    let lengths = "SELECT DISTINCT CAST(length(hash) AS TEXT) FROM chunks";
+    assert_eq!(rows(&s.path("a.db"), lengths), ["32"]);
+
+    // The copy with bytes appended shares every chunk of f but the last.
+    s.add(&["g"]).unwrap();
+    let g = s.stored("g").chunks;
+    assert_eq!(g[..f.len() - 1], f[..f.len() - 1]);
+    let count = "SELECT CAST(count(*) AS TEXT) FROM chunks";
+    assert_eq!(rows(&s.path("a.db"), count), [(g.len() + 1).to_string()]);
+    assert_eq!(s.cat("g").unwrap(), appended);
+    s.run(&["Verify"]).unwrap();
+
+    let err = s.run(&["--hash-bytes", "32", "Add", "f"]).unwrap_err();
+    assert!(err.to_string().contains("Compact --hash"), "{}", err);
+}
+
+#[test]
+fn extract_tar_round_trips_through_tar_archive() {
+    use std::io::Read;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let s = Scratch::new("tar");
+    let long = format!("p/{}/f", "d".repeat(120));
+    let files = [
+        ("p/big".to_owned(), sample(3_000_000, 1)),
+        ("p/small".to_owned(), FOX.to_vec()),
+        (long, b"long name".to_vec()),
+    ];
+    for (name, data) in &files {
+        s.write(name, data);
+    }
+    let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let f = fs::File::options()
+        .write(true)
+        .open(s.path("p/small"))
+        .unwrap();
+    f.set_modified(old).unwrap();
+    s.add(&["p"]).unwrap();
+
+    let app = s.parse(&["Extract", "--tar", "--strip-components", "1", "p"]);
+    let (selected, opt) = match app.unwrap().cmd {
+        OptCommand::Extract { files, extract_opt } => (files, extract_opt),
+        _ => unreachable!(),
+    };
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let cache = ChunkCache::new(0);
+    let mut out = Vec::new();
+    let encoding = NameEncoding::Bytes;
+    let summary = extract_tar(&mut trans, selected, &opt, encoding, &cache, &mut out).unwrap();
+    assert_eq!(summary.extracted, 3);
+
+    // Entries come back named as they'd be extracted, with their content and mtime.
+    let mut archive = tar::Archive::new(&out[..]);
+    let mut entries = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_str().unwrap().to_owned();
+        let mtime = entry.header().mtime().unwrap();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        entries.push((name, data, mtime));
+    }
+    entries.sort();
+    let mut expected: Vec<_> = files
+        .iter()
+        .map(|(name, data)| (name["p/".len()..].to_owned(), data.clone()))
+        .collect();
+    expected.sort();
+    let got: Vec<_> = entries
+        .iter()
+        .map(|(n, d, _)| (n.clone(), d.clone()))
+        .collect();
+    assert_eq!(got, expected);
+    let small = entries.iter().find(|(name, _, _)| name == "small").unwrap();
+    assert_eq!(small.2, 1_000_000_000);
+}
+
+#[test]
+fn schema_versions_gate_reading_and_writing() {
+    let s = Scratch::new("schema-version");
+    s.write("f", b"f");
+    s.add(&["f"]).unwrap();
+    let version = |key| get_meta::<u32>(&s.open().connection, key).unwrap();
+    assert_eq!(version("schema_version"), Some(SCHEMA_VERSION));
+    assert!(version("min_reader_version").unwrap() <= SCHEMA_VERSION);
+
+    let set = |key, value: Option<u32>| {
+        let connection = rusqlite::Connection::open(s.path("a.db")).unwrap();
+        match value {
+            Some(value) => set_meta(&connection, key, &value).unwrap(),
+            None => {
+                connection
+                    .execute("DELETE FROM meta WHERE key=?", &[&key])
+                    .unwrap();
+            }
+        }
+    };
+    let refused = |args: &[&str]| match s.run(args).map_err(|e| e.downcast::<ChsqlarError>()) {
+        Err(Ok(e)) => e,
+        other => panic!("{:?}", other.map(|_| ())),
+    };
+
+    // An archive from before versions were recorded reads, but has to be migrated to write.
+    set("schema_version", None);
+    set("min_reader_version", None);
+    s.run(&["List"]).unwrap();
+    match refused(&["Add", "f"]) {
+        ChsqlarError::NeedsMigrate { version: 0 } => {}
+        e => panic!("{}", e),
+    }
+    s.run(&["Migrate"]).unwrap();
+    assert_eq!(version("schema_version"), Some(SCHEMA_VERSION));
+    s.run(&["Add", "f"]).unwrap();
+
+    // A newer one still reads unless it says older readers can't.
+    set("schema_version", Some(SCHEMA_VERSION + 1));
+    s.run(&["List"]).unwrap();
+    match refused(&["Add", "f"]) {
+        ChsqlarError::SchemaTooNew { .. } => {}
+        e => panic!("{}", e),
+    }
+    set("min_reader_version", Some(SCHEMA_VERSION + 1));
+    match refused(&["List"]) {
+        ChsqlarError::SchemaTooNew { required } => assert_eq!(required, SCHEMA_VERSION + 1),
+        e => panic!("{}", e),
+    }
+}
+
+#[test]
+fn dedup_window_skips_compressing_recent_chunks() {
+    let s = Scratch::new("dedup-window");
+    s.run(&["--chunker", "fixed", "--chunk-size", "4096", "Migrate"])
+        .unwrap();
+    // Three blocks, the first repeated right after itself and the second much later.
+    let blocks: Vec<_> = (0..3).map(|i| noise(4096, i)).collect();
+    let order = [0, 0, 1, 2, 0, 2, 1];
+    let data: Vec<u8> = order.iter().flat_map(|&i| blocks[i].clone()).collect();
+    s.write("f", &data);
+    s.write("g", &data);
+
+    let compressed = |summary: AddSummary| summary.metrics.compress_in.into_inner();
+    // Without a window, every chunk is compressed before the insert finds it's there.
+    assert_eq!(compressed(s.add(&["f"]).unwrap()), data.len() as u64);
+    let f = s.stored("f").chunks;
+
+    // With one, only chunks it hasn't seen yet are.
+    let summary = s.add(&["--dedup-window", "100", "g"]).unwrap();
+    assert_eq!(compressed(summary), 3 * 4096);
+    assert_eq!(s.stored("g").chunks, f);
+    assert_eq!(s.cat("g").unwrap(), data);
+
+    // A window of one only catches the block repeated right away.
+    fs::remove_file(s.path("a.db")).unwrap();
+    s.run(&["--chunker", "fixed", "--chunk-size", "4096", "Migrate"])
+        .unwrap();
+    let summary = s.add(&["--dedup-window", "1", "f"]).unwrap();
+    assert_eq!(compressed(summary), 6 * 4096);
+    let refcounts = "SELECT CAST(refcount AS TEXT) FROM chunks ORDER BY refcount";
+    assert_eq!(rows(&s.path("a.db"), refcounts), ["2", "2", "3"]);
+}
+
+/// Answers `request` as a daemon on the scratch archive started with the Add options `args`
+/// would, sending `data` with it for add-bytes.
+#[cfg(unix)]
+fn daemon_request(
+    s: &Scratch,
+    args: &[&str],
+    request: serde_json::Value,
+    data: Option<Vec<u8>>,
+) -> Result<serde_json::Value, Error> {
+    let app = s.parse(&[&["Daemon", "--socket", "socket"], args].concat())?;
+    let add_opt = match app.cmd {
+        OptCommand::Daemon { add_opt, .. } => add_opt,
+        _ => unreachable!(),
+    };
+    let mut db = s.open();
+    let mut reader = rusqlite::Connection::open(s.path("a.db"))?;
+    let budget = MemoryBudget::new(None);
+    let writer = Mutex::new(crate::daemon::Writer {
+        db: &mut db,
+        budget: &budget,
+    });
+    let encoding = NameEncoding::Bytes;
+    crate::daemon::handle(
+        &request,
+        data,
+        &mut reader,
+        &writer,
+        &add_opt,
+        encoding,
+        &app.opt,
+    )
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_add_bytes_stores_chunks_an_earlier_add_removed() {
+    let s = Scratch::new("daemon-window");
+    let data = noise(50_000, 1);
+    s.write("f", &data);
+    let args = ["--dedup-window", "100", "--inline-threshold", "0"];
+    let dir = s.dir.to_str().unwrap();
+    let add = serde_json::json!({ "op": "add-path", "cwd": dir, "paths": [dir.to_owned() + "/f"] });
+    daemon_request(&s, &args, add, None).unwrap();
+    let remove = serde_json::json!({ "op": "remove", "names": ["f"] });
+    daemon_request(&s, &args, remove, None).unwrap();
+    assert!(rows(&s.path("a.db"), "SELECT hash FROM chunks").is_empty());
+
+    // The window of the add-path is gone with it, so the same chunks are stored again.
+    let add_bytes = serde_json::json!({ "op": "add-bytes", "name": "g" });
+    daemon_request(&s, &args, add_bytes, Some(data.clone())).unwrap();
+    assert_eq!(s.cat("g").unwrap(), data);
+    s.run(&["Verify"]).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_names_stay_inside_and_extract_refuses_those_that_dont() {
+    let s = Scratch::new("daemon-names");
+    let add_bytes = |name: &str| {
+        let request = serde_json::json!({ "op": "add-bytes", "name": name });
+        daemon_request(&s, &[], request, Some(FOX.to_vec()))
+    };
+    add_bytes("/etc/passwd").unwrap();
+    add_bytes("../../up").unwrap();
+    add_bytes("./a/../b").unwrap();
+    let err = add_bytes("/..").unwrap_err();
+    assert!(err.to_string().contains("doesn't name a file"), "{}", err);
+    let names = "SELECT name FROM files ORDER BY name";
+    assert_eq!(rows(&s.path("a.db"), names), ["a/b", "etc/passwd", "up"]);
+
+    // An archive written by something else can still hold such names.
+    let escape = format!("../{}-escape", s.dir.file_name().unwrap().to_str().unwrap());
+    let db = s.open();
+    let sql = "INSERT INTO files (name, size, chunks, inline_data, inline_codec)
+        SELECT ?, size, chunks, inline_data, inline_codec FROM files WHERE name='up'";
+    for name in &[&escape, "/abs"] {
+        db.connection.execute(sql, &[name]).unwrap();
+    }
+    assert_eq!(s.extract(&[""]).unwrap().extracted, 3);
+    assert_eq!(fs::read(s.path("up")).unwrap(), FOX);
+    assert!(!s.dir.join(&escape).exists());
+    assert!(!Path::new("/abs").exists());
+}
+
+#[test]
+fn refcounts_stay_equal_to_a_rebuild() {
+    let s = Scratch::new("refcounts");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let drifted = || {
+        let db = s.open();
+        rebuild_refcounts(&db.connection).unwrap().1
+    };
+    s.write("a", &noise(100_000, 1));
+    s.write("b", &noise(100_000, 1));
+    s.write("c", &noise(100_000, 2));
+    s.add(&["a", "b", "c"]).unwrap();
+    assert_eq!(drifted(), 0);
+
+    // Replacing a file, storing a blob and cutting everything again keep them right.
+    s.write("c", &noise(50_000, 3));
+    s.add(&["c"]).unwrap();
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    put_blob(&mut trans, noise(100_000, 1), &MemoryBudget::new(None)).unwrap();
+    trans.commit().unwrap();
+    assert_eq!(drifted(), 0);
+    let zero = "SELECT hash FROM chunks WHERE refcount = 0";
+    assert!(!rows(&s.path("a.db"), zero).is_empty());
+    s.run(&["Compact", "--chunk-bits", "13"]).unwrap();
+    assert_eq!(drifted(), 0);
+    assert!(rows(&s.path("a.db"), zero).is_empty());
+
+    // A count that drifted is found and fixed.
+    db.connection
+        .execute(
+            "UPDATE chunks SET refcount = refcount + 5 WHERE hash = (SELECT min(hash) FROM chunks)",
+            NO_PARAMS,
+        )
+        .unwrap();
+    let most = "SELECT CAST(max(refcount) AS TEXT) FROM chunks";
+    assert_ne!(rows(&s.path("a.db"), most), ["3"]);
+    s.run(&["Compact", "--rebuild-refcounts"]).unwrap();
+    assert_eq!(rows(&s.path("a.db"), most), ["3"]);
+    assert_eq!(drifted(), 0);
+}
+
+#[test]
+fn doctor_names_planted_defects() {
+    let s = Scratch::new("doctor");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    for (i, name) in ["a", "b", "c", "stray"].iter().enumerate() {
+        s.write(name, &noise(100_000, i as u64));
+    }
+    s.add(&["a", "b", "c", "stray"]).unwrap();
+    let doctor = |fix| {
+        let mut out = Vec::new();
+        let healthy = doctor_cmd(&mut s.open(), fix, NameEncoding::Bytes, &mut out).unwrap();
+        (healthy, String::from_utf8(out).unwrap())
+    };
+    let (healthy, report) = doctor(false);
+    assert!(healthy, "{}", report);
+    assert!(
+        report.ends_with("healthy: every check passed\n"),
+        "{}",
+        report
+    );
+
+    let a = s.stored("a").chunks;
+    let b = s.stored("b").chunks;
+    let stray = s.stored("stray").chunks;
+    let db = s.open();
+    let plant = |sql: &str, hash: &str| {
+        db.connection.execute(sql, &[&hash]).unwrap();
+    };
+    plant(
+        "UPDATE files SET chunks = chunks || ';' || ? WHERE name = 'c'",
+        "nothex",
+    );
+    plant("DELETE FROM chunks WHERE hash = ?", &b[0]);
+    plant(
+        "UPDATE chunks SET data = zeroblob(length(data)) WHERE hash = ?",
+        &a[0],
+    );
+    plant(
+        "UPDATE chunks SET refcount = refcount + 1 WHERE hash = ?",
+        &a[1],
+    );
+    plant("DELETE FROM files WHERE name = ?", "stray");
+    for hash in &stray {
+        plant("UPDATE chunks SET refcount = 0 WHERE hash = ?", hash);
+    }
+
+    let (healthy, report) = doctor(false);
+    assert!(!healthy);
+    for expected in &[
+        "chunk lists: 1 malformed entries\n    c: 'nothex' isn't a chunk hash\n".to_owned(),
+        format!(
+            "dangling references: 1 references to chunks that aren't stored\n    b: {}\n",
+            b[0]
+        ),
+        format!(
+            "reference counts: 1 chunks have the wrong count\n    {}\n",
+            a[1]
+        ),
+        format!("unreferenced chunks: {} chunks", stray.len()),
+        "chunk data: 1 of ".to_owned(),
+        format!("    {}: ", a[0]),
+        "unhealthy: 5 of 5 problems left, of which Doctor --fix can fix 1\n".to_owned(),
+    ] {
+        assert!(
+            report.contains(expected.as_str()),
+            "{}\n{}",
+            expected,
+            report
+        );
+    }
+
+    // --fix rebuilds the counts, but keeps the chunks while a list is malformed.
+    let (healthy, report) = doctor(true);
+    assert!(!healthy);
+    assert!(
+        report.contains("not deleting them while chunk lists are malformed"),
+        "{}",
+        report
+    );
+    assert!(doctor(false).1.contains("reference counts: ok"));
+    plant(
+        "UPDATE files SET chunks = replace(chunks, ';nothex', '') WHERE name = ?",
+        "c",
+    );
+    let (_, report) = doctor(true);
+    assert!(
+        report.contains(&format!("  deleted {} chunks", stray.len())),
+        "{}",
+        report
+    );
+    let (_, report) = doctor(false);
+    assert!(
+        report.contains("unhealthy: 2 of 2 problems left\n"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn uncompressed_sizes_are_kept_and_backfilled() {
+    let s = Scratch::new("uncompressed-size");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let (f, g) = (sample(200_000, 1), sample(150_000, 2));
+    s.write("f", &f);
+    s.write("g", &g);
+    s.add(&["f"]).unwrap();
+    // Past level 19, zstd frames record the content size, which the backfill reads.
+    s.add(&["--level", "22", "g"]).unwrap();
+    let sizes = "SELECT hash || ' ' || uncompressed_size FROM chunks ORDER BY hash";
+    let kept = rows(&s.path("a.db"), sizes);
+    let total = "SELECT CAST(sum(uncompressed_size) AS TEXT) FROM chunks";
+    assert_eq!(
+        rows(&s.path("a.db"), total),
+        [(f.len() + g.len()).to_string()]
+    );
+
+    // An archive from before sizes were kept gets them back from Migrate.
+    let db = s.open();
+    db.connection
+        .execute("UPDATE chunks SET uncompressed_size = NULL", NO_PARAMS)
+        .unwrap();
+    set_meta(&db.connection, "schema_version", &2).unwrap();
+    s.run(&["Migrate"]).unwrap();
+    assert_eq!(rows(&s.path("a.db"), sizes), kept);
+
+    // Verify notices a recorded size that's wrong.
+    let hash = s.stored("f").chunks.remove(0);
+    db.connection
+        .execute(
+            "UPDATE chunks SET uncompressed_size = uncompressed_size + 1 WHERE hash = ?",
+            &[&hash],
+        )
+        .unwrap();
+    let mut faults = Vec::new();
+    verify_chunks(&db.connection, false, |hash, fault| {
+        faults.push((hash.to_owned(), fault.to_string()))
+    })
+    .unwrap();
+    let length = "decompressed length doesn't match the recorded one".to_owned();
+    assert_eq!(faults, [(hash, length)]);
+}
+
+#[test]
+fn compact_dry_run_reports_and_deletes_nothing() {
+    let s = Scratch::new("dry-run");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    s.write("a", &noise(100_000, 1));
+    s.write("b", &sample(100_000, 1));
+    s.add(&["a", "b"]).unwrap();
+    s.write("a", &noise(50_000, 2));
+    s.write("b", &sample(50_000, 2));
+    s.add(&["a", "b"]).unwrap();
+
+    let db = s.open();
+    let totals = "SELECT count(*) || ' ' || sum(COALESCE(size, length(data))) || ' '
+                  || sum(uncompressed_size) FROM chunks WHERE refcount = 0";
+    let orphans: Vec<String> = rows(&s.path("a.db"), totals)[0]
+        .split(' ')
+        .map(str::to_owned)
+        .collect();
+    assert_ne!(orphans[0], "0");
+    let everything = "SELECT hash FROM chunks ORDER BY hash";
+    let before = rows(&s.path("a.db"), everything);
+
+    let dry_run = |rebuild| {
+        let mut out = Vec::new();
+        compact_dry_run(&mut s.open(), rebuild, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let report = dry_run(false);
+    let expected = format!(
+        "would remove {} old chunks ({} bytes, {} uncompressed)\n",
+        orphans[0], orphans[1], orphans[2]
+    );
+    assert!(report.starts_with(&expected), "{}", report);
+    assert!(report.contains("\n  store: "), "{}", report);
+    assert!(report.contains("\n  zstd: "), "{}", report);
+    assert_eq!(rows(&s.path("a.db"), everything), before);
+
+    // A drifted count is previewed as rebuilt, and left as it was.
+    let live = s.stored("a").chunks.remove(0);
+    db.connection
+        .execute("UPDATE chunks SET refcount = 0 WHERE hash = ?", &[&live])
+        .unwrap();
+    let report = dry_run(true);
+    assert!(report.contains(", 1 have drifted\n"), "{}", report);
+    assert!(report.contains(&expected), "{}", report);
+    let zero = "SELECT hash FROM chunks WHERE refcount = 0";
+    assert!(rows(&s.path("a.db"), zero).contains(&live));
+
+    s.run(&["Compact", "--rebuild-refcounts"]).unwrap();
+    let removed = before.len() - rows(&s.path("a.db"), everything).len();
+    assert_eq!(removed.to_string(), orphans[0]);
+}
+
+#[test]
+fn compact_converts_between_chunk_hashes() {
+    let s = Scratch::new("hash-convert");
+    assert!(s
+        .run(&["--hash", "sha3-512", "--hash-bytes", "16", "Migrate"])
+        .is_err());
+    s.run(&["--hash", "blake3", "--chunk-bits", "14", "Migrate"])
+        .unwrap();
+    let data = noise(100_000, 1);
+    s.write("f", &data);
+    s.add(&["f"]).unwrap();
+    let lengths = "SELECT DISTINCT CAST(length(hash) AS TEXT) FROM chunks";
+    assert_eq!(rows(&s.path("a.db"), lengths), ["64"]);
+    let blake3 = s.stored("f").chunks;
+
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    let blob = put_blob(&mut trans, noise(50_000, 2), &MemoryBudget::new(None)).unwrap();
+    trans.commit().unwrap();
+
+    // Every chunk is stored again under the new hash, and blobs keep theirs.
+    s.run(&["Compact", "--hash", "sha3-512"]).unwrap();
+    assert_eq!(ChunkHash::load(&db.connection).unwrap(), ChunkHash::Sha3);
+    assert_eq!(rows(&s.path("a.db"), lengths), ["128"]);
+    assert_eq!(s.cat("f").unwrap(), data);
+    let mut trans = db.connection.transaction().unwrap();
+    assert_eq!(get_blob(&mut trans, &blob).unwrap(), noise(50_000, 2));
+    drop(trans);
+    s.run(&["Verify"]).unwrap();
+    let err = s.run(&["--hash", "blake3", "Add", "f"]).unwrap_err();
+    assert!(err.to_string().contains("Compact --hash blake3"), "{}", err);
+
+    // And back, to the same chunks as before.
+    s.run(&["Compact", "--hash", "blake3"]).unwrap();
+    assert_eq!(s.stored("f").chunks, blake3);
+    s.run(&["Verify"]).unwrap();
+}
+
+#[test]
+fn temp_dir_must_exist_and_is_handed_to_sqlite() {
+    let s = Scratch::new("temp-dir");
+    let before = std::env::var_os("SQLITE_TMPDIR");
+    let set = |dir: &str| {
+        let app = s.parse(&["--temp-dir", dir, "Info"]).unwrap();
+        set_sqlite_temp_dir(&app.opt.db_opt)
+    };
+
+    let err = set("missing").unwrap_err();
+    assert!(err.to_string().contains("is not a directory"), "{}", err);
+    assert_eq!(std::env::var_os("SQLITE_TMPDIR"), before);
+
+    // SQLite reads the variable once per process, so where its files end up can't be
+    // checked from here, only that it's set.
+    fs::create_dir(s.path("scratch")).unwrap();
+    let scratch = s.path("scratch");
+    let result = set(scratch.to_str().unwrap());
+    let handed = std::env::var_os("SQLITE_TMPDIR");
+    match &before {
+        Some(before) => std::env::set_var("SQLITE_TMPDIR", before),
+        None => std::env::remove_var("SQLITE_TMPDIR"),
+    }
+    result.unwrap();
+    assert_eq!(handed, Some(scratch.into_os_string()));
+}
+
+#[test]
+fn verify_after_commit_catches_a_corrupted_chunk() {
+    let s = Scratch::new("verify-after-commit");
+    s.run(&["--chunk-bits", "14", "Migrate"]).unwrap();
+    let data = noise(100_000, 1);
+    for name in &["a", "copy", "other"] {
+        s.write(name, &data);
+    }
+    s.add(&["--verify-after-commit", "a"]).unwrap();
+
+    // Replaced with other bytes of the same length and a matching checksum, so only reading
+    // the file back shows it.
+    let hash = s.stored("a").chunks.remove(1);
+    let db = s.open();
+    let stored: Vec<u8> = db
+        .connection
+        .query_row("SELECT data FROM chunks WHERE hash = ?", &[&hash], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let forged = noise(stored.len(), 2);
+    db.connection
+        .execute(
+            "UPDATE chunks SET data = ?, checksum = ? WHERE hash = ?",
+            &[&forged as &dyn ToSql, &checksum_blob(&forged), &hash],
+        )
+        .unwrap();
+
+    // A copy that dedups to the chunk reads back differently, and fails the add.
+    let err = match s.add(&["--verify-after-commit", "copy"]) {
+        Err(err) => err,
+        Ok(_) => panic!("a corrupted chunk read back the same"),
+    };
+    let expected = "1 of 1 files added read back differently";
+    assert!(err.to_string().contains(expected), "{}", err);
+    // Without the check, the same add goes through.
+    s.add(&["other"]).unwrap();
+    assert_ne!(s.cat("other").unwrap(), data);
+}
+
+#[test]
+fn content_hashes_are_stored_checked_and_backfilled() {
+    let s = Scratch::new("content-hash");
+    let data = noise(100_000, 1);
+    s.write("f", &data);
+    s.write("small", FOX);
+    s.add(&["f", "small"]).unwrap();
+    assert_eq!(s.stored("f").content_hash, Some(ChunkHash::Sha3.of(&data)));
+    assert_eq!(
+        s.stored("small").content_hash,
+        Some(ChunkHash::Sha3.of(FOX))
+    );
+    s.run(&["Verify", "--files"]).unwrap();
+
+    let db = s.open();
+    let set = |hash: Option<&str>| {
+        db.connection
+            .execute("UPDATE files SET content_hash=? WHERE name='f'", &[&hash])
+            .unwrap();
+    };
+    let planted = "0".repeat(128);
+    set(Some(&planted));
+    let err = s.run(&["Verify", "--files"]).unwrap_err();
+    assert_eq!(err.to_string(), "1 files failed verification");
+
+    // Checksum gives the stored hash rather than reading the file again.
+    let mut out = Vec::new();
+    let (cache, encoding) = (ChunkCache::new(0), NameEncoding::Bytes);
+    let f = Some(PathBuf::from("f"));
+    checksum_cmd(&mut s.open(), "sha3-512", f, 0, encoding, &cache, &mut out).unwrap();
+    assert_eq!(out, format!("{}  f\n", planted).as_bytes());
+
+    // A file without one is skipped, until Migrate fills it in.
+    set(None);
+    s.run(&["Verify", "--files"]).unwrap();
+    s.run(&["Migrate", "--compute-file-hashes"]).unwrap();
+    assert_eq!(s.stored("f").content_hash, Some(ChunkHash::Sha3.of(&data)));
+}
+
+#[test]
+fn tags_filter_the_list() {
+    let s = Scratch::new("tags");
+    for name in &["a", "b", "c"] {
+        s.write(name, name.as_bytes());
+    }
+    s.add(&["a", "b", "c"]).unwrap();
+    s.run(&["SetTag", "a", "host", "x"]).unwrap();
+    s.run(&["SetTag", "b", "host", "y"]).unwrap();
+    s.run(&["SetTag", "b", "host", "x"]).unwrap();
+    s.run(&["SetTag", "b", "kind", "log"]).unwrap();
+    assert!(s.run(&["SetTag", "missing", "host", "x"]).is_err());
+    assert!(s.run(&["SetTag", "a", "k=v", "x"]).is_err());
+    assert!(s.parse(&["List", "--tag", "host"]).is_err());
+
+    let list = |tags: &[&str]| {
+        let mut args = vec!["List"];
+        for tag in tags {
+            args.extend(&["--tag", tag]);
+        }
+        let tags = match s.parse(&args).unwrap().cmd {
+            OptCommand::List { tags, .. } => tags,
+            _ => unreachable!(),
+        };
+        let mut out = Vec::new();
+        let encoding = NameEncoding::Bytes;
+        list_cmd(
+            &mut s.open(),
+            false,
+            false,
+            None,
+            &tags,
+            b' ',
+            encoding,
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(list(&[]), "a b c ");
+    assert_eq!(list(&["host=x"]), "a b ");
+    assert_eq!(list(&["host=x", "kind=log"]), "b ");
+    assert_eq!(list(&["host=y"]), "");
+
+    let tags = |name: &str| {
+        let mut out = Vec::new();
+        get_tags_cmd(&mut s.open(), name.into(), NameEncoding::Bytes, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(tags("b"), "host=x\nkind=log\n");
+    let mut out = Vec::new();
+    let cache = ChunkCache::new(0);
+    manifest_cmd(
+        &mut s.open(),
+        "json-lines",
+        NameEncoding::Bytes,
+        &cache,
+        &mut out,
+    )
+    .unwrap();
+    let b: serde_json::Value =
+        serde_json::from_slice(out.split(|&c| c == b'\n').nth(1).unwrap()).unwrap();
+    assert_eq!(b["tags"], serde_json::json!({"host": "x", "kind": "log"}));
+
+    // Tags survive adding the file again, and go with it when it's removed.
+    s.write("b", b"changed");
+    s.add(&["b"]).unwrap();
+    assert_eq!(tags("b"), "host=x\nkind=log\n");
+    let mut db = s.open();
+    let mut trans = db.connection.transaction().unwrap();
+    remove_files(&mut trans, &[PathBuf::from("b")], NameEncoding::Bytes).unwrap();
+    trans.commit().unwrap();
+    assert_eq!(
+        rows(&s.path("a.db"), "SELECT file_name FROM file_meta"),
+        ["a"]
+    );
+}
+
+#[test]
+fn dictionaries_compress_small_chunks_and_stay_readable() {
+    // Only one scratch directory can be open at a time.
+    {
+        let e = Scratch::new("zstd-dict-encrypted");
+        encrypt(&e, false);
+        e.write("f", FOX);
+        e.run(&["--key-file", "key", "Add", "--inline-threshold", "0", "f"])
+            .unwrap();
+        let err = e.run(&["--key-file", "key", "TrainDict"]).unwrap_err();
+        assert!(err.to_string().contains("encrypted archive"), "{}", err);
+    }
+
+    let s = Scratch::new("zstd-dict");
+    // Small JSON records that share their keys, which a dictionary learns.
+    let record = |i: usize| {
+        let n = noise(8, i as u64);
+        format!(
+            "{{\"id\": {}, \"name\": \"user-{}\", \"email\": \"user{}@example.com\", \
+             \"active\": {}, \"score\": {}, \"tags\": [\"alpha\", \"beta\"]}}\n",
+            i,
+            n[0],
+            n[1],
+            n[2] < 128,
+            n[3]
+        )
+        .repeat(4)
+    };
+    let names: Vec<String> = (0..300).map(|i| format!("j{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        s.write(name, record(i).as_bytes());
+    }
+    let mut args = vec!["--inline-threshold", "0"];
+    args.extend(names.iter().map(String::as_str));
+    s.add(&args).unwrap();
+    let err = s.run(&["Add", "--use-dict", "j0"]).unwrap_err();
+    assert!(err.to_string().contains("TrainDict"), "{}", err);
+
+    s.run(&["TrainDict", "--max-size", "4K"]).unwrap();
+    let db = s.open();
+    let first: u32 = get_meta(&db.connection, "zstd_dict").unwrap().unwrap();
+    assert!(
+        get_meta::<u32>(&db.connection, "min_reader_version")
+            .unwrap()
+            .unwrap()
+            >= 5
+    );
+    let mut out = Vec::new();
+    stats_cmd(&mut s.open(), &mut out).unwrap();
+    let stats = String::from_utf8(out).unwrap();
+    assert!(
+        stats.contains(&format!("zstd dictionary: {}", first)),
+        "{}",
+        stats
+    );
+
+    // New chunks and inline files use the dictionary, and read back.
+    s.write("new", record(1000).as_bytes());
+    s.write("inline", record(1001).as_bytes());
+    s.add(&["--use-dict", "--inline-threshold", "0", "new"])
+        .unwrap();
+    s.add(&["--use-dict", "inline"]).unwrap();
+    assert!(s.treatments("new").iter().all(|t| t.0 == "zstd-dict"));
+    assert_eq!(s.stored("inline").inline.unwrap().0, "zstd-dict");
+    assert_eq!(s.cat("new").unwrap(), record(1000).as_bytes());
+    assert_eq!(s.cat("inline").unwrap(), record(1001).as_bytes());
+
+    // Existing chunks are converted, and stay readable after a second dictionary.
+    s.run(&["Verify", "--repair-compression", "3", "--use-dict"])
+        .unwrap();
+    assert!(s
+        .treatments("j0")
+        .iter()
+        .all(|t| *t == ("zstd-dict".to_owned(), Some(3))));
+    s.run(&["TrainDict", "--max-size", "4K"]).unwrap();
+    let dicts = "SELECT key FROM meta WHERE key LIKE 'zstd_dict/%'";
+    assert_eq!(rows(&s.path("a.db"), dicts).len(), 2);
+    s.run(&["Verify"]).unwrap();
+    s.run(&["Compact"]).unwrap();
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(s.cat(name).unwrap(), record(i).as_bytes());
+    }
+}
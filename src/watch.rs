@@ -0,0 +1,166 @@
+//! `Watch` keeps an archive up to date with a directory, adding files again as they're
+//! created or modified.
+//!
+//! Changes are collected until none have come in for the debounce period, then added in one
+//! go with --skip-unchanged, so a file written several times is only read once and events
+//! that didn't change the content's size or mtime cost nothing. Deleted files stay in the
+//! archive.
+
+use crate::output::{paint, Stream, Style};
+use crate::{
+    add_files, install_interrupt_handler, store_dir, AddOpt, CommonOpt, MemoryBudget, NameEncoding,
+    SqliteDatabase, INTERRUPTED,
+};
+use failure::{format_err, Error};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::env::current_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How often the loop checks for Ctrl-C while no events come in.
+const POLL: Duration = Duration::from_millis(100);
+
+pub(crate) fn watch_cmd(
+    db: &mut SqliteDatabase,
+    paths: Vec<PathBuf>,
+    debounce: Duration,
+    add_opt: AddOpt,
+    encoding: NameEncoding,
+    opt: &CommonOpt,
+) -> Result<(), Error> {
+    install_interrupt_handler()?;
+    let add_opt = watch_add_opt(add_opt);
+    let cwd = current_dir()?;
+    let budget = MemoryBudget::new(opt.memory_limit);
+
+    let ignored = archive_paths(Path::new(&opt.database), store_dir(&db.connection)?)?;
+
+    let (sender, events) = channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for path in &paths {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+    }
+
+    // Watching starts first so nothing changed during the initial pass is missed.
+    let mut pending: BTreeSet<PathBuf> = paths.into_iter().collect();
+    let mut last_event: Option<Instant> = None;
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match events.recv_timeout(POLL) {
+            Ok(Err(e)) => {
+                // Like an overflowed event queue, which is worth knowing about but shouldn't
+                // stop the watching.
+                let warning = format!("watching failed: {}", e);
+                eprintln!("{}", paint(Stream::Stderr, Style::Warning, warning));
+                continue;
+            }
+            Ok(Ok(event)) => {
+                if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
+                    pending.extend(event.paths);
+                    last_event = Some(Instant::now());
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if pending.is_empty() || last_event.is_some_and(|t| t.elapsed() < debounce) {
+            continue;
+        }
+
+        let changed = std::mem::take(&mut pending)
+            .into_iter()
+            .filter(|path| is_watched(path, &ignored))
+            .collect();
+        ingest(db, &cwd, changed, &add_opt, encoding, opt, &budget)?;
+    }
+
+    if !pending.is_empty() {
+        eprintln!("stopped with {} changes not yet added", pending.len());
+    }
+
+    Ok(())
+}
+
+/// The Add options `ingest` adds changes with.
+pub(crate) fn watch_add_opt(mut add_opt: AddOpt) -> AddOpt {
+    add_opt.skip_unchanged = true;
+    // Each batch of changes is small, and files left out of one would never be retried.
+    add_opt.time_budget = None;
+    // The paths watched are usually directories, and so are many of the changes.
+    add_opt.no_recurse = false;
+    add_opt
+}
+
+/// The paths the archive writes to, whose changes would otherwise be added: the database,
+/// the files SQLite keeps beside it, and `store_dir`.
+pub(crate) fn archive_paths(
+    database: &Path,
+    store_dir: Option<PathBuf>,
+) -> Result<Vec<PathBuf>, Error> {
+    let database = fs::canonicalize(database)?;
+    let mut paths: Vec<PathBuf> = ["-wal", "-shm", "-journal"]
+        .iter()
+        .map(|suffix| {
+            let mut path = database.clone().into_os_string();
+            path.push(suffix);
+            path.into()
+        })
+        .collect();
+    paths.push(database);
+    paths.extend(store_dir);
+
+    Ok(paths)
+}
+
+/// Whether `path` still exists and isn't one of `ignored` or under one of them.
+pub(crate) fn is_watched(path: &Path, ignored: &[PathBuf]) -> bool {
+    match fs::canonicalize(path) {
+        Ok(path) => !ignored.iter().any(|ignored| path.starts_with(ignored)),
+        Err(_) => false,
+    }
+}
+
+/// Adds `changed`, printing how much was stored. Files that can't be read are reported as
+/// they fail, like in Add.
+pub(crate) fn ingest(
+    db: &mut SqliteDatabase,
+    cwd: &Path,
+    changed: Vec<PathBuf>,
+    add_opt: &AddOpt,
+    encoding: NameEncoding,
+    opt: &CommonOpt,
+    budget: &MemoryBudget,
+) -> Result<(), Error> {
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let summary = add_files(
+        db,
+        cwd,
+        changed,
+        add_opt.clone(),
+        opt.verbosity,
+        encoding,
+        budget,
+    )?;
+
+    let stored = summary.metrics.files.load(Ordering::Relaxed);
+    if stored > 0 {
+        eprintln!("stored {} changed files, {} bytes", stored, summary.bytes);
+    }
+
+    if !summary.over_quota.is_empty() {
+        return Err(format_err!(
+            "archive reached --max-db-size with {} changed files not added; stopped watching",
+            summary.over_quota.len()
+        ));
+    }
+
+    Ok(())
+}